@@ -1,185 +1,170 @@
-use crate::debug::disassemble;
-use crate::value::Value;
+use crate::debug::{check_stack, disassemble};
+use crate::symbol::Symbol;
+use crate::value::{Function, Value};
+use parking_lot::RwLock;
 use std::fmt::Display;
+use std::rc::Rc;
 
-#[derive(Debug)]
-pub enum OpCode {
-    Return = 0x01,
-    Negate,
-    Add,
-    Subtract,
-    Multiply,
-    Divide,
-    Constant,
-    Nil,
-    True,
-    False,
-    Not,
-    Equal,
-    Greater,
-    Less,
-    Print,
-    Pop,
-    DefineGlobal,
-    GetGlobal,
-    SetGlobal,
-    GetLocal,
-    SetLocal,
-    JumpIfFalse,
-    Jump,
-    Loop,
-    Duplicate,
-    JumpIfTrue,
-    Call,
-    Closure,
-    GetUpvalue,
-    SetUpvalue,
-    CloseUpvalue,
-    Class,
-    GetProperty,
-    SetProperty,
-    Method,
-    Invoke,
-    Inherit,
-    GetSuper,
-    SuperInvoke,
-}
+/// Declares `OpCode` together with its byte encoding and display names, so
+/// adding an opcode only touches the list below. Variants are numbered
+/// contiguously from 0 in declaration order.
+macro_rules! opcodes {
+    ($($name:ident => $display:literal,)*) => {
+        #[derive(Debug, Clone, Copy, PartialEq)]
+        #[repr(u8)]
+        pub enum OpCode {
+            $($name,)*
+        }
+
+        const OPCODES: &[OpCode] = &[$(OpCode::$name,)*];
+
+        pub const OPCODE_COUNT: usize = OPCODES.len();
 
-impl From<u8> for OpCode {
-    fn from(byte: u8) -> Self {
-        match byte {
-            0x01 => OpCode::Return,
-            0x02 => OpCode::Negate,
-            0x03 => OpCode::Add,
-            0x04 => OpCode::Subtract,
-            0x05 => OpCode::Multiply,
-            0x06 => OpCode::Divide,
-            0x07 => OpCode::Constant,
-            0x08 => OpCode::Nil,
-            0x09 => OpCode::True,
-            0x0A => OpCode::False,
-            0x0B => OpCode::Not,
-            0x0C => OpCode::Equal,
-            0x0D => OpCode::Greater,
-            0x0E => OpCode::Less,
-            0x0F => OpCode::Print,
-            0x10 => OpCode::Pop,
-            0x11 => OpCode::DefineGlobal,
-            0x12 => OpCode::GetGlobal,
-            0x13 => OpCode::SetGlobal,
-            0x14 => OpCode::GetLocal,
-            0x15 => OpCode::SetLocal,
-            0x16 => OpCode::JumpIfFalse,
-            0x17 => OpCode::Jump,
-            0x18 => OpCode::Loop,
-            0x19 => OpCode::Duplicate,
-            0x1A => OpCode::JumpIfTrue,
-            0x1B => OpCode::Call,
-            0x1C => OpCode::Closure,
-            0x1D => OpCode::GetUpvalue,
-            0x1E => OpCode::SetUpvalue,
-            0x1F => OpCode::CloseUpvalue,
-            0x20 => OpCode::Class,
-            0x21 => OpCode::GetProperty,
-            0x22 => OpCode::SetProperty,
-            0x23 => OpCode::Method,
-            0x24 => OpCode::Invoke,
-            0x25 => OpCode::Inherit,
-            0x26 => OpCode::GetSuper,
-            0x27 => OpCode::SuperInvoke,
-            _ => panic!("Unknown OpCode: {}", byte),
+        // Every opcode must be encodable in a single byte.
+        const _: () = assert!(OPCODE_COUNT <= u8::MAX as usize + 1);
+
+        impl TryFrom<u8> for OpCode {
+            type Error = u8;
+
+            fn try_from(byte: u8) -> Result<Self, Self::Error> {
+                OPCODES.get(byte as usize).copied().ok_or(byte)
+            }
         }
-    }
-}
 
-impl From<OpCode> for u8 {
-    fn from(op: OpCode) -> Self {
-        match op {
-            OpCode::Return => 0x01,
-            OpCode::Negate => 0x02,
-            OpCode::Add => 0x03,
-            OpCode::Subtract => 0x04,
-            OpCode::Multiply => 0x05,
-            OpCode::Divide => 0x06,
-            OpCode::Constant => 0x07,
-            OpCode::Nil => 0x08,
-            OpCode::True => 0x09,
-            OpCode::False => 0x0A,
-            OpCode::Not => 0x0B,
-            OpCode::Equal => 0x0C,
-            OpCode::Greater => 0x0D,
-            OpCode::Less => 0x0E,
-            OpCode::Print => 0x0F,
-            OpCode::Pop => 0x10,
-            OpCode::DefineGlobal => 0x11,
-            OpCode::GetGlobal => 0x12,
-            OpCode::SetGlobal => 0x13,
-            OpCode::GetLocal => 0x14,
-            OpCode::SetLocal => 0x15,
-            OpCode::JumpIfFalse => 0x16,
-            OpCode::Jump => 0x17,
-            OpCode::Loop => 0x18,
-            OpCode::Duplicate => 0x19,
-            OpCode::JumpIfTrue => 0x1A,
-            OpCode::Call => 0x1B,
-            OpCode::Closure => 0x1C,
-            OpCode::GetUpvalue => 0x1D,
-            OpCode::SetUpvalue => 0x1E,
-            OpCode::CloseUpvalue => 0x1F,
-            OpCode::Class => 0x20,
-            OpCode::GetProperty => 0x21,
-            OpCode::SetProperty => 0x22,
-            OpCode::Method => 0x23,
-            OpCode::Invoke => 0x24,
-            OpCode::Inherit => 0x25,
-            OpCode::GetSuper => 0x26,
-            OpCode::SuperInvoke => 0x27,
+        impl From<OpCode> for u8 {
+            fn from(op: OpCode) -> Self {
+                op as u8
+            }
         }
-    }
+
+        impl Display for OpCode {
+            fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                match self {
+                    $(OpCode::$name => write!(f, $display),)*
+                }
+            }
+        }
+    };
+}
+
+opcodes! {
+    Return => "RETURN",
+    Negate => "NEGATE",
+    Add => "ADD",
+    Subtract => "SUBTRACT",
+    Multiply => "MULTIPLY",
+    Divide => "DIVIDE",
+    Modulo => "MODULO",
+    Power => "POWER",
+    Constant => "CONSTANT",
+    Nil => "NIL",
+    True => "TRUE",
+    False => "FALSE",
+    Not => "NOT",
+    Equal => "EQUAL",
+    Greater => "GREATER",
+    Less => "LESS",
+    Print => "PRINT",
+    Pop => "POP",
+    PopN => "POP_N",
+    DefineGlobal => "DEFINE_GLOBAL",
+    GetGlobal => "GET_GLOBAL",
+    SetGlobal => "SET_GLOBAL",
+    GetLocal => "GET_LOCAL",
+    SetLocal => "SET_LOCAL",
+    JumpIfFalse => "JUMP_IF_FALSE",
+    Jump => "JUMP",
+    Loop => "LOOP",
+    Duplicate => "DUPLICATE",
+    Tuck => "TUCK",
+    CheckCount => "CHECK_COUNT",
+    Resource => "RESOURCE",
+    BuildArray => "BUILD_ARRAY",
+    Next => "NEXT",
+    ToString => "TO_STRING",
+    JumpIfTrue => "JUMP_IF_TRUE",
+    Call => "CALL",
+    Closure => "CLOSURE",
+    GetUpvalue => "GET_UPVALUE",
+    SetUpvalue => "SET_UPVALUE",
+    CloseUpvalue => "CLOSE_UPVALUE",
+    Class => "CLASS",
+    GetProperty => "GET_PROPERTY",
+    SetProperty => "SET_PROPERTY",
+    GetIndex => "GET_INDEX",
+    SetIndex => "SET_INDEX",
+    Method => "METHOD",
+    Invoke => "INVOKE",
+    Inherit => "INHERIT",
+    GetSuper => "GET_SUPER",
+    SuperInvoke => "SUPER_INVOKE",
+    Switch => "SWITCH",
+    TailCall => "TAIL_CALL",
+    ConstantLong => "CONSTANT_LONG",
+    DefineGlobalLong => "DEFINE_GLOBAL_LONG",
+    GetGlobalLong => "GET_GLOBAL_LONG",
+    SetGlobalLong => "SET_GLOBAL_LONG",
+    ClassLong => "CLASS_LONG",
+    GetPropertyLong => "GET_PROPERTY_LONG",
+    SetPropertyLong => "SET_PROPERTY_LONG",
+    MethodLong => "METHOD_LONG",
+    GetSuperLong => "GET_SUPER_LONG",
+    InvokeLong => "INVOKE_LONG",
+    SuperInvokeLong => "SUPER_INVOKE_LONG",
+    ClosureLong => "CLOSURE_LONG",
+    SwitchLong => "SWITCH_LONG",
 }
 
-impl Display for OpCode {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        match self {
-            OpCode::Return => write!(f, "RETURN"),
-            OpCode::Constant => write!(f, "CONSTANT"),
-            OpCode::Add => write!(f, "ADD"),
-            OpCode::Subtract => write!(f, "SUBTRACT"),
-            OpCode::Multiply => write!(f, "MULTIPLY"),
-            OpCode::Divide => write!(f, "DIVIDE"),
-            OpCode::Negate => write!(f, "NEGATE"),
-            OpCode::Nil => write!(f, "NIL"),
-            OpCode::True => write!(f, "TRUE"),
-            OpCode::False => write!(f, "FALSE"),
-            OpCode::Not => write!(f, "NOT"),
-            OpCode::Equal => write!(f, "EQUAL"),
-            OpCode::Greater => write!(f, "GREATER"),
-            OpCode::Less => write!(f, "LESS"),
-            OpCode::Print => write!(f, "PRINT"),
-            OpCode::Pop => write!(f, "POP"),
-            OpCode::DefineGlobal => write!(f, "DEFINE_GLOBAL"),
-            OpCode::GetGlobal => write!(f, "GET_GLOBAL"),
-            OpCode::SetGlobal => write!(f, "SET_GLOBAL"),
-            OpCode::GetLocal => write!(f, "GET_LOCAL"),
-            OpCode::SetLocal => write!(f, "SET_LOCAL"),
-            OpCode::JumpIfFalse => write!(f, "JUMP_IF_FALSE"),
-            OpCode::Jump => write!(f, "JUMP"),
-            OpCode::Loop => write!(f, "LOOP"),
-            OpCode::Duplicate => write!(f, "DUPLICATE"),
-            OpCode::JumpIfTrue => write!(f, "JUMP_IF_TRUE"),
-            OpCode::Call => write!(f, "CALL"),
-            OpCode::Closure => write!(f, "CLOSURE"),
-            OpCode::GetUpvalue => write!(f, "GET_UPVALUE"),
-            OpCode::SetUpvalue => write!(f, "SET_UPVALUE"),
-            OpCode::CloseUpvalue => write!(f, "CLOSE_UPVALUE"),
-            OpCode::Class => write!(f, "CLASS"),
-            OpCode::GetProperty => write!(f, "GET_PROPERTY"),
-            OpCode::SetProperty => write!(f, "SET_PROPERTY"),
-            OpCode::Method => write!(f, "METHOD"),
-            OpCode::Invoke => write!(f, "INVOKE"),
-            OpCode::Inherit => write!(f, "INHERIT"),
-            OpCode::GetSuper => write!(f, "GET_SUPER"),
-            OpCode::SuperInvoke => write!(f, "SUPER_INVOKE"),
+impl OpCode {
+    /// The form of an opcode whose constant operand is a 24-bit index
+    /// rather than a byte, for chunks with more than 256 constants.
+    pub fn long_form(self) -> Option<OpCode> {
+        let long = match self {
+            OpCode::Constant => OpCode::ConstantLong,
+            OpCode::DefineGlobal => OpCode::DefineGlobalLong,
+            OpCode::GetGlobal => OpCode::GetGlobalLong,
+            OpCode::SetGlobal => OpCode::SetGlobalLong,
+            OpCode::Class => OpCode::ClassLong,
+            OpCode::GetProperty => OpCode::GetPropertyLong,
+            OpCode::SetProperty => OpCode::SetPropertyLong,
+            OpCode::Method => OpCode::MethodLong,
+            OpCode::GetSuper => OpCode::GetSuperLong,
+            OpCode::Invoke => OpCode::InvokeLong,
+            OpCode::SuperInvoke => OpCode::SuperInvokeLong,
+            OpCode::Closure => OpCode::ClosureLong,
+            OpCode::Switch => OpCode::SwitchLong,
+            _ => return None,
+        };
+        Some(long)
+    }
+
+    /// Whether the opcode's constant operand is a 24-bit index.
+    pub fn is_long(self) -> bool {
+        matches!(
+            self,
+            OpCode::ConstantLong
+                | OpCode::DefineGlobalLong
+                | OpCode::GetGlobalLong
+                | OpCode::SetGlobalLong
+                | OpCode::ClassLong
+                | OpCode::GetPropertyLong
+                | OpCode::SetPropertyLong
+                | OpCode::MethodLong
+                | OpCode::GetSuperLong
+                | OpCode::InvokeLong
+                | OpCode::SuperInvokeLong
+                | OpCode::ClosureLong
+                | OpCode::SwitchLong
+        )
+    }
+
+    /// Bytes taken by the opcode's constant index: three for the long forms
+    /// and one otherwise.
+    pub fn constant_width(self) -> usize {
+        if self.is_long() {
+            3
+        } else {
+            1
         }
     }
 }
@@ -189,6 +174,7 @@ pub struct Chunk {
     pub code: Vec<u8>,
     pub constants: Vec<Value>,
     pub lines: Vec<usize>,
+    pub columns: Vec<usize>,
 }
 
 impl Chunk {
@@ -197,13 +183,15 @@ impl Chunk {
             code: Vec::with_capacity(256),
             constants: Vec::with_capacity(256),
             lines: Vec::with_capacity(256),
+            columns: Vec::with_capacity(256),
         }
     }
 
     #[inline(always)]
-    pub fn write(&mut self, byte: u8, line: usize) {
+    pub fn write(&mut self, byte: u8, line: usize, column: usize) {
         self.code.push(byte);
         self.lines.push(line);
+        self.columns.push(column);
     }
 
     #[inline(always)]
@@ -214,6 +202,533 @@ impl Chunk {
 
     #[inline(always)]
     pub fn disassemble(&self, name: &str, current_offset: Option<usize>) {
-        disassemble(self, name, current_offset);
+        print!("{}", disassemble(self, name, current_offset));
+    }
+}
+
+// Precompiled bytecode files start with this magic and a format version,
+// followed by the script function. Integers are little-endian; lengths and
+// counts are u32.
+pub const BYTECODE_MAGIC: &[u8; 4] = b"LBC\0";
+pub const BYTECODE_VERSION: u8 = 2;
+
+const TAG_NIL: u8 = 0;
+const TAG_BOOL: u8 = 1;
+const TAG_INT: u8 = 2;
+const TAG_FLOAT: u8 = 3;
+const TAG_STRING: u8 = 4;
+const TAG_SYMBOL: u8 = 5;
+const TAG_FUNCTION: u8 = 6;
+
+/// Encodes a compiled script, nested functions and constants included, in
+/// the bytecode file format.
+pub fn serialize(function: &Function) -> Result<Vec<u8>, String> {
+    let mut out = BYTECODE_MAGIC.to_vec();
+    out.push(BYTECODE_VERSION);
+    write_function(&mut out, function)?;
+    Ok(out)
+}
+
+fn write_u32(out: &mut Vec<u8>, n: usize) {
+    out.extend_from_slice(&(n as u32).to_le_bytes());
+}
+
+fn write_str(out: &mut Vec<u8>, s: &str) {
+    write_u32(out, s.len());
+    out.extend_from_slice(s.as_bytes());
+}
+
+// name, arity, upvalue count, variadic flag, code, one line and one column
+// per code byte, then the constants.
+fn write_function(out: &mut Vec<u8>, function: &Function) -> Result<(), String> {
+    write_str(out, function.name());
+    write_u32(out, function.arity());
+    out.push(function.up_value_count());
+    out.push(function.variadic() as u8);
+
+    let chunk = function.chunk();
+    write_u32(out, chunk.code.len());
+    out.extend_from_slice(&chunk.code);
+    for (&line, &column) in chunk.lines.iter().zip(&chunk.columns) {
+        write_u32(out, line);
+        write_u32(out, column);
+    }
+
+    write_u32(out, chunk.constants.len());
+    for constant in &chunk.constants {
+        write_constant(out, constant)?;
+    }
+    Ok(())
+}
+
+fn write_constant(out: &mut Vec<u8>, value: &Value) -> Result<(), String> {
+    match value {
+        Value::Nil => out.push(TAG_NIL),
+        Value::Bool(b) => out.extend_from_slice(&[TAG_BOOL, *b as u8]),
+        Value::Int(i) => {
+            out.push(TAG_INT);
+            out.extend_from_slice(&i.to_le_bytes());
+        }
+        Value::Float(f) => {
+            out.push(TAG_FLOAT);
+            out.extend_from_slice(&f.to_bits().to_le_bytes());
+        }
+        Value::String(s) => {
+            out.push(TAG_STRING);
+            write_str(out, s);
+        }
+        Value::Symbol(symbol) => {
+            out.push(TAG_SYMBOL);
+            write_str(out, &symbol.to_string());
+        }
+        Value::Function(function) => {
+            out.push(TAG_FUNCTION);
+            write_function(out, &function.read())?;
+        }
+        value => return Err(format!("Cannot serialize a {} constant", value.type_name())),
+    }
+    Ok(())
+}
+
+/// Decodes a bytecode file written by `serialize` back into the script
+/// function. Files with the wrong magic or version, that are cut short, or
+/// whose code the VM couldn't safely run are rejected.
+pub fn deserialize(bytes: &[u8]) -> Result<Function, String> {
+    let mut reader = Reader { bytes, pos: 0 };
+
+    if reader.take(BYTECODE_MAGIC.len()).ok() != Some(&BYTECODE_MAGIC[..]) {
+        return Err("Not a bytecode file".to_string());
+    }
+    let version = reader.read_u8()?;
+    if version != BYTECODE_VERSION {
+        return Err(format!(
+            "Unsupported bytecode version {} (expected {})",
+            version, BYTECODE_VERSION
+        ));
+    }
+
+    let function = reader.read_function()?;
+    if reader.pos != bytes.len() {
+        return Err("Unexpected data after the script".to_string());
+    }
+    Ok(function)
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        let bytes = self
+            .bytes
+            .get(self.pos..self.pos.saturating_add(len))
+            .ok_or("Bytecode file is truncated")?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn read_u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn read_u32(&mut self) -> Result<usize, String> {
+        let bytes = self.take(4)?.try_into().unwrap();
+        Ok(u32::from_le_bytes(bytes) as usize)
+    }
+
+    fn read_u64(&mut self) -> Result<u64, String> {
+        let bytes = self.take(8)?.try_into().unwrap();
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    fn read_str(&mut self) -> Result<String, String> {
+        let len = self.read_u32()?;
+        let bytes = self.take(len)?;
+        String::from_utf8(bytes.to_vec())
+            .map_err(|_| "Invalid UTF-8 in string constant".to_string())
+    }
+
+    // The same layout `write_function` produces.
+    fn read_function(&mut self) -> Result<Function, String> {
+        let mut function = Function::new(self.read_str()?);
+        function.arity = self.read_u32()?;
+        function.up_value_count = self.read_u8()?;
+        function.variadic = self.read_u8()? != 0;
+
+        let mut chunk = Chunk::new();
+        let code_len = self.read_u32()?;
+        chunk.code = self.take(code_len)?.to_vec();
+        for _ in 0..code_len {
+            chunk.lines.push(self.read_u32()?);
+            chunk.columns.push(self.read_u32()?);
+        }
+
+        let constant_count = self.read_u32()?;
+        for _ in 0..constant_count {
+            let constant = self.read_constant()?;
+            chunk.write_constant(constant);
+        }
+
+        function.chunk = Rc::new(RwLock::new(chunk));
+        verify(&function)?;
+        Ok(function)
+    }
+
+    fn read_constant(&mut self) -> Result<Value, String> {
+        let value = match self.read_u8()? {
+            TAG_NIL => Value::Nil,
+            TAG_BOOL => Value::Bool(self.read_u8()? != 0),
+            TAG_INT => Value::Int(self.read_u64()? as i64),
+            TAG_FLOAT => Value::Float(f64::from_bits(self.read_u64()?)),
+            TAG_STRING => Value::String(self.read_str()?),
+            TAG_SYMBOL => Value::Symbol(Symbol::intern(&self.read_str()?)),
+            TAG_FUNCTION => Value::Function(Rc::new(RwLock::new(self.read_function()?))),
+            tag => return Err(format!("Unknown constant tag {}", tag)),
+        };
+        Ok(value)
+    }
+}
+
+// Checks that a decoded function's code is something the VM can run: known
+// opcodes with all their operands, constants and upvalues that exist and
+// have the right type, and jumps that land on an instruction. The stack
+// checker then walks every path for balance. Nested functions are checked
+// as they are read.
+fn verify(function: &Function) -> Result<(), String> {
+    if function.variadic() && function.arity() == 0 {
+        return Err(format!(
+            "Variadic function '{}' has no parameters",
+            function.display_name()
+        ));
+    }
+
+    let chunk = function.chunk();
+    let code = &chunk.code;
+    let mut starts = vec![false; code.len()];
+    let mut targets = Vec::new();
+    let mut offset = 0;
+
+    while offset < code.len() {
+        starts[offset] = true;
+        let instruction = OpCode::try_from(code[offset])
+            .map_err(|byte| format!("Unknown opcode {} at {}", byte, offset))?;
+        let operand = |i: usize| {
+            code.get(offset + i)
+                .map(|&byte| byte as usize)
+                .ok_or_else(|| format!("Truncated {} at {}", instruction, offset))
+        };
+        let constant = |index: usize| {
+            chunk
+                .constants
+                .get(index)
+                .ok_or_else(|| format!("Constant {} out of range at {}", index, offset))
+        };
+        // The constant index right after the opcode, and where the operands
+        // after it start.
+        let width = instruction.constant_width();
+        let index =
+            || (1..=width).try_fold(0, |index, i| Ok::<_, String>(index << 8 | operand(i)?));
+        let rest = 1 + width;
+        let name = || match constant(index()?)? {
+            Value::Symbol(_) => Ok(()),
+            _ => Err(format!("Expected a name constant at {}", offset)),
+        };
+        let up_value = |index: usize| {
+            if index < function.up_value_count() as usize {
+                Ok(())
+            } else {
+                Err(format!("Upvalue {} out of range at {}", index, offset))
+            }
+        };
+
+        let length = match instruction {
+            OpCode::Constant | OpCode::ConstantLong => {
+                constant(index()?)?;
+                rest
+            }
+            OpCode::DefineGlobal
+            | OpCode::GetGlobal
+            | OpCode::SetGlobal
+            | OpCode::Class
+            | OpCode::GetProperty
+            | OpCode::SetProperty
+            | OpCode::Method
+            | OpCode::GetSuper
+            | OpCode::DefineGlobalLong
+            | OpCode::GetGlobalLong
+            | OpCode::SetGlobalLong
+            | OpCode::ClassLong
+            | OpCode::GetPropertyLong
+            | OpCode::SetPropertyLong
+            | OpCode::MethodLong
+            | OpCode::GetSuperLong => {
+                name()?;
+                rest
+            }
+            OpCode::Invoke | OpCode::SuperInvoke | OpCode::InvokeLong | OpCode::SuperInvokeLong => {
+                name()?;
+                operand(rest)?;
+                rest + 1
+            }
+            OpCode::Print
+            | OpCode::PopN
+            | OpCode::GetLocal
+            | OpCode::SetLocal
+            | OpCode::BuildArray
+            | OpCode::Next
+            | OpCode::Call
+            | OpCode::TailCall => {
+                operand(1)?;
+                2
+            }
+            OpCode::GetUpvalue | OpCode::SetUpvalue => {
+                up_value(operand(1)?)?;
+                2
+            }
+            OpCode::Jump | OpCode::JumpIfFalse | OpCode::JumpIfTrue => {
+                targets.push(offset + 3 + (operand(1)? << 8 | operand(2)?));
+                3
+            }
+            OpCode::Loop => {
+                let target = (offset + 3)
+                    .checked_sub(operand(1)? << 8 | operand(2)?)
+                    .ok_or_else(|| format!("Loop before the start of the chunk at {}", offset))?;
+                targets.push(target);
+                3
+            }
+            // One target per label, then the default, all relative to the
+            // end of the table.
+            OpCode::Switch | OpCode::SwitchLong => {
+                if !matches!(constant(index()?)?, Value::Int(_)) {
+                    return Err(format!("Expected an int constant at {}", offset));
+                }
+                let count = operand(rest)?;
+                let table = rest + 1;
+                let end = table + 2 * (count + 1);
+                for i in 0..=count {
+                    let jump = operand(table + 2 * i)? << 8 | operand(table + 2 * i + 1)?;
+                    targets.push(offset + end + jump);
+                }
+                end
+            }
+            OpCode::Closure | OpCode::ClosureLong => {
+                let up_value_count = match constant(index()?)? {
+                    Value::Function(function) => function.read().up_value_count() as usize,
+                    _ => return Err(format!("Expected a function constant at {}", offset)),
+                };
+                for i in 0..up_value_count {
+                    let is_local = operand(rest + 2 * i)? == 1;
+                    let index = operand(rest + 2 * i + 1)?;
+                    if !is_local {
+                        up_value(index)?;
+                    }
+                }
+                rest + 2 * up_value_count
+            }
+            _ => 1,
+        };
+        offset += length;
+    }
+
+    if let Some(target) = targets
+        .into_iter()
+        .find(|&target| !starts.get(target).copied().unwrap_or(false))
+    {
+        return Err(format!("Jump to {} is not an instruction", target));
+    }
+
+    check_stack(&chunk, function.arity(), &[])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::compile;
+
+    #[test]
+    fn every_opcode_round_trips_through_its_byte() {
+        for byte in 0..OPCODE_COUNT as u8 {
+            let op = OpCode::try_from(byte).unwrap();
+            assert_eq!(u8::from(op), byte);
+            assert_eq!(OPCODES[byte as usize], op);
+        }
+        assert_eq!(
+            OpCode::try_from(OPCODE_COUNT as u8),
+            Err(OPCODE_COUNT as u8)
+        );
+        assert_eq!(OpCode::try_from(u8::MAX), Err(u8::MAX));
+
+        let mut names: Vec<String> = OPCODES.iter().map(|op| op.to_string()).collect();
+        names.sort();
+        names.dedup();
+        assert_eq!(names.len(), OPCODE_COUNT);
+    }
+
+    fn compiled(source: &str) -> Vec<u8> {
+        serialize(&compile(source.to_string()).function.read()).unwrap()
+    }
+
+    // A bytecode file for a script whose code is exactly `code`.
+    fn file_with(code: &[u8], constants: Vec<Value>, arity: usize, variadic: bool) -> Vec<u8> {
+        let mut function = Function::new_script();
+        function.arity = arity;
+        function.variadic = variadic;
+        {
+            let mut chunk = function.chunk.write();
+            for &byte in code {
+                chunk.write(byte, 1, 1);
+            }
+            for constant in constants {
+                chunk.write_constant(constant);
+            }
+        }
+        serialize(&function).unwrap()
+    }
+
+    fn rejection(bytes: &[u8]) -> String {
+        deserialize(bytes).expect_err("file should be rejected")
+    }
+
+    #[test]
+    fn round_trips_a_compiled_script() {
+        let bytes = compiled("fun add(a, b) { return a + b; } print add(1, 2.5); print \"s\";");
+        assert_eq!(serialize(&deserialize(&bytes).unwrap()).unwrap(), bytes);
+    }
+
+    #[test]
+    fn rejects_every_truncation() {
+        let bytes = compiled("var a = [1, 2]; for (var i = 0; i < 2; i = i + 1) print a[i];");
+        for len in 0..bytes.len() {
+            assert!(
+                deserialize(&bytes[..len]).is_err(),
+                "accepted {} bytes",
+                len
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_bad_headers_and_trailing_data() {
+        assert_eq!(rejection(b"LBX\0\x02"), "Not a bytecode file");
+        assert!(rejection(b"LBC\0\x09").starts_with("Unsupported bytecode version 9"));
+
+        let mut bytes = compiled("print 1;");
+        bytes.push(0);
+        assert_eq!(rejection(&bytes), "Unexpected data after the script");
+    }
+
+    #[test]
+    fn rejects_empty_code() {
+        assert!(rejection(&file_with(&[], vec![], 0, false)).contains("past the end"));
+    }
+
+    #[test]
+    fn rejects_unknown_opcodes() {
+        let code = [OPCODE_COUNT as u8, OpCode::Return as u8];
+        assert_eq!(
+            rejection(&file_with(&code, vec![], 0, false)),
+            format!("Unknown opcode {} at 0", OPCODE_COUNT)
+        );
+    }
+
+    #[test]
+    fn rejects_truncated_operands() {
+        let code = [OpCode::Nil as u8, OpCode::Jump as u8, 0];
+        assert_eq!(
+            rejection(&file_with(&code, vec![], 0, false)),
+            "Truncated JUMP at 1"
+        );
+    }
+
+    #[test]
+    fn rejects_constants_out_of_range() {
+        let code = [OpCode::Constant as u8, 5, OpCode::Return as u8];
+        assert_eq!(
+            rejection(&file_with(&code, vec![], 0, false)),
+            "Constant 5 out of range at 0"
+        );
+    }
+
+    #[test]
+    fn rejects_names_that_are_not_symbols() {
+        let code = [OpCode::GetGlobal as u8, 0, OpCode::Return as u8];
+        assert_eq!(
+            rejection(&file_with(&code, vec![Value::Int(1)], 0, false)),
+            "Expected a name constant at 0"
+        );
+    }
+
+    #[test]
+    fn rejects_upvalues_the_function_does_not_have() {
+        let code = [OpCode::GetUpvalue as u8, 0, OpCode::Return as u8];
+        assert_eq!(
+            rejection(&file_with(&code, vec![], 0, false)),
+            "Upvalue 0 out of range at 0"
+        );
+    }
+
+    #[test]
+    fn rejects_jumps_into_an_instruction() {
+        let code = [
+            OpCode::Constant as u8,
+            0,
+            OpCode::Jump as u8,
+            0,
+            0xff,
+            OpCode::Return as u8,
+        ];
+        assert!(rejection(&file_with(&code, vec![Value::Nil], 0, false)).starts_with("Jump to"));
+
+        let code = [OpCode::Loop as u8, 0, 9, OpCode::Return as u8];
+        assert_eq!(
+            rejection(&file_with(&code, vec![], 0, false)),
+            "Loop before the start of the chunk at 0"
+        );
+    }
+
+    #[test]
+    fn rejects_unbalanced_stacks() {
+        let code = [OpCode::Pop as u8, OpCode::Pop as u8, OpCode::Return as u8];
+        assert_eq!(
+            rejection(&file_with(&code, vec![], 0, false)),
+            "Stack underflow at 1"
+        );
+    }
+
+    #[test]
+    fn rejects_variadic_functions_without_parameters() {
+        let code = [OpCode::Nil as u8, OpCode::Return as u8];
+        assert_eq!(
+            rejection(&file_with(&code, vec![], 0, true)),
+            "Variadic function 'script' has no parameters"
+        );
+    }
+
+    #[test]
+    fn rejects_locals_above_the_stack() {
+        let code = [OpCode::GetLocal as u8, 2, OpCode::Return as u8];
+        assert_eq!(
+            rejection(&file_with(&code, vec![], 1, false)),
+            "Local 2 out of range at 0"
+        );
+    }
+
+    #[test]
+    fn accepts_long_forms() {
+        let mut source: String = (0..300).map(|i| format!("var g{} = {};\n", i, i)).collect();
+        source.push_str("class A { m() { return this.f; } } var a = A(); a.f = g1; print a.m();");
+        let bytes = compiled(&source);
+
+        let function = deserialize(&bytes).unwrap();
+        let listing = disassemble(&function.chunk(), "script", None);
+        for name in [
+            "OP_GET_GLOBAL_LONG",
+            "OP_CLASS_LONG",
+            "OP_SET_PROPERTY_LONG",
+        ] {
+            assert!(listing.contains(name), "no {} in\n{}", name, listing);
+        }
     }
 }
@@ -1,11 +1,14 @@
 use crate::chunk::{Chunk, OpCode};
+use crate::debug;
 use crate::parser_rules::ParseRule;
 use crate::parser_rules::RULES;
 use crate::scanner::{Scanner, Token};
+use crate::symbol::Symbol;
 use crate::token_type::TokenType;
 use crate::value::{Function, FunctionType, Upvalue, Value};
-use crate::vm::DEBUG_PRINT_CODE;
+use crate::vm::{DEBUG_CHECK_STACK, DEBUG_PRINT_CODE};
 use parking_lot::RwLock;
+use std::collections::HashSet;
 use std::rc::Rc;
 use std::sync::atomic::AtomicUsize;
 
@@ -20,9 +23,27 @@ pub enum Precedence {
     Term,       // + -
     Factor,     // * /
     Unary,      // ! -
+    Power,      // **
     Call,       // . ()
 }
 
+impl Precedence {
+    fn next(self) -> Precedence {
+        match self {
+            Precedence::None => Precedence::Assignment,
+            Precedence::Assignment => Precedence::Or,
+            Precedence::Or => Precedence::And,
+            Precedence::And => Precedence::Equality,
+            Precedence::Equality => Precedence::Comparison,
+            Precedence::Comparison => Precedence::Term,
+            Precedence::Term => Precedence::Factor,
+            Precedence::Factor => Precedence::Unary,
+            Precedence::Unary => Precedence::Power,
+            Precedence::Power | Precedence::Call => Precedence::Call,
+        }
+    }
+}
+
 struct ScannerState {
     scanner: Rc<RwLock<Scanner>>,
     current: Box<Token>,
@@ -30,15 +51,76 @@ struct ScannerState {
 }
 
 struct ErrorState {
-    had_error: bool,
+    diagnostics: Vec<Diagnostic>,
     panic_mode: bool,
 }
 
+/// One compile error, as `[line N] Error at 'x': message`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diagnostic {
+    pub line: usize,
+    pub column: usize,
+    // " at 'x'", " at end", or empty for errors from the scanner.
+    pub location: String,
+    pub message: String,
+}
+
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "[line {}:col {}] Error{}: {}",
+            self.line, self.column, self.location, self.message
+        )
+    }
+}
+
+/// Everything a compile produced. `function` holds whatever was compiled even
+/// when there are diagnostics, but only runs if there are none.
+pub struct CompileResult {
+    pub function: Rc<RwLock<Function>>,
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl CompileResult {
+    pub fn is_runnable(&self) -> bool {
+        self.diagnostics.is_empty()
+    }
+}
+
+/// Compiles a whole script without running or printing anything.
+pub fn compile(source: String) -> CompileResult {
+    let scanner = Rc::new(RwLock::new(Scanner::new(source)));
+    Compiler::new(FunctionType::Script, scanner).compile()
+}
+
 #[derive(Debug)]
 struct Local {
     pub name: String,
     pub depth: usize,
     pub is_captured: bool,
+    // Bound by `with`; closed whenever control leaves its scope.
+    pub is_resource: bool,
+    pub is_const: bool,
+    // Arity and loop nesting of a local non-variadic `fun` that has not been
+    // reassigned, so direct calls to it can be checked at compile time.
+    pub known_arity: Option<(usize, usize)>,
+}
+
+#[derive(Debug)]
+struct LoopContext {
+    // Scope depth the loop was entered at; locals deeper than this belong to
+    // the loop body and are discarded before jumping out.
+    scope_depth: usize,
+    // Where `continue` jumps back to: the condition of a `while`, or the
+    // increment clause of a `for` when it has one. `None` when the target
+    // comes after the body, as with `do`, in which case `continue` jumps
+    // forward and is patched once the body is compiled.
+    continue_target: Option<usize>,
+    continue_jumps: Vec<usize>,
+    break_jumps: Vec<usize>,
+    // Set by `name:` before the loop, for `break name` and `continue name`.
+    label: Option<String>,
 }
 
 #[derive(Clone, Debug)]
@@ -58,6 +140,34 @@ pub struct Compiler {
     enclosing: Option<Box<Compiler>>,
     up_values: Rc<RwLock<Vec<Upvalue>>>,
     class_compiler: Rc<RwLock<Option<Box<ClassCompiler>>>>,
+    loops: Rc<RwLock<Vec<LoopContext>>>,
+    // The label just read, waiting for the loop after it to claim it.
+    loop_label: Rc<RwLock<Option<String>>>,
+    // Names of globals declared `const`, shared with nested compilers.
+    const_globals: Rc<RwLock<HashSet<String>>>,
+    // Arity of the local function just read by `named_variable` and the code
+    // offset right after the read; `call` checks it when nothing came between.
+    callee_arity: Rc<RwLock<Option<(usize, usize)>>>,
+    // The code offset right after the last Call emitted, so `return` can tell
+    // when its value is a call in tail position.
+    last_call: Rc<RwLock<Option<usize>>>,
+    // In REPL mode a trailing top-level expression statement returns its value
+    // from the script instead of discarding it.
+    repl: bool,
+    // (offset, expected stack depth) at statement boundaries, for
+    // DEBUG_CHECK_STACK.
+    stack_checks: Rc<RwLock<Vec<(usize, usize)>>>,
+}
+
+// The value of an integer literal in decimal or with a 0x, 0b or 0o prefix.
+fn int_literal(lexeme: &str) -> Option<i64> {
+    let radix = match lexeme.get(..2) {
+        Some("0x") => 16,
+        Some("0b") => 2,
+        Some("0o") => 8,
+        _ => return lexeme.parse().ok(),
+    };
+    i64::from_str_radix(&lexeme[2..], radix).ok()
 }
 
 impl Compiler {
@@ -69,12 +179,18 @@ impl Compiler {
                 name: String::from("this"),
                 depth: 0,
                 is_captured: false,
+                is_resource: false,
+                is_const: false,
+                known_arity: None,
             });
         } else {
             locals.push(Local {
                 name: String::from(""),
                 depth: 0,
                 is_captured: false,
+                is_resource: false,
+                is_const: false,
+                known_arity: None,
             });
         }
 
@@ -85,7 +201,7 @@ impl Compiler {
                 previous: Box::new(Token::new()),
             })),
             error_state: Rc::new(RwLock::new(ErrorState {
-                had_error: false,
+                diagnostics: Vec::new(),
                 panic_mode: false,
             })),
             locals: Rc::new(RwLock::new(locals)),
@@ -95,18 +211,31 @@ impl Compiler {
             enclosing: None,
             up_values: Rc::new(RwLock::new(Vec::new())),
             class_compiler: Rc::new(RwLock::new(None)),
+            loops: Rc::new(RwLock::new(Vec::new())),
+            loop_label: Rc::new(RwLock::new(None)),
+            const_globals: Rc::new(RwLock::new(HashSet::new())),
+            callee_arity: Rc::new(RwLock::new(None)),
+            last_call: Rc::new(RwLock::new(None)),
+            repl: false,
+            stack_checks: Rc::new(RwLock::new(Vec::new())),
         }
     }
 
     pub fn new_enclosed(&self, function_type: FunctionType) -> Self {
         let function = match function_type {
-            FunctionType::Function => Function::new(String::from(
-                self.scanner_state.read().previous.clone().lexeme,
-            )),
+            FunctionType::Function => {
+                let previous = self.scanner_state.read().previous.clone();
+                // Function expressions are left unnamed.
+                if previous.token_type == TokenType::Fun {
+                    Function::new(String::new())
+                } else {
+                    Function::new(previous.lexeme)
+                }
+            }
             FunctionType::Script => Function::new_script(),
-            FunctionType::Method => Function::new(String::from(
-                self.scanner_state.read().previous.clone().lexeme,
-            )),
+            FunctionType::Method => {
+                Function::new(self.scanner_state.read().previous.clone().lexeme)
+            }
             FunctionType::Initializer => Function::new(String::from("init")),
         };
 
@@ -117,12 +246,18 @@ impl Compiler {
                 name: String::from("this"),
                 depth: 0,
                 is_captured: false,
+                is_resource: false,
+                is_const: false,
+                known_arity: None,
             });
         } else {
             locals.push(Local {
                 name: String::from(""),
                 depth: 0,
                 is_captured: false,
+                is_resource: false,
+                is_const: false,
+                known_arity: None,
             });
         }
 
@@ -136,34 +271,81 @@ impl Compiler {
             enclosing: Some(Box::new(self.clone())),
             up_values: Rc::new(RwLock::new(Vec::new())),
             class_compiler: self.class_compiler.clone(),
+            loops: Rc::new(RwLock::new(Vec::new())),
+            loop_label: Rc::new(RwLock::new(None)),
+            const_globals: self.const_globals.clone(),
+            callee_arity: Rc::new(RwLock::new(None)),
+            last_call: Rc::new(RwLock::new(None)),
+            repl: false,
+            stack_checks: Rc::new(RwLock::new(Vec::new())),
         }
     }
 
+    pub fn set_repl(&mut self, repl: bool) {
+        self.repl = repl;
+    }
+
+    // Shares the VM's const globals, so declarations from earlier scripts
+    // still count and new ones are remembered for later scripts.
+    pub fn set_const_globals(&mut self, const_globals: Rc<RwLock<HashSet<String>>>) {
+        self.const_globals = const_globals;
+    }
+
     fn get_chunk(&self) -> Rc<RwLock<Chunk>> {
         let function = self.function.read();
         function.chunk.clone()
     }
 
-    pub fn compile(&mut self) -> Option<Rc<RwLock<Function>>> {
+    pub fn compile(&mut self) -> CompileResult {
         self.advance();
 
         while self.scanner_state.read().current.token_type != TokenType::Eof {
             self.declaration();
         }
 
-        self.end_compiler()
+        self.end_compiler();
+        CompileResult {
+            function: self.function.clone(),
+            diagnostics: std::mem::take(&mut self.error_state.write().diagnostics),
+        }
+    }
+
+    /// Compiles for the VM: diagnostics go to stderr and the script is only
+    /// returned if it can run.
+    pub fn compile_function(&mut self) -> Option<Rc<RwLock<Function>>> {
+        let result = self.compile();
+        for diagnostic in &result.diagnostics {
+            eprintln!("{}", diagnostic);
+        }
+
+        result.is_runnable().then_some(result.function)
+    }
+
+    fn had_error(&self) -> bool {
+        !self.error_state.read().diagnostics.is_empty()
     }
 
     fn end_compiler(&self) -> Option<Rc<RwLock<Function>>> {
         self.emit_return();
 
-        if !self.error_state.read().had_error && DEBUG_PRINT_CODE {
+        if !self.had_error() && DEBUG_CHECK_STACK {
+            let result = debug::check_stack(
+                &self.get_chunk().read(),
+                self.function.read().arity(),
+                &self.stack_checks.read(),
+            );
+            if let Err(message) = result {
+                self.error(&message);
+            }
+        }
+
+        if !self.had_error() && DEBUG_PRINT_CODE {
             self.get_chunk()
                 .read()
                 .disassemble(&self.function.read().name, None);
         }
 
-        if !self.error_state.read().had_error {
+        if !self.had_error() {
             Some(self.function.clone())
         } else {
             None
@@ -171,18 +353,23 @@ impl Compiler {
     }
 
     fn advance(&self) {
-        let mut scanner_state = self.scanner_state.write();
-        scanner_state.previous = scanner_state.current.clone();
+        {
+            let mut scanner_state = self.scanner_state.write();
+            scanner_state.previous = scanner_state.current.clone();
+        }
 
         loop {
+            let mut scanner_state = self.scanner_state.write();
             let scanner = scanner_state.scanner.clone();
-            scanner_state.current = Box::new(scanner.write().scan_token());
+            *scanner_state.current = scanner.write().scan_token();
 
             if scanner_state.current.token_type != TokenType::Error {
                 break;
             }
 
-            self.error_at_current(&self.scanner_state.read().current.lexeme);
+            let message = scanner_state.current.lexeme.clone();
+            drop(scanner_state);
+            self.error_at_current(&message);
         }
     }
 
@@ -199,24 +386,49 @@ impl Compiler {
             return;
         }
 
-        self.error_state.write().had_error = true;
-        self.error_state.write().panic_mode = true;
-
-        eprint!("[line {}] Error", token.line);
-
-        if token.token_type == TokenType::Eof {
-            eprint!(" at end");
+        let location = if token.token_type == TokenType::Eof {
+            String::from(" at end")
         } else if token.token_type == TokenType::Error {
-            // Nothing.
+            String::new()
         } else {
-            eprint!(" at '{}'", token.lexeme);
-        }
+            format!(" at '{}'", token.lexeme)
+        };
 
-        eprintln!(": {}", message);
+        let mut error_state = self.error_state.write();
+        error_state.panic_mode = true;
+        error_state.diagnostics.push(Diagnostic {
+            line: token.line,
+            column: token.column,
+            location,
+            message: message.to_string(),
+        });
     }
 
     fn consume(&self, token_type: TokenType, message: &str) {
-        if self.scanner_state.read().current.token_type == token_type {
+        let current = self.scanner_state.read().current.clone();
+        if current.token_type == token_type {
+            self.advance();
+            return;
+        }
+
+        // Name the keyword rather than just asking for a name. The kind of
+        // name comes from messages like "Expect variable name."
+        if token_type == TokenType::Identifier && current.token_type.is_keyword() {
+            let kind = message
+                .strip_prefix("Expect ")
+                .and_then(|rest| rest.find(" name").map(|end| &rest[..end]));
+            let message = match kind {
+                Some(kind) => format!(
+                    "'{}' is a reserved keyword and cannot be used as a {} name.",
+                    current.lexeme, kind
+                ),
+                None => format!(
+                    "'{}' is a reserved keyword and cannot be used as a name.",
+                    current.lexeme
+                ),
+            };
+            // Take the keyword as the name, so parsing resumes after it.
+            self.error_at_current(&message);
             self.advance();
             return;
         }
@@ -225,9 +437,10 @@ impl Compiler {
     }
 
     fn emit_byte(&self, byte: u8) {
+        let previous = &self.scanner_state.read().previous;
         self.get_chunk()
             .write()
-            .write(byte, self.scanner_state.read().previous.line);
+            .write(byte, previous.line, previous.column);
     }
 
     fn emit_return(&self) {
@@ -239,18 +452,34 @@ impl Compiler {
         self.emit_byte(OpCode::Return.into());
     }
 
-    fn make_constant(&self, value: Value) -> u8 {
+    // Constants are indexed with at most 24 bits, by the long forms.
+    fn make_constant(&self, value: Value) -> usize {
         let constant = self.get_chunk().write().write_constant(value);
-        if constant > u8::MAX as usize {
+        if constant >= 1 << 24 {
             self.error("Too many constants in one chunk.");
             return 0;
         }
 
-        constant as u8
+        constant
     }
 
     fn emit_constant(&self, value: Value) {
-        self.emit_bytes(OpCode::Constant.into(), self.make_constant(value));
+        let constant = self.make_constant(value);
+        self.emit_indexed(OpCode::Constant, constant);
+    }
+
+    // Emits `op` with an index operand. Past the first 256 constants, it
+    // switches to the long form of `op` and a 24-bit index; local and
+    // upvalue slots always fit in the byte.
+    fn emit_indexed(&self, op: OpCode, index: usize) {
+        match op.long_form() {
+            Some(long) if index > u8::MAX as usize => {
+                self.emit_byte(long.into());
+                self.emit_byte((index >> 16) as u8);
+                self.emit_bytes((index >> 8) as u8, index as u8);
+            }
+            _ => self.emit_bytes(op.into(), index as u8),
+        }
     }
 
     fn emit_bytes(&self, byte1: u8, byte2: u8) {
@@ -263,24 +492,41 @@ impl Compiler {
     }
 
     fn declaration(&self) {
+        if DEBUG_CHECK_STACK {
+            self.record_stack_check();
+        }
+
         if self.match_token(TokenType::Class) {
             self.class_declaration();
         } else if self.match_token(TokenType::Fun) {
             self.fun_declaration();
         } else if self.match_token(TokenType::Var) {
             self.var_declaration();
+        } else if self.match_token(TokenType::Const) {
+            self.const_declaration();
         } else {
             self.statement();
         }
 
+        if DEBUG_CHECK_STACK {
+            self.record_stack_check();
+        }
+
         if self.error_state.read().panic_mode {
             self.synchronize();
         }
     }
 
+    // Between statements the stack holds exactly the locals in scope.
+    fn record_stack_check(&self) {
+        let offset = self.get_chunk().read().code.len();
+        let depth = self.locals.read().len();
+        self.stack_checks.write().push((offset, depth));
+    }
+
     fn method(&self) {
         self.consume(TokenType::Identifier, "Expect method name.");
-        let constant = self.identifier_constant(self.scanner_state.read().previous.clone());
+        let constant = self.identifier_constant(&self.scanner_state.read().previous.clone());
 
         let mut function_type = FunctionType::Method;
 
@@ -290,17 +536,20 @@ impl Compiler {
 
         self.function(function_type);
 
-        self.emit_bytes(OpCode::Method.into(), constant);
+        self.emit_indexed(OpCode::Method, constant);
     }
 
     fn class_declaration(&self) {
         self.consume(TokenType::Identifier, "Expect class name.");
         let class_name = self.scanner_state.read().previous.clone();
-        let name_constant = self.identifier_constant(self.scanner_state.read().previous.clone());
+        let name_constant = self.identifier_constant(&self.scanner_state.read().previous.clone());
 
         self.declare_variable();
+        if self.scope_depth.load(std::sync::atomic::Ordering::SeqCst) == 0 {
+            self.const_globals.write().remove(&class_name.lexeme);
+        }
 
-        self.emit_bytes(OpCode::Class.into(), name_constant);
+        self.emit_indexed(OpCode::Class, name_constant);
         self.define_variable(name_constant);
 
         let class_compiler = ClassCompiler {
@@ -321,7 +570,7 @@ impl Compiler {
             }
 
             self.begin_scope();
-            self.add_local(self.synthetic_token("super"));
+            self.add_local(&self.synthetic_token("super"));
             self.define_variable(0);
 
             self.named_variable(class_name.clone(), false);
@@ -336,12 +585,13 @@ impl Compiler {
             self.method();
         }
         self.consume(TokenType::RightBrace, "Expect '}' after class body.");
+        self.emit_byte(OpCode::Pop.into());
 
         if self
             .class_compiler
             .read()
             .clone()
-            .map_or(false, |c| c.has_superclass)
+            .is_some_and(|c| c.has_superclass)
         {
             self.end_scope();
         }
@@ -377,29 +627,37 @@ impl Compiler {
 
         self.consume(TokenType::Dot, "Expect '.' after 'super'.");
         self.consume(TokenType::Identifier, "Expect superclass method name.");
-        let name = self.identifier_constant(self.scanner_state.read().previous.clone());
+        let name = self.identifier_constant(&self.scanner_state.read().previous.clone());
 
         self.named_variable(self.synthetic_token("this"), false);
 
         if self.match_token(TokenType::LeftParen) {
             let arg_count = self.argument_list();
             self.named_variable(self.synthetic_token("super"), false);
-            self.emit_bytes(OpCode::SuperInvoke.into(), name);
+            self.emit_indexed(OpCode::SuperInvoke, name);
             self.emit_byte(arg_count);
         } else {
             self.named_variable(self.synthetic_token("super"), false);
-            self.emit_bytes(OpCode::GetSuper.into(), name);
+            self.emit_indexed(OpCode::GetSuper, name);
         }
     }
 
     fn fun_declaration(&self) {
         let global = self.parse_variable("Expect function name.");
         self.mark_initialized();
-        self.function(FunctionType::Function);
+        let arity = self.function(FunctionType::Function);
+
+        if let Some(arity) = arity {
+            if self.scope_depth.load(std::sync::atomic::Ordering::SeqCst) != 0 {
+                self.locals.write().last_mut().unwrap().known_arity =
+                    Some((arity, self.loops.read().len()));
+            }
+        }
         self.define_variable(global);
     }
 
-    fn function(&self, function_type: FunctionType) {
+    // Returns the arity of the compiled function unless it is variadic.
+    fn function(&self, function_type: FunctionType) -> Option<usize> {
         let compiler = self.new_enclosed(function_type);
         compiler.begin_scope();
 
@@ -412,9 +670,17 @@ impl Compiler {
                     compiler.error_at_current("Cannot have more than 255 parameters.");
                 }
 
+                let variadic = compiler.match_token(TokenType::DotDotDot);
                 let constant = compiler.parse_variable("Expect parameter name.");
                 compiler.define_variable(constant);
 
+                if variadic {
+                    compiler.function.write().variadic = true;
+                    if compiler.check(&TokenType::Comma) {
+                        compiler.error_at_current("Rest parameter must be last.");
+                    }
+                }
+
                 if !compiler.match_token(TokenType::Comma) {
                     break;
                 }
@@ -425,12 +691,13 @@ impl Compiler {
         compiler.consume(TokenType::LeftBrace, "Expect '{' before function body.");
         compiler.block();
 
-        let function = match compiler.end_compiler() {
-            Some(function) => function,
-            None => return,
+        let function = compiler.end_compiler()?;
+        let arity = {
+            let function = function.read();
+            (!function.variadic()).then_some(function.arity)
         };
-        self.emit_bytes(
-            OpCode::Closure.into(),
+        self.emit_indexed(
+            OpCode::Closure,
             self.make_constant(Value::Function(function)),
         );
 
@@ -438,11 +705,113 @@ impl Compiler {
             self.emit_byte(if up_value.is_local { 1 } else { 0 });
             self.emit_byte(up_value.index);
         }
+
+        arity
     }
 
     fn var_declaration(&self) {
+        if self.match_token(TokenType::LeftBrace) {
+            self.destructuring_declaration();
+            return;
+        }
+
         let global = self.parse_variable("Expect variable name.");
+        self.var_initializer(global);
+    }
+
+    // `var { name, age } = person;` binds each listed field of an instance to
+    // a variable of the same name. A missing field is a runtime error, as
+    // with `person.name`.
+    fn destructuring_declaration(&self) {
+        let mut names: Vec<Box<Token>> = Vec::new();
+        loop {
+            self.consume(TokenType::Identifier, "Expect field name.");
+            let name = self.scanner_state.read().previous.clone();
+            if names.iter().any(|other| other.lexeme == name.lexeme) {
+                self.error("Already variable with this name in this scope.");
+            }
+            names.push(name);
+            if !self.match_token(TokenType::Comma) {
+                break;
+            }
+        }
+
+        self.consume(TokenType::RightBrace, "Expect '}' after field names.");
+        self.consume(TokenType::Equal, "Expect '=' after destructuring pattern.");
+        self.expression();
+        self.consume(
+            TokenType::Semicolon,
+            "Expect ';' after variable declaration.",
+        );
+
+        if self.scope_depth.load(std::sync::atomic::Ordering::SeqCst) == 0 {
+            for name in names {
+                // Like any other declaration, this replaces a const global.
+                self.const_globals.write().remove(&name.lexeme);
+                let field = self.identifier_constant(&name);
+                self.emit_byte(OpCode::Duplicate.into());
+                self.emit_indexed(OpCode::GetProperty, field);
+                self.emit_indexed(OpCode::DefineGlobal, field);
+            }
+            self.emit_byte(OpCode::Pop.into());
+            return;
+        }
+
+        // The source stays in a hidden local below the new ones until the
+        // scope ends.
+        self.add_local(&self.synthetic_token(""));
+        self.mark_initialized();
+        let source = (self.locals.read().len() - 1) as u8;
+
+        for name in names {
+            self.emit_bytes(OpCode::GetLocal.into(), source);
+            let field = self.identifier_constant(&name);
+            self.emit_indexed(OpCode::GetProperty, field);
+            self.declare_local(&name);
+            self.mark_initialized();
+        }
+    }
+
+    fn const_declaration(&self) {
+        let global = self.parse_variable("Expect constant name.");
+        let name = self.scanner_state.read().previous.lexeme.clone();
+
+        if self.scope_depth.load(std::sync::atomic::Ordering::SeqCst) == 0 {
+            self.const_globals.write().insert(name);
+        } else {
+            self.locals.write().last_mut().unwrap().is_const = true;
+        }
+
+        self.consume(TokenType::Equal, "Expect '=' after constant name.");
+        self.expression();
+        self.consume(
+            TokenType::Semicolon,
+            "Expect ';' after constant declaration.",
+        );
+
+        self.define_variable(global);
+    }
+
+    // Whether `name` resolves to a `const` binding, looking through enclosing
+    // functions the way upvalue resolution does.
+    fn is_const(&self, name: &str) -> bool {
+        if let Some(local) = self
+            .locals
+            .read()
+            .iter()
+            .rev()
+            .find(|local| local.name == name)
+        {
+            return local.is_const;
+        }
+
+        match &self.enclosing {
+            Some(enclosing) => enclosing.is_const(name),
+            None => self.const_globals.read().contains(name),
+        }
+    }
 
+    fn var_initializer(&self, global: usize) {
         if self.match_token(TokenType::Equal) {
             self.expression();
         } else {
@@ -458,7 +827,9 @@ impl Compiler {
     }
 
     fn statement(&self) {
-        if self.match_token(TokenType::Print) {
+        if self.check(&TokenType::Identifier) && self.peek_next() == TokenType::Colon {
+            self.labeled_statement();
+        } else if self.match_token(TokenType::Print) {
             self.print_statement();
         } else if self.match_token(TokenType::If) {
             self.if_statement();
@@ -470,6 +841,16 @@ impl Compiler {
             self.for_statement();
         } else if self.match_token(TokenType::Switch) {
             self.switch_statement();
+        } else if self.match_token(TokenType::Repeat) {
+            self.repeat_statement();
+        } else if self.match_token(TokenType::Do) {
+            self.do_statement();
+        } else if self.match_token(TokenType::With) {
+            self.with_statement();
+        } else if self.match_token(TokenType::Break) {
+            self.break_statement();
+        } else if self.match_token(TokenType::Continue) {
+            self.continue_statement();
         } else if self.match_token(TokenType::LeftBrace) {
             self.begin_scope();
             self.block();
@@ -485,6 +866,7 @@ impl Compiler {
         }
 
         if self.match_token(TokenType::Semicolon) {
+            self.close_resources(0);
             self.emit_return();
         } else {
             if *self.function_type.read() == FunctionType::Initializer {
@@ -496,26 +878,73 @@ impl Compiler {
             }
             self.expression();
             self.consume(TokenType::Semicolon, "Expect ';' after return value.");
+            self.close_resources(0);
+
+            // A call whose result is returned as is reuses the frame. The
+            // Return stays, for callees that don't push a frame of their own.
+            let chunk = self.get_chunk();
+            let end = chunk.read().code.len();
+            if *self.last_call.read() == Some(end) {
+                chunk.write().code[end - 2] = OpCode::TailCall.into();
+            }
             self.emit_byte(OpCode::Return.into());
         }
     }
 
     fn switch_statement(&self) {
-        self.consume(TokenType::LeftParen, "Expect '(' after 'switch'.");
-        self.expression(); // switch condition
-        self.consume(TokenType::RightParen, "Expect ')' after switch condition.");
+        // Without a subject each case is a guard tested on its own.
+        let has_subject = !self.check(&TokenType::LeftBrace);
+        if has_subject {
+            self.consume(TokenType::LeftParen, "Expect '(' after 'switch'.");
+            self.expression(); // switch condition
+            self.consume(TokenType::RightParen, "Expect ')' after switch condition.");
+        }
         self.consume(TokenType::LeftBrace, "Expect '{' before switch cases.");
 
+        // The switch value lives in a hidden local so that locals declared in
+        // case bodies, and `break` out of an enclosing loop, account for it.
+        if has_subject {
+            self.begin_scope();
+            self.add_local(&self.synthetic_token(""));
+            self.mark_initialized();
+
+            if let Some((first, count)) = self.dense_case_labels() {
+                self.switch_table(first, count);
+                self.end_scope();
+                self.consume(TokenType::RightBrace, "Expect '}' after switch cases.");
+                return;
+            }
+        }
+
         let mut breaks_jumps = Vec::new();
 
         // Performing the comparison for all cases
         while self.scanner_state.read().current.clone().token_type != TokenType::RightBrace
             && self.scanner_state.read().current.clone().token_type != TokenType::Eof
         {
+            // Reached only once every case has failed
+            if self.match_token(TokenType::Default) {
+                self.consume(TokenType::Colon, "Expect ':' after 'default'.");
+                self.consume(TokenType::LeftBrace, "Expect '{' before default body.");
+
+                self.begin_scope();
+                self.block();
+                self.end_scope();
+
+                if self.check(&TokenType::Case) || self.check(&TokenType::Default) {
+                    self.error_at_current("Default case must be last.");
+                }
+                continue;
+            }
+
             self.consume(TokenType::Case, "Expect 'case' after 'switch'.");
-            self.emit_byte(OpCode::Duplicate.into()); // Duplicating switch value for comparison
+            if has_subject {
+                self.emit_byte(OpCode::Duplicate.into()); // Duplicating switch value for comparison
+            }
             self.expression(); // case condition
-            self.emit_byte(OpCode::Equal.into());
+            if has_subject {
+                self.emit_byte(OpCode::Equal.into());
+            }
 
             let jump = self.emit_jump(OpCode::JumpIfFalse.into());
             self.emit_byte(OpCode::Pop.into());
@@ -523,7 +952,9 @@ impl Compiler {
             self.consume(TokenType::Colon, "Expect ':' after case expression.");
             self.consume(TokenType::LeftBrace, "Expect '{' before case body.");
 
+            self.begin_scope();
             self.block();
+            self.end_scope();
 
             breaks_jumps.push(self.emit_jump(OpCode::Jump.into()));
 
@@ -536,111 +967,549 @@ impl Compiler {
             self.patch_jump(jump);
         }
 
-        self.emit_byte(OpCode::Pop.into()); // Remove switch value from the stack
+        if has_subject {
+            self.end_scope(); // Remove switch value from the stack
+        }
         self.consume(TokenType::RightBrace, "Expect '}' after switch cases.");
     }
 
-    fn for_statement(&self) {
-        self.begin_scope();
+    // Looks ahead through the switch body for labels that are all integer
+    // literals packed closely enough for a jump table, returning the lowest
+    // label and the table size. Any other label means the comparison chain.
+    fn dense_case_labels(&self) -> Option<(i64, usize)> {
+        let mut scanner = self.scanner_state.read().scanner.read().clone();
+        let mut token = (*self.scanner_state.read().current).clone();
+        let mut labels = Vec::new();
+        let mut depth = 0;
 
-        self.consume(TokenType::LeftParen, "Expect '(' after 'for'.");
+        loop {
+            match token.token_type {
+                TokenType::Eof | TokenType::Error => return None,
+                TokenType::LeftBrace => depth += 1,
+                TokenType::RightBrace if depth == 0 => break,
+                TokenType::RightBrace => depth -= 1,
+                TokenType::Case if depth == 0 => {
+                    let mut label = scanner.scan_token();
+                    let negative = label.token_type == TokenType::Minus;
+                    if negative {
+                        label = scanner.scan_token();
+                    }
+                    if label.token_type != TokenType::Number
+                        || scanner.scan_token().token_type != TokenType::Colon
+                    {
+                        return None;
+                    }
+
+                    let value = int_literal(&label.lexeme)?;
+                    labels.push(if negative {
+                        value.checked_neg()?
+                    } else {
+                        value
+                    });
+                }
+                _ => {}
+            }
+            token = scanner.scan_token();
+        }
 
-        if self.match_token(TokenType::Semicolon) {
-            // No initializer.
-        } else if self.match_token(TokenType::Var) {
-            self.var_declaration();
-        } else {
-            self.expression_statement();
+        // A short chain of comparisons is as quick as the table.
+        if labels.len() < 4 {
+            return None;
         }
 
-        let mut loop_start = self.get_chunk().read().code.len();
+        let first = *labels.iter().min()?;
+        let count = labels.iter().max()?.checked_sub(first)?.checked_add(1)? as usize;
+        if count > 2 * labels.len() || count > u8::MAX as usize {
+            return None;
+        }
+        Some((first, count))
+    }
 
-        let mut exit_jump = None;
-        if !self.match_token(TokenType::Semicolon) {
-            self.expression();
-            self.consume(TokenType::Semicolon, "Expect ';' after loop condition.");
+    // Emits a Switch that jumps straight to the case for the subject, with a
+    // slot for every label from `first` up. Unused slots and unmatched
+    // subjects go to the default case, or past the switch if there is none.
+    fn switch_table(&self, first: i64, count: usize) {
+        let constant = self.make_constant(Value::Int(first));
+        self.emit_indexed(OpCode::Switch, constant);
+        self.emit_byte(count as u8);
 
-            exit_jump = Some(self.emit_jump(OpCode::JumpIfFalse.into()));
-            self.emit_byte(OpCode::Pop.into());
+        let table = self.get_chunk().read().code.len();
+        for _ in 0..=count {
+            self.emit_bytes(0xff, 0xff);
         }
+        let start = self.get_chunk().read().code.len();
 
-        if !self.match_token(TokenType::RightParen) {
-            let body_jump = self.emit_jump(OpCode::Jump.into());
-            let increment_start = self.get_chunk().read().code.len();
-            self.expression();
-            self.emit_byte(OpCode::Pop.into());
-            self.consume(TokenType::RightParen, "Expect ')' after for clauses.");
+        let mut targets = vec![None; count];
+        let mut default = None;
+        let mut breaks_jumps = Vec::new();
 
-            self.emit_loop(loop_start);
-            loop_start = increment_start;
-            self.patch_jump(body_jump);
-        }
+        while self.scanner_state.read().current.clone().token_type != TokenType::RightBrace
+            && self.scanner_state.read().current.clone().token_type != TokenType::Eof
+        {
+            if self.match_token(TokenType::Default) {
+                self.consume(TokenType::Colon, "Expect ':' after 'default'.");
+                self.consume(TokenType::LeftBrace, "Expect '{' before default body.");
 
-        self.statement();
+                default = Some(self.get_chunk().read().code.len());
+                self.begin_scope();
+                self.block();
+                self.end_scope();
 
-        self.emit_loop(loop_start);
+                if self.check(&TokenType::Case) || self.check(&TokenType::Default) {
+                    self.error_at_current("Default case must be last.");
+                }
+                continue;
+            }
 
-        if let Some(exit_jump) = exit_jump {
-            self.patch_jump(exit_jump);
-            self.emit_byte(OpCode::Pop.into());
+            // `dense_case_labels` already checked the label's shape.
+            self.consume(TokenType::Case, "Expect 'case' after 'switch'.");
+            let negative = self.match_token(TokenType::Minus);
+            self.consume(TokenType::Number, "Expect case label.");
+            let value = int_literal(&self.scanner_state.read().previous.lexeme).unwrap_or(first);
+            let value = if negative { -value } else { value };
+            self.consume(TokenType::Colon, "Expect ':' after case expression.");
+            self.consume(TokenType::LeftBrace, "Expect '{' before case body.");
+
+            // Like the chain, a repeated label only ever selects its first case.
+            let slot = &mut targets[(value - first) as usize];
+            if slot.is_none() {
+                *slot = Some(self.get_chunk().read().code.len());
+            }
+
+            self.begin_scope();
+            self.block();
+            self.end_scope();
+
+            breaks_jumps.push(self.emit_jump(OpCode::Jump.into()));
         }
 
-        self.end_scope();
+        let end = self.get_chunk().read().code.len();
+        for jump in breaks_jumps {
+            self.patch_jump(jump);
+        }
+
+        let fallback = default.unwrap_or(end);
+        let targets = targets
+            .into_iter()
+            .map(|target| target.unwrap_or(fallback))
+            .chain(std::iter::once(fallback));
+        for (i, target) in targets.enumerate() {
+            let jump = target - start;
+            if jump > u16::MAX as usize {
+                self.error("Too much code to jump over.");
+            }
+
+            let chunk = self.get_chunk();
+            let mut chunk = chunk.write();
+            chunk.code[table + 2 * i] = ((jump >> 8) & 0xff) as u8;
+            chunk.code[table + 2 * i + 1] = (jump & 0xff) as u8;
+        }
     }
 
-    fn while_statement(&self) {
-        // We also handle break
+    // `name: <loop>`, naming the loop for `break` and `continue`.
+    fn labeled_statement(&self) {
+        self.advance();
+        let label = self.scanner_state.read().previous.lexeme.clone();
+        self.advance();
 
-        let loop_start = self.get_chunk().read().code.len();
+        if !(self.check(&TokenType::While)
+            || self.check(&TokenType::For)
+            || self.check(&TokenType::Do)
+            || self.check(&TokenType::Repeat))
+        {
+            self.error_at_current("Expect a loop after a label.");
+            return;
+        }
 
-        self.consume(TokenType::LeftParen, "Expect '(' after 'while'.");
-        self.expression();
-        self.consume(TokenType::RightParen, "Expect ')' after condition.");
+        if self
+            .loops
+            .read()
+            .iter()
+            .any(|loop_context| loop_context.label.as_ref() == Some(&label))
+        {
+            self.error(format!("Label '{}' is already in use.", label).as_str());
+        }
 
-        let exit_jump = self.emit_jump(OpCode::JumpIfFalse.into());
-        self.emit_byte(OpCode::Pop.into());
+        *self.loop_label.write() = Some(label);
         self.statement();
-        self.emit_loop(loop_start);
-
-        self.patch_jump(exit_jump);
-        self.emit_byte(OpCode::Pop.into());
     }
 
-    fn emit_loop(&self, loop_start: usize) {
-        self.emit_byte(OpCode::Loop.into());
-
-        let offset = self.get_chunk().read().code.len() - loop_start + 2;
-        if offset > u16::MAX as usize {
-            self.error("Loop body too large.");
+    // The loop `break` or `continue` targets, counting from the innermost:
+    // the one named by the label after the keyword, if there is one.
+    fn target_loop(&self, keyword: &str) -> Option<usize> {
+        if self.match_token(TokenType::Identifier) {
+            let label = self.scanner_state.read().previous.lexeme.clone();
+            let target = self
+                .loops
+                .read()
+                .iter()
+                .rposition(|loop_context| loop_context.label.as_ref() == Some(&label));
+            if target.is_none() {
+                self.error(format!("Unknown loop label '{}'.", label).as_str());
+            }
+            return target;
         }
 
-        self.emit_byte(((offset >> 8) & 0xff) as u8);
-        self.emit_byte((offset & 0xff) as u8);
+        let target = self.loops.read().len().checked_sub(1);
+        if target.is_none() {
+            self.error(format!("Cannot use '{}' outside of a loop.", keyword).as_str());
+        }
+        target
     }
 
-    fn if_statement(&self) {
-        self.consume(TokenType::LeftParen, "Expect '(' after 'if'.");
-        self.expression();
-        self.consume(TokenType::RightParen, "Expect ')' after condition.");
+    fn break_statement(&self) {
+        let target = match self.target_loop("break") {
+            Some(target) => target,
+            None => return,
+        };
+        let scope_depth = self.loops.read()[target].scope_depth;
 
-        let then_jump = self.emit_jump(OpCode::JumpIfFalse.into());
-        self.emit_byte(OpCode::Pop.into());
-        self.statement();
+        self.consume(TokenType::Semicolon, "Expect ';' after 'break'.");
 
-        let else_jump = self.emit_jump(OpCode::Jump.into());
+        self.discard_locals(scope_depth);
+        let jump = self.emit_jump(OpCode::Jump.into());
+        self.loops.write()[target].break_jumps.push(jump);
+    }
 
-        self.patch_jump(then_jump);
-        self.emit_byte(OpCode::Pop.into());
+    fn continue_statement(&self) {
+        let target = match self.target_loop("continue") {
+            Some(target) => target,
+            None => return,
+        };
+        let (scope_depth, continue_target) = {
+            let loops = self.loops.read();
+            (loops[target].scope_depth, loops[target].continue_target)
+        };
 
-        if self.match_token(TokenType::Else) {
-            self.statement();
+        self.consume(TokenType::Semicolon, "Expect ';' after 'continue'.");
+
+        self.discard_locals(scope_depth);
+        match continue_target {
+            Some(continue_target) => self.emit_loop(continue_target),
+            None => {
+                let jump = self.emit_jump(OpCode::Jump.into());
+                self.loops.write()[target].continue_jumps.push(jump);
+            }
         }
+    }
 
-        self.patch_jump(else_jump);
+    fn begin_loop(&self, continue_target: Option<usize>) {
+        self.loops.write().push(LoopContext {
+            scope_depth: self.scope_depth.load(std::sync::atomic::Ordering::SeqCst),
+            continue_target,
+            continue_jumps: Vec::new(),
+            break_jumps: Vec::new(),
+            label: self.loop_label.write().take(),
+        });
     }
 
-    fn emit_jump(&self, instruction: u8) -> usize {
-        self.emit_byte(instruction);
+    // Lands the forward `continue` jumps of the innermost loop here.
+    fn patch_continues(&self) {
+        let jumps = std::mem::take(&mut self.loops.write().last_mut().unwrap().continue_jumps);
+        for jump in jumps {
+            self.patch_jump(jump);
+        }
+    }
+
+    // Must be called once the loop's condition value has been popped, so
+    // that `break` lands with the same stack as a normal exit.
+    fn end_loop(&self) {
+        let loop_context = self.loops.write().pop().unwrap();
+        for jump in loop_context.break_jumps {
+            self.patch_jump(jump);
+        }
+    }
+
+    // Emits the pops for locals deeper than `depth` without forgetting them,
+    // for jumps that leave their scope early.
+    fn discard_locals(&self, depth: usize) {
+        self.close_resources(depth);
+
+        let locals = self.locals.read();
+        let mut pops = 0;
+        for local in locals.iter().rev() {
+            if local.depth <= depth {
+                break;
+            }
+
+            if local.is_captured {
+                self.emit_pops(pops);
+                pops = 0;
+                self.emit_byte(OpCode::CloseUpvalue.into());
+            } else {
+                pops += 1;
+            }
+        }
+        self.emit_pops(pops);
+    }
+
+    // Emits a `close()` call for each `with` resource deeper than `depth`,
+    // leaving the stack as it was.
+    fn close_resources(&self, depth: usize) {
+        let resources: Vec<u8> = self
+            .locals
+            .read()
+            .iter()
+            .enumerate()
+            .rev()
+            .take_while(|(_, local)| local.depth > depth)
+            .filter(|(_, local)| local.is_resource)
+            .map(|(slot, _)| slot as u8)
+            .collect();
+
+        for slot in resources {
+            let close = self.identifier_constant(&self.synthetic_token("close"));
+            self.emit_bytes(OpCode::GetLocal.into(), slot);
+            self.emit_indexed(OpCode::Invoke, close);
+            self.emit_byte(0);
+            self.emit_byte(OpCode::Pop.into());
+        }
+    }
+
+    fn with_statement(&self) {
+        self.begin_scope();
+
+        self.consume(TokenType::LeftParen, "Expect '(' after 'with'.");
+        self.parse_variable("Expect resource name.");
+        self.consume(TokenType::Equal, "Expect '=' after resource name.");
+        self.expression();
+        self.consume(TokenType::RightParen, "Expect ')' after resource.");
+        self.mark_initialized();
+        self.locals.write().last_mut().unwrap().is_resource = true;
+        self.emit_byte(OpCode::Resource.into());
+
+        self.statement();
+
+        self.end_scope();
+    }
+
+    fn for_statement(&self) {
+        self.begin_scope();
+
+        self.consume(TokenType::LeftParen, "Expect '(' after 'for'.");
+
+        if self.match_token(TokenType::Semicolon) {
+            // No initializer.
+        } else if self.match_token(TokenType::Var) {
+            let global = self.parse_variable("Expect variable name.");
+            if self.match_token(TokenType::Comma) {
+                self.indexed_for();
+                return;
+            }
+            self.var_initializer(global);
+        } else {
+            self.expression_statement();
+        }
+
+        let mut loop_start = self.get_chunk().read().code.len();
+        self.begin_loop(Some(loop_start));
+
+        let mut exit_jump = None;
+        if !self.match_token(TokenType::Semicolon) {
+            self.expression();
+            self.consume(TokenType::Semicolon, "Expect ';' after loop condition.");
+
+            exit_jump = Some(self.emit_jump(OpCode::JumpIfFalse.into()));
+            self.emit_byte(OpCode::Pop.into());
+        }
+
+        if !self.match_token(TokenType::RightParen) {
+            let body_jump = self.emit_jump(OpCode::Jump.into());
+            let increment_start = self.get_chunk().read().code.len();
+            self.expression();
+            self.emit_byte(OpCode::Pop.into());
+            self.consume(TokenType::RightParen, "Expect ')' after for clauses.");
+
+            self.emit_loop(loop_start);
+            loop_start = increment_start;
+            self.loops.write().last_mut().unwrap().continue_target = Some(increment_start);
+            self.patch_jump(body_jump);
+        }
+
+        self.statement();
+
+        self.emit_loop(loop_start);
+
+        if let Some(exit_jump) = exit_jump {
+            self.patch_jump(exit_jump);
+            self.emit_byte(OpCode::Pop.into());
+        }
+
+        self.end_loop();
+        self.end_scope();
+    }
+
+    // `for (var i, x in sequence)`, with the index variable already declared.
+    // Runs the body once per element of a string, array or bytes value, with
+    // `x` bound to `sequence[i]`.
+    fn indexed_for(&self) {
+        let index = (self.locals.read().len() - 1) as u8;
+        self.emit_constant(Value::Int(-1));
+        self.mark_initialized();
+
+        self.parse_variable("Expect variable name.");
+        let element = (self.locals.read().len() - 1) as u8;
+        self.emit_byte(OpCode::Nil.into());
+        self.mark_initialized();
+
+        self.consume(TokenType::In, "Expect 'in' after loop variables.");
+        self.expression();
+        self.consume(TokenType::RightParen, "Expect ')' after sequence.");
+
+        // The sequence lives in a hidden local, evaluated once, followed by
+        // the cursor OP_NEXT walks it with. For strings that is a byte offset,
+        // so each step is constant time.
+        self.add_local(&self.synthetic_token(""));
+        self.mark_initialized();
+        let sequence = (self.locals.read().len() - 1) as u8;
+        self.emit_constant(Value::Int(0));
+        self.add_local(&self.synthetic_token(""));
+        self.mark_initialized();
+
+        // The index is advanced at the top, so `continue` can jump there.
+        let loop_start = self.get_chunk().read().code.len();
+        self.emit_bytes(OpCode::GetLocal.into(), index);
+        self.emit_constant(Value::Int(1));
+        self.emit_byte(OpCode::Add.into());
+        self.emit_bytes(OpCode::SetLocal.into(), index);
+        self.emit_byte(OpCode::Pop.into());
+
+        // Pushes the next element, or nil past the end, then whether there
+        // was one.
+        self.emit_bytes(OpCode::Next.into(), sequence);
+        let exit_jump = self.emit_jump(OpCode::JumpIfFalse.into());
+        self.emit_byte(OpCode::Pop.into());
+        self.emit_bytes(OpCode::SetLocal.into(), element);
+        self.emit_byte(OpCode::Pop.into());
+
+        self.begin_loop(Some(loop_start));
+        self.statement();
+        self.emit_loop(loop_start);
+
+        self.patch_jump(exit_jump);
+        self.emit_byte(OpCode::Pop.into());
+        self.emit_byte(OpCode::Pop.into());
+        self.end_loop();
+
+        self.end_scope();
+    }
+
+    fn repeat_statement(&self) {
+        self.begin_scope();
+
+        self.consume(TokenType::LeftParen, "Expect '(' after 'repeat'.");
+        self.expression();
+        self.consume(TokenType::RightParen, "Expect ')' after repeat count.");
+        self.emit_byte(OpCode::CheckCount.into());
+
+        // The remaining count lives in a hidden local, counted down before
+        // each run of the body.
+        self.add_local(&self.synthetic_token(""));
+        self.mark_initialized();
+        let counter = (self.locals.read().len() - 1) as u8;
+
+        let loop_start = self.get_chunk().read().code.len();
+        self.emit_bytes(OpCode::GetLocal.into(), counter);
+        self.emit_constant(Value::Int(0));
+        self.emit_byte(OpCode::Greater.into());
+
+        let exit_jump = self.emit_jump(OpCode::JumpIfFalse.into());
+        self.emit_byte(OpCode::Pop.into());
+
+        self.emit_bytes(OpCode::GetLocal.into(), counter);
+        self.emit_constant(Value::Int(1));
+        self.emit_byte(OpCode::Subtract.into());
+        self.emit_bytes(OpCode::SetLocal.into(), counter);
+        self.emit_byte(OpCode::Pop.into());
+
+        self.begin_loop(Some(loop_start));
+        self.statement();
+        self.emit_loop(loop_start);
+
+        self.patch_jump(exit_jump);
+        self.emit_byte(OpCode::Pop.into());
+        self.end_loop();
+
+        self.end_scope();
+    }
+
+    fn while_statement(&self) {
+        let loop_start = self.get_chunk().read().code.len();
+
+        self.consume(TokenType::LeftParen, "Expect '(' after 'while'.");
+        self.expression();
+        self.consume(TokenType::RightParen, "Expect ')' after condition.");
+
+        let exit_jump = self.emit_jump(OpCode::JumpIfFalse.into());
+        self.emit_byte(OpCode::Pop.into());
+        self.begin_loop(Some(loop_start));
+        self.statement();
+        self.emit_loop(loop_start);
+
+        self.patch_jump(exit_jump);
+        self.emit_byte(OpCode::Pop.into());
+        self.end_loop();
+    }
+
+    // The body runs before the condition is first checked, so it always
+    // runs at least once.
+    fn do_statement(&self) {
+        let loop_start = self.get_chunk().read().code.len();
+
+        self.begin_loop(None);
+        self.statement();
+        self.patch_continues();
+
+        self.consume(TokenType::While, "Expect 'while' after 'do' body.");
+        self.consume(TokenType::LeftParen, "Expect '(' after 'while'.");
+        self.expression();
+        self.consume(TokenType::RightParen, "Expect ')' after condition.");
+        self.consume(TokenType::Semicolon, "Expect ';' after condition.");
+
+        let exit_jump = self.emit_jump(OpCode::JumpIfFalse.into());
+        self.emit_byte(OpCode::Pop.into());
+        self.emit_loop(loop_start);
+
+        self.patch_jump(exit_jump);
+        self.emit_byte(OpCode::Pop.into());
+        self.end_loop();
+    }
+
+    fn emit_loop(&self, loop_start: usize) {
+        self.emit_byte(OpCode::Loop.into());
+
+        let offset = self.get_chunk().read().code.len() - loop_start + 2;
+        if offset > u16::MAX as usize {
+            self.error("Loop body too large.");
+        }
+
+        self.emit_byte(((offset >> 8) & 0xff) as u8);
+        self.emit_byte((offset & 0xff) as u8);
+    }
+
+    fn if_statement(&self) {
+        self.consume(TokenType::LeftParen, "Expect '(' after 'if'.");
+        self.expression();
+        self.consume(TokenType::RightParen, "Expect ')' after condition.");
+
+        let then_jump = self.emit_jump(OpCode::JumpIfFalse.into());
+        self.emit_byte(OpCode::Pop.into());
+        self.statement();
+
+        let else_jump = self.emit_jump(OpCode::Jump.into());
+
+        self.patch_jump(then_jump);
+        self.emit_byte(OpCode::Pop.into());
+
+        if self.match_token(TokenType::Else) {
+            self.statement();
+        }
+
+        self.patch_jump(else_jump);
+    }
+
+    fn emit_jump(&self, instruction: u8) -> usize {
+        self.emit_byte(instruction);
         self.emit_byte(0xff);
         self.emit_byte(0xff);
         self.get_chunk().read().code.len() - 2
@@ -665,19 +1534,33 @@ impl Compiler {
     fn end_scope(&self) {
         self.scope_depth
             .fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
+        self.close_resources(self.scope_depth.load(std::sync::atomic::Ordering::SeqCst));
 
         let mut locals = self.locals.write();
-        while locals.len() > 0
+        let mut pops = 0;
+        while !locals.is_empty()
             && locals[locals.len() - 1].depth
                 > self.scope_depth.load(std::sync::atomic::Ordering::SeqCst)
         {
             if locals[locals.len() - 1].is_captured {
+                self.emit_pops(pops);
+                pops = 0;
                 self.emit_byte(OpCode::CloseUpvalue.into());
             } else {
-                self.emit_byte(OpCode::Pop.into());
+                pops += 1;
             }
             locals.pop();
         }
+        self.emit_pops(pops);
+    }
+
+    // Runs of plain locals leaving scope are dropped with a single PopN.
+    fn emit_pops(&self, count: usize) {
+        match count {
+            0 => {}
+            1 => self.emit_byte(OpCode::Pop.into()),
+            _ => self.emit_bytes(OpCode::PopN.into(), count as u8),
+        }
     }
 
     fn block(&self) {
@@ -693,13 +1576,57 @@ impl Compiler {
     fn expression_statement(&self) {
         self.expression();
         self.consume(TokenType::Semicolon, "Expect ';' after expression.");
-        self.emit_byte(OpCode::Pop.into());
+
+        // A loop body is never the last statement, even at the end of the
+        // source: returning there would cut the loop short.
+        if self.repl
+            && self.scope_depth.load(std::sync::atomic::Ordering::SeqCst) == 0
+            && self.loops.read().is_empty()
+            && self.check(&TokenType::Eof)
+        {
+            self.emit_byte(OpCode::Return.into());
+        } else {
+            self.emit_byte(OpCode::Pop.into());
+        }
     }
 
+    // `print a, b;` and `print(a, b);` both print their values on one line,
+    // separated by spaces. `print (a) + b;` still prints the sum.
     fn print_statement(&self) {
-        self.expression();
+        let mut count = 0;
+        if self.match_token(TokenType::LeftParen) {
+            if !self.check(&TokenType::RightParen) {
+                count = self.print_values();
+            }
+            self.consume(TokenType::RightParen, "Expect ')' after values.");
+
+            if count == 1 {
+                self.parse_infix(Precedence::Assignment, true);
+                if self.match_token(TokenType::Comma) {
+                    count += self.print_values();
+                }
+            }
+        } else {
+            count = self.print_values();
+        }
+
         self.consume(TokenType::Semicolon, "Expect ';' after value.");
-        self.emit_byte(OpCode::Print.into());
+        self.emit_bytes(OpCode::Print.into(), count);
+    }
+
+    fn print_values(&self) -> u8 {
+        let mut count = 0;
+        loop {
+            self.expression();
+            if count == 255 {
+                self.error("Cannot print more than 255 values.");
+            }
+            count += 1;
+            if !self.match_token(TokenType::Comma) {
+                break;
+            }
+        }
+        count as u8
     }
 
     fn synchronize(&self) {
@@ -714,9 +1641,12 @@ impl Compiler {
                 TokenType::Class
                 | TokenType::Fun
                 | TokenType::Var
+                | TokenType::Const
                 | TokenType::For
                 | TokenType::If
                 | TokenType::While
+                | TokenType::Do
+                | TokenType::With
                 | TokenType::Print
                 | TokenType::Return => return,
                 _ => {}
@@ -727,49 +1657,85 @@ impl Compiler {
     }
 
     pub fn number(&self, _can_assign: bool) {
-        let value = self
-            .scanner_state
-            .read()
-            .previous
-            .clone()
-            .lexeme
-            .parse::<i64>();
-        if value.is_ok() {
-            self.emit_constant(Value::Int(value.unwrap()));
+        let lexeme = self.scanner_state.read().previous.clone().lexeme;
+
+        if let Some(value) = int_literal(&lexeme) {
+            self.emit_constant(Value::Int(value));
+        } else if let Ok(value) = lexeme.parse::<f64>() {
+            self.emit_constant(Value::Float(value));
         } else {
-            let value = self
-                .scanner_state
-                .read()
-                .previous
-                .clone()
-                .lexeme
-                .parse::<f64>();
-            if value.is_ok() {
-                self.emit_constant(Value::Float(value.unwrap()));
-            } else {
-                self.error("Invalid number.");
-            }
+            self.error("Invalid number.");
         }
     }
 
     pub fn string(&self, _can_assign: bool) {
         let value = self.scanner_state.read().previous.clone().lexeme
             [1..self.scanner_state.read().previous.clone().lexeme.len() - 1]
-            .to_string();
+            .replace("\\$", "$");
         self.emit_constant(Value::String(value));
     }
 
+    // `"a ${x} b"` compiles to `"a " + str(x) + " b"`. Each chunk of text is
+    // an Interpolation token, bar the last, which is a plain String.
+    pub fn interpolation(&self, _can_assign: bool) {
+        self.interpolation_text();
+
+        loop {
+            // `${}`: the closing brace already resumed the string.
+            if self.scanner_state.read().current.lexeme.starts_with('}') {
+                self.error_at_current("Expect expression.");
+            }
+
+            self.expression();
+            self.emit_byte(OpCode::ToString.into());
+            self.emit_byte(OpCode::Add.into());
+
+            if !self.match_token(TokenType::Interpolation) {
+                break;
+            }
+            self.interpolation_text();
+            self.emit_byte(OpCode::Add.into());
+        }
+
+        if self.match_token(TokenType::String) {
+            self.string(false);
+            self.emit_byte(OpCode::Add.into());
+        } else {
+            self.error_at_current("Expect end of string interpolation.");
+        }
+    }
+
+    fn interpolation_text(&self) {
+        let lexeme = self.scanner_state.read().previous.clone().lexeme;
+        let text = lexeme[1..lexeme.len() - 2].replace("\\$", "$");
+        self.emit_constant(Value::String(text));
+    }
+
     pub fn variable(&self, can_assign: bool) {
         let previous = self.scanner_state.read().previous.clone();
+
+        // `__line__` is replaced by the line it appears on.
+        if previous.lexeme == "__line__" {
+            self.emit_constant(Value::Int(previous.line as i64));
+            return;
+        }
+
         self.named_variable(previous, can_assign);
     }
 
-    pub fn this(&self, _can_assign: bool) {
+    pub fn this(&self, can_assign: bool) {
         if self.class_compiler.read().is_none() {
             self.error("Cannot use 'this' outside of a class.");
             return;
         }
 
+        // `this` lives in slot 0, which holds the receiver for the whole call.
+        if can_assign && self.match_token(TokenType::Equal) {
+            self.error("Cannot assign to 'this'.");
+            self.expression();
+            return;
+        }
+
         self.variable(false);
     }
 
@@ -796,38 +1762,100 @@ impl Compiler {
     fn named_variable(&self, name: Box<Token>, can_assign: bool) {
         let get_op;
         let set_op;
-        let mut arg = self.resolve_local(name.clone());
+        let mut arg = self.resolve_local(&name) as usize;
 
-        if arg != u8::MAX {
+        if arg != u8::MAX as usize {
             get_op = OpCode::GetLocal;
             set_op = OpCode::SetLocal;
-        } else if self.resolve_up_value(name.clone()) != u8::MAX {
-            arg = self.resolve_up_value(name.clone());
+        } else if self.resolve_up_value(&name) != u8::MAX {
+            arg = self.resolve_up_value(&name) as usize;
             get_op = OpCode::GetUpvalue;
             set_op = OpCode::SetUpvalue;
         } else {
             get_op = OpCode::GetGlobal;
             set_op = OpCode::SetGlobal;
-            arg = self.identifier_constant(name.clone());
+            arg = self.identifier_constant(&name);
         }
 
         if can_assign && self.match_token(TokenType::Equal) {
+            if self.is_const(&name.lexeme) {
+                self.error("Cannot assign to const variable.");
+            }
+            self.forget_arity(&name.lexeme);
+            self.expression();
+            self.emit_indexed(set_op, arg);
+        } else if let Some(op) = self.match_compound_assign(can_assign) {
+            if self.is_const(&name.lexeme) {
+                self.error("Cannot assign to const variable.");
+            }
+            self.forget_arity(&name.lexeme);
+            self.emit_indexed(get_op, arg);
             self.expression();
-            self.emit_bytes(set_op.into(), arg);
+            self.emit_byte(op.into());
+            self.emit_indexed(set_op, arg);
         } else {
-            self.emit_bytes(get_op.into(), arg);
+            self.emit_indexed(get_op, arg);
+
+            // Only trusted outside loops entered since the declaration, where a
+            // later reassignment could run before this read.
+            if get_op == OpCode::GetLocal {
+                let known_arity = self.locals.read()[arg].known_arity;
+                if let Some((arity, loop_depth)) = known_arity {
+                    if loop_depth == self.loops.read().len() {
+                        *self.callee_arity.write() =
+                            Some((arity, self.get_chunk().read().code.len()));
+                    }
+                }
+            }
         }
     }
 
-    fn resolve_up_value(&self, name: Box<Token>) -> u8 {
+    // An assigned local function may no longer have the arity it was declared
+    // with, including when it is assigned through an upvalue.
+    fn forget_arity(&self, name: &str) {
+        if let Some(local) = self
+            .locals
+            .write()
+            .iter_mut()
+            .rev()
+            .find(|local| local.name == name)
+        {
+            local.known_arity = None;
+            return;
+        }
+
         if let Some(enclosing) = &self.enclosing {
-            let local = enclosing.resolve_local(name.clone());
+            enclosing.forget_arity(name);
+        }
+    }
+
+    // Consumes a compound assignment operator such as `+=` and returns the
+    // arithmetic opcode it applies before storing.
+    fn match_compound_assign(&self, can_assign: bool) -> Option<OpCode> {
+        if !can_assign {
+            return None;
+        }
+
+        let op = match self.scanner_state.read().current.token_type {
+            TokenType::PlusEqual => OpCode::Add,
+            TokenType::MinusEqual => OpCode::Subtract,
+            TokenType::StarEqual => OpCode::Multiply,
+            TokenType::SlashEqual => OpCode::Divide,
+            _ => return None,
+        };
+        self.advance();
+        Some(op)
+    }
+
+    fn resolve_up_value(&self, name: &Token) -> u8 {
+        if let Some(enclosing) = &self.enclosing {
+            let local = enclosing.resolve_local(name);
             if local != u8::MAX {
                 self.enclosing.as_ref().unwrap().locals.write()[local as usize].is_captured = true;
                 return self.add_up_value(local, true);
             }
 
-            let up_value = enclosing.resolve_up_value(name.clone());
+            let up_value = enclosing.resolve_up_value(name);
             if up_value != u8::MAX {
                 return self.add_up_value(up_value, false);
             }
@@ -850,7 +1878,7 @@ impl Compiler {
         self.up_values.read().len() as u8 - 1
     }
 
-    fn resolve_local(&self, name: Box<Token>) -> u8 {
+    fn resolve_local(&self, name: &Token) -> u8 {
         let locals = self.locals.read();
         for i in (0..locals.len()).rev() {
             let local = &locals[i];
@@ -865,6 +1893,29 @@ impl Compiler {
         u8::MAX
     }
 
+    pub fn array(&self, _can_assign: bool) {
+        let mut count = 0;
+        if !self.check(&TokenType::RightBracket) {
+            loop {
+                self.expression();
+                if count == 255 {
+                    self.error("Cannot have more than 255 elements in an array literal.");
+                }
+                count += 1;
+                if !self.match_token(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+
+        self.consume(TokenType::RightBracket, "Expect ']' after array elements.");
+        self.emit_bytes(OpCode::BuildArray.into(), count as u8);
+    }
+
+    pub fn lambda(&self, _can_assign: bool) {
+        self.function(FunctionType::Function);
+    }
+
     pub fn grouping(&self, _can_assign: bool) {
         self.expression();
         self.consume(TokenType::RightParen, "Expect ')' after expression.");
@@ -873,8 +1924,13 @@ impl Compiler {
     pub fn unary(&self, _can_assign: bool) {
         let operator_type = &self.scanner_state.read().previous.clone().token_type;
 
+        let operand_start = self.get_chunk().read().code.len();
         self.parse_precedence(Precedence::Unary);
 
+        if *operator_type == TokenType::Minus && self.fold_negation(operand_start) {
+            return;
+        }
+
         match operator_type {
             TokenType::Minus => self.emit_byte(OpCode::Negate.into()),
             TokenType::Bang => self.emit_byte(OpCode::Not.into()),
@@ -882,57 +1938,179 @@ impl Compiler {
         }
     }
 
+    // If the operand compiled to nothing but a numeric constant, negate the
+    // constant in place instead of emitting a Negate.
+    fn fold_negation(&self, operand_start: usize) -> bool {
+        let chunk = self.get_chunk();
+        let mut chunk = chunk.write();
+
+        let constant = match chunk
+            .code
+            .get(operand_start)
+            .map(|&byte| OpCode::try_from(byte))
+        {
+            Some(Ok(OpCode::Constant)) if chunk.code.len() == operand_start + 2 => {
+                chunk.code[operand_start + 1] as usize
+            }
+            Some(Ok(OpCode::ConstantLong)) if chunk.code.len() == operand_start + 4 => {
+                let operand = &chunk.code[operand_start + 1..];
+                (operand[0] as usize) << 16 | (operand[1] as usize) << 8 | operand[2] as usize
+            }
+            _ => return false,
+        };
+        let negated = match chunk.constants[constant] {
+            Value::Int(i) => match i.checked_neg() {
+                Some(i) => Value::Int(i),
+                None => return false,
+            },
+            Value::Float(f) => Value::Float(-f),
+            _ => return false,
+        };
+
+        chunk.constants[constant] = negated;
+        true
+    }
+
     pub fn binary(&self, _can_assign: bool) {
-        let operator_type = self.scanner_state.read().previous.clone().token_type;
+        let operator = self.scanner_state.read().previous.clone();
+        let operator_type = operator.token_type.clone();
 
+        // Binary operators are left-associative, so the right operand binds
+        // tighter; `**` is right-associative and parses at its own level.
         let rule = self.get_rule(&operator_type);
-        self.parse_precedence(rule.precedence.into());
+        if operator_type == TokenType::StarStar {
+            self.parse_precedence(rule.precedence);
+        } else {
+            self.parse_precedence(rule.precedence.next());
+        }
 
-        match operator_type {
-            TokenType::BangEqual => {
-                self.emit_bytes(OpCode::Equal.into(), OpCode::Not.into());
-            }
-            TokenType::EqualEqual => {
-                self.emit_byte(OpCode::Equal.into());
+        if rule.precedence == Precedence::Comparison {
+            self.comparison_chain(operator);
+        } else {
+            self.emit_operator(&operator);
+        }
+    }
+
+    // `a < b < c` means `a < b and b < c`, with `b` evaluated once: each
+    // middle operand is tucked under the comparison that consumes it, and
+    // the first false link short-circuits the rest.
+    fn comparison_chain(&self, first: Box<Token>) {
+        let mut operator = first;
+        let mut fail_jumps = Vec::new();
+
+        while self
+            .get_rule(&self.scanner_state.read().current.token_type)
+            .precedence
+            == Precedence::Comparison
+        {
+            self.emit_byte(OpCode::Tuck.into());
+            self.emit_operator(&operator);
+            fail_jumps.push(self.emit_jump(OpCode::JumpIfFalse.into()));
+            self.emit_byte(OpCode::Pop.into());
+
+            self.advance();
+            operator = self.scanner_state.read().previous.clone();
+            self.parse_precedence(Precedence::Comparison.next());
+        }
+
+        self.emit_operator(&operator);
+
+        if !fail_jumps.is_empty() {
+            let end_jump = self.emit_jump(OpCode::Jump.into());
+            for jump in fail_jumps {
+                self.patch_jump(jump);
             }
-            TokenType::Greater => {
-                self.emit_byte(OpCode::Greater.into());
+            // [operand false] -> [false]
+            self.emit_bytes(OpCode::Pop.into(), OpCode::Pop.into());
+            self.emit_byte(OpCode::False.into());
+            self.patch_jump(end_jump);
+        }
+    }
+
+    // Tagged with the operator's position rather than the right operand's, so
+    // a failing operation is reported where the operator is written.
+    fn emit_operator(&self, operator: &Token) {
+        let chunk = self.get_chunk();
+        let emit = |op: OpCode| {
+            chunk
+                .write()
+                .write(op.into(), operator.line, operator.column)
+        };
+
+        match operator.token_type {
+            TokenType::BangEqual => {
+                emit(OpCode::Equal);
+                emit(OpCode::Not);
             }
+            TokenType::EqualEqual => emit(OpCode::Equal),
+            TokenType::Greater => emit(OpCode::Greater),
             TokenType::GreaterEqual => {
-                self.emit_bytes(OpCode::Less.into(), OpCode::Not.into());
-            }
-            TokenType::Less => {
-                self.emit_byte(OpCode::Less.into());
+                emit(OpCode::Less);
+                emit(OpCode::Not);
             }
+            TokenType::Less => emit(OpCode::Less),
             TokenType::LessEqual => {
-                self.emit_bytes(OpCode::Greater.into(), OpCode::Not.into());
+                emit(OpCode::Greater);
+                emit(OpCode::Not);
             }
-            TokenType::Plus => self.emit_byte(OpCode::Add.into()),
-            TokenType::Minus => self.emit_byte(OpCode::Subtract.into()),
-            TokenType::Star => self.emit_byte(OpCode::Multiply.into()),
-            TokenType::Slash => self.emit_byte(OpCode::Divide.into()),
+            TokenType::Plus => emit(OpCode::Add),
+            TokenType::Minus => emit(OpCode::Subtract),
+            TokenType::Star => emit(OpCode::Multiply),
+            TokenType::Slash => emit(OpCode::Divide),
+            TokenType::Percent => emit(OpCode::Modulo),
+            TokenType::StarStar => emit(OpCode::Power),
             _ => unreachable!(),
         }
     }
 
     pub fn call(&self, _can_assign: bool) {
+        let callee_end = self.get_chunk().read().code.len();
+        let callee_arity = self.callee_arity.write().take();
         let arg_count = self.argument_list();
+
+        if let Some((arity, end)) = callee_arity {
+            if end == callee_end && arity != arg_count as usize {
+                self.error(&format!(
+                    "Expected {} arguments but got {}.",
+                    arity, arg_count
+                ));
+            }
+        }
         self.emit_bytes(OpCode::Call.into(), arg_count);
+        *self.last_call.write() = Some(self.get_chunk().read().code.len());
     }
 
     pub fn dot(&self, can_assign: bool) {
         self.consume(TokenType::Identifier, "Expect property name after '.'.");
-        let name = self.identifier_constant(self.scanner_state.read().previous.clone());
+        let name = self.identifier_constant(&self.scanner_state.read().previous.clone());
 
         if can_assign && self.match_token(TokenType::Equal) {
             self.expression();
-            self.emit_bytes(OpCode::SetProperty.into(), name);
+            self.emit_indexed(OpCode::SetProperty, name);
+        } else if let Some(op) = self.match_compound_assign(can_assign) {
+            self.emit_byte(OpCode::Duplicate.into());
+            self.emit_indexed(OpCode::GetProperty, name);
+            self.expression();
+            self.emit_byte(op.into());
+            self.emit_indexed(OpCode::SetProperty, name);
         } else if self.match_token(TokenType::LeftParen) {
             let arg_count = self.argument_list();
-            self.emit_bytes(OpCode::Invoke.into(), name);
+            self.emit_indexed(OpCode::Invoke, name);
             self.emit_byte(arg_count);
         } else {
-            self.emit_bytes(OpCode::GetProperty.into(), name);
+            self.emit_indexed(OpCode::GetProperty, name);
+        }
+    }
+
+    pub fn index(&self, can_assign: bool) {
+        self.expression();
+        self.consume(TokenType::RightBracket, "Expect ']' after index.");
+
+        if can_assign && self.match_token(TokenType::Equal) {
+            self.expression();
+            self.emit_byte(OpCode::SetIndex.into());
+        } else {
+            self.emit_byte(OpCode::GetIndex.into());
         }
     }
 
@@ -980,6 +2158,12 @@ impl Compiler {
         let can_assign = precedence <= Precedence::Assignment;
         prefix_rule.as_ref().unwrap()(self, can_assign);
 
+        self.parse_infix(precedence, can_assign);
+    }
+
+    // The infix half of `parse_precedence`, continuing an expression whose
+    // first operand has been compiled.
+    fn parse_infix(&self, precedence: Precedence, can_assign: bool) {
         while precedence
             <= self
                 .get_rule(&self.scanner_state.read().current.clone().token_type)
@@ -993,12 +2177,14 @@ impl Compiler {
             infix_rule.as_ref().unwrap()(self, can_assign);
         }
 
-        if can_assign && self.match_token(TokenType::Equal) {
+        if can_assign
+            && (self.match_token(TokenType::Equal) || self.match_compound_assign(true).is_some())
+        {
             self.error("Invalid assignment target.");
         }
     }
 
-    fn parse_variable(&self, error_message: &str) -> u8 {
+    fn parse_variable(&self, error_message: &str) -> usize {
         self.consume(TokenType::Identifier, error_message);
 
         self.declare_variable();
@@ -1007,16 +2193,20 @@ impl Compiler {
             return 0;
         }
 
-        self.identifier_constant(self.scanner_state.read().previous.clone())
+        // Declaring a global again replaces it; `const_declaration` marks it
+        // const again if that's what this declaration is.
+        let name = self.scanner_state.read().previous.clone();
+        self.const_globals.write().remove(&name.lexeme);
+        self.identifier_constant(&name)
     }
 
-    fn define_variable(&self, global: u8) {
+    fn define_variable(&self, global: usize) {
         if self.scope_depth.load(std::sync::atomic::Ordering::SeqCst) != 0 {
             self.mark_initialized();
             return;
         }
 
-        self.emit_bytes(OpCode::DefineGlobal.into(), global);
+        self.emit_indexed(OpCode::DefineGlobal, global);
     }
 
     fn mark_initialized(&self) {
@@ -1029,20 +2219,38 @@ impl Compiler {
         locals[length - 1].depth = self.scope_depth.load(std::sync::atomic::Ordering::SeqCst);
     }
 
-    fn identifier_constant(&self, name: Box<Token>) -> u8 {
-        self.make_constant(Value::String(name.lexeme.clone()))
+    // Names are reused rather than added again, keeping the table small and
+    // their indices low.
+    fn identifier_constant(&self, name: &Token) -> usize {
+        let symbol = Value::Symbol(Symbol::intern(&name.lexeme));
+        let existing = self
+            .get_chunk()
+            .read()
+            .constants
+            .iter()
+            .position(|constant| *constant == symbol);
+        match existing {
+            Some(constant) => constant,
+            None => self.make_constant(symbol),
+        }
     }
 
-    fn add_local(&self, name: Box<Token>) {
+    fn add_local(&self, name: &Token) {
         if self.locals.read().len() == u8::MAX as usize {
             self.error("Too many local variables in function.");
             return;
         }
 
+        // The local stays uninitialized (usize::MAX) until `mark_initialized`
+        // runs, so reading it from its own initializer, directly or through an
+        // upvalue in a nested function, is reported by `resolve_local`.
         self.locals.write().push(Local {
             name: name.lexeme.clone(),
-            depth: self.scope_depth.load(std::sync::atomic::Ordering::SeqCst),
+            depth: usize::MAX,
             is_captured: false,
+            is_resource: false,
+            is_const: false,
+            known_arity: None,
         });
     }
 
@@ -1052,7 +2260,10 @@ impl Compiler {
         }
 
         let name = self.scanner_state.read().previous.clone();
+        self.declare_local(&name);
+    }
 
+    fn declare_local(&self, name: &Token) {
         for i in (0..self.locals.read().len()).rev() {
             let local = &self.locals.read()[i];
             if local.depth != usize::MAX
@@ -1070,7 +2281,7 @@ impl Compiler {
     }
 
     fn get_rule(&self, token_type: &TokenType) -> &ParseRule {
-        RULES.get(token_type).unwrap()
+        &RULES[token_type.clone() as usize]
     }
 
     fn match_token(&self, token_type: TokenType) -> bool {
@@ -1085,4 +2296,390 @@ impl Compiler {
     fn check(&self, token_type: &TokenType) -> bool {
         self.scanner_state.read().current.token_type == *token_type
     }
+
+    // The type of the token after `current`, without advancing.
+    fn peek_next(&self) -> TokenType {
+        let scanner = self.scanner_state.read().scanner.clone();
+        let token_type = scanner.write().peek_token().token_type;
+        token_type
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compiles(source: &str) -> bool {
+        let scanner = Rc::new(RwLock::new(Scanner::new(source.to_string())));
+        Compiler::new(FunctionType::Script, scanner)
+            .compile_function()
+            .is_some()
+    }
+
+    fn messages(source: &str) -> Vec<String> {
+        compile(source.to_string())
+            .diagnostics
+            .into_iter()
+            .map(|diagnostic| diagnostic.message)
+            .collect()
+    }
+
+    #[test]
+    fn locals_cannot_read_themselves_in_their_initializer() {
+        assert!(!compiles("{ var a = a; }"));
+        assert!(!compiles("{ var a = 1; { var a = a + 1; } }"));
+        assert!(!compiles("fun f() { var a = a; }"));
+
+        assert!(compiles("{ var a = 1; { var b = a; } }"));
+        assert!(compiles("{ var a = 1; fun f() { return a; } }"));
+        assert!(!compiles("{ var a = fun() { return a; }(); }"));
+        assert!(!compiles(
+            "fun f() { var a = fun() { return fun() { return a; }; }; }"
+        ));
+        assert!(compiles("{ var a = 1; var b = fun() { return a; }(); }"));
+        // Globals are resolved at runtime instead.
+        assert!(compiles("var a = a;"));
+    }
+
+    fn constants(source: &str) -> (Vec<u8>, Vec<Value>) {
+        let scanner = Rc::new(RwLock::new(Scanner::new(source.to_string())));
+        let function = Compiler::new(FunctionType::Script, scanner)
+            .compile_function()
+            .expect("source should compile");
+        let function = function.read();
+        let chunk = function.chunk.read();
+        (chunk.code.clone(), chunk.constants.clone())
+    }
+
+    #[test]
+    fn negative_literals_compile_to_one_constant() {
+        let constant = u8::from(OpCode::Constant);
+        let print = u8::from(OpCode::Print);
+        let (code, values) = constants("print -5; print -2.5; print -(7);");
+        assert_eq!(
+            code[..12],
+            [constant, 0, print, 1, constant, 1, print, 1, constant, 2, print, 1]
+        );
+        assert_eq!(values, [Value::Int(-5), Value::Float(-2.5), Value::Int(-7)]);
+
+        let (code, _) = constants("var x = 2; print -x;");
+        let negate = u8::from(OpCode::Negate);
+        assert!(code.windows(2).any(|pair| pair == [negate, print]));
+
+        // Past the 256th constant the literal is a CONSTANT_LONG.
+        let filler: String = (0..300).map(|i| format!("print {};", i)).collect();
+        let (code, values) = constants(&format!("{} print -5;", filler));
+        let long = u8::from(OpCode::ConstantLong);
+        assert_eq!(code[code.len() - 8..code.len() - 4], [long, 0, 1, 44]);
+        assert_eq!(values.last(), Some(&Value::Int(-5)));
+    }
+
+    // The function constant named `name` among `function`'s constants.
+    fn function_named(function: &Function, name: &str) -> Function {
+        function
+            .chunk()
+            .constants
+            .iter()
+            .find_map(|constant| match constant {
+                Value::Function(f) if f.read().name() == name => Some(f.read().clone()),
+                _ => None,
+            })
+            .unwrap_or_else(|| panic!("no function named {}", name))
+    }
+
+    #[test]
+    fn exposes_function_metadata() {
+        let scanner = Rc::new(RwLock::new(Scanner::new(
+            "fun add(a, b) { return a + b; }
+             fun counter() { var n = 0; fun inc() { n = n + 1; } return inc; }"
+                .to_string(),
+        )));
+        let script = Compiler::new(FunctionType::Script, scanner)
+            .compile_function()
+            .expect("source should compile");
+        let script = script.read();
+        assert_eq!(script.arity(), 0);
+        assert_eq!(script.up_value_count(), 0);
+
+        let add = function_named(&script, "add");
+        assert_eq!(add.name(), "add");
+        assert_eq!(add.arity(), 2);
+        assert!(!add.chunk().code.is_empty());
+
+        let inc = function_named(&function_named(&script, "counter"), "inc");
+        assert_eq!(inc.arity(), 0);
+        assert_eq!(inc.up_value_count(), 1);
+    }
+
+    #[test]
+    fn this_cannot_be_assigned() {
+        assert!(!compiles("class A { m() { this = 5; } }"));
+        assert!(!compiles("class A { m() { fun f() { this = nil; } } }"));
+        assert!(compiles("class A { m() { this.x = 5; return this; } }"));
+    }
+
+    #[test]
+    fn continue_needs_an_enclosing_loop() {
+        assert!(!compiles("continue;"));
+        assert!(!compiles("if (true) { continue; }"));
+        assert!(!compiles("while (true) { fun f() { continue; } }"));
+        assert!(compiles("while (true) { { var a = 1; continue; } }"));
+    }
+
+    #[test]
+    fn compound_assignment_needs_a_target() {
+        for source in [
+            "1 += 2;",
+            "var a; var b; a + b -= 1;",
+            "(a) *= 2;",
+            "f() /= 2;",
+        ] {
+            assert!(!compiles(source), "{}", source);
+        }
+        assert!(compiles("var a = 1; a += 1; a -= 1; a *= 2; a /= 2;"));
+    }
+
+    #[test]
+    fn parses_radix_prefixed_integers() {
+        let (_, values) = constants("print 0xFF; print 0xff; print 0b1010; print 0o17;");
+        assert_eq!(
+            values,
+            [
+                Value::Int(255),
+                Value::Int(255),
+                Value::Int(10),
+                Value::Int(15)
+            ]
+        );
+        let (_, values) = constants("print 0x7FFFFFFFFFFFFFFF;");
+        assert_eq!(values, [Value::Int(i64::MAX)]);
+
+        for source in [
+            "print 0xZZ;",
+            "print 0b;",
+            "print 0o8;",
+            "print 0b102;",
+            "print 0x8000000000000000;",
+        ] {
+            assert!(!compiles(source), "{}", source);
+        }
+    }
+
+    #[test]
+    fn line_intrinsic_is_a_constant() {
+        let source = format!("{}print __line__;", "\n".repeat(9));
+        let (code, values) = constants(&source);
+        assert_eq!(code[..2], [u8::from(OpCode::Constant), 0]);
+        assert_eq!(values, [Value::Int(10)]);
+
+        assert!(!compiles("__line__ = 3;"));
+    }
+
+    #[test]
+    fn inheritance_errors() {
+        assert!(!compiles("class A < A {}"));
+        assert!(!compiles("class A { m() { return super.m(); } }"));
+        assert!(!compiles("fun f() { super.m(); }"));
+        assert!(compiles(
+            "class A {} class B < A { m() { return super.m; } }"
+        ));
+    }
+
+    #[test]
+    fn rest_parameter_must_be_last() {
+        assert!(!compiles("fun f(...rest, last) {}"));
+        assert!(compiles("fun f(...rest) {} fun g(a, b, ...rest) {}"));
+    }
+
+    #[test]
+    fn constants_cannot_be_reassigned() {
+        assert!(compiles("const a = 1; print a + 1;"));
+        assert!(!compiles("const a = 1; a = 2;"));
+        assert!(!compiles("{ const a = 1; a = 2; }"));
+        assert!(!compiles("fun f() { const a = 1; fun g() { a = 2; } }"));
+        assert!(!compiles("const a;"));
+
+        // A local of the same name shadows the constant.
+        assert!(compiles("const a = 1; { var a = 2; a = 3; }"));
+        assert!(compiles("const a = 1; var a = 2; a = 3;"));
+    }
+
+    #[test]
+    fn interpolations_need_an_expression() {
+        assert!(compiles(r#"var x = 1; print "${x}${x + 1}";"#));
+        assert!(!compiles(r#"print "${}";"#));
+        assert!(!compiles(r#"print "${1 2}";"#));
+        assert!(!compiles(r#"print "${1"#));
+    }
+
+    #[test]
+    fn direct_calls_to_local_functions_check_arity() {
+        assert!(!compiles("{ fun f(a) {} f(1, 2); }"));
+        assert!(!compiles("fun outer() { fun f(a) {} return f(1, 2); }"));
+        assert!(!compiles("{ fun f() {} f(1); }"));
+
+        assert!(compiles("{ fun f(a) {} f(1); }"));
+        // Variadic, global, reassigned and loop-reassignable functions are
+        // only checked at runtime.
+        assert!(compiles("{ fun f(a, ...rest) {} f(1, 2, 3); }"));
+        assert!(compiles("fun f(a) {} f(1, 2);"));
+        assert!(compiles("{ fun f(a) {} f = fun(a, b) {}; f(1, 2); }"));
+        assert!(compiles(
+            "{ fun f(a) {} while (true) { f(1, 2); f = fun(a, b) {}; } }"
+        ));
+    }
+
+    #[test]
+    fn locals_leaving_scope_are_popped_together() {
+        let pop = u8::from(OpCode::Pop);
+        let pop_n = u8::from(OpCode::PopN);
+        let close = u8::from(OpCode::CloseUpvalue);
+        let ret = u8::from(OpCode::Return);
+
+        let (code, _) = constants("{ var a = 1; var b = 2; var c = 3; }");
+        assert!(code.ends_with(&[pop_n, 3, u8::from(OpCode::Nil), ret]));
+
+        let (code, _) = constants("{ var a = 1; }");
+        assert!(code.ends_with(&[pop, u8::from(OpCode::Nil), ret]));
+        assert!(!code.contains(&pop_n));
+
+        // A captured local is closed on its own and splits the run.
+        let (code, _) = constants(
+            "{ var a = 1; var b = 2; var c = fun() { return b; }; var d = 4; var e = 5; }",
+        );
+        assert!(code
+            .windows(5)
+            .any(|window| window == [pop_n, 3, close, pop, u8::from(OpCode::Nil)]));
+    }
+
+    #[test]
+    fn destructuring_patterns_are_checked() {
+        assert!(compiles("var { a, b } = x; { var { c } = x; }"));
+        assert!(!compiles("{ var { a, a } = x; }"));
+        assert!(!compiles("var { a, b = x;"));
+        assert!(!compiles("var { a } x;"));
+
+        // Destructuring declares its names, so a const of the same name is
+        // replaced rather than assigned to.
+        assert!(compiles("const a = 1; var { a } = x; a = 2;"));
+        assert!(compiles("{ const a = 1; { var { a } = x; a = 2; } }"));
+    }
+
+    #[test]
+    fn compile_keeps_the_code_around_an_error() {
+        let result = compile("print \"before\";\nvar = 1;\nprint \"after\";".to_string());
+        assert!(!result.is_runnable());
+        assert_eq!(result.diagnostics.len(), 1);
+        let diagnostic = &result.diagnostics[0];
+        assert_eq!(
+            (
+                diagnostic.line,
+                diagnostic.location.as_str(),
+                diagnostic.message.as_str()
+            ),
+            (2, " at '='", "Expect variable name.")
+        );
+
+        let function = result.function.read();
+        let constants = &function.chunk.read().constants;
+        assert!(constants.contains(&Value::String("before".to_string())));
+        assert!(constants.contains(&Value::String("after".to_string())));
+
+        let result = compile("print ;\nprint 1;\nvar 2;".to_string());
+        let lines: Vec<usize> = result.diagnostics.iter().map(|d| d.line).collect();
+        assert_eq!(lines, vec![1, 3]);
+    }
+
+    #[test]
+    fn int_literal_reads_every_radix() {
+        assert_eq!(int_literal("42"), Some(42));
+        assert_eq!(int_literal("0xff"), Some(255));
+        assert_eq!(int_literal("0b1010"), Some(10));
+        assert_eq!(int_literal("0o17"), Some(15));
+        assert_eq!(int_literal("0x8000000000000000"), None);
+        assert_eq!(int_literal("0b102"), None);
+        assert_eq!(int_literal("1.5"), None);
+    }
+
+    #[test]
+    fn diagnostics_point_at_the_offending_column() {
+        let result = compile("var a = 1;\nprint a +* 2;".to_string());
+        assert_eq!(
+            result.diagnostics[0].to_string(),
+            "[line 2:col 10] Error at '*': Expect expression."
+        );
+        let result = compile("print 1; var b = 2 print b;".to_string());
+        assert_eq!(
+            (result.diagnostics[0].line, result.diagnostics[0].column),
+            (1, 20)
+        );
+    }
+
+    #[test]
+    fn only_returned_calls_become_tail_calls() {
+        let listing = |body: &str| {
+            let result = compile(format!("fun g(x) {{ {} }}", body));
+            assert!(result.is_runnable());
+            let script = result.function.read();
+            let chunk = script.chunk.read();
+            let function = chunk
+                .constants
+                .iter()
+                .find_map(|constant| match constant {
+                    Value::Function(function) => Some(function.clone()),
+                    _ => None,
+                })
+                .unwrap();
+            let function = function.read();
+            let listing = crate::debug::disassemble(&function.chunk.read(), "g", None);
+            listing
+        };
+
+        assert!(listing("return g(x);").contains("OP_TAIL_CALL"));
+        assert!(listing("if (x) return g(x); return x;").contains("OP_TAIL_CALL"));
+        for body in [
+            "return g(x) + 1;",
+            "g(x); return x;",
+            "return -g(x);",
+            "return x;",
+        ] {
+            let code = listing(body);
+            assert!(!code.contains("OP_TAIL_CALL"), "{}\n{}", body, code);
+        }
+    }
+
+    #[test]
+    fn do_while_needs_its_condition() {
+        assert!(messages("do print 1; while (true);").is_empty());
+        assert_eq!(
+            messages("do { print 1; } (true);"),
+            vec!["Expect 'while' after 'do' body.".to_string()]
+        );
+        assert_eq!(
+            messages("do { print 1; } while (true)"),
+            vec!["Expect ';' after condition.".to_string()]
+        );
+    }
+
+    #[test]
+    fn loop_labels_are_checked() {
+        assert_eq!(
+            messages("a: while (true) { break b; }"),
+            vec!["Unknown loop label 'b'.".to_string()]
+        );
+        assert_eq!(
+            messages("a: while (true) { a: while (true) {} }")[0],
+            "Label 'a' is already in use."
+        );
+        assert_eq!(
+            messages("a: print 1;"),
+            vec!["Expect a loop after a label.".to_string()]
+        );
+        // A function body is not inside the loops around it.
+        assert_eq!(
+            messages("a: while (true) { fun f() { continue a; } }"),
+            vec!["Unknown loop label 'a'.".to_string()]
+        );
+        assert!(messages("a: while (true) {} a: while (true) { break a; }").is_empty());
+    }
 }
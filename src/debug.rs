@@ -1,139 +1,509 @@
 use crate::chunk::{Chunk, OpCode};
 use crate::value::Value;
+use std::fmt::Write;
 
-pub fn disassemble(chunk: &Chunk, name: &str, current_offset: Option<usize>) {
+// Renders `chunk` as text, marking `current_offset` with an arrow if given.
+pub fn disassemble(chunk: &Chunk, name: &str, current_offset: Option<usize>) -> String {
+    let mut out = String::new();
     let mut offset = 0;
 
-    fn simple_instruction(name: &str, offset: &mut usize) {
-        println!("{}", name);
+    fn simple_instruction(out: &mut String, name: &str, offset: &mut usize) {
+        writeln!(out, "{}", name).unwrap();
         *offset += 1;
     }
 
-    fn constant_instruction(chunk: &Chunk, name: &str, offset: &mut usize) {
-        let constant = chunk.code[*offset + 1];
-        print!("{:16} {:4} '", name, constant);
-        println!("{}'", chunk.constants[constant as usize]);
-        *offset += 2;
+    // The constant index after the opcode at `offset`, a byte or, for the
+    // long forms, 24 bits.
+    fn constant_index(chunk: &Chunk, offset: usize, width: usize) -> usize {
+        chunk.code[offset + 1..offset + 1 + width]
+            .iter()
+            .fold(0, |index, &byte| index << 8 | byte as usize)
+    }
+
+    fn constant_instruction(
+        out: &mut String,
+        chunk: &Chunk,
+        name: &str,
+        offset: &mut usize,
+        width: usize,
+    ) {
+        let constant = constant_index(chunk, *offset, width);
+        write!(out, "{:16} {:4} '", name, constant).unwrap();
+        writeln!(out, "{}'", chunk.constants[constant]).unwrap();
+        *offset += 1 + width;
     }
 
-    fn byte_instruction(chunk: &Chunk, name: &str, offset: &mut usize) {
+    fn byte_instruction(out: &mut String, chunk: &Chunk, name: &str, offset: &mut usize) {
         let slot = chunk.code[*offset + 1];
-        print!("{:16} {:4}", name, slot);
+        write!(out, "{:16} {:4}", name, slot).unwrap();
         if chunk.lines.len() > *offset + 1 {
-            print!(" (line {})", chunk.lines[*offset + 1]);
+            write!(out, " (line {})", chunk.lines[*offset + 1]).unwrap();
         }
-        println!();
+        writeln!(out).unwrap();
         *offset += 2;
     }
 
-    fn jump_instruction(chunk: &Chunk, name: &str, offset: &mut usize) {
+    fn jump_instruction(out: &mut String, chunk: &Chunk, name: &str, offset: &mut usize) {
         // 16 bits
         let jump = (chunk.code[*offset + 1] as u16) << 8 | chunk.code[*offset + 2] as u16;
-        print!("{:16} {:4} -> ", name, jump);
+        write!(out, "{:16} {:4} -> ", name, jump).unwrap();
         if chunk.lines.len() > *offset + 1 {
-            print!(" (line {})", chunk.lines[*offset + 1]);
+            write!(out, " (line {})", chunk.lines[*offset + 1]).unwrap();
         }
-        println!();
+        writeln!(out).unwrap();
         *offset += 3;
     }
 
-    fn invoke_instruction(chunk: &Chunk, name: &str, offset: &mut usize) {
-        let constant = chunk.code[*offset + 1];
-        let arg_count = chunk.code[*offset + 2];
-        print!("{:16} {:4} {:4} ", name, constant, arg_count);
+    fn invoke_instruction(
+        out: &mut String,
+        chunk: &Chunk,
+        name: &str,
+        offset: &mut usize,
+        width: usize,
+    ) {
+        let constant = constant_index(chunk, *offset, width);
+        let arg_count = chunk.code[*offset + 1 + width];
+        write!(out, "{:16} {:4} {:4} ", name, constant, arg_count).unwrap();
 
-        let constant = match &chunk.constants[constant as usize] {
-            Value::String(s) => s,
-            _ => panic!("Expected string"),
+        let constant = match &chunk.constants[constant] {
+            Value::Symbol(s) => s,
+            _ => panic!("Expected symbol"),
         };
 
-        println!("{} ", constant);
+        writeln!(out, "{} ", constant).unwrap();
 
-        *offset += 3;
+        *offset += 2 + width;
     }
 
-    fn disassemble_instruction(chunk: &Chunk, offset: &mut usize, current_offset: Option<usize>) {
+    fn disassemble_instruction(
+        out: &mut String,
+        chunk: &Chunk,
+        offset: &mut usize,
+        current_offset: Option<usize>,
+    ) {
         if let Some(line) = current_offset {
             if line == *offset {
-                print!("=> ");
+                write!(out, "=> ").unwrap();
             } else {
-                print!("   ");
+                write!(out, "   ").unwrap();
             }
         }
 
-        print!("{:04} ", *offset);
+        write!(out, "{:04} ", *offset).unwrap();
 
         if *offset > 0 && chunk.lines[*offset] == chunk.lines[*offset - 1] {
-            print!("   | ");
+            write!(out, "   | ").unwrap();
         } else {
-            print!("{:4} ", chunk.lines[*offset]);
+            write!(out, "{:4} ", chunk.lines[*offset]).unwrap();
         }
 
-        let instruction = OpCode::from(chunk.code[*offset]);
+        let instruction = match OpCode::try_from(chunk.code[*offset]) {
+            Ok(instruction) => instruction,
+            Err(byte) => {
+                writeln!(out, "Unknown opcode {}", byte).unwrap();
+                *offset += 1;
+                return;
+            }
+        };
 
         match instruction {
-            OpCode::Return => simple_instruction("OP_RETURN", offset),
-            OpCode::Constant => constant_instruction(chunk, "OP_CONSTANT", offset),
-            OpCode::Negate => simple_instruction("OP_NEGATE", offset),
-            OpCode::Add => simple_instruction("OP_ADD", offset),
-            OpCode::Subtract => simple_instruction("OP_SUBTRACT", offset),
-            OpCode::Multiply => simple_instruction("OP_MULTIPLY", offset),
-            OpCode::Divide => simple_instruction("OP_DIVIDE", offset),
-            OpCode::Nil => simple_instruction("OP_NIL", offset),
-            OpCode::True => simple_instruction("OP_TRUE", offset),
-            OpCode::False => simple_instruction("OP_FALSE", offset),
-            OpCode::Not => simple_instruction("OP_NOT", offset),
-            OpCode::Equal => simple_instruction("OP_EQUAL", offset),
-            OpCode::Greater => simple_instruction("OP_GREATER", offset),
-            OpCode::Less => simple_instruction("OP_LESS", offset),
-            OpCode::Print => simple_instruction("OP_PRINT", offset),
-            OpCode::Pop => simple_instruction("OP_POP", offset),
-            OpCode::DefineGlobal => constant_instruction(chunk, "OP_DEFINE_GLOBAL", offset),
-            OpCode::GetGlobal => constant_instruction(chunk, "OP_GET_GLOBAL", offset),
-            OpCode::SetGlobal => constant_instruction(chunk, "OP_SET_GLOBAL", offset),
-            OpCode::GetLocal => byte_instruction(chunk, "OP_GET_LOCAL", offset),
-            OpCode::SetLocal => byte_instruction(chunk, "OP_SET_LOCAL", offset),
-            OpCode::JumpIfFalse => jump_instruction(chunk, "OP_JUMP_IF_FALSE", offset),
-            OpCode::Jump => jump_instruction(chunk, "OP_JUMP", offset),
-            OpCode::Loop => jump_instruction(chunk, "OP_LOOP", offset),
-            OpCode::Duplicate => simple_instruction("OP_DUPLICATE", offset),
-            OpCode::JumpIfTrue => jump_instruction(chunk, "OP_JUMP_IF_TRUE", offset),
-            OpCode::Call => byte_instruction(chunk, "OP_CALL", offset),
-            OpCode::Closure => {
-                let constant = chunk.code[*offset + 1];
-                println!("{:16} {:4} ", "OP_CLOSURE", constant);
-                let function = match &chunk.constants[constant as usize] {
+            OpCode::Return => simple_instruction(out, "OP_RETURN", offset),
+            OpCode::Constant => constant_instruction(out, chunk, "OP_CONSTANT", offset, 1),
+            OpCode::ConstantLong => constant_instruction(out, chunk, "OP_CONSTANT_LONG", offset, 3),
+            OpCode::Negate => simple_instruction(out, "OP_NEGATE", offset),
+            OpCode::Add => simple_instruction(out, "OP_ADD", offset),
+            OpCode::Subtract => simple_instruction(out, "OP_SUBTRACT", offset),
+            OpCode::Multiply => simple_instruction(out, "OP_MULTIPLY", offset),
+            OpCode::Divide => simple_instruction(out, "OP_DIVIDE", offset),
+            OpCode::Modulo => simple_instruction(out, "OP_MODULO", offset),
+            OpCode::Power => simple_instruction(out, "OP_POWER", offset),
+            OpCode::Nil => simple_instruction(out, "OP_NIL", offset),
+            OpCode::True => simple_instruction(out, "OP_TRUE", offset),
+            OpCode::False => simple_instruction(out, "OP_FALSE", offset),
+            OpCode::Not => simple_instruction(out, "OP_NOT", offset),
+            OpCode::Equal => simple_instruction(out, "OP_EQUAL", offset),
+            OpCode::Greater => simple_instruction(out, "OP_GREATER", offset),
+            OpCode::Less => simple_instruction(out, "OP_LESS", offset),
+            OpCode::Print => byte_instruction(out, chunk, "OP_PRINT", offset),
+            OpCode::Pop => simple_instruction(out, "OP_POP", offset),
+            OpCode::PopN => byte_instruction(out, chunk, "OP_POP_N", offset),
+            OpCode::DefineGlobal => constant_instruction(out, chunk, "OP_DEFINE_GLOBAL", offset, 1),
+            OpCode::GetGlobal => constant_instruction(out, chunk, "OP_GET_GLOBAL", offset, 1),
+            OpCode::SetGlobal => constant_instruction(out, chunk, "OP_SET_GLOBAL", offset, 1),
+            OpCode::GetLocal => byte_instruction(out, chunk, "OP_GET_LOCAL", offset),
+            OpCode::SetLocal => byte_instruction(out, chunk, "OP_SET_LOCAL", offset),
+            OpCode::JumpIfFalse => jump_instruction(out, chunk, "OP_JUMP_IF_FALSE", offset),
+            OpCode::Jump => jump_instruction(out, chunk, "OP_JUMP", offset),
+            OpCode::Loop => jump_instruction(out, chunk, "OP_LOOP", offset),
+            OpCode::Duplicate => simple_instruction(out, "OP_DUPLICATE", offset),
+            OpCode::Tuck => simple_instruction(out, "OP_TUCK", offset),
+            OpCode::CheckCount => simple_instruction(out, "OP_CHECK_COUNT", offset),
+            OpCode::Resource => simple_instruction(out, "OP_RESOURCE", offset),
+            OpCode::BuildArray => byte_instruction(out, chunk, "OP_BUILD_ARRAY", offset),
+            OpCode::Next => byte_instruction(out, chunk, "OP_NEXT", offset),
+            OpCode::ToString => simple_instruction(out, "OP_TO_STRING", offset),
+            OpCode::JumpIfTrue => jump_instruction(out, chunk, "OP_JUMP_IF_TRUE", offset),
+            OpCode::Call => byte_instruction(out, chunk, "OP_CALL", offset),
+            OpCode::TailCall => byte_instruction(out, chunk, "OP_TAIL_CALL", offset),
+            OpCode::Closure | OpCode::ClosureLong => {
+                let width = instruction.constant_width();
+                let name = if width == 1 {
+                    "OP_CLOSURE"
+                } else {
+                    "OP_CLOSURE_LONG"
+                };
+                let constant = constant_index(chunk, *offset, width);
+                writeln!(out, "{:16} {:4} ", name, constant).unwrap();
+                let function = match &chunk.constants[constant] {
                     Value::Function(f) => f,
                     _ => panic!("Expected function"),
                 };
-                for _ in 0..function.read().up_value_count {
-                    let is_local = chunk.code[*offset + 2] == 1;
-                    let index = chunk.code[*offset + 3];
-                    print!("   ");
-                    print!("{:04}       |                 ", *offset);
-                    print!("{} ", if is_local { "local" } else { "upvalue" });
-                    println!("{} ", index);
+                let start = *offset;
+                *offset += 1 + width;
+                for _ in 0..function.read().up_value_count() {
+                    let is_local = chunk.code[*offset] == 1;
+                    let index = chunk.code[*offset + 1];
+                    write!(out, "   ").unwrap();
+                    write!(out, "{:04}       |                 ", start).unwrap();
+                    write!(out, "{} ", if is_local { "local" } else { "upvalue" }).unwrap();
+                    writeln!(out, "{} ", index).unwrap();
                     *offset += 2;
                 }
-                *offset += 2;
-            }
-            OpCode::GetUpvalue => byte_instruction(chunk, "OP_GET_UPVALUE", offset),
-            OpCode::SetUpvalue => byte_instruction(chunk, "OP_SET_UPVALUE", offset),
-            OpCode::CloseUpvalue => simple_instruction("OP_CLOSE_UPVALUE", offset),
-            OpCode::Class => constant_instruction(chunk, "OP_CLASS", offset),
-            OpCode::GetProperty => constant_instruction(chunk, "OP_GET_PROPERTY", offset),
-            OpCode::SetProperty => constant_instruction(chunk, "OP_SET_PROPERTY", offset),
-            OpCode::Method => constant_instruction(chunk, "OP_METHOD", offset),
-            OpCode::Invoke => invoke_instruction(chunk, "OP_INVOKE", offset),
-            OpCode::Inherit => simple_instruction("OP_INHERIT", offset),
-            OpCode::GetSuper => constant_instruction(chunk, "OP_GET_SUPER", offset),
-            OpCode::SuperInvoke => invoke_instruction(chunk, "OP_SUPER_INVOKE", offset),
+            }
+            OpCode::GetUpvalue => byte_instruction(out, chunk, "OP_GET_UPVALUE", offset),
+            OpCode::SetUpvalue => byte_instruction(out, chunk, "OP_SET_UPVALUE", offset),
+            OpCode::CloseUpvalue => simple_instruction(out, "OP_CLOSE_UPVALUE", offset),
+            OpCode::Class => constant_instruction(out, chunk, "OP_CLASS", offset, 1),
+            OpCode::GetProperty => constant_instruction(out, chunk, "OP_GET_PROPERTY", offset, 1),
+            OpCode::SetProperty => constant_instruction(out, chunk, "OP_SET_PROPERTY", offset, 1),
+            OpCode::GetIndex => simple_instruction(out, "OP_GET_INDEX", offset),
+            OpCode::SetIndex => simple_instruction(out, "OP_SET_INDEX", offset),
+            OpCode::Method => constant_instruction(out, chunk, "OP_METHOD", offset, 1),
+            OpCode::Invoke => invoke_instruction(out, chunk, "OP_INVOKE", offset, 1),
+            OpCode::Inherit => simple_instruction(out, "OP_INHERIT", offset),
+            OpCode::GetSuper => constant_instruction(out, chunk, "OP_GET_SUPER", offset, 1),
+            OpCode::SuperInvoke => invoke_instruction(out, chunk, "OP_SUPER_INVOKE", offset, 1),
+            OpCode::DefineGlobalLong => {
+                constant_instruction(out, chunk, "OP_DEFINE_GLOBAL_LONG", offset, 3)
+            }
+            OpCode::GetGlobalLong => {
+                constant_instruction(out, chunk, "OP_GET_GLOBAL_LONG", offset, 3)
+            }
+            OpCode::SetGlobalLong => {
+                constant_instruction(out, chunk, "OP_SET_GLOBAL_LONG", offset, 3)
+            }
+            OpCode::ClassLong => constant_instruction(out, chunk, "OP_CLASS_LONG", offset, 3),
+            OpCode::GetPropertyLong => {
+                constant_instruction(out, chunk, "OP_GET_PROPERTY_LONG", offset, 3)
+            }
+            OpCode::SetPropertyLong => {
+                constant_instruction(out, chunk, "OP_SET_PROPERTY_LONG", offset, 3)
+            }
+            OpCode::MethodLong => constant_instruction(out, chunk, "OP_METHOD_LONG", offset, 3),
+            OpCode::GetSuperLong => {
+                constant_instruction(out, chunk, "OP_GET_SUPER_LONG", offset, 3)
+            }
+            OpCode::InvokeLong => invoke_instruction(out, chunk, "OP_INVOKE_LONG", offset, 3),
+            OpCode::SuperInvokeLong => {
+                invoke_instruction(out, chunk, "OP_SUPER_INVOKE_LONG", offset, 3)
+            }
+            OpCode::Switch | OpCode::SwitchLong => {
+                let width = instruction.constant_width();
+                let name = if width == 1 {
+                    "OP_SWITCH"
+                } else {
+                    "OP_SWITCH_LONG"
+                };
+                let constant = constant_index(chunk, *offset, width);
+                let count = chunk.code[*offset + 1 + width] as usize;
+                writeln!(out, "{:16} {:4} {:4} ", name, constant, count).unwrap();
+                let first = match &chunk.constants[constant] {
+                    Value::Int(first) => *first,
+                    _ => panic!("Expected int"),
+                };
+                let table = *offset + 2 + width;
+                let end = table + 2 * (count + 1);
+                for i in 0..=count {
+                    let jump = (chunk.code[table + 2 * i] as usize) << 8
+                        | chunk.code[table + 2 * i + 1] as usize;
+                    let label = if i < count {
+                        (first + i as i64).to_string()
+                    } else {
+                        String::from("default")
+                    };
+                    write!(out, "   ").unwrap();
+                    write!(out, "{:04}       |                 ", *offset).unwrap();
+                    writeln!(out, "{} -> {}", label, end + jump).unwrap();
+                }
+                *offset = end;
+            }
         }
     }
 
-    println!("== {} ==", name);
+    writeln!(out, "== {} ==", name).unwrap();
 
     while offset < chunk.code.len() {
-        disassemble_instruction(chunk, &mut offset, current_offset);
+        disassemble_instruction(&mut out, chunk, &mut offset, current_offset);
+    }
+
+    out
+}
+
+// Walks every path through `chunk`, tracking how deep the stack is at each
+// instruction. Fails if two paths reach an instruction at different depths,
+// if an instruction pops more than is there, or if a checkpoint's depth is
+// wrong. Unreachable checkpoints are skipped.
+pub fn check_stack(
+    chunk: &Chunk,
+    arity: usize,
+    checkpoints: &[(usize, usize)],
+) -> Result<(), String> {
+    let mut depths: Vec<Option<usize>> = vec![None; chunk.code.len()];
+    // Calls start with the callee in slot 0 followed by the arguments.
+    let mut pending = vec![(0, arity + 1)];
+
+    while let Some((offset, depth)) = pending.pop() {
+        match depths.get(offset) {
+            None => return Err(format!("Jump to {} past the end of the chunk", offset)),
+            Some(Some(seen)) if *seen == depth => continue,
+            Some(Some(seen)) => {
+                return Err(format!(
+                    "Stack imbalance at {}: reached with depth {} and {}",
+                    offset, seen, depth
+                ));
+            }
+            Some(None) => depths[offset] = Some(depth),
+        }
+
+        let instruction = OpCode::try_from(chunk.code[offset])
+            .map_err(|byte| format!("Unknown opcode {} at {}", byte, offset))?;
+        let operand = |i: usize| chunk.code[offset + i] as usize;
+        // Operands after a constant index start past its one or three bytes.
+        let rest = 1 + instruction.constant_width();
+
+        // (values popped, values pushed, instruction length)
+        let (pops, pushes, length) = match instruction {
+            OpCode::Return | OpCode::Pop | OpCode::CloseUpvalue => (1, 0, 1),
+            OpCode::Print | OpCode::PopN => (operand(1), 0, 2),
+            OpCode::Negate
+            | OpCode::Not
+            | OpCode::CheckCount
+            | OpCode::Resource
+            | OpCode::ToString => (1, 1, 1),
+            OpCode::Add
+            | OpCode::Subtract
+            | OpCode::Multiply
+            | OpCode::Divide
+            | OpCode::Modulo
+            | OpCode::Power
+            | OpCode::Equal
+            | OpCode::Greater
+            | OpCode::Less
+            | OpCode::GetIndex
+            | OpCode::Inherit => (2, 1, 1),
+            OpCode::Nil | OpCode::True | OpCode::False => (0, 1, 1),
+            OpCode::ConstantLong | OpCode::GetGlobalLong | OpCode::ClassLong => (0, 1, 4),
+            OpCode::Duplicate => (1, 2, 1),
+            OpCode::Tuck => (2, 3, 1),
+            OpCode::SetIndex => (3, 1, 1),
+            OpCode::Constant
+            | OpCode::GetGlobal
+            | OpCode::GetLocal
+            | OpCode::GetUpvalue
+            | OpCode::Class => (0, 1, 2),
+            OpCode::DefineGlobal => (1, 0, 2),
+            OpCode::DefineGlobalLong => (1, 0, 4),
+            OpCode::SetGlobal | OpCode::SetLocal | OpCode::SetUpvalue | OpCode::GetProperty => {
+                (1, 1, 2)
+            }
+            OpCode::SetGlobalLong | OpCode::GetPropertyLong => (1, 1, 4),
+            OpCode::SetProperty | OpCode::Method | OpCode::GetSuper => (2, 1, 2),
+            OpCode::Next => (0, 2, 2),
+            OpCode::SetPropertyLong | OpCode::MethodLong | OpCode::GetSuperLong => (2, 1, 4),
+            OpCode::Jump | OpCode::Loop => (0, 0, 3),
+            OpCode::JumpIfFalse | OpCode::JumpIfTrue => (1, 1, 3),
+            OpCode::Call | OpCode::TailCall => (operand(1) + 1, 1, 2),
+            OpCode::BuildArray => (operand(1), 1, 2),
+            OpCode::Invoke | OpCode::InvokeLong => (operand(rest) + 1, 1, rest + 1),
+            OpCode::SuperInvoke | OpCode::SuperInvokeLong => (operand(rest) + 2, 1, rest + 1),
+            OpCode::Switch | OpCode::SwitchLong => (0, 0, rest + 1 + 2 * (operand(rest) + 1)),
+            OpCode::Closure | OpCode::ClosureLong => {
+                let constant = (1..rest).fold(0, |index, i| index << 8 | operand(i));
+                let up_value_count = match &chunk.constants[constant] {
+                    Value::Function(function) => function.read().up_value_count() as usize,
+                    _ => return Err(format!("Expected function for closure at {}", offset)),
+                };
+                (0, 1, rest + 2 * up_value_count)
+            }
+        };
+
+        if depth < pops {
+            return Err(format!("Stack underflow at {}", offset));
+        }
+
+        // Locals live below the top of the stack; `next` also reads the
+        // cursor just above its sequence.
+        let locals = match instruction {
+            OpCode::GetLocal | OpCode::SetLocal => vec![operand(1)],
+            OpCode::Next => vec![operand(1) + 1],
+            OpCode::Closure | OpCode::ClosureLong => (0..(length - rest) / 2)
+                .filter(|i| operand(rest + 2 * i) == 1)
+                .map(|i| operand(rest + 2 * i + 1))
+                .collect(),
+            _ => vec![],
+        };
+        if let Some(slot) = locals.into_iter().find(|&slot| slot >= depth) {
+            return Err(format!("Local {} out of range at {}", slot, offset));
+        }
+
+        let depth = depth - pops + pushes;
+        let next = offset + length;
+        let jump = || operand(1) << 8 | operand(2);
+
+        match instruction {
+            OpCode::Return => {}
+            OpCode::Jump => pending.push((next + jump(), depth)),
+            OpCode::Loop => pending.push((next - jump(), depth)),
+            OpCode::JumpIfFalse | OpCode::JumpIfTrue => {
+                pending.push((next, depth));
+                pending.push((next + jump(), depth));
+            }
+            // One target per label, then the default.
+            OpCode::Switch | OpCode::SwitchLong => {
+                for i in 0..=operand(rest) {
+                    let jump = operand(rest + 1 + 2 * i) << 8 | operand(rest + 2 + 2 * i);
+                    pending.push((next + jump, depth));
+                }
+            }
+            _ => pending.push((next, depth)),
+        }
+    }
+
+    for &(offset, expected) in checkpoints {
+        if let Some(Some(depth)) = depths.get(offset) {
+            if *depth != expected {
+                return Err(format!(
+                    "Stack imbalance at {} (line {}): expected depth {}, found {}",
+                    offset, chunk.lines[offset], expected, depth
+                ));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::Compiler;
+    use crate::scanner::Scanner;
+    use crate::value::{Function, FunctionType};
+    use parking_lot::RwLock;
+    use std::rc::Rc;
+
+    fn chunk_with(code: &[OpCode]) -> Chunk {
+        let mut chunk = Chunk::new();
+        for &op in code {
+            chunk.write(op.into(), 1, 1);
+        }
+        chunk
+    }
+
+    // Checks `function` and every function nested in it.
+    fn check_all(function: &Function) -> Result<(), String> {
+        let chunk = function.chunk();
+        check_stack(&chunk, function.arity(), &[])?;
+        for constant in &chunk.constants {
+            if let Value::Function(nested) = constant {
+                check_all(&nested.read())?;
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn compiled_programs_keep_the_stack_balanced() {
+        let source = "class A { init(x) { this.x = x; } get() { return this.x; } }
+             class B < A { get() { return super.get() + 1; } }
+             fun count(n) {
+                 var total = 0;
+                 for (var i = 0; i < n; i = i + 1) {
+                     var step = i;
+                     if (step == 2) continue;
+                     if (step == 8) break;
+                     total += step;
+                 }
+                 repeat (2) { total = total * 2; }
+                 for (var i, x in [1, 2, 3]) { if (x == 2) continue; if (i == 2) break; }
+                 switch (total) { case 1: { print 1; } case 2: { print 0; } }
+                 fun get() { return total; }
+                 return get;
+             }
+             print B(1).get() < count(10)();";
+        let scanner = Rc::new(RwLock::new(Scanner::new(source.to_string())));
+        let function = Compiler::new(FunctionType::Script, scanner)
+            .compile_function()
+            .expect("source should compile");
+        assert_eq!(check_all(&function.read()), Ok(()));
+    }
+
+    #[test]
+    fn reports_mis_emitted_code() {
+        // A statement that leaves an extra value behind: the checkpoint after
+        // it expects only the script slot.
+        let chunk = chunk_with(&[OpCode::Nil, OpCode::Nil, OpCode::Pop, OpCode::Return]);
+        assert_eq!(check_stack(&chunk, 0, &[(0, 1), (3, 2)]), Ok(()));
+        assert_eq!(
+            check_stack(&chunk, 0, &[(3, 1)]),
+            Err("Stack imbalance at 3 (line 1): expected depth 1, found 2".to_string())
+        );
+
+        // A branch that pushes on only one path.
+        let mut chunk = chunk_with(&[OpCode::True, OpCode::JumpIfFalse]);
+        chunk.write(0, 1, 1);
+        chunk.write(1, 1, 1);
+        for op in [OpCode::Nil, OpCode::Pop, OpCode::Return] {
+            chunk.write(op.into(), 1, 1);
+        }
+        assert_eq!(
+            check_stack(&chunk, 0, &[]),
+            Err("Stack imbalance at 5: reached with depth 2 and 3".to_string())
+        );
+
+        let chunk = chunk_with(&[OpCode::Pop, OpCode::Pop, OpCode::Return]);
+        assert_eq!(
+            check_stack(&chunk, 0, &[]),
+            Err("Stack underflow at 1".to_string())
+        );
+    }
+
+    #[test]
+    fn disassembles_into_a_string() {
+        let mut chunk = Chunk::new();
+        let constant = chunk.write_constant(Value::Int(7)) as u8;
+        chunk.write(OpCode::Constant.into(), 1, 7);
+        chunk.write(constant, 1, 7);
+        chunk.write(OpCode::Negate.into(), 1, 6);
+        chunk.write(OpCode::Print.into(), 2, 1);
+        chunk.write(1, 2, 1);
+        chunk.write(OpCode::Return.into(), 2, 1);
+
+        assert_eq!(
+            disassemble(&chunk, "tiny", None),
+            "== tiny ==\n\
+             0000    1 OP_CONSTANT         0 '7'\n\
+             0002    | OP_NEGATE\n\
+             0003    2 OP_PRINT            1 (line 2)\n\
+             0005    | OP_RETURN\n"
+        );
+        assert_eq!(
+            disassemble(&chunk, "tiny", Some(3)),
+            "== tiny ==\n\
+             \x20  0000    1 OP_CONSTANT         0 '7'\n\
+             \x20  0002    | OP_NEGATE\n\
+             => 0003    2 OP_PRINT            1 (line 2)\n\
+             \x20  0005    | OP_RETURN\n"
+        );
     }
 }
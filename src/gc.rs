@@ -0,0 +1,302 @@
+use crate::symbol::Symbol;
+use crate::value::{BoundMethod, Class, Closure, Instance, UpValueObject, Value};
+use parking_lot::RwLock;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::rc::{Rc, Weak};
+
+// Collections run once this many objects are tracked, and afterwards once the
+// count doubles from what survived.
+const INITIAL_THRESHOLD: usize = 1024;
+
+/// Reclaims reference cycles, which `Rc` alone never frees: an instance whose
+/// field holds a closure capturing the instance, say, or a local recursive
+/// function. Every instance, class, closure and array the VM creates is
+/// tracked weakly, and a collection finds the tracked objects only kept alive
+/// by each other.
+///
+/// Nothing has to be registered as a root. An object is live if something
+/// outside the tracked graph holds it, which shows up as more strong
+/// references than the graph accounts for; that covers globals, the stack and
+/// values an embedder kept.
+pub struct Heap {
+    objects: Vec<Tracked>,
+    threshold: usize,
+}
+
+enum Tracked {
+    Instance(Weak<RwLock<Instance>>),
+    Class(Weak<RwLock<Class>>),
+    Closure(Weak<RwLock<Closure>>),
+    Array(Weak<RwLock<Vec<Value>>>),
+}
+
+// Every shared allocation an object graph can pass through. Cycles always go
+// through one of the mutable containers, which is where they get broken.
+#[derive(Clone)]
+enum Node {
+    Instance(Rc<RwLock<Instance>>),
+    Class(Rc<RwLock<Class>>),
+    Closure(Rc<RwLock<Closure>>),
+    BoundMethod(Rc<RwLock<BoundMethod>>),
+    Array(Rc<RwLock<Vec<Value>>>),
+    Fields(Rc<RwLock<HashMap<Symbol, Value>>>),
+    Methods(Rc<RwLock<HashMap<Symbol, Rc<RwLock<Closure>>>>>),
+    UpValues(Rc<RwLock<Vec<Rc<RwLock<UpValueObject>>>>>),
+    UpValue(Rc<RwLock<UpValueObject>>),
+    Receiver(Rc<RwLock<Value>>),
+}
+
+impl Node {
+    fn of_value(value: &Value) -> Option<Node> {
+        match value {
+            Value::Instance(instance) => Some(Node::Instance(instance.clone())),
+            Value::Class(class) => Some(Node::Class(class.clone())),
+            Value::Closure(closure) => Some(Node::Closure(closure.clone())),
+            Value::BoundMethod(bound_method) => Some(Node::BoundMethod(bound_method.clone())),
+            Value::Array(array) => Some(Node::Array(array.clone())),
+            _ => None,
+        }
+    }
+
+    fn id(&self) -> usize {
+        match self {
+            Node::Instance(rc) => Rc::as_ptr(rc) as *const () as usize,
+            Node::Class(rc) => Rc::as_ptr(rc) as *const () as usize,
+            Node::Closure(rc) => Rc::as_ptr(rc) as *const () as usize,
+            Node::BoundMethod(rc) => Rc::as_ptr(rc) as *const () as usize,
+            Node::Array(rc) => Rc::as_ptr(rc) as *const () as usize,
+            Node::Fields(rc) => Rc::as_ptr(rc) as *const () as usize,
+            Node::Methods(rc) => Rc::as_ptr(rc) as *const () as usize,
+            Node::UpValues(rc) => Rc::as_ptr(rc) as *const () as usize,
+            Node::UpValue(rc) => Rc::as_ptr(rc) as *const () as usize,
+            Node::Receiver(rc) => Rc::as_ptr(rc) as *const () as usize,
+        }
+    }
+
+    fn strong_count(&self) -> usize {
+        match self {
+            Node::Instance(rc) => Rc::strong_count(rc),
+            Node::Class(rc) => Rc::strong_count(rc),
+            Node::Closure(rc) => Rc::strong_count(rc),
+            Node::BoundMethod(rc) => Rc::strong_count(rc),
+            Node::Array(rc) => Rc::strong_count(rc),
+            Node::Fields(rc) => Rc::strong_count(rc),
+            Node::Methods(rc) => Rc::strong_count(rc),
+            Node::UpValues(rc) => Rc::strong_count(rc),
+            Node::UpValue(rc) => Rc::strong_count(rc),
+            Node::Receiver(rc) => Rc::strong_count(rc),
+        }
+    }
+
+    // One entry per strong reference the node holds.
+    fn children(&self) -> Vec<Node> {
+        match self {
+            Node::Instance(instance) => {
+                let instance = instance.read();
+                vec![
+                    Node::Class(instance.class.clone()),
+                    Node::Fields(instance.fields.clone()),
+                ]
+            }
+            Node::Class(class) => vec![Node::Methods(class.read().methods.clone())],
+            Node::Closure(closure) => vec![Node::UpValues(closure.read().up_values.clone())],
+            Node::BoundMethod(bound_method) => {
+                let bound_method = bound_method.read();
+                vec![
+                    Node::Receiver(bound_method.receiver.clone()),
+                    Node::Closure(bound_method.method.clone()),
+                ]
+            }
+            Node::Array(array) => array.read().iter().filter_map(Node::of_value).collect(),
+            Node::Fields(fields) => fields.read().values().filter_map(Node::of_value).collect(),
+            Node::Methods(methods) => methods
+                .read()
+                .values()
+                .map(|method| Node::Closure(method.clone()))
+                .collect(),
+            Node::UpValues(up_values) => up_values
+                .read()
+                .iter()
+                .map(|up_value| Node::UpValue(up_value.clone()))
+                .collect(),
+            Node::UpValue(up_value) => match &*up_value.read() {
+                UpValueObject::Closed(value) => Node::of_value(value).into_iter().collect(),
+                // The slot belongs to a live frame, outside the graph.
+                UpValueObject::Open { .. } => Vec::new(),
+            },
+            Node::Receiver(receiver) => Node::of_value(&receiver.read()).into_iter().collect(),
+        }
+    }
+
+    // Drops the references out of an unreachable container, so the cycle
+    // through it comes apart once the collector lets go.
+    fn clear(&self) {
+        match self {
+            Node::Array(array) => array.write().clear(),
+            Node::Fields(fields) => fields.write().clear(),
+            Node::Methods(methods) => methods.write().clear(),
+            Node::UpValues(up_values) => up_values.write().clear(),
+            Node::UpValue(up_value) => *up_value.write() = UpValueObject::Closed(Value::Nil),
+            Node::Receiver(receiver) => *receiver.write() = Value::Nil,
+            Node::Instance(_) | Node::Class(_) | Node::Closure(_) | Node::BoundMethod(_) => {}
+        }
+    }
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Heap {
+            objects: Vec::new(),
+            threshold: INITIAL_THRESHOLD,
+        }
+    }
+}
+
+impl Heap {
+    pub fn new() -> Self {
+        Heap::default()
+    }
+
+    /// Starts tracking `value` if it is an object that can be part of a cycle.
+    pub fn track(&mut self, value: &Value) {
+        let tracked = match value {
+            Value::Instance(instance) => Tracked::Instance(Rc::downgrade(instance)),
+            Value::Class(class) => Tracked::Class(Rc::downgrade(class)),
+            Value::Closure(closure) => Tracked::Closure(Rc::downgrade(closure)),
+            Value::Array(array) => Tracked::Array(Rc::downgrade(array)),
+            _ => return,
+        };
+        self.objects.push(tracked);
+    }
+
+    pub fn should_collect(&self) -> bool {
+        self.objects.len() >= self.threshold
+    }
+
+    /// Frees every tracked object that is only reachable from other garbage,
+    /// returning how many there were.
+    pub fn collect(&mut self) -> usize {
+        // Everything reachable from a tracked object, and how many of each
+        // node's strong references come from inside that graph. `nodes` holds
+        // one more.
+        let mut nodes: HashMap<usize, Node> = HashMap::new();
+        let mut internal: HashMap<usize, usize> = HashMap::new();
+        let mut pending: Vec<Node> = Vec::new();
+
+        for object in &self.objects {
+            let node = match object {
+                Tracked::Instance(weak) => weak.upgrade().map(Node::Instance),
+                Tracked::Class(weak) => weak.upgrade().map(Node::Class),
+                Tracked::Closure(weak) => weak.upgrade().map(Node::Closure),
+                Tracked::Array(weak) => weak.upgrade().map(Node::Array),
+            };
+            if let Some(node) = node {
+                if let Entry::Vacant(entry) = nodes.entry(node.id()) {
+                    entry.insert(node.clone());
+                    pending.push(node);
+                }
+            }
+        }
+
+        while let Some(node) = pending.pop() {
+            for child in node.children() {
+                *internal.entry(child.id()).or_insert(0) += 1;
+                if let Entry::Vacant(entry) = nodes.entry(child.id()) {
+                    entry.insert(child.clone());
+                    pending.push(child);
+                }
+            }
+        }
+
+        // Held from outside the graph, so live along with all they reach.
+        // Only `nodes` and the graph's own references are accounted for;
+        // `pending` is empty by now.
+        let mut live: HashSet<usize> = HashSet::new();
+        let mut pending: Vec<Node> = nodes
+            .values()
+            .filter(|node| node.strong_count() > 1 + internal.get(&node.id()).unwrap_or(&0))
+            .cloned()
+            .collect();
+        while let Some(node) = pending.pop() {
+            if live.insert(node.id()) {
+                pending.extend(node.children());
+            }
+        }
+
+        let garbage: Vec<Node> = nodes
+            .into_values()
+            .filter(|node| !live.contains(&node.id()))
+            .collect();
+        for node in &garbage {
+            node.clear();
+        }
+        let freed = garbage
+            .iter()
+            .filter(|node| {
+                matches!(
+                    node,
+                    Node::Instance(_) | Node::Class(_) | Node::Closure(_) | Node::Array(_)
+                )
+            })
+            .count();
+        drop(garbage);
+
+        self.objects.retain(|object| match object {
+            Tracked::Instance(weak) => weak.strong_count() > 0,
+            Tracked::Class(weak) => weak.strong_count() > 0,
+            Tracked::Closure(weak) => weak.strong_count() > 0,
+            Tracked::Array(weak) => weak.strong_count() > 0,
+        });
+        self.threshold = INITIAL_THRESHOLD.max(self.objects.len() * 2);
+
+        freed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A tracked array holding itself, which `Rc` alone would never free.
+    fn self_cycle(heap: &mut Heap) -> Rc<RwLock<Vec<Value>>> {
+        let array = Rc::new(RwLock::new(Vec::new()));
+        array.write().push(Value::Array(array.clone()));
+        heap.track(&Value::Array(array.clone()));
+        array
+    }
+
+    #[test]
+    fn frees_cycles_nothing_else_holds() {
+        let mut heap = Heap::new();
+        let garbage = Rc::downgrade(&self_cycle(&mut heap));
+        let kept = self_cycle(&mut heap);
+
+        assert_eq!(heap.collect(), 1);
+        assert_eq!(garbage.strong_count(), 0);
+        assert_eq!(heap.objects.len(), 1);
+
+        // Whatever a live object reaches stays, even through a cycle.
+        let reached = Rc::downgrade(&self_cycle(&mut heap));
+        kept.write().push(Value::Array(reached.upgrade().unwrap()));
+        assert_eq!(heap.collect(), 0);
+        assert!(reached.upgrade().is_some());
+
+        drop(kept);
+        assert_eq!(heap.collect(), 2);
+        assert!(reached.upgrade().is_none());
+        assert!(heap.objects.is_empty());
+    }
+
+    #[test]
+    fn repeated_cycles_do_not_grow_the_heap() {
+        let mut heap = Heap::new();
+        for _ in 0..100 * INITIAL_THRESHOLD {
+            self_cycle(&mut heap);
+            if heap.should_collect() {
+                heap.collect();
+            }
+            assert!(heap.objects.len() <= INITIAL_THRESHOLD);
+        }
+    }
+}
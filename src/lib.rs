@@ -0,0 +1,15 @@
+mod chunk;
+mod compiler;
+mod debug;
+mod gc;
+mod parser_rules;
+mod scanner;
+mod symbol;
+mod token_type;
+pub mod value;
+pub mod vm;
+
+pub use chunk::{deserialize, serialize};
+pub use compiler::{compile, CompileResult, Diagnostic};
+pub use value::Value;
+pub use vm::{ErrorKind, InterpretResult, NumberFormat, OutputBuffer, VM};
@@ -1,41 +1,130 @@
+use ci_bytecode_vm::{value, vm};
 use std::io::Write;
 
-mod chunk;
-mod compiler;
-mod debug;
-mod parser_rules;
-mod scanner;
-mod token_type;
-mod value;
-mod vm;
-
 fn repl(vm: &mut vm::VM) {
+    vm.set_interactive(true);
+
+    // Lines typed so far of a statement that isn't complete yet.
+    let mut pending = String::new();
+
     loop {
-        print!("> ");
+        print!("{}", if pending.is_empty() { "> " } else { "... " });
         std::io::stdout().flush().expect("Failed to flush stdout");
 
         let mut line = String::new();
-        std::io::stdin()
+        let read = std::io::stdin()
             .read_line(&mut line)
             .expect("Failed to read line");
 
-        vm.interpret(line);
+        // EOF (Ctrl-D, or stdin closed): end the session on a fresh line.
+        if read == 0 {
+            println!();
+            break;
+        }
+
+        if !pending.is_empty() {
+            pending.push_str(&line);
+            // A blank line gives up on completing it and runs what's there.
+            if !line.trim().is_empty() && is_incomplete(&pending) {
+                continue;
+            }
+            line = std::mem::take(&mut pending);
+        } else if !line.trim_start().starts_with(':') && is_incomplete(&line) {
+            pending = line;
+            continue;
+        }
+
+        if line.trim() == ":globals" {
+            for (name, value) in vm.globals() {
+                println!("{} = {}", name, value);
+            }
+            continue;
+        }
+
+        if line.trim() == ":stack" {
+            println!("max stack used: {}", vm.max_stack_used());
+            continue;
+        }
+
+        if line.trim() == ":gc" {
+            println!("freed {} objects", vm.collect_garbage());
+            continue;
+        }
+
+        // `:numbers ,.` groups thousands with ',' and uses '.' as the decimal
+        // point; `:numbers plain` goes back to the default.
+        if let Some(spec) = line.trim().strip_prefix(":numbers") {
+            let mut chars = spec.trim().chars();
+            match (spec.trim(), chars.next(), chars.next(), chars.next()) {
+                ("plain", ..) => vm.set_number_format(vm::NumberFormat::default()),
+                (_, Some(thousands), Some(decimal), None) => {
+                    vm.set_number_format(vm::NumberFormat {
+                        thousands: Some(thousands),
+                        decimal,
+                    })
+                }
+                _ => println!("usage: :numbers <thousands><decimal> | plain"),
+            }
+            continue;
+        }
+
+        if let Ok(value) = vm.eval(line) {
+            if value != value::Value::Nil {
+                println!("{}", vm.format_value(&value));
+            }
+        }
     }
 }
 
+// Whether `source` only failed to compile because it ended too soon, like an
+// unclosed block or string, so more lines could complete it.
+fn is_incomplete(source: &str) -> bool {
+    ci_bytecode_vm::compile(source.to_string())
+        .diagnostics
+        .iter()
+        .any(|diagnostic| {
+            diagnostic.location == " at end" || diagnostic.message == "Unterminated string"
+        })
+}
+
 fn read_file(path: &str) -> String {
     std::fs::read_to_string(path).expect("Failed to read file")
 }
 
+// Files ending in `.lbc` are precompiled bytecode, run without compiling.
 fn run_file(path: &str, vm: &mut vm::VM) {
-    let source = read_file(path);
-
-    let result = vm.interpret(source);
+    let result = if path.ends_with(".lbc") {
+        vm.run_bytecode(&std::fs::read(path).expect("Failed to read file"))
+    } else {
+        vm.run_script(read_file(path))
+    };
 
     match result {
         vm::InterpretResult::Ok => std::process::exit(0),
         vm::InterpretResult::CompileError => std::process::exit(65),
-        vm::InterpretResult::RuntimeError => std::process::exit(70),
+        vm::InterpretResult::RuntimeError(vm::ErrorKind::Assertion, _) => std::process::exit(71),
+        vm::InterpretResult::RuntimeError(..) => std::process::exit(70),
+    }
+}
+
+// `--compile out.lbc path` writes the compiled script to `out.lbc` instead of
+// running it.
+fn compile_file(path: &str, output: &str) {
+    let result = ci_bytecode_vm::compile(read_file(path));
+    for diagnostic in &result.diagnostics {
+        eprintln!("{}", diagnostic);
+    }
+    if !result.is_runnable() {
+        std::process::exit(65);
+    }
+
+    let bytes = ci_bytecode_vm::serialize(&result.function.read());
+    match bytes {
+        Ok(bytes) => std::fs::write(output, bytes).expect("Failed to write bytecode file"),
+        Err(message) => {
+            eprintln!("{}", message);
+            std::process::exit(65);
+        }
     }
 }
 
@@ -48,8 +137,25 @@ fn main() {
         repl(&mut vm);
     } else if args.len() == 2 {
         run_file(&args[1], &mut vm);
+    } else if args.len() == 4 && args[1] == "--compile" {
+        compile_file(&args[3], &args[2]);
     } else {
-        println!("Usage: rlox [path]");
+        println!("Usage: rlox [path | file.lbc] | rlox --compile out.lbc path");
         std::process::exit(64);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_input_that_ends_too_soon_is_incomplete() {
+        for source in ["fun f() {\n", "print (1 +\n", "print \"a\n", "class A {"] {
+            assert!(is_incomplete(source), "{:?}", source);
+        }
+        for source in ["print 1;\n", "print ;\n", "print 1; }\n", "\n"] {
+            assert!(!is_incomplete(source), "{:?}", source);
+        }
+    }
+}
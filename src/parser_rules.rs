@@ -1,386 +1,391 @@
 use crate::compiler::{Compiler, Precedence};
 use crate::token_type::TokenType;
 use lazy_static::lazy_static;
-use std::collections::HashMap;
+
+pub type ParseFn = fn(&Compiler, bool);
 
 pub struct ParseRule {
-    pub prefix: Option<Box<fn(&Compiler, bool)>>,
-    pub infix: Option<Box<fn(&Compiler, bool)>>,
+    pub prefix: Option<Box<ParseFn>>,
+    pub infix: Option<Box<ParseFn>>,
     pub precedence: Precedence,
 }
 
 lazy_static! {
-    pub static ref RULES: HashMap<TokenType, ParseRule> = {
-        let mut m = HashMap::new();
-        m.insert(
-            TokenType::LeftParen,
-            ParseRule {
-                prefix: Some(Box::new(Compiler::grouping)),
-                infix: Some(Box::new(Compiler::call)),
-                precedence: Precedence::Call,
-            },
-        );
-        m.insert(
-            TokenType::RightParen,
-            ParseRule {
-                prefix: None,
-                infix: None,
-                precedence: Precedence::None,
-            },
-        );
-        m.insert(
-            TokenType::LeftBrace,
-            ParseRule {
-                prefix: None,
-                infix: None,
-                precedence: Precedence::None,
-            },
-        );
-        m.insert(
-            TokenType::RightBrace,
-            ParseRule {
-                prefix: None,
-                infix: None,
-                precedence: Precedence::None,
-            },
-        );
-        m.insert(
-            TokenType::Comma,
-            ParseRule {
-                prefix: None,
-                infix: None,
-                precedence: Precedence::None,
-            },
-        );
-        m.insert(
-            TokenType::Dot,
-            ParseRule {
-                prefix: None,
-                infix: Some(Box::new(Compiler::dot)),
-                precedence: Precedence::Call,
-            },
-        );
-        m.insert(
-            TokenType::Minus,
-            ParseRule {
-                prefix: Some(Box::new(Compiler::unary)),
-                infix: Some(Box::new(Compiler::binary)),
-                precedence: Precedence::Term,
-            },
-        );
-        m.insert(
-            TokenType::Plus,
-            ParseRule {
-                prefix: None,
-                infix: Some(Box::new(Compiler::binary)),
-                precedence: Precedence::Term,
-            },
-        );
-        m.insert(
-            TokenType::Semicolon,
-            ParseRule {
-                prefix: None,
-                infix: None,
-                precedence: Precedence::None,
-            },
-        );
-        m.insert(
-            TokenType::Slash,
-            ParseRule {
-                prefix: None,
-                infix: Some(Box::new(Compiler::binary)),
-                precedence: Precedence::Factor,
-            },
-        );
-        m.insert(
-            TokenType::Star,
-            ParseRule {
-                prefix: None,
-                infix: Some(Box::new(Compiler::binary)),
-                precedence: Precedence::Factor,
-            },
-        );
-        m.insert(
-            TokenType::Colon,
-            ParseRule {
-                prefix: None,
-                infix: None,
-                precedence: Precedence::None,
-            },
-        );
-        m.insert(
-            TokenType::Bang,
-            ParseRule {
-                prefix: Some(Box::new(Compiler::unary)),
-                infix: None,
-                precedence: Precedence::None,
-            },
-        );
-        m.insert(
-            TokenType::BangEqual,
-            ParseRule {
-                prefix: None,
-                infix: Some(Box::new(Compiler::binary)),
-                precedence: Precedence::Equality,
-            },
-        );
-        m.insert(
-            TokenType::Equal,
-            ParseRule {
-                prefix: None,
-                infix: None,
-                precedence: Precedence::None,
-            },
-        );
-        m.insert(
-            TokenType::EqualEqual,
-            ParseRule {
-                prefix: None,
-                infix: Some(Box::new(Compiler::binary)),
-                precedence: Precedence::Equality,
-            },
-        );
-        m.insert(
-            TokenType::Greater,
-            ParseRule {
-                prefix: None,
-                infix: Some(Box::new(Compiler::binary)),
-                precedence: Precedence::Comparison,
-            },
-        );
-        m.insert(
-            TokenType::GreaterEqual,
-            ParseRule {
-                prefix: None,
-                infix: Some(Box::new(Compiler::binary)),
-                precedence: Precedence::Comparison,
-            },
-        );
-        m.insert(
-            TokenType::Less,
-            ParseRule {
-                prefix: None,
-                infix: Some(Box::new(Compiler::binary)),
-                precedence: Precedence::Comparison,
-            },
-        );
-        m.insert(
-            TokenType::LessEqual,
-            ParseRule {
-                prefix: None,
-                infix: Some(Box::new(Compiler::binary)),
-                precedence: Precedence::Comparison,
-            },
-        );
-        m.insert(
-            TokenType::Identifier,
-            ParseRule {
-                prefix: Some(Box::new(Compiler::variable)),
-                infix: None,
-                precedence: Precedence::None,
-            },
-        );
-        m.insert(
-            TokenType::String,
-            ParseRule {
-                prefix: Some(Box::new(Compiler::string)),
-                infix: None,
-                precedence: Precedence::None,
-            },
-        );
-        m.insert(
-            TokenType::Number,
-            ParseRule {
-                prefix: Some(Box::new(Compiler::number)),
-                infix: None,
-                precedence: Precedence::None,
-            },
-        );
-        m.insert(
-            TokenType::And,
-            ParseRule {
-                prefix: None,
-                infix: Some(Box::new(Compiler::and)),
-                precedence: Precedence::And,
-            },
-        );
-        m.insert(
-            TokenType::Class,
-            ParseRule {
-                prefix: None,
-                infix: None,
-                precedence: Precedence::None,
-            },
-        );
-        m.insert(
-            TokenType::Else,
-            ParseRule {
-                prefix: None,
-                infix: None,
-                precedence: Precedence::None,
-            },
-        );
-        m.insert(
-            TokenType::False,
-            ParseRule {
-                prefix: Some(Box::new(Compiler::literal)),
-                infix: None,
-                precedence: Precedence::None,
-            },
-        );
-        m.insert(
-            TokenType::Fun,
-            ParseRule {
-                prefix: None,
-                infix: None,
-                precedence: Precedence::None,
-            },
-        );
-        m.insert(
-            TokenType::For,
-            ParseRule {
-                prefix: None,
-                infix: None,
-                precedence: Precedence::None,
-            },
-        );
-        m.insert(
-            TokenType::If,
-            ParseRule {
-                prefix: None,
-                infix: None,
-                precedence: Precedence::None,
-            },
-        );
-        m.insert(
-            TokenType::Nil,
-            ParseRule {
-                prefix: Some(Box::new(Compiler::literal)),
-                infix: None,
-                precedence: Precedence::None,
-            },
-        );
-        m.insert(
-            TokenType::Or,
-            ParseRule {
-                prefix: None,
-                infix: Some(Box::new(Compiler::or)),
-                precedence: Precedence::Or,
-            },
-        );
-        m.insert(
-            TokenType::Print,
-            ParseRule {
-                prefix: None,
-                infix: None,
-                precedence: Precedence::None,
-            },
-        );
-        m.insert(
-            TokenType::Return,
-            ParseRule {
-                prefix: None,
-                infix: None,
-                precedence: Precedence::None,
-            },
-        );
-        m.insert(
-            TokenType::Super,
-            ParseRule {
-                prefix: Some(Box::new(Compiler::super_)),
-                infix: None,
-                precedence: Precedence::None,
-            },
-        );
-        m.insert(
-            TokenType::This,
-            ParseRule {
-                prefix: Some(Box::new(Compiler::this)),
-                infix: None,
-                precedence: Precedence::None,
-            },
-        );
-        m.insert(
-            TokenType::True,
-            ParseRule {
-                prefix: Some(Box::new(Compiler::literal)),
-                infix: None,
-                precedence: Precedence::None,
-            },
-        );
-        m.insert(
-            TokenType::Var,
-            ParseRule {
-                prefix: None,
-                infix: None,
-                precedence: Precedence::None,
-            },
-        );
-        m.insert(
-            TokenType::While,
-            ParseRule {
-                prefix: None,
-                infix: None,
-                precedence: Precedence::None,
-            },
-        );
-        m.insert(
-            TokenType::Switch,
-            ParseRule {
-                prefix: None,
-                infix: None,
-                precedence: Precedence::None,
-            },
-        );
-        m.insert(
-            TokenType::Case,
-            ParseRule {
-                prefix: None,
-                infix: None,
-                precedence: Precedence::None,
-            },
-        );
-        m.insert(
-            TokenType::Break,
-            ParseRule {
-                prefix: None,
-                infix: None,
-                precedence: Precedence::None,
-            },
-        );
-        m.insert(
-            TokenType::Default,
-            ParseRule {
-                prefix: None,
-                infix: None,
-                precedence: Precedence::None,
-            },
-        );
-        m.insert(
-            TokenType::Continue,
-            ParseRule {
-                prefix: None,
-                infix: None,
-                precedence: Precedence::None,
-            },
-        );
-        m.insert(
-            TokenType::Eof,
-            ParseRule {
-                prefix: None,
-                infix: None,
-                precedence: Precedence::None,
-            },
-        );
-        m.insert(
-            TokenType::Error,
-            ParseRule {
-                prefix: None,
-                infix: None,
-                precedence: Precedence::None,
-            },
-        );
+    // Indexed by `TokenType as usize`, so finding a rule costs no hashing.
+    pub static ref RULES: [ParseRule; TokenType::COUNT] = {
+        let mut m: Vec<Option<ParseRule>> = (0..TokenType::COUNT).map(|_| None).collect();
+        m[TokenType::LeftParen as usize] = Some(ParseRule {
+            prefix: Some(Box::new(Compiler::grouping)),
+            infix: Some(Box::new(Compiler::call)),
+            precedence: Precedence::Call,
+        });
+        m[TokenType::RightParen as usize] = Some(ParseRule {
+            prefix: None,
+            infix: None,
+            precedence: Precedence::None,
+        });
+        m[TokenType::LeftBrace as usize] = Some(ParseRule {
+            prefix: None,
+            infix: None,
+            precedence: Precedence::None,
+        });
+        m[TokenType::RightBrace as usize] = Some(ParseRule {
+            prefix: None,
+            infix: None,
+            precedence: Precedence::None,
+        });
+        m[TokenType::LeftBracket as usize] = Some(ParseRule {
+            prefix: Some(Box::new(Compiler::array)),
+            infix: Some(Box::new(Compiler::index)),
+            precedence: Precedence::Call,
+        });
+        m[TokenType::RightBracket as usize] = Some(ParseRule {
+            prefix: None,
+            infix: None,
+            precedence: Precedence::None,
+        });
+        m[TokenType::Comma as usize] = Some(ParseRule {
+            prefix: None,
+            infix: None,
+            precedence: Precedence::None,
+        });
+        m[TokenType::Dot as usize] = Some(ParseRule {
+            prefix: None,
+            infix: Some(Box::new(Compiler::dot)),
+            precedence: Precedence::Call,
+        });
+        m[TokenType::DotDotDot as usize] = Some(ParseRule {
+            prefix: None,
+            infix: None,
+            precedence: Precedence::None,
+        });
+        m[TokenType::Minus as usize] = Some(ParseRule {
+            prefix: Some(Box::new(Compiler::unary)),
+            infix: Some(Box::new(Compiler::binary)),
+            precedence: Precedence::Term,
+        });
+        m[TokenType::Plus as usize] = Some(ParseRule {
+            prefix: None,
+            infix: Some(Box::new(Compiler::binary)),
+            precedence: Precedence::Term,
+        });
+        m[TokenType::Semicolon as usize] = Some(ParseRule {
+            prefix: None,
+            infix: None,
+            precedence: Precedence::None,
+        });
+        m[TokenType::Slash as usize] = Some(ParseRule {
+            prefix: None,
+            infix: Some(Box::new(Compiler::binary)),
+            precedence: Precedence::Factor,
+        });
+        m[TokenType::Star as usize] = Some(ParseRule {
+            prefix: None,
+            infix: Some(Box::new(Compiler::binary)),
+            precedence: Precedence::Factor,
+        });
+        m[TokenType::StarStar as usize] = Some(ParseRule {
+            prefix: None,
+            infix: Some(Box::new(Compiler::binary)),
+            precedence: Precedence::Power,
+        });
+        m[TokenType::Percent as usize] = Some(ParseRule {
+            prefix: None,
+            infix: Some(Box::new(Compiler::binary)),
+            precedence: Precedence::Factor,
+        });
+        m[TokenType::Colon as usize] = Some(ParseRule {
+            prefix: None,
+            infix: None,
+            precedence: Precedence::None,
+        });
+        m[TokenType::Bang as usize] = Some(ParseRule {
+            prefix: Some(Box::new(Compiler::unary)),
+            infix: None,
+            precedence: Precedence::None,
+        });
+        m[TokenType::BangEqual as usize] = Some(ParseRule {
+            prefix: None,
+            infix: Some(Box::new(Compiler::binary)),
+            precedence: Precedence::Equality,
+        });
+        m[TokenType::Equal as usize] = Some(ParseRule {
+            prefix: None,
+            infix: None,
+            precedence: Precedence::None,
+        });
+        m[TokenType::EqualEqual as usize] = Some(ParseRule {
+            prefix: None,
+            infix: Some(Box::new(Compiler::binary)),
+            precedence: Precedence::Equality,
+        });
+        m[TokenType::Greater as usize] = Some(ParseRule {
+            prefix: None,
+            infix: Some(Box::new(Compiler::binary)),
+            precedence: Precedence::Comparison,
+        });
+        m[TokenType::GreaterEqual as usize] = Some(ParseRule {
+            prefix: None,
+            infix: Some(Box::new(Compiler::binary)),
+            precedence: Precedence::Comparison,
+        });
+        m[TokenType::Less as usize] = Some(ParseRule {
+            prefix: None,
+            infix: Some(Box::new(Compiler::binary)),
+            precedence: Precedence::Comparison,
+        });
+        m[TokenType::LessEqual as usize] = Some(ParseRule {
+            prefix: None,
+            infix: Some(Box::new(Compiler::binary)),
+            precedence: Precedence::Comparison,
+        });
+        m[TokenType::PlusEqual as usize] = Some(ParseRule {
+            prefix: None,
+            infix: None,
+            precedence: Precedence::None,
+        });
+        m[TokenType::MinusEqual as usize] = Some(ParseRule {
+            prefix: None,
+            infix: None,
+            precedence: Precedence::None,
+        });
+        m[TokenType::StarEqual as usize] = Some(ParseRule {
+            prefix: None,
+            infix: None,
+            precedence: Precedence::None,
+        });
+        m[TokenType::SlashEqual as usize] = Some(ParseRule {
+            prefix: None,
+            infix: None,
+            precedence: Precedence::None,
+        });
+        m[TokenType::LessLess as usize] = Some(ParseRule {
+            prefix: None,
+            infix: None,
+            precedence: Precedence::None,
+        });
+        m[TokenType::GreaterGreater as usize] = Some(ParseRule {
+            prefix: None,
+            infix: None,
+            precedence: Precedence::None,
+        });
+        m[TokenType::Identifier as usize] = Some(ParseRule {
+            prefix: Some(Box::new(Compiler::variable)),
+            infix: None,
+            precedence: Precedence::None,
+        });
+        m[TokenType::String as usize] = Some(ParseRule {
+            prefix: Some(Box::new(Compiler::string)),
+            infix: None,
+            precedence: Precedence::None,
+        });
+        m[TokenType::Interpolation as usize] = Some(ParseRule {
+            prefix: Some(Box::new(Compiler::interpolation)),
+            infix: None,
+            precedence: Precedence::None,
+        });
+        m[TokenType::Number as usize] = Some(ParseRule {
+            prefix: Some(Box::new(Compiler::number)),
+            infix: None,
+            precedence: Precedence::None,
+        });
+        m[TokenType::And as usize] = Some(ParseRule {
+            prefix: None,
+            infix: Some(Box::new(Compiler::and)),
+            precedence: Precedence::And,
+        });
+        m[TokenType::Class as usize] = Some(ParseRule {
+            prefix: None,
+            infix: None,
+            precedence: Precedence::None,
+        });
+        m[TokenType::Else as usize] = Some(ParseRule {
+            prefix: None,
+            infix: None,
+            precedence: Precedence::None,
+        });
+        m[TokenType::False as usize] = Some(ParseRule {
+            prefix: Some(Box::new(Compiler::literal)),
+            infix: None,
+            precedence: Precedence::None,
+        });
+        m[TokenType::Fun as usize] = Some(ParseRule {
+            prefix: Some(Box::new(Compiler::lambda)),
+            infix: None,
+            precedence: Precedence::None,
+        });
+        m[TokenType::For as usize] = Some(ParseRule {
+            prefix: None,
+            infix: None,
+            precedence: Precedence::None,
+        });
+        m[TokenType::If as usize] = Some(ParseRule {
+            prefix: None,
+            infix: None,
+            precedence: Precedence::None,
+        });
+        m[TokenType::Nil as usize] = Some(ParseRule {
+            prefix: Some(Box::new(Compiler::literal)),
+            infix: None,
+            precedence: Precedence::None,
+        });
+        m[TokenType::Or as usize] = Some(ParseRule {
+            prefix: None,
+            infix: Some(Box::new(Compiler::or)),
+            precedence: Precedence::Or,
+        });
+        m[TokenType::Print as usize] = Some(ParseRule {
+            prefix: None,
+            infix: None,
+            precedence: Precedence::None,
+        });
+        m[TokenType::Return as usize] = Some(ParseRule {
+            prefix: None,
+            infix: None,
+            precedence: Precedence::None,
+        });
+        m[TokenType::Super as usize] = Some(ParseRule {
+            prefix: Some(Box::new(Compiler::super_)),
+            infix: None,
+            precedence: Precedence::None,
+        });
+        m[TokenType::This as usize] = Some(ParseRule {
+            prefix: Some(Box::new(Compiler::this)),
+            infix: None,
+            precedence: Precedence::None,
+        });
+        m[TokenType::True as usize] = Some(ParseRule {
+            prefix: Some(Box::new(Compiler::literal)),
+            infix: None,
+            precedence: Precedence::None,
+        });
+        m[TokenType::Var as usize] = Some(ParseRule {
+            prefix: None,
+            infix: None,
+            precedence: Precedence::None,
+        });
+        m[TokenType::While as usize] = Some(ParseRule {
+            prefix: None,
+            infix: None,
+            precedence: Precedence::None,
+        });
+        m[TokenType::In as usize] = Some(ParseRule {
+            prefix: None,
+            infix: None,
+            precedence: Precedence::None,
+        });
+        m[TokenType::Const as usize] = Some(ParseRule {
+            prefix: None,
+            infix: None,
+            precedence: Precedence::None,
+        });
+        m[TokenType::With as usize] = Some(ParseRule {
+            prefix: None,
+            infix: None,
+            precedence: Precedence::None,
+        });
+        m[TokenType::Repeat as usize] = Some(ParseRule {
+            prefix: None,
+            infix: None,
+            precedence: Precedence::None,
+        });
+        m[TokenType::Do as usize] = Some(ParseRule {
+            prefix: None,
+            infix: None,
+            precedence: Precedence::None,
+        });
+        m[TokenType::Switch as usize] = Some(ParseRule {
+            prefix: None,
+            infix: None,
+            precedence: Precedence::None,
+        });
+        m[TokenType::Case as usize] = Some(ParseRule {
+            prefix: None,
+            infix: None,
+            precedence: Precedence::None,
+        });
+        m[TokenType::Break as usize] = Some(ParseRule {
+            prefix: None,
+            infix: None,
+            precedence: Precedence::None,
+        });
+        m[TokenType::Default as usize] = Some(ParseRule {
+            prefix: None,
+            infix: None,
+            precedence: Precedence::None,
+        });
+        m[TokenType::Continue as usize] = Some(ParseRule {
+            prefix: None,
+            infix: None,
+            precedence: Precedence::None,
+        });
+        m[TokenType::Eof as usize] = Some(ParseRule {
+            prefix: None,
+            infix: None,
+            precedence: Precedence::None,
+        });
+        m[TokenType::Error as usize] = Some(ParseRule {
+            prefix: None,
+            infix: None,
+            precedence: Precedence::None,
+        });
 
-        m
+        let rules: Vec<ParseRule> = m
+            .into_iter()
+            .enumerate()
+            .map(|(i, rule)| rule.unwrap_or_else(|| panic!("No parse rule for token type {}", i)))
+            .collect();
+        rules.try_into().unwrap_or_else(|_| unreachable!())
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::compile;
+
+    fn bytecode(source: &str) -> Vec<u8> {
+        let result = compile(source.to_string());
+        assert!(result.is_runnable(), "{}", source);
+        let function = result.function.read();
+        let code = function.chunk.read().code.clone();
+        code
+    }
+
+    #[test]
+    fn every_token_type_has_a_rule() {
+        assert_eq!(RULES.len(), TokenType::COUNT);
+        assert_eq!(
+            RULES[TokenType::Star as usize].precedence,
+            Precedence::Factor
+        );
+        assert_eq!(
+            RULES[TokenType::StarStar as usize].precedence,
+            Precedence::Power
+        );
+        assert_eq!(RULES[TokenType::Or as usize].precedence, Precedence::Or);
+        assert!(RULES[TokenType::Error as usize].prefix.is_none());
+    }
+
+    #[test]
+    fn precedence_matches_explicit_grouping() {
+        let cases = [
+            (
+                "a or b and c == !a < -b + c * a ** b",
+                "a or (b and (c == ((!a) < ((-b) + (c * (a ** b))))))",
+            ),
+            ("a - b - c / a % b", "(a - b) - ((c / a) % b)"),
+            ("a[0](c)[1] != a.b", "(((a[0])(c))[1]) != (a.b)"),
+            ("a = b or c", "a = (b or c)"),
+        ];
+        for (implicit, explicit) in cases {
+            let declare = "var a; var b; var c; print ";
+            assert_eq!(
+                bytecode(&format!("{}{};", declare, implicit)),
+                bytecode(&format!("{}{};", declare, explicit)),
+                "{}",
+                implicit
+            );
+        }
+    }
+}
@@ -5,6 +5,9 @@ pub struct Token {
     pub token_type: TokenType,
     pub lexeme: String,
     pub line: usize,
+    // 1-based, counted in characters from the start of the line the token
+    // starts on.
+    pub column: usize,
 }
 
 impl Token {
@@ -13,33 +16,74 @@ impl Token {
             token_type: TokenType::Error,
             lexeme: String::new(),
             line: 0,
+            column: 0,
         }
     }
 }
 
+#[derive(Clone)]
 pub struct Scanner {
     pub source: String,
-    // Pointer to the start of the current lexeme
+    // Byte offset of the start of the current lexeme
     start: usize,
-    // Pointer to the current character
+    // Byte offset of the current character
     current: usize,
     pub(crate) line: usize,
+    // Where the current line and the current lexeme's line begin.
+    line_start: usize,
+    start_line_start: usize,
+    // Unclosed `{` count for each `${` being scanned, innermost last. A `}`
+    // with none open resumes the enclosing string.
+    interpolations: Vec<usize>,
 }
 
 impl Scanner {
     pub fn new(source: String) -> Self {
+        // Skip a leading `#!` line so scripts can be made executable. Its
+        // newline is left in place to keep line numbers right.
+        let current = if source.starts_with("#!") {
+            source.find('\n').unwrap_or(source.len())
+        } else {
+            0
+        };
+
         Scanner {
             source,
             start: 0,
-            current: 0,
+            current,
             line: 1,
+            line_start: 0,
+            start_line_start: 0,
+            interpolations: Vec::new(),
         }
     }
 
+    // The token `scan_token` would return next, leaving the scanner where it
+    // was.
+    pub fn peek_token(&mut self) -> Token {
+        let start = self.start;
+        let current = self.current;
+        let line = self.line;
+        let line_start = self.line_start;
+        let start_line_start = self.start_line_start;
+        let interpolations = self.interpolations.clone();
+
+        let token = self.scan_token();
+
+        self.start = start;
+        self.current = current;
+        self.line = line;
+        self.line_start = line_start;
+        self.start_line_start = start_line_start;
+        self.interpolations = interpolations;
+        token
+    }
+
     pub fn scan_token(&mut self) -> Token {
         self.skip_whitespace();
 
         self.start = self.current;
+        self.start_line_start = self.line_start;
 
         if self.is_at_end() {
             return self.make_token(TokenType::Eof);
@@ -47,64 +91,77 @@ impl Scanner {
 
         let c = self.advance();
 
-        if c.is_digit(10) {
+        if c.is_ascii_digit() {
             return self.number();
         }
 
-        if c.is_alphabetic() {
+        if c.is_alphabetic() || c == '_' {
             return self.identifier();
         }
 
         match c {
             '(' => self.make_token(TokenType::LeftParen),
             ')' => self.make_token(TokenType::RightParen),
-            '{' => self.make_token(TokenType::LeftBrace),
-            '}' => self.make_token(TokenType::RightBrace),
-            ';' => self.make_token(TokenType::Semicolon),
-            ',' => self.make_token(TokenType::Comma),
-            '.' => self.make_token(TokenType::Dot),
-            '-' => self.make_token(TokenType::Minus),
-            '+' => self.make_token(TokenType::Plus),
-            '/' => self.make_token(TokenType::Slash),
-            '*' => self.make_token(TokenType::Star),
-            ':' => self.make_token(TokenType::Colon),
-            '!' => {
-                if self.match_char('=') {
-                    self.make_token(TokenType::BangEqual)
-                } else {
-                    self.make_token(TokenType::Bang)
-                }
-            }
-            '=' => {
-                if self.match_char('=') {
-                    self.make_token(TokenType::EqualEqual)
-                } else {
-                    self.make_token(TokenType::Equal)
+            '{' => {
+                if let Some(depth) = self.interpolations.last_mut() {
+                    *depth += 1;
                 }
+                self.make_token(TokenType::LeftBrace)
             }
-            '<' => {
-                if self.match_char('=') {
-                    self.make_token(TokenType::LessEqual)
-                } else {
-                    self.make_token(TokenType::Less)
+            '}' => match self.interpolations.last_mut() {
+                Some(0) => {
+                    self.interpolations.pop();
+                    self.string()
                 }
-            }
-            '>' => {
-                if self.match_char('=') {
-                    self.make_token(TokenType::GreaterEqual)
-                } else {
-                    self.make_token(TokenType::Greater)
+                Some(depth) => {
+                    *depth -= 1;
+                    self.make_token(TokenType::RightBrace)
                 }
+                None => self.make_token(TokenType::RightBrace),
+            },
+            '[' => self.make_token(TokenType::LeftBracket),
+            ']' => self.make_token(TokenType::RightBracket),
+            ';' => self.make_token(TokenType::Semicolon),
+            ',' => self.make_token(TokenType::Comma),
+            '.' if self.peek() == '.' && self.peek_next() == '.' => {
+                self.advance();
+                self.advance();
+                self.make_token(TokenType::DotDotDot)
             }
+            '.' => self.make_token(TokenType::Dot),
+            '-' => self.two_char_token('=', TokenType::MinusEqual, TokenType::Minus),
+            '+' => self.two_char_token('=', TokenType::PlusEqual, TokenType::Plus),
+            '/' => self.two_char_token('=', TokenType::SlashEqual, TokenType::Slash),
+            '*' if self.match_char('*') => self.make_token(TokenType::StarStar),
+            '*' => self.two_char_token('=', TokenType::StarEqual, TokenType::Star),
+            '%' => self.make_token(TokenType::Percent),
+            ':' => self.make_token(TokenType::Colon),
+            '!' => self.two_char_token('=', TokenType::BangEqual, TokenType::Bang),
+            '=' => self.two_char_token('=', TokenType::EqualEqual, TokenType::Equal),
+            '<' if self.match_char('<') => self.make_token(TokenType::LessLess),
+            '<' => self.two_char_token('=', TokenType::LessEqual, TokenType::Less),
+            '>' if self.match_char('>') => self.make_token(TokenType::GreaterGreater),
+            '>' => self.two_char_token('=', TokenType::GreaterEqual, TokenType::Greater),
             '"' => self.string(),
             _ => self.error_token("Unexpected character"),
         }
     }
 
+    // Scans the rest of a string literal, stopping early at a `${`. `\$` keeps
+    // a literal `$`.
     fn string(&mut self) -> Token {
         while self.peek() != '"' && !self.is_at_end() {
             if self.peek() == '\n' {
                 self.line += 1;
+                self.line_start = self.current + 1;
+            }
+            if self.peek() == '\\' && self.peek_next() == '$' {
+                self.advance();
+            } else if self.peek() == '$' && self.peek_next() == '{' {
+                self.advance();
+                self.advance();
+                self.interpolations.push(0);
+                return self.make_token(TokenType::Interpolation);
             }
             self.advance();
         }
@@ -120,16 +177,30 @@ impl Scanner {
     }
 
     fn number(&mut self) -> Token {
-        while self.peek().is_digit(10) {
+        // Radix-prefixed integers: 0x, 0b and 0o. Digits are validated by the
+        // compiler, so `0xZZ` scans as one malformed number.
+        if self.current - self.start == 1
+            && self.byte_at(self.start) == '0'
+            && matches!(self.peek(), 'x' | 'b' | 'o')
+        {
+            self.advance();
+            while self.peek().is_ascii_alphanumeric() {
+                self.advance();
+            }
+
+            return self.make_token(TokenType::Number);
+        }
+
+        while self.peek().is_ascii_digit() {
             self.advance();
         }
 
         // Look for a fractional part.
-        if self.peek() == '.' && self.peek_next().is_digit(10) {
+        if self.peek() == '.' && self.peek_next().is_ascii_digit() {
             // Consume the "."
             self.advance();
 
-            while self.peek().is_digit(10) {
+            while self.peek().is_ascii_digit() {
                 self.advance();
             }
         }
@@ -138,7 +209,7 @@ impl Scanner {
     }
 
     fn identifier(&mut self) -> Token {
-        while self.peek().is_alphanumeric() {
+        while self.peek().is_alphanumeric() || self.peek() == '_' {
             self.advance();
         }
 
@@ -153,7 +224,8 @@ impl Scanner {
         token_type: TokenType,
     ) -> TokenType {
         if self.current - self.start == start + length
-            && &self.source[self.start + start..self.start + start + length] == rest
+            && &self.source.as_bytes()[self.start + start..self.start + start + length]
+                == rest.as_bytes()
         {
             return token_type;
         }
@@ -162,13 +234,18 @@ impl Scanner {
     }
 
     fn identifier_type(&self) -> TokenType {
-        match self.source.chars().nth(self.start).unwrap() {
+        match self.byte_at(self.start) {
             'a' => self.check_keyword(1, 2, "nd", TokenType::And),
             'b' => self.check_keyword(1, 4, "reak", TokenType::Break),
             'c' => {
                 if self.current - self.start > 1 {
-                    match self.source.chars().nth(self.start + 1).unwrap() {
+                    match self.byte_at(self.start + 1) {
                         'a' => self.check_keyword(2, 2, "se", TokenType::Case),
+                        'o' if self.current - self.start > 3
+                            && self.byte_at(self.start + 3) == 's' =>
+                        {
+                            self.check_keyword(2, 3, "nst", TokenType::Const)
+                        }
                         'o' => self.check_keyword(2, 6, "ntinue", TokenType::Continue),
                         'l' => self.check_keyword(2, 3, "ass", TokenType::Class),
                         _ => TokenType::Identifier,
@@ -177,11 +254,21 @@ impl Scanner {
                     TokenType::Identifier
                 }
             }
-            'd' => self.check_keyword(1, 6, "efault", TokenType::Default),
+            'd' => {
+                if self.current - self.start > 1 {
+                    match self.byte_at(self.start + 1) {
+                        'e' => self.check_keyword(2, 5, "fault", TokenType::Default),
+                        'o' => self.check_keyword(2, 0, "", TokenType::Do),
+                        _ => TokenType::Identifier,
+                    }
+                } else {
+                    TokenType::Identifier
+                }
+            }
             'e' => self.check_keyword(1, 3, "lse", TokenType::Else),
             'f' => {
                 if self.current - self.start > 1 {
-                    match self.source.chars().nth(self.start + 1).unwrap() {
+                    match self.byte_at(self.start + 1) {
                         'a' => self.check_keyword(2, 3, "lse", TokenType::False),
                         'o' => self.check_keyword(2, 1, "r", TokenType::For),
                         'u' => self.check_keyword(2, 1, "n", TokenType::Fun),
@@ -191,14 +278,34 @@ impl Scanner {
                     TokenType::Identifier
                 }
             }
-            'i' => self.check_keyword(1, 1, "f", TokenType::If),
+            'i' => {
+                if self.current - self.start > 1 {
+                    match self.byte_at(self.start + 1) {
+                        'f' => self.check_keyword(2, 0, "", TokenType::If),
+                        'n' => self.check_keyword(2, 0, "", TokenType::In),
+                        _ => TokenType::Identifier,
+                    }
+                } else {
+                    TokenType::Identifier
+                }
+            }
             'n' => self.check_keyword(1, 2, "il", TokenType::Nil),
             'o' => self.check_keyword(1, 1, "r", TokenType::Or),
             'p' => self.check_keyword(1, 4, "rint", TokenType::Print),
-            'r' => self.check_keyword(1, 5, "eturn", TokenType::Return),
+            'r' => {
+                if self.current - self.start > 2 && self.byte_at(self.start + 1) == 'e' {
+                    match self.byte_at(self.start + 2) {
+                        't' => self.check_keyword(3, 3, "urn", TokenType::Return),
+                        'p' => self.check_keyword(3, 3, "eat", TokenType::Repeat),
+                        _ => TokenType::Identifier,
+                    }
+                } else {
+                    TokenType::Identifier
+                }
+            }
             's' => {
                 if self.current - self.start > 1 {
-                    match self.source.chars().nth(self.start + 1).unwrap() {
+                    match self.byte_at(self.start + 1) {
                         'u' => self.check_keyword(2, 3, "per", TokenType::Super),
                         'w' => self.check_keyword(2, 4, "itch", TokenType::Switch),
                         _ => TokenType::Identifier,
@@ -209,7 +316,7 @@ impl Scanner {
             }
             't' => {
                 if self.current - self.start > 1 {
-                    match self.source.chars().nth(self.start + 1).unwrap() {
+                    match self.byte_at(self.start + 1) {
                         'h' => self.check_keyword(2, 2, "is", TokenType::This),
                         'r' => self.check_keyword(2, 2, "ue", TokenType::True),
                         _ => TokenType::Identifier,
@@ -219,7 +326,17 @@ impl Scanner {
                 }
             }
             'v' => self.check_keyword(1, 2, "ar", TokenType::Var),
-            'w' => self.check_keyword(1, 4, "hile", TokenType::While),
+            'w' => {
+                if self.current - self.start > 1 {
+                    match self.byte_at(self.start + 1) {
+                        'h' => self.check_keyword(2, 3, "ile", TokenType::While),
+                        'i' => self.check_keyword(2, 2, "th", TokenType::With),
+                        _ => TokenType::Identifier,
+                    }
+                } else {
+                    TokenType::Identifier
+                }
+            }
             _ => TokenType::Identifier,
         }
     }
@@ -229,24 +346,38 @@ impl Scanner {
     }
 
     fn advance(&mut self) -> char {
-        self.current += 1;
-        self.source.chars().nth(self.current - 1).unwrap()
+        let c = self.peek();
+        self.current += c.len_utf8();
+        c
     }
 
     fn peek(&self) -> char {
-        if self.is_at_end() {
-            return '\0';
-        }
-
-        self.source.chars().nth(self.current).unwrap()
+        self.source[self.current..].chars().next().unwrap_or('\0')
     }
 
     fn peek_next(&self) -> char {
-        if self.current + 1 >= self.source.len() {
-            return '\0';
-        }
+        self.source[self.current..].chars().nth(1).unwrap_or('\0')
+    }
+
+    // The byte at `offset` as a char, for matching ASCII. Any other byte,
+    // including one inside a multi-byte character, matches no ASCII char.
+    fn byte_at(&self, offset: usize) -> char {
+        self.source.as_bytes()[offset] as char
+    }
 
-        self.source.chars().nth(self.current + 1).unwrap()
+    // Makes `matched` if the next character is `expected`, consuming it, and
+    // `otherwise` if not.
+    fn two_char_token(
+        &mut self,
+        expected: char,
+        matched: TokenType,
+        otherwise: TokenType,
+    ) -> Token {
+        if self.match_char(expected) {
+            self.make_token(matched)
+        } else {
+            self.make_token(otherwise)
+        }
     }
 
     fn match_char(&mut self, expected: char) -> bool {
@@ -254,11 +385,11 @@ impl Scanner {
             return false;
         }
 
-        if self.source.chars().nth(self.current).unwrap() != expected {
+        if self.peek() != expected {
             return false;
         }
 
-        self.current += 1;
+        self.current += expected.len_utf8();
         true
     }
 
@@ -267,14 +398,23 @@ impl Scanner {
             token_type,
             lexeme: self.source[self.start..self.current].to_string(),
             line: self.line,
+            column: self.column(),
         }
     }
 
+    fn column(&self) -> usize {
+        self.source[self.start_line_start..self.start]
+            .chars()
+            .count()
+            + 1
+    }
+
     fn error_token(&self, message: &str) -> Token {
         Token {
             token_type: TokenType::Error,
             lexeme: message.to_string(),
             line: self.line,
+            column: self.column(),
         }
     }
 
@@ -288,15 +428,12 @@ impl Scanner {
                 '\n' => {
                     self.line += 1;
                     self.advance();
+                    self.line_start = self.current;
                 }
-                '/' => {
-                    if self.peek_next() == '/' {
-                        // A comment goes until the end of the line.
-                        while self.peek() != '\n' && !self.is_at_end() {
-                            self.advance();
-                        }
-                    } else {
-                        return;
+                '/' if self.peek_next() == '/' => {
+                    // A comment goes until the end of the line.
+                    while self.peek() != '\n' && !self.is_at_end() {
+                        self.advance();
                     }
                 }
                 _ => return,
@@ -304,3 +441,185 @@ impl Scanner {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Every token in `source` up to, but not including, the end of file.
+    fn tokens(source: &str) -> Vec<Token> {
+        let mut scanner = Scanner::new(source.to_string());
+        let mut tokens = Vec::new();
+        loop {
+            let token = scanner.scan_token();
+            if token.token_type == TokenType::Eof {
+                return tokens;
+            }
+            tokens.push(token);
+        }
+    }
+
+    fn types(source: &str) -> Vec<TokenType> {
+        tokens(source)
+            .into_iter()
+            .map(|token| token.token_type)
+            .collect()
+    }
+
+    #[test]
+    fn scans_multi_character_operators() {
+        use TokenType::*;
+        let cases = [
+            ("!", Bang),
+            ("!=", BangEqual),
+            ("=", Equal),
+            ("==", EqualEqual),
+            ("<", Less),
+            ("<=", LessEqual),
+            ("<<", LessLess),
+            (">", Greater),
+            (">=", GreaterEqual),
+            (">>", GreaterGreater),
+            ("+", Plus),
+            ("+=", PlusEqual),
+            ("-", Minus),
+            ("-=", MinusEqual),
+            ("*", Star),
+            ("*=", StarEqual),
+            ("**", StarStar),
+            ("/", Slash),
+            ("/=", SlashEqual),
+            (".", Dot),
+            ("...", DotDotDot),
+        ];
+        for (source, expected) in cases {
+            assert_eq!(types(source), vec![expected], "{}", source);
+        }
+
+        // The longest operator wins, and whatever follows starts a new token.
+        assert_eq!(types("<<="), vec![LessLess, Equal]);
+        assert_eq!(types("a<=b"), vec![Identifier, LessEqual, Identifier]);
+        assert_eq!(types("< ="), vec![Less, Equal]);
+        assert_eq!(types("===!"), vec![EqualEqual, Equal, Bang]);
+        assert_eq!(types("***"), vec![StarStar, Star]);
+        assert_eq!(types(".."), vec![Dot, Dot]);
+        assert_eq!(types("...."), vec![DotDotDot, Dot]);
+    }
+
+    #[test]
+    fn skips_a_leading_shebang_line() {
+        let scanned = tokens("#!/usr/bin/env rlox\nprint 1;");
+        assert_eq!(scanned[0].token_type, TokenType::Print);
+        assert_eq!(scanned[0].line, 2);
+        assert_eq!(types("#!only a shebang"), vec![]);
+
+        // Only the very first line may be a shebang.
+        assert_eq!(types(" #!x")[0], TokenType::Error);
+        assert_eq!(types("print 1;\n#!x")[3..].first(), Some(&TokenType::Error));
+    }
+
+    #[test]
+    fn scans_radix_prefixed_numbers_as_one_token() {
+        for source in ["0xFF", "0b1010", "0o17", "0xZZ", "0b"] {
+            let scanned = tokens(source);
+            assert_eq!(scanned.len(), 1, "{}", source);
+            assert_eq!(scanned[0].token_type, TokenType::Number);
+            assert_eq!(scanned[0].lexeme, source);
+        }
+        assert_eq!(types("10x"), vec![TokenType::Number, TokenType::Identifier]);
+    }
+
+    #[test]
+    fn scans_interpolated_strings_in_pieces() {
+        use TokenType::*;
+        let lexemes = |source: &str| {
+            tokens(source)
+                .into_iter()
+                .map(|token| token.lexeme)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            types(r#""a ${x} b""#),
+            vec![Interpolation, Identifier, String]
+        );
+        assert_eq!(lexemes(r#""a ${x} b""#), vec![r#""a ${"#, "x", r#"} b""#]);
+
+        // Braces inside the expression don't end it, and strings nest.
+        assert_eq!(
+            types(r#""${ {} } ${"${y}"}""#),
+            vec![
+                Interpolation,
+                LeftBrace,
+                RightBrace,
+                Interpolation,
+                Interpolation,
+                Identifier,
+                String,
+                String
+            ]
+        );
+
+        // `\$` and a `$` without a brace stay part of the text.
+        assert_eq!(types(r#""\${x} $5""#), vec![String]);
+    }
+
+    #[test]
+    fn tokens_carry_their_line_and_column() {
+        let positions = |source: &str| -> Vec<(String, usize, usize)> {
+            tokens(source)
+                .into_iter()
+                .map(|token| (token.lexeme, token.line, token.column))
+                .collect()
+        };
+        let at = |lexeme: &str, line, column| (lexeme.to_string(), line, column);
+
+        assert_eq!(
+            positions("var x = 10;\n  print x;"),
+            vec![
+                at("var", 1, 1),
+                at("x", 1, 5),
+                at("=", 1, 7),
+                at("10", 1, 9),
+                at(";", 1, 11),
+                at("print", 2, 3),
+                at("x", 2, 9),
+                at(";", 2, 10),
+            ]
+        );
+        // Columns restart after a newline inside a string.
+        assert_eq!(
+            positions("x = \"a\nb\"; y")[3..],
+            [at(";", 2, 3), at("y", 2, 5)]
+        );
+    }
+
+    #[test]
+    fn scans_multi_byte_characters() {
+        let scanned = tokens("var café = \"héllo ✓\"; print(café);");
+        let lexemes: Vec<&str> = scanned.iter().map(|token| token.lexeme.as_str()).collect();
+        assert_eq!(
+            lexemes,
+            vec![
+                "var",
+                "café",
+                "=",
+                "\"héllo ✓\"",
+                ";",
+                "print",
+                "(",
+                "café",
+                ")",
+                ";"
+            ]
+        );
+        assert_eq!(scanned[1].token_type, TokenType::Identifier);
+        // Columns count characters, not bytes.
+        assert_eq!(scanned[5].column, 23);
+
+        // Only ASCII bytes can match the keyword tables.
+        assert_eq!(types("éor prínt ré"), vec![TokenType::Identifier; 3]);
+        assert_eq!(types("#!é\nnil")[0], TokenType::Nil);
+        assert_eq!(types("\"ß")[0], TokenType::Error);
+    }
+}
@@ -0,0 +1,71 @@
+use lazy_static::lazy_static;
+use parking_lot::RwLock;
+use std::collections::HashMap;
+use std::fmt::Display;
+
+/// An interned identifier. Property and method tables are keyed by symbols so
+/// lookups hash a `u32` instead of a freshly allocated `String`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Symbol(u32);
+
+struct Interner {
+    ids: HashMap<String, Symbol>,
+    names: Vec<String>,
+}
+
+lazy_static! {
+    static ref INTERNER: RwLock<Interner> = {
+        let mut interner = Interner {
+            ids: HashMap::new(),
+            names: Vec::new(),
+        };
+
+        // Pre-interned so INIT is always Symbol(0).
+        interner.ids.insert(String::from("init"), Symbol(0));
+        interner.names.push(String::from("init"));
+
+        RwLock::new(interner)
+    };
+}
+
+impl Symbol {
+    pub const INIT: Symbol = Symbol(0);
+
+    pub fn intern(name: &str) -> Self {
+        if let Some(symbol) = INTERNER.read().ids.get(name) {
+            return *symbol;
+        }
+
+        let mut interner = INTERNER.write();
+        if let Some(symbol) = interner.ids.get(name) {
+            return *symbol;
+        }
+
+        let symbol = Symbol(interner.names.len() as u32);
+        interner.ids.insert(name.to_string(), symbol);
+        interner.names.push(name.to_string());
+        symbol
+    }
+}
+
+impl Display for Symbol {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", INTERNER.read().names[self.0 as usize])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interning_is_stable() {
+        assert_eq!(Symbol::intern("init"), Symbol::INIT);
+        let a = Symbol::intern("symbol_test_a");
+        let b = Symbol::intern("symbol_test_b");
+        assert_ne!(a, b);
+        assert_eq!(Symbol::intern("symbol_test_a"), a);
+        assert_eq!(a.to_string(), "symbol_test_a");
+        assert_eq!(Symbol::INIT.to_string(), "init");
+    }
+}
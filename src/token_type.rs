@@ -5,13 +5,22 @@ pub enum TokenType {
     RightParen,
     LeftBrace,
     RightBrace,
+    LeftBracket,
+    RightBracket,
     Comma,
     Dot,
+    DotDotDot,
     Minus,
+    MinusEqual,
     Plus,
+    PlusEqual,
     Semicolon,
     Slash,
+    SlashEqual,
     Star,
+    StarEqual,
+    StarStar,
+    Percent,
     Colon,
 
     // One or two character tokens.
@@ -23,10 +32,14 @@ pub enum TokenType {
     GreaterEqual,
     Less,
     LessEqual,
+    LessLess,
+    GreaterGreater,
 
     // Literals.
     Identifier,
     String,
+    // A string literal's text up to a `${`, the embedded expression follows.
+    Interpolation,
     Number,
 
     // Keywords.
@@ -45,13 +58,54 @@ pub enum TokenType {
     This,
     True,
     Var,
+    Const,
     While,
     Switch,
     Case,
     Break,
     Default,
     Continue,
+    Repeat,
+    Do,
+    With,
+    In,
 
     Eof,
     Error,
 }
+
+impl TokenType {
+    pub const COUNT: usize = TokenType::Error as usize + 1;
+
+    pub fn is_keyword(&self) -> bool {
+        matches!(
+            self,
+            TokenType::And
+                | TokenType::Class
+                | TokenType::Else
+                | TokenType::False
+                | TokenType::Fun
+                | TokenType::For
+                | TokenType::If
+                | TokenType::Nil
+                | TokenType::Or
+                | TokenType::Print
+                | TokenType::Return
+                | TokenType::Super
+                | TokenType::This
+                | TokenType::True
+                | TokenType::Var
+                | TokenType::Const
+                | TokenType::While
+                | TokenType::Switch
+                | TokenType::Case
+                | TokenType::Break
+                | TokenType::Default
+                | TokenType::Continue
+                | TokenType::Repeat
+                | TokenType::Do
+                | TokenType::With
+                | TokenType::In
+        )
+    }
+}
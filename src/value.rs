@@ -1,46 +1,177 @@
 use crate::chunk::Chunk;
-use parking_lot::RwLock;
-use std::collections::HashMap;
+use crate::symbol::Symbol;
+use crate::vm::ErrorKind;
+use parking_lot::{RwLock, RwLockReadGuard};
+use std::collections::{HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::io::{BufRead, BufReader, Read, Write};
 use std::rc::Rc;
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Default)]
 pub enum Value {
     Int(i64),
     Float(f64),
     Bool(bool),
+    #[default]
     Nil,
     String(String),
+    Symbol(Symbol),
     Function(Rc<RwLock<Function>>),
-    Closure(Box<Closure>),
+    Closure(Rc<RwLock<Closure>>),
     NativeFunction(Rc<RwLock<NativeFunction>>),
-    RunTimeError(String),
+    RunTimeError(ErrorKind, String),
     Class(Rc<RwLock<Class>>),
     Instance(Rc<RwLock<Instance>>),
     BoundMethod(Rc<RwLock<BoundMethod>>),
+    Bytes(Rc<RwLock<Vec<u8>>>),
+    File(Rc<RwLock<File>>),
+    Set(Rc<RwLock<HashSet<HashKey>>>),
+    Array(Rc<RwLock<Vec<Value>>>),
 }
 
 #[derive(Clone, Debug)]
 pub struct BoundMethod {
     pub receiver: Rc<RwLock<Value>>,
-    pub method: Box<Closure>,
+    pub method: Rc<RwLock<Closure>>,
 }
 
 impl BoundMethod {
-    pub fn new(receiver: Rc<RwLock<Value>>, method: Box<Closure>) -> Self {
+    pub fn new(receiver: Rc<RwLock<Value>>, method: Rc<RwLock<Closure>>) -> Self {
         BoundMethod { receiver, method }
     }
 }
 
 impl PartialEq for BoundMethod {
     fn eq(&self, other: &Self) -> bool {
-        self.method.function.read().name == other.method.function.read().name
+        Rc::ptr_eq(&self.method, &other.method) && *self.receiver.read() == *other.receiver.read()
+    }
+}
+
+// A value usable as a set element. Only ints, floats, bools, nil, strings and
+// symbols are hashable. Floats hash by their bits, with -0.0 folded into 0.0
+// and every NaN treated as the same key, so a set holds at most one NaN. An
+// integral float is the same key as the equal int.
+#[derive(Clone, Debug)]
+pub struct HashKey(Value);
+
+impl HashKey {
+    pub fn new(value: Value) -> Option<Self> {
+        match value {
+            Value::Int(_)
+            | Value::Float(_)
+            | Value::Bool(_)
+            | Value::Nil
+            | Value::String(_)
+            | Value::Symbol(_) => Some(HashKey(value)),
+            _ => None,
+        }
+    }
+
+    fn float_bits(f: f64) -> u64 {
+        if f.is_nan() {
+            f64::NAN.to_bits()
+        } else if f == 0.0 {
+            0.0f64.to_bits()
+        } else {
+            f.to_bits()
+        }
+    }
+}
+
+impl PartialEq for HashKey {
+    fn eq(&self, other: &Self) -> bool {
+        match (&self.0, &other.0) {
+            (Value::Float(f1), Value::Float(f2)) => {
+                HashKey::float_bits(*f1) == HashKey::float_bits(*f2)
+            }
+            (v1, v2) => v1 == v2,
+        }
+    }
+}
+
+impl Eq for HashKey {}
+
+impl Hash for HashKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        // A float equal to an int hashes as that int, since the keys match.
+        if let Some(i) = self.0.as_exact_int() {
+            std::mem::discriminant(&Value::Int(0)).hash(state);
+            return i.hash(state);
+        }
+
+        std::mem::discriminant(&self.0).hash(state);
+        match &self.0 {
+            Value::Int(i) => i.hash(state),
+            Value::Float(f) => HashKey::float_bits(*f).hash(state),
+            Value::Bool(b) => b.hash(state),
+            Value::String(s) => s.hash(state),
+            Value::Symbol(s) => s.hash(state),
+            _ => {}
+        }
+    }
+}
+
+// An open file. The OS handle is released by `close`, or when the last
+// reference to the value is dropped.
+#[derive(Debug)]
+pub struct File {
+    pub path: String,
+    handle: Option<BufReader<std::fs::File>>,
+}
+
+impl File {
+    pub fn new(path: String, file: std::fs::File) -> Self {
+        File {
+            path,
+            handle: Some(BufReader::new(file)),
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.handle.is_none()
+    }
+
+    pub fn close(&mut self) {
+        self.handle = None;
+    }
+
+    fn handle(&mut self) -> std::io::Result<&mut BufReader<std::fs::File>> {
+        self.handle
+            .as_mut()
+            .ok_or_else(|| std::io::Error::other("file is closed"))
+    }
+
+    pub fn read(&mut self) -> std::io::Result<String> {
+        let mut contents = String::new();
+        self.handle()?.read_to_string(&mut contents)?;
+        Ok(contents)
+    }
+
+    // The next line without its terminator, or None at end of file.
+    pub fn read_line(&mut self) -> std::io::Result<Option<String>> {
+        let mut line = String::new();
+        if self.handle()?.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+
+        if line.ends_with('\n') {
+            line.pop();
+            if line.ends_with('\r') {
+                line.pop();
+            }
+        }
+        Ok(Some(line))
+    }
+
+    pub fn write(&mut self, contents: &str) -> std::io::Result<()> {
+        self.handle()?.get_mut().write_all(contents.as_bytes())
     }
 }
 
 #[derive(Clone, Debug)]
 pub struct Instance {
     pub class: Rc<RwLock<Class>>,
-    pub fields: Rc<RwLock<HashMap<String, Value>>>,
+    pub fields: Rc<RwLock<HashMap<Symbol, Value>>>,
 }
 
 impl Instance {
@@ -55,7 +186,7 @@ impl Instance {
 #[derive(Clone, Debug)]
 pub struct Class {
     pub name: String,
-    pub methods: Rc<RwLock<HashMap<String, Box<Closure>>>>,
+    pub methods: Rc<RwLock<HashMap<Symbol, Rc<RwLock<Closure>>>>>,
 }
 
 impl Class {
@@ -73,31 +204,13 @@ impl PartialEq for Class {
     }
 }
 
-#[derive(Clone, Debug)]
-pub struct UpValueObject {
-    pub location: Value,
-    pub closed: bool,
-}
-
-impl PartialEq for UpValueObject {
-    fn eq(&self, other: &Self) -> bool {
-        self.location == other.location
-    }
-}
-
-impl UpValueObject {
-    pub fn new(location: Value) -> Self {
-        UpValueObject {
-            location,
-            closed: false,
-        }
-    }
-}
-
-impl Default for Value {
-    fn default() -> Self {
-        Value::Nil
-    }
+// A variable captured by a closure. While the variable's frame is live the
+// upvalue points at its slot, so every closure sharing it sees assignments;
+// when the variable goes out of scope its value moves into the upvalue.
+#[derive(Clone, Debug, PartialEq)]
+pub enum UpValueObject {
+    Open { frame: usize, slot: usize },
+    Closed(Value),
 }
 
 impl PartialEq for Value {
@@ -105,19 +218,61 @@ impl PartialEq for Value {
         match (self, other) {
             (Value::Float(f1), Value::Float(f2)) => f1 == f2,
             (Value::Int(i1), Value::Int(i2)) => i1 == i2,
+            (Value::Int(i), f @ Value::Float(_)) | (f @ Value::Float(_), Value::Int(i)) => {
+                f.as_exact_int() == Some(*i)
+            }
             (Value::Bool(b1), Value::Bool(b2)) => b1 == b2,
             (Value::Nil, Value::Nil) => true,
             (Value::String(s1), Value::String(s2)) => s1 == s2,
-            (Value::Function(f1), Value::Function(f2)) => {
-                let f1 = f1.read();
-                let f2 = f2.read();
-                f1.eq(&f2)
+            (Value::Symbol(s1), Value::Symbol(s2)) => s1 == s2,
+            (Value::Function(f1), Value::Function(f2)) => Rc::ptr_eq(f1, f2),
+            (Value::Closure(c1), Value::Closure(c2)) => {
+                Rc::ptr_eq(c1, c2) || *c1.read() == *c2.read()
             }
+            (Value::Instance(i1), Value::Instance(i2)) => Rc::ptr_eq(i1, i2),
+            (Value::BoundMethod(b1), Value::BoundMethod(b2)) => {
+                Rc::ptr_eq(b1, b2) || *b1.read() == *b2.read()
+            }
+            (Value::Bytes(b1), Value::Bytes(b2)) => *b1.read() == *b2.read(),
+            (Value::File(f1), Value::File(f2)) => Rc::ptr_eq(f1, f2),
+            (Value::Set(s1), Value::Set(s2)) => Rc::ptr_eq(s1, s2) || *s1.read() == *s2.read(),
+            (Value::Array(a1), Value::Array(a2)) => arrays_equal(a1, a2, &mut Vec::new()),
             _ => false,
         }
     }
 }
 
+type ArrayPtr = *const RwLock<Vec<Value>>;
+
+// Compares arrays element by element. A pair of arrays already being compared
+// further up counts as equal, so arrays that contain themselves terminate.
+fn arrays_equal(
+    a1: &Rc<RwLock<Vec<Value>>>,
+    a2: &Rc<RwLock<Vec<Value>>>,
+    comparing: &mut Vec<(ArrayPtr, ArrayPtr)>,
+) -> bool {
+    let pair = (Rc::as_ptr(a1), Rc::as_ptr(a2));
+    if Rc::ptr_eq(a1, a2) || comparing.contains(&pair) {
+        return true;
+    }
+
+    let (elements1, elements2) = (a1.read(), a2.read());
+    if elements1.len() != elements2.len() {
+        return false;
+    }
+
+    comparing.push(pair);
+    let equal = elements1
+        .iter()
+        .zip(elements2.iter())
+        .all(|elements| match elements {
+            (Value::Array(e1), Value::Array(e2)) => arrays_equal(e1, e2, comparing),
+            (e1, e2) => e1 == e2,
+        });
+    comparing.pop();
+    equal
+}
+
 #[derive(Clone, Debug)]
 pub struct Closure {
     pub function: Rc<RwLock<Function>>,
@@ -139,9 +294,11 @@ impl Closure {
     }
 }
 
+// Functions compare by identity: two distinct functions are unequal even
+// when they share a name.
 impl PartialEq for Closure {
     fn eq(&self, other: &Self) -> bool {
-        self.function.read().name == other.function.read().name
+        Rc::ptr_eq(&self.function, &other.function) && Rc::ptr_eq(&self.up_values, &other.up_values)
     }
 }
 
@@ -151,11 +308,13 @@ pub struct Function {
     pub chunk: Rc<RwLock<Chunk>>,
     pub name: String,
     pub up_value_count: u8,
+    // The last parameter collects any surplus arguments into an array.
+    pub variadic: bool,
 }
 
 impl PartialEq for Function {
     fn eq(&self, other: &Self) -> bool {
-        self.name == other.name
+        Rc::ptr_eq(&self.chunk, &other.chunk)
     }
 }
 
@@ -166,6 +325,7 @@ impl Function {
             chunk: Rc::new(RwLock::new(Chunk::new())),
             name,
             up_value_count: 0,
+            variadic: false,
         }
     }
 
@@ -175,15 +335,58 @@ impl Function {
             chunk: Rc::new(RwLock::new(Chunk::new())),
             name: String::from("script"),
             up_value_count: 0,
+            variadic: false,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn arity(&self) -> usize {
+        self.arity
+    }
+
+    pub fn variadic(&self) -> bool {
+        self.variadic
+    }
+
+    pub fn up_value_count(&self) -> u8 {
+        self.up_value_count
+    }
+
+    pub fn chunk(&self) -> RwLockReadGuard<'_, Chunk> {
+        self.chunk.read()
+    }
+
+    pub fn display_name(&self) -> &str {
+        if self.name.is_empty() {
+            "anonymous"
+        } else {
+            &self.name
         }
     }
 }
 
+impl std::fmt::Display for Function {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "<fn {}>", self.display_name())
+    }
+}
+
+/// The Rust side of a native. Most only see their arguments; the ones that
+/// print also get the VM's output.
+#[derive(Clone, Copy, Debug)]
+pub enum NativeFn {
+    Plain(fn(Vec<Value>) -> Value),
+    Output(fn(Vec<Value>, &mut dyn Write) -> Value),
+}
+
 #[derive(Clone, Debug)]
 pub struct NativeFunction {
     pub name: String,
     pub arity: usize,
-    pub function: Box<fn(Vec<Value>) -> Value>,
+    pub function: NativeFn,
 }
 
 impl PartialEq for NativeFunction {
@@ -193,7 +396,7 @@ impl PartialEq for NativeFunction {
 }
 
 impl NativeFunction {
-    pub fn new(name: String, arity: usize, function: Box<fn(Vec<Value>) -> Value>) -> Self {
+    pub fn new(name: String, arity: usize, function: NativeFn) -> Self {
         NativeFunction {
             name,
             arity,
@@ -211,40 +414,303 @@ pub enum FunctionType {
 }
 
 impl Value {
+    /// Only `nil` and `false` are falsy, as in Lox; every number, zero
+    /// included, is truthy.
     pub fn is_falsely(&self) -> bool {
         match self {
             Value::Nil => true,
             Value::Bool(b) => !b,
-            Value::Int(i) => *i == 0,
-            Value::Float(f) => *f == 0.0,
             _ => false,
         }
     }
+
+    /// The integer a number is exactly equal to, if any: an int, or a float
+    /// with no fractional part that fits in an `i64`. `2` and `2.0` are equal
+    /// because both give `Some(2)`.
+    pub fn as_exact_int(&self) -> Option<i64> {
+        match self {
+            Value::Int(i) => Some(*i),
+            // -2^63 is exact as an f64 but 2^63 - 1 rounds up, so the upper
+            // bound is exclusive.
+            Value::Float(f) if f.fract() == 0.0 && *f >= -(2f64.powi(63)) && *f < 2f64.powi(63) => {
+                Some(*f as i64)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Int(_) => "int",
+            Value::Float(_) => "float",
+            Value::Bool(_) => "bool",
+            Value::Nil => "nil",
+            Value::String(_) => "string",
+            Value::Symbol(_) => "symbol",
+            Value::Function(_) | Value::Closure(_) | Value::BoundMethod(_) => "function",
+            Value::NativeFunction(_) => "native function",
+            Value::RunTimeError(..) => "error",
+            Value::Class(_) => "class",
+            Value::Instance(_) => "instance",
+            Value::Bytes(_) => "bytes",
+            Value::File(_) => "file",
+            Value::Set(_) => "set",
+            Value::Array(_) => "array",
+        }
+    }
 }
 
 impl std::fmt::Display for Value {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
             Value::Int(i) => write!(f, "{}", i),
-            Value::Float(fl) => write!(f, "{:.?}", fl),
+            // Shortest round-tripping digits, never in exponent form, and
+            // with a decimal point so floats stay apart from ints.
+            Value::Float(fl) if fl.is_finite() && fl.fract() == 0.0 => write!(f, "{}.0", fl),
+            Value::Float(fl) => write!(f, "{}", fl),
             Value::Bool(b) => write!(f, "{}", b),
             Value::Nil => write!(f, "nil"),
             Value::String(s) => write!(f, "{}", s),
-            Value::Function(func) => write!(f, "<fn {}>", func.read().name),
-            Value::Closure(closure) => write!(f, "<fn {}>", closure.function.read().name),
+            Value::Symbol(s) => write!(f, "{}", s),
+            Value::Function(func) => write!(f, "{}", func.read()),
+            Value::Closure(closure) => write!(f, "{}", closure.read().function.read()),
             Value::NativeFunction(func) => write!(f, "<native fn {}>", func.read().name),
-            Value::RunTimeError(s) => write!(f, "{}", s),
+            Value::RunTimeError(_, s) => write!(f, "{}", s),
             Value::Class(class) => write!(f, "<class {}>", class.read().name),
             Value::Instance(instance) => {
                 write!(f, "<instance {}>", instance.read().class.read().name)
             }
             Value::BoundMethod(bound_method) => {
-                write!(
-                    f,
-                    "<bound method {}>",
-                    bound_method.read().method.function.read().name
-                )
+                let function = bound_method.read().method.read().function.clone();
+                let function = function.read();
+                write!(f, "<bound method {}>", function.display_name())
             }
+            Value::Bytes(bytes) => write!(f, "<bytes {}>", bytes.read().len()),
+            Value::File(file) => write!(f, "<file {}>", file.read().path),
+            Value::Set(set) => write!(f, "<set {}>", set.read().len()),
+            Value::Array(array) => write_array(f, array, &mut Vec::new()),
+        }
+    }
+}
+
+// Writes `array` as `[a, b, c]`. An array already being written further up
+// shows as `[...]`, so arrays that contain themselves terminate.
+fn write_array(
+    f: &mut std::fmt::Formatter,
+    array: &Rc<RwLock<Vec<Value>>>,
+    writing: &mut Vec<ArrayPtr>,
+) -> std::fmt::Result {
+    if writing.contains(&Rc::as_ptr(array)) {
+        return write!(f, "[...]");
+    }
+
+    writing.push(Rc::as_ptr(array));
+    write!(f, "[")?;
+    for (i, element) in array.read().iter().enumerate() {
+        if i > 0 {
+            write!(f, ", ")?;
+        }
+        match element {
+            Value::Array(nested) => write_array(f, nested, writing)?,
+            element => write!(f, "{}", element)?,
+        }
+    }
+    writing.pop();
+    write!(f, "]")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn closure(function: &Rc<RwLock<Function>>) -> Value {
+        Value::Closure(Rc::new(RwLock::new(Closure::new(function.clone()))))
+    }
+
+    #[test]
+    fn functions_compare_by_identity() {
+        let first = Rc::new(RwLock::new(Function::new(String::new())));
+        let second = Rc::new(RwLock::new(Function::new(String::new())));
+        assert_eq!(
+            Value::Function(first.clone()),
+            Value::Function(first.clone())
+        );
+        assert_ne!(
+            Value::Function(first.clone()),
+            Value::Function(second.clone())
+        );
+
+        let shared = closure(&first);
+        assert_eq!(shared, shared.clone());
+        assert_ne!(closure(&first), closure(&second));
+        // The same function closed over twice captures separate variables.
+        assert_ne!(closure(&first), closure(&first));
+    }
+
+    #[test]
+    fn functions_display_a_label_even_without_a_name() {
+        let anonymous = Rc::new(RwLock::new(Function::new(String::new())));
+        let named = Rc::new(RwLock::new(Function::new(String::from("add"))));
+        assert_eq!(
+            Value::Function(anonymous.clone()).to_string(),
+            "<fn anonymous>"
+        );
+        assert_eq!(closure(&anonymous).to_string(), "<fn anonymous>");
+        assert_eq!(closure(&named).to_string(), "<fn add>");
+        assert_eq!(
+            Value::RunTimeError(ErrorKind::Type, String::from("Bad operand")).to_string(),
+            "Bad operand"
+        );
+    }
+
+    fn key(value: Value) -> HashKey {
+        HashKey::new(value).expect("value should be hashable")
+    }
+
+    fn hash_of(key: &HashKey) -> u64 {
+        use std::hash::{Hash, Hasher};
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        key.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    #[test]
+    fn equal_keys_hash_alike() {
+        let same = [
+            (Value::Float(0.0), Value::Float(-0.0)),
+            (Value::Float(f64::NAN), Value::Float(-f64::NAN)),
+            (Value::Int(1), Value::Float(1.0)),
+            (Value::Int(-3), Value::Float(-3.0)),
+            (
+                Value::String("a".to_string()),
+                Value::String("a".to_string()),
+            ),
+        ];
+        for (a, b) in same {
+            let (a, b) = (key(a), key(b));
+            assert_eq!(a, b);
+            assert_eq!(hash_of(&a), hash_of(&b));
+        }
+
+        assert_ne!(key(Value::Int(1)), key(Value::Float(1.5)));
+        assert_ne!(key(Value::Int(1)), key(Value::Bool(true)));
+        assert_ne!(key(Value::Nil), key(Value::Bool(false)));
+    }
+
+    #[test]
+    fn only_some_values_are_hashable() {
+        let function = Rc::new(RwLock::new(Function::new(String::new())));
+        assert!(HashKey::new(Value::Function(function.clone())).is_none());
+        assert!(HashKey::new(closure(&function)).is_none());
+        let class = Rc::new(RwLock::new(Class::new(String::from("C"))));
+        let instance = Instance::new(class);
+        assert!(HashKey::new(Value::Instance(Rc::new(RwLock::new(instance)))).is_none());
+        assert!(HashKey::new(Value::Symbol(crate::symbol::Symbol::INIT)).is_some());
+    }
+
+    fn array(elements: Vec<Value>) -> Value {
+        Value::Array(Rc::new(RwLock::new(elements)))
+    }
+
+    #[test]
+    fn arrays_display_and_compare_element_wise() {
+        let nested = array(vec![
+            Value::Int(1),
+            array(vec![Value::String("a".to_string()), Value::Nil]),
+            Value::Float(2.5),
+        ]);
+        assert_eq!(nested.to_string(), "[1, [a, nil], 2.5]");
+        assert_eq!(array(vec![]).to_string(), "[]");
+
+        assert_eq!(
+            array(vec![Value::Int(1), array(vec![Value::Int(2)])]),
+            array(vec![Value::Int(1), array(vec![Value::Int(2)])])
+        );
+        assert_ne!(
+            array(vec![Value::Int(1), Value::Int(2)]),
+            array(vec![Value::Int(2), Value::Int(1)])
+        );
+        assert_ne!(array(vec![Value::Int(1)]), array(vec![]));
+        assert_eq!(nested, nested.clone());
+    }
+
+    // An array holding `first` followed by itself.
+    fn self_containing(first: Value) -> Value {
+        let array = Rc::new(RwLock::new(vec![first]));
+        array.write().push(Value::Array(array.clone()));
+        Value::Array(array)
+    }
+
+    #[test]
+    fn self_containing_arrays_display_a_placeholder() {
+        let a = self_containing(Value::Int(1));
+        assert_eq!(a.to_string(), "[1, [...]]");
+        // Only arrays being written further up are elided, not repeats.
+        assert_eq!(
+            array(vec![a.clone(), a.clone()]).to_string(),
+            "[[1, [...]], [1, [...]]]"
+        );
+    }
+
+    #[test]
+    fn self_containing_arrays_compare() {
+        let a = self_containing(Value::Int(1));
+        assert_eq!(a, a.clone());
+        assert_eq!(a, self_containing(Value::Int(1)));
+        assert_ne!(a, self_containing(Value::Int(2)));
+    }
+
+    #[test]
+    fn floats_display_without_exponents() {
+        let cases = [
+            (0.5, "0.5"),
+            (3.0, "3.0"),
+            (2.5 * 2.0, "5.0"),
+            (1.0 / 3.0, "0.3333333333333333"),
+            (0.1 + 0.2, "0.30000000000000004"),
+            (1e21, "1000000000000000000000.0"),
+            (1e-7, "0.0000001"),
+            (-0.0, "-0.0"),
+            (f64::INFINITY, "inf"),
+            (f64::NAN, "NaN"),
+        ];
+        for (float, shown) in cases {
+            assert_eq!(Value::Float(float).to_string(), shown);
+        }
+    }
+
+    #[test]
+    fn ints_equal_floats_of_the_same_value() {
+        assert_eq!(Value::Int(2), Value::Float(2.0));
+        assert_eq!(Value::Float(-0.0), Value::Int(0));
+        assert_ne!(Value::Int(2), Value::Float(2.5));
+        assert_ne!(Value::Int(0), Value::Float(f64::NAN));
+        // Compared exactly, not by rounding the int to a float.
+        assert_ne!(
+            Value::Int(9007199254740993),
+            Value::Float(9007199254740992.0)
+        );
+        assert_eq!(
+            Value::Int(9007199254740992),
+            Value::Float(9007199254740992.0)
+        );
+        assert_ne!(Value::Int(i64::MAX), Value::Float(2f64.powi(63)));
+        assert_eq!(Value::Int(i64::MIN), Value::Float(-(2f64.powi(63))));
+    }
+
+    #[test]
+    fn only_nil_and_false_are_falsy() {
+        assert!(Value::Nil.is_falsely());
+        assert!(Value::Bool(false).is_falsely());
+        for truthy in [
+            Value::Bool(true),
+            Value::Int(0),
+            Value::Float(0.0),
+            Value::Float(f64::NAN),
+            Value::String(String::new()),
+            array(vec![]),
+        ] {
+            assert!(!truthy.is_falsely(), "{}", truthy);
         }
     }
 }
@@ -1,37 +1,175 @@
+use crate::chunk;
 use crate::chunk::OpCode;
 use crate::compiler::Compiler;
+use crate::debug;
+use crate::gc::Heap;
 use crate::scanner::Scanner;
+use crate::symbol::Symbol;
 use crate::value;
-use crate::value::{Closure, FunctionType, Value};
+use crate::value::{Closure, Function, FunctionType, HashKey, Value};
 use parking_lot::RwLock;
-use std::collections::HashMap;
-use std::io::Read;
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
 use std::rc::Rc;
 
 pub const DEBUG_PRINT_CODE: bool = false;
 pub const DEBUG_TRACE_EXECUTION: bool = false;
+// Verify after compiling each function that every statement leaves the
+// stack as deep as the locals in scope.
+pub const DEBUG_CHECK_STACK: bool = false;
 
-pub const FRAMES_MAX: usize = 64;
-pub const STACK_MAX: usize = 256;
+// Maximum call depth. The frame stack is allocated up front with this
+// capacity, so recursing up to the limit never reallocates it.
+pub const FRAMES_MAX: usize = 4096;
+// Slots a frame starts out with room for; frames holding more grow as needed.
+const FRAME_SLOTS: usize = 256;
+// Maximum number of values on the stack, counted across every frame.
+// Pushing past it is a runtime error rather than unbounded host memory.
+pub const STACK_MAX: usize = FRAMES_MAX * FRAME_SLOTS;
 
-#[derive(PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ErrorKind {
+    Type,
+    Name,
+    Arity,
+    DivByZero,
+    Overflow,
+    Argument,
+    Index,
+    Io,
+    // Raised by `throw`, for programs to handle.
+    Thrown,
+    // Raised by `abort`; always ends the run, even once errors can be caught.
+    Abort,
+    Assertion,
+    Internal,
+}
+
+#[derive(Debug, PartialEq)]
 pub enum InterpretResult {
     Ok,
     CompileError,
-    RuntimeError,
+    // The error's kind and its report: the message followed by the backtrace.
+    RuntimeError(ErrorKind, String),
+}
+
+/// How `print` writes numbers: an optional thousands separator and the
+/// decimal point. The default is plain, as in `1234.5`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct NumberFormat {
+    pub thousands: Option<char>,
+    pub decimal: char,
+}
+
+impl Default for NumberFormat {
+    fn default() -> Self {
+        NumberFormat {
+            thousands: None,
+            decimal: '.',
+        }
+    }
+}
+
+impl NumberFormat {
+    // Reformats a number as `Display` writes it. Anything without leading
+    // digits, like `inf` or `NaN`, is left alone.
+    fn format(&self, plain: &str) -> String {
+        let (sign, unsigned) = match plain.strip_prefix('-') {
+            Some(unsigned) => ("-", unsigned),
+            None => ("", plain),
+        };
+        let digits = unsigned.len()
+            - unsigned
+                .trim_start_matches(|c: char| c.is_ascii_digit())
+                .len();
+        if digits == 0 {
+            return plain.to_string();
+        }
+
+        let mut formatted = String::from(sign);
+        for (i, digit) in unsigned[..digits].chars().enumerate() {
+            if let Some(separator) = self.thousands {
+                if i > 0 && (digits - i) % 3 == 0 {
+                    formatted.push(separator);
+                }
+            }
+            formatted.push(digit);
+        }
+
+        let rest = &unsigned[digits..];
+        match rest.strip_prefix('.') {
+            Some(fraction) => {
+                formatted.push(self.decimal);
+                formatted.push_str(fraction);
+            }
+            None => formatted.push_str(rest),
+        }
+        formatted
+    }
 }
 
 pub struct VM {
-    globals: HashMap<String, Value>,
+    globals: HashMap<Symbol, Value>,
     frames: Vec<CallFrame>,
+    // Upvalues still pointing at a live frame's slot.
+    open_up_values: Vec<Rc<RwLock<value::UpValueObject>>>,
+    heap: Heap,
     stack: Vec<Value>,
+    // Values live across all frames' slots, and the most ever live at once.
+    stack_depth: usize,
+    max_stack_depth: usize,
+    // Flush after every `print`, so output interleaved with prompts shows up
+    // immediately.
+    interactive: bool,
+    // What the last script returned: nil, unless `eval` kept a trailing
+    // expression's value.
+    result: Value,
+    // Globals declared `const`, kept across scripts so a later REPL line
+    // can't assign to one either.
+    const_globals: Rc<RwLock<HashSet<String>>>,
+    number_format: NumberFormat,
+    // Where `print` and execution tracing go: stdout unless an embedder passes
+    // its own sink to `with_writer`.
+    out: Box<dyn Write>,
+    // Whether runtime errors are printed to stderr as they happen. Either way
+    // the report comes back in `InterpretResult::RuntimeError`.
+    report_errors: bool,
+    error_report: String,
+}
+
+/// An in-memory sink for `VM::with_writer`. Clones share the same buffer, so
+/// one can be kept to read back what the VM printed.
+#[derive(Clone, Default)]
+pub struct OutputBuffer(Rc<RwLock<Vec<u8>>>);
+
+impl OutputBuffer {
+    pub fn new() -> Self {
+        OutputBuffer::default()
+    }
+
+    pub fn contents(&self) -> String {
+        String::from_utf8_lossy(&self.0.read()).into_owned()
+    }
+}
+
+impl Write for OutputBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.write().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
 }
 
 #[derive(Clone, Debug)]
 pub struct CallFrame {
-    closure: Box<Closure>,
+    closure: Rc<RwLock<Closure>>,
     ip: usize,
     slots: Vec<Value>,
+    // Files opened by `with` in this frame, closed if an error unwinds it.
+    resources: Vec<Rc<RwLock<value::File>>>,
 }
 
 pub fn clock_native(_: Vec<Value>) -> Value {
@@ -43,14 +181,148 @@ pub fn clock_native(_: Vec<Value>) -> Value {
     )
 }
 
+// Numeric kind rule for math natives: functions whose result is exact for
+// their input (abs, min, max, ...) return the same kind they receive, so an
+// `Int` stays an `Int`; functions with inexact results (sqrt, trigonometry,
+// logarithms, ...) always return a `Float`.
+
+pub fn abs_native(args: Vec<Value>) -> Value {
+    match args[0] {
+        Value::Int(i) => match i.checked_abs() {
+            Some(i) => Value::Int(i),
+            None => Value::RunTimeError(ErrorKind::Overflow, "Integer overflow in abs".to_string()),
+        },
+        Value::Float(f) => Value::Float(f.abs()),
+        _ => Value::RunTimeError(ErrorKind::Type, "Abs argument must be a number".to_string()),
+    }
+}
+
 pub fn sqrt_native(args: Vec<Value>) -> Value {
     match args[0] {
         Value::Float(f) => Value::Float(f.sqrt()),
         Value::Int(i) => Value::Float((i as f64).sqrt()),
-        _ => Value::RunTimeError("Sqrt argument must be a number".to_string()),
+        _ => Value::RunTimeError(
+            ErrorKind::Type,
+            "Sqrt argument must be a number".to_string(),
+        ),
+    }
+}
+
+// The argument as a float, for the math natives that always return one.
+fn float_arg(value: &Value, name: &str) -> Result<f64, Value> {
+    match value {
+        Value::Int(i) => Ok(*i as f64),
+        Value::Float(f) => Ok(*f),
+        _ => Err(Value::RunTimeError(
+            ErrorKind::Type,
+            format!("{} argument must be a number", name),
+        )),
+    }
+}
+
+fn float_math_native(args: Vec<Value>, name: &str, f: fn(f64) -> f64) -> Value {
+    match float_arg(&args[0], name) {
+        Ok(x) => Value::Float(f(x)),
+        Err(error) => error,
+    }
+}
+
+// Ints are already whole, so rounding natives return them unchanged.
+fn rounding_native(args: Vec<Value>, name: &str, f: fn(f64) -> f64) -> Value {
+    match args[0] {
+        Value::Int(i) => Value::Int(i),
+        _ => float_math_native(args, name, f),
+    }
+}
+
+pub fn floor_native(args: Vec<Value>) -> Value {
+    rounding_native(args, "Floor", f64::floor)
+}
+
+pub fn ceil_native(args: Vec<Value>) -> Value {
+    rounding_native(args, "Ceil", f64::ceil)
+}
+
+pub fn round_native(args: Vec<Value>) -> Value {
+    rounding_native(args, "Round", f64::round)
+}
+
+pub fn sin_native(args: Vec<Value>) -> Value {
+    float_math_native(args, "Sin", f64::sin)
+}
+
+pub fn cos_native(args: Vec<Value>) -> Value {
+    float_math_native(args, "Cos", f64::cos)
+}
+
+pub fn tan_native(args: Vec<Value>) -> Value {
+    float_math_native(args, "Tan", f64::tan)
+}
+
+pub fn log_native(args: Vec<Value>) -> Value {
+    float_math_native(args, "Log", f64::ln)
+}
+
+// Ints are always finite, which converting them to a float preserves.
+fn float_predicate_native(args: Vec<Value>, name: &str, f: fn(f64) -> bool) -> Value {
+    match float_arg(&args[0], name) {
+        Ok(x) => Value::Bool(f(x)),
+        Err(error) => error,
+    }
+}
+
+pub fn is_nan_native(args: Vec<Value>) -> Value {
+    float_predicate_native(args, "is_nan", f64::is_nan)
+}
+
+pub fn is_infinite_native(args: Vec<Value>) -> Value {
+    float_predicate_native(args, "is_infinite", f64::is_infinite)
+}
+
+pub fn is_finite_native(args: Vec<Value>) -> Value {
+    float_predicate_native(args, "is_finite", f64::is_finite)
+}
+
+// Same rules as `**`: an int to a non-negative int power stays an int.
+pub fn pow_native(args: Vec<Value>) -> Value {
+    match (&args[0], &args[1]) {
+        (Value::Int(base), Value::Int(exponent)) if *exponent >= 0 => {
+            match int_pow(*base, *exponent) {
+                Some(i) => Value::Int(i),
+                None => {
+                    Value::RunTimeError(ErrorKind::Overflow, "Integer overflow in pow".to_string())
+                }
+            }
+        }
+        (base, exponent) => match (float_arg(base, "Pow"), float_arg(exponent, "Pow")) {
+            (Ok(base), Ok(exponent)) => Value::Float(base.powf(exponent)),
+            (Err(error), _) | (_, Err(error)) => error,
+        },
+    }
+}
+
+// Returns whichever argument `pick` chooses, keeping its type.
+fn min_max_native(args: Vec<Value>, name: &str, pick: fn(f64, f64) -> bool) -> Value {
+    match (float_arg(&args[0], name), float_arg(&args[1], name)) {
+        (Ok(a), Ok(b)) => {
+            if pick(a, b) {
+                args[0].clone()
+            } else {
+                args[1].clone()
+            }
+        }
+        (Err(error), _) | (_, Err(error)) => error,
     }
 }
 
+pub fn min_native(args: Vec<Value>) -> Value {
+    min_max_native(args, "Min", |a, b| a <= b)
+}
+
+pub fn max_native(args: Vec<Value>) -> Value {
+    min_max_native(args, "Max", |a, b| a >= b)
+}
+
 pub fn input_native(_: Vec<Value>) -> Value {
     let mut input = String::new();
     std::io::stdin()
@@ -60,30 +332,396 @@ pub fn input_native(_: Vec<Value>) -> Value {
 }
 
 pub fn throw_native(args: Vec<Value>) -> Value {
-    Value::RunTimeError(args[0].to_string())
+    Value::RunTimeError(ErrorKind::Thrown, args[0].to_string())
+}
+
+pub fn abort_native(args: Vec<Value>) -> Value {
+    Value::RunTimeError(ErrorKind::Abort, args[0].to_string())
 }
 
 pub fn open_file_native(args: Vec<Value>) -> Value {
     match &args[0] {
-        Value::String(s) => match std::fs::File::open(s.clone()) {
-            Ok(file) => {
-                let mut file = std::io::BufReader::new(file);
-                let mut contents = String::new();
-                file.read_to_string(&mut contents)
-                    .expect("Failed to read file");
+        Value::String(s) => match std::fs::File::open(s) {
+            Ok(file) => Value::File(Rc::new(RwLock::new(value::File::new(s.clone(), file)))),
+            Err(_) => Value::RunTimeError(ErrorKind::Io, format!("Failed to open file '{}'", s)),
+        },
+        _ => Value::RunTimeError(ErrorKind::Type, "Expected string".to_string()),
+    }
+}
+
+pub fn create_file_native(args: Vec<Value>) -> Value {
+    match &args[0] {
+        Value::String(s) => match std::fs::File::create(s) {
+            Ok(file) => Value::File(Rc::new(RwLock::new(value::File::new(s.clone(), file)))),
+            Err(_) => Value::RunTimeError(ErrorKind::Io, format!("Failed to create file '{}'", s)),
+        },
+        _ => Value::RunTimeError(ErrorKind::Type, "Expected string".to_string()),
+    }
+}
+
+pub fn read_bytes_native(args: Vec<Value>) -> Value {
+    match &args[0] {
+        Value::String(s) => match std::fs::read(s) {
+            Ok(bytes) => Value::Bytes(Rc::new(RwLock::new(bytes))),
+            Err(_) => Value::RunTimeError(ErrorKind::Io, format!("Failed to read file '{}'", s)),
+        },
+        _ => Value::RunTimeError(ErrorKind::Type, "Expected string".to_string()),
+    }
+}
+
+pub fn write_bytes_native(args: Vec<Value>) -> Value {
+    match (&args[0], &args[1]) {
+        (Value::String(s), Value::Bytes(bytes)) => match std::fs::write(s, &*bytes.read()) {
+            Ok(_) => Value::Bool(true),
+            Err(_) => Value::RunTimeError(ErrorKind::Io, format!("Failed to write file '{}'", s)),
+        },
+        _ => Value::RunTimeError(ErrorKind::Type, "Expected string and bytes".to_string()),
+    }
+}
+
+pub fn write_file_native(args: Vec<Value>) -> Value {
+    match (&args[0], &args[1]) {
+        (Value::String(path), Value::String(contents)) => match std::fs::write(path, contents) {
+            Ok(_) => Value::Bool(true),
+            Err(_) => {
+                Value::RunTimeError(ErrorKind::Io, format!("Failed to write file '{}'", path))
+            }
+        },
+        _ => Value::RunTimeError(ErrorKind::Type, "Expected string and string".to_string()),
+    }
+}
+
+pub fn append_file_native(args: Vec<Value>) -> Value {
+    match (&args[0], &args[1]) {
+        (Value::String(path), Value::String(contents)) => {
+            let appended = std::fs::OpenOptions::new()
+                .append(true)
+                .create(true)
+                .open(path)
+                .and_then(|mut file| file.write_all(contents.as_bytes()));
+            match appended {
+                Ok(_) => Value::Bool(true),
+                Err(_) => Value::RunTimeError(
+                    ErrorKind::Io,
+                    format!("Failed to append to file '{}'", path),
+                ),
+            }
+        }
+        _ => Value::RunTimeError(ErrorKind::Type, "Expected string and string".to_string()),
+    }
+}
+
+pub fn len_native(args: Vec<Value>) -> Value {
+    match &args[0] {
+        Value::String(s) => Value::Int(s.chars().count() as i64),
+        Value::Bytes(bytes) => Value::Int(bytes.read().len() as i64),
+        Value::Set(set) => Value::Int(set.read().len() as i64),
+        Value::Array(array) => Value::Int(array.read().len() as i64),
+        _ => Value::RunTimeError(
+            ErrorKind::Type,
+            "Expected string, bytes, set or array".to_string(),
+        ),
+    }
+}
+
+pub fn set_native(_: Vec<Value>) -> Value {
+    Value::Set(Rc::new(RwLock::new(HashSet::new())))
+}
+
+// Runs `f` on the set and element key, for the set natives.
+fn with_set_key(args: Vec<Value>, f: fn(&mut HashSet<HashKey>, HashKey) -> bool) -> Value {
+    match &args[0] {
+        Value::Set(set) => match HashKey::new(args[1].clone()) {
+            Some(key) => Value::Bool(f(&mut set.write(), key)),
+            None => Value::RunTimeError(
+                ErrorKind::Type,
+                format!("Unhashable type: {}", args[1].type_name()),
+            ),
+        },
+        value => Value::RunTimeError(
+            ErrorKind::Type,
+            format!("Expected set, got {}", value.type_name()),
+        ),
+    }
+}
+
+pub fn add_native(args: Vec<Value>) -> Value {
+    with_set_key(args, |set, key| set.insert(key))
+}
+
+pub fn has_native(args: Vec<Value>) -> Value {
+    with_set_key(args, |set, key| set.contains(&key))
+}
+
+pub fn remove_native(args: Vec<Value>) -> Value {
+    with_set_key(args, |set, key| set.remove(&key))
+}
+
+// Floats truncate toward zero; strings parse as decimal integers.
+pub fn int_native(args: Vec<Value>) -> Value {
+    match &args[0] {
+        Value::Int(i) => Value::Int(*i),
+        Value::Float(f) if f.is_finite() && f.trunc().abs() < i64::MAX as f64 => {
+            Value::Int(*f as i64)
+        }
+        Value::Float(f) => Value::RunTimeError(
+            ErrorKind::Argument,
+            format!("Cannot convert {:?} to int", f),
+        ),
+        Value::String(s) => match s.trim().parse::<i64>() {
+            Ok(i) => Value::Int(i),
+            Err(_) => Value::RunTimeError(
+                ErrorKind::Argument,
+                format!("Cannot convert '{}' to int", s),
+            ),
+        },
+        value => Value::RunTimeError(
+            ErrorKind::Type,
+            format!("Cannot convert {} to int", value.type_name()),
+        ),
+    }
+}
+
+pub fn float_native(args: Vec<Value>) -> Value {
+    match &args[0] {
+        Value::Int(i) => Value::Float(*i as f64),
+        Value::Float(f) => Value::Float(*f),
+        Value::String(s) => match s.trim().parse::<f64>() {
+            Ok(f) => Value::Float(f),
+            Err(_) => Value::RunTimeError(
+                ErrorKind::Argument,
+                format!("Cannot convert '{}' to float", s),
+            ),
+        },
+        value => Value::RunTimeError(
+            ErrorKind::Type,
+            format!("Cannot convert {} to float", value.type_name()),
+        ),
+    }
+}
+
+pub fn str_native(args: Vec<Value>) -> Value {
+    Value::String(args[0].to_string())
+}
+
+// Applies `f` to the single string argument of upper, lower and trim.
+fn string_map_native(args: Vec<Value>, f: fn(&str) -> String) -> Value {
+    match &args[0] {
+        Value::String(s) => Value::String(f(s)),
+        value => Value::RunTimeError(
+            ErrorKind::Type,
+            format!("Expected string, got {}", value.type_name()),
+        ),
+    }
+}
+
+pub fn upper_native(args: Vec<Value>) -> Value {
+    string_map_native(args, str::to_uppercase)
+}
+
+pub fn lower_native(args: Vec<Value>) -> Value {
+    string_map_native(args, str::to_lowercase)
+}
+
+pub fn trim_native(args: Vec<Value>) -> Value {
+    string_map_native(args, |s| s.trim().to_string())
+}
+
+// An empty separator splits the string into its characters.
+pub fn split_native(args: Vec<Value>) -> Value {
+    let parts: Vec<Value> = match (&args[0], &args[1]) {
+        (Value::String(s), Value::String(sep)) if sep.is_empty() => {
+            s.chars().map(|c| Value::String(c.to_string())).collect()
+        }
+        (Value::String(s), Value::String(sep)) => s
+            .split(sep.as_str())
+            .map(|part| Value::String(part.to_string()))
+            .collect(),
+        _ => return Value::RunTimeError(ErrorKind::Type, "Expected string and string".to_string()),
+    };
+    Value::Array(Rc::new(RwLock::new(parts)))
+}
+
+pub fn replace_native(args: Vec<Value>) -> Value {
+    match (&args[0], &args[1], &args[2]) {
+        (Value::String(s), Value::String(from), Value::String(to)) => {
+            Value::String(s.replace(from.as_str(), to))
+        }
+        _ => Value::RunTimeError(
+            ErrorKind::Type,
+            "Expected string, string and string".to_string(),
+        ),
+    }
+}
+
+// Indices count characters and are clamped to 0..=len, so a negative start
+// reads from the beginning and an end past the string reads to its end; an
+// end at or before start gives "".
+pub fn substring_native(args: Vec<Value>) -> Value {
+    match (&args[0], &args[1], &args[2]) {
+        (Value::String(s), Value::Int(start), Value::Int(end)) => {
+            let len = s.chars().count() as i64;
+            let start = (*start).clamp(0, len) as usize;
+            let end = (*end).clamp(0, len) as usize;
+            Value::String(
+                s.chars()
+                    .skip(start)
+                    .take(end.saturating_sub(start))
+                    .collect(),
+            )
+        }
+        _ => Value::RunTimeError(ErrorKind::Type, "Expected string, int and int".to_string()),
+    }
+}
 
-                Value::String(contents)
+pub fn ord_native(args: Vec<Value>) -> Value {
+    match &args[0] {
+        Value::String(s) => match s.chars().next() {
+            Some(c) => Value::Int(c as i64),
+            None => {
+                Value::RunTimeError(ErrorKind::Argument, "Expected non-empty string".to_string())
             }
-            Err(_) => Value::RunTimeError(format!("Failed to open file '{}'", s)),
         },
-        _ => Value::RunTimeError("Expected string".to_string()),
+        value => Value::RunTimeError(
+            ErrorKind::Type,
+            format!("Expected string, got {}", value.type_name()),
+        ),
+    }
+}
+
+pub fn chr_native(args: Vec<Value>) -> Value {
+    match &args[0] {
+        Value::Int(i) => match u32::try_from(*i).ok().and_then(char::from_u32) {
+            Some(c) => Value::String(c.to_string()),
+            None => Value::RunTimeError(ErrorKind::Argument, format!("Invalid code point: {}", i)),
+        },
+        value => Value::RunTimeError(
+            ErrorKind::Type,
+            format!("Expected int, got {}", value.type_name()),
+        ),
+    }
+}
+
+// Returns the bytecode listing of a function, the way DEBUG_PRINT_CODE shows it.
+pub fn dis_native(args: Vec<Value>) -> Value {
+    let function = match &args[0] {
+        Value::Closure(closure) => closure.read().function.clone(),
+        Value::Function(function) => function.clone(),
+        _ => return Value::RunTimeError(ErrorKind::Type, "Expected function".to_string()),
+    };
+
+    let function = function.read();
+    let listing = debug::disassemble(&function.chunk.read(), function.display_name(), None);
+    Value::String(listing)
+}
+
+// Instances report their class name; everything else its type name.
+pub fn type_native(args: Vec<Value>) -> Value {
+    match &args[0] {
+        Value::Instance(instance) => Value::String(instance.read().class.read().name.clone()),
+        value => Value::String(value.type_name().to_string()),
+    }
+}
+
+// Unlike `type`, instances report "instance" rather than their class name.
+pub fn typeof_native(args: Vec<Value>) -> Value {
+    Value::String(args[0].type_name().to_string())
+}
+
+pub fn parse_int_native(args: Vec<Value>) -> Value {
+    match (&args[0], &args[1]) {
+        (Value::String(s), Value::Int(radix)) => {
+            if !(2..=36).contains(radix) {
+                return Value::RunTimeError(
+                    ErrorKind::Argument,
+                    format!("Radix must be between 2 and 36, got {}", radix),
+                );
+            }
+
+            match i64::from_str_radix(s, *radix as u32) {
+                Ok(i) => Value::Int(i),
+                Err(_) => Value::RunTimeError(
+                    ErrorKind::Argument,
+                    format!("Invalid digits for radix {}: '{}'", radix, s),
+                ),
+            }
+        }
+        _ => Value::RunTimeError(ErrorKind::Type, "Expected string and int".to_string()),
+    }
+}
+
+pub fn assert_native(args: Vec<Value>) -> Value {
+    if args[0].is_falsely() {
+        Value::RunTimeError(ErrorKind::Assertion, "Assertion failed".to_string())
+    } else {
+        Value::Nil
+    }
+}
+
+pub fn assert_eq_native(args: Vec<Value>) -> Value {
+    if args[0] == args[1] {
+        Value::Nil
+    } else {
+        Value::RunTimeError(
+            ErrorKind::Assertion,
+            format!("Assertion failed: {} != {}", args[0], args[1]),
+        )
+    }
+}
+
+// Like print but without the trailing newline; call flush to force it out.
+pub fn write_native(args: Vec<Value>, out: &mut dyn Write) -> Value {
+    match write!(out, "{}", args[0]) {
+        Ok(_) => Value::Nil,
+        Err(_) => Value::RunTimeError(ErrorKind::Io, "Failed to write output".to_string()),
+    }
+}
+
+pub fn flush_native(_: Vec<Value>, out: &mut dyn Write) -> Value {
+    match out.flush() {
+        Ok(_) => Value::Nil,
+        Err(_) => Value::RunTimeError(ErrorKind::Io, "Failed to flush output".to_string()),
     }
 }
 
 pub fn exit_native(args: Vec<Value>) -> Value {
     match args[0] {
         Value::Int(i) => std::process::exit(i as i32),
-        _ => Value::RunTimeError("Expected int".to_string()),
+        _ => Value::RunTimeError(ErrorKind::Type, "Expected int".to_string()),
+    }
+}
+
+fn operator_symbol(op: OpCode) -> &'static str {
+    match op {
+        OpCode::Add => "+",
+        OpCode::Subtract => "-",
+        OpCode::Multiply => "*",
+        OpCode::Divide => "/",
+        OpCode::Modulo => "%",
+        OpCode::Power => "**",
+        OpCode::Greater => ">",
+        OpCode::Less => "<",
+        _ => "?",
+    }
+}
+
+// `base ** exponent` for a non-negative exponent, or None if it overflows.
+// Exponents too big for `checked_pow` only fit for bases 0, 1 and -1.
+fn int_pow(base: i64, exponent: i64) -> Option<i64> {
+    match u32::try_from(exponent) {
+        Ok(exponent) => base.checked_pow(exponent),
+        Err(_) => match base {
+            0 | 1 => Some(base),
+            -1 if exponent % 2 == 0 => Some(1),
+            -1 => Some(-1),
+            _ => None,
+        },
+    }
+}
+
+impl Default for VM {
+    fn default() -> Self {
+        VM::new()
     }
 }
 
@@ -92,56 +730,253 @@ impl VM {
         let mut vm = VM {
             globals: HashMap::new(),
             frames: Vec::with_capacity(FRAMES_MAX),
-            stack: Vec::with_capacity(STACK_MAX),
+            open_up_values: Vec::new(),
+            heap: Heap::new(),
+            stack: Vec::with_capacity(FRAME_SLOTS),
+            stack_depth: 0,
+            max_stack_depth: 0,
+            interactive: false,
+            result: Value::Nil,
+            const_globals: Rc::new(RwLock::new(HashSet::new())),
+            number_format: NumberFormat::default(),
+            out: Box::new(std::io::stdout()),
+            report_errors: true,
+            error_report: String::new(),
         };
 
-        vm.define_native("clock".to_string(), Box::new(clock_native), 0);
-        vm.define_native("sqrt".to_string(), Box::new(sqrt_native), 1);
-        vm.define_native("input".to_string(), Box::new(input_native), 0);
-        vm.define_native("throw".to_string(), Box::new(throw_native), 1);
-        vm.define_native("open".to_string(), Box::new(open_file_native), 1);
-        vm.define_native("exit".to_string(), Box::new(exit_native), 1);
+        vm.define_native("clock".to_string(), clock_native, 0);
+        vm.define_native("sqrt".to_string(), sqrt_native, 1);
+        vm.define_native("abs".to_string(), abs_native, 1);
+        vm.define_native("floor".to_string(), floor_native, 1);
+        vm.define_native("ceil".to_string(), ceil_native, 1);
+        vm.define_native("round".to_string(), round_native, 1);
+        vm.define_native("pow".to_string(), pow_native, 2);
+        vm.define_native("min".to_string(), min_native, 2);
+        vm.define_native("max".to_string(), max_native, 2);
+        vm.define_native("sin".to_string(), sin_native, 1);
+        vm.define_native("cos".to_string(), cos_native, 1);
+        vm.define_native("tan".to_string(), tan_native, 1);
+        vm.define_native("log".to_string(), log_native, 1);
+        vm.define_native("is_nan".to_string(), is_nan_native, 1);
+        vm.define_native("is_infinite".to_string(), is_infinite_native, 1);
+        vm.define_native("is_finite".to_string(), is_finite_native, 1);
+        vm.define_native("input".to_string(), input_native, 0);
+        vm.define_native("throw".to_string(), throw_native, 1);
+        vm.define_native("abort".to_string(), abort_native, 1);
+        vm.define_native("open".to_string(), open_file_native, 1);
+        vm.define_native("create".to_string(), create_file_native, 1);
+        vm.define_native("exit".to_string(), exit_native, 1);
+        vm.define_native("read_bytes".to_string(), read_bytes_native, 1);
+        vm.define_native("write_bytes".to_string(), write_bytes_native, 2);
+        vm.define_native("write_file".to_string(), write_file_native, 2);
+        vm.define_native("append_file".to_string(), append_file_native, 2);
+        vm.define_native("len".to_string(), len_native, 1);
+        vm.define_native("type".to_string(), type_native, 1);
+        vm.define_native("typeof".to_string(), typeof_native, 1);
+        vm.define_native("dis".to_string(), dis_native, 1);
+        vm.define_native("int".to_string(), int_native, 1);
+        vm.define_native("float".to_string(), float_native, 1);
+        vm.define_native("str".to_string(), str_native, 1);
+        vm.define_native("upper".to_string(), upper_native, 1);
+        vm.define_native("lower".to_string(), lower_native, 1);
+        vm.define_native("trim".to_string(), trim_native, 1);
+        vm.define_native("split".to_string(), split_native, 2);
+        vm.define_native("replace".to_string(), replace_native, 3);
+        vm.define_native("substring".to_string(), substring_native, 3);
+        vm.define_native("ord".to_string(), ord_native, 1);
+        vm.define_native("chr".to_string(), chr_native, 1);
+        vm.define_native("parse_int".to_string(), parse_int_native, 2);
+        vm.define_native("assert".to_string(), assert_native, 1);
+        vm.define_native("assert_eq".to_string(), assert_eq_native, 2);
+        vm.define_output_native("write".to_string(), write_native, 1);
+        vm.define_output_native("flush".to_string(), flush_native, 0);
+        vm.define_native("set".to_string(), set_native, 0);
+        vm.define_native("add".to_string(), add_native, 2);
+        vm.define_native("has".to_string(), has_native, 2);
+        vm.define_native("remove".to_string(), remove_native, 2);
 
         vm
     }
 
+    /// A VM whose `print` output goes to `out` instead of stdout.
+    pub fn with_writer(out: Box<dyn Write>) -> Self {
+        let mut vm = VM::new();
+        vm.out = out;
+        vm
+    }
+
+    /// Globals defined by the program, sorted by name. Natives are left out;
+    /// a name missing from the list is undefined, while `var x;` shows as nil.
+    pub fn globals(&self) -> Vec<(String, Value)> {
+        let mut globals = self
+            .globals
+            .iter()
+            .filter(|(_, value)| !matches!(value, Value::NativeFunction(_)))
+            .map(|(name, value)| (name.to_string(), value.clone()))
+            .collect::<Vec<_>>();
+        globals.sort_by(|(a, _), (b, _)| a.cmp(b));
+        globals
+    }
+
+    /// The current value of a global, natives included.
+    pub fn get_global(&self, name: &str) -> Option<Value> {
+        self.globals.get(&Symbol::intern(name)).cloned()
+    }
+
+    /// Defines or overwrites a global, visible to every script run afterwards.
+    pub fn set_global(&mut self, name: &str, value: Value) {
+        self.globals.insert(Symbol::intern(name), value);
+    }
+
+    /// Frees objects only kept alive by reference cycles, returning how many.
+    /// This also happens on its own as objects are created.
+    pub fn collect_garbage(&mut self) -> usize {
+        self.heap.collect()
+    }
+
+    /// The most values that were live on the stack at once, across all runs.
+    pub fn max_stack_used(&self) -> usize {
+        self.max_stack_depth
+    }
+
+    pub fn set_interactive(&mut self, interactive: bool) {
+        self.interactive = interactive;
+    }
+
+    /// Turns off printing runtime errors to stderr, for embedders that read
+    /// the report from the returned `InterpretResult` instead.
+    pub fn set_report_errors(&mut self, report_errors: bool) {
+        self.report_errors = report_errors;
+    }
+
+    pub fn set_number_format(&mut self, number_format: NumberFormat) {
+        self.number_format = number_format;
+    }
+
+    /// `value` as `print` writes it.
+    pub fn format_value(&self, value: &Value) -> String {
+        match value {
+            Value::Int(_) | Value::Float(_) => self.number_format.format(&value.to_string()),
+            _ => value.to_string(),
+        }
+    }
+
+    // Drops every frame along with the values on the stack. Upvalues still
+    // open are closed first, so a closure that escaped a failed call keeps
+    // the values it captured.
     fn reset_stack(&mut self) {
+        for up_value in self.open_up_values.drain(..) {
+            let mut up_value = up_value.write();
+            if let value::UpValueObject::Open { frame, slot } = *up_value {
+                let value = self
+                    .frames
+                    .get(frame)
+                    .and_then(|frame| frame.slots.get(slot))
+                    .cloned()
+                    .unwrap_or_default();
+                *up_value = value::UpValueObject::Closed(value);
+            }
+        }
+        self.frames.clear();
         self.stack.clear();
+        self.stack_depth = 0;
+    }
+
+    /// Runs one independent script. Globals carry over between scripts, but
+    /// each starts with no frames and leaves none behind, whatever its result.
+    pub fn run_script(&mut self, source: String) -> InterpretResult {
+        let result = self.interpret(source);
+
+        self.reset_stack();
+        result
+    }
+
+    /// Runs `source` like `run_script`, returning the value of its last
+    /// statement when that is an expression statement, and nil otherwise.
+    pub fn eval(&mut self, source: String) -> Result<Value, InterpretResult> {
+        let result = self.compile_and_run(source, true);
+
+        self.reset_stack();
+        match result {
+            InterpretResult::Ok => Ok(std::mem::take(&mut self.result)),
+            result => Err(result),
+        }
     }
 
     pub fn interpret(&mut self, source: String) -> InterpretResult {
+        self.compile_and_run(source, false)
+    }
+
+    fn compile_and_run(&mut self, source: String, repl: bool) -> InterpretResult {
         self.reset_stack();
 
         let scanner = Rc::new(RwLock::new(Scanner::new(source)));
         let mut compiler = Compiler::new(FunctionType::Script, scanner);
+        compiler.set_repl(repl);
+        compiler.set_const_globals(self.const_globals.clone());
 
-        let function = compiler.compile();
-
-        let res = match function {
-            Some(function) => {
-                let closure = Box::new(Closure::new(function.clone()));
+        // A script that doesn't compile defines nothing, const or not.
+        let const_globals = self.const_globals.read().clone();
+        let function = compiler.compile_function();
+        if function.is_none() {
+            *self.const_globals.write() = const_globals;
+        }
 
-                self.stack.pop();
+        match function {
+            Some(function) => self.run_function(function),
+            None => InterpretResult::CompileError,
+        }
+    }
 
-                self.frames.push(CallFrame {
-                    closure,
-                    ip: 0,
-                    slots: Vec::with_capacity(STACK_MAX),
-                });
+    /// Runs a script precompiled with `--compile`, skipping the compiler. A
+    /// file that isn't valid bytecode is reported and counts as a compile
+    /// error.
+    pub fn run_bytecode(&mut self, bytes: &[u8]) -> InterpretResult {
+        self.reset_stack();
 
-                InterpretResult::Ok
+        let result = match chunk::deserialize(bytes) {
+            Ok(function) => self.run_function(Rc::new(RwLock::new(function))),
+            Err(message) => {
+                eprintln!("Invalid bytecode file: {}", message);
+                InterpretResult::CompileError
             }
-            None => InterpretResult::CompileError,
         };
 
-        if res == InterpretResult::Ok {
-            self.run()
-        } else {
-            res
-        }
+        self.reset_stack();
+        result
+    }
+
+    fn run_function(&mut self, function: Rc<RwLock<Function>>) -> InterpretResult {
+        let closure = Rc::new(RwLock::new(Closure::new(function)));
+
+        // Slot 0 holds the script closure, matching the slot the compiler
+        // reserves for it.
+        let mut slots = Vec::with_capacity(FRAME_SLOTS);
+        slots.push(Value::Closure(closure.clone()));
+
+        self.frames.push(CallFrame {
+            closure,
+            ip: 0,
+            slots,
+            resources: Vec::new(),
+        });
+        self.stack_depth = self.frames.iter().map(|frame| frame.slots.len()).sum();
+        self.max_stack_depth = self.max_stack_depth.max(self.stack_depth);
+
+        match self.run() {
+            Ok(()) => InterpretResult::Ok,
+            Err(kind) => {
+                InterpretResult::RuntimeError(kind, std::mem::take(&mut self.error_report))
+            }
+        }
     }
 
-    fn binary_op(&mut self, op: OpCode) {
+    fn binary_op(&mut self, op: OpCode) -> Result<(), ErrorKind> {
+        // Leaves both operands in place as receiver and argument.
+        if let Some(method) = self.operator_method(op) {
+            return self.call(method, 1);
+        }
+
         let b = self.pop().unwrap();
         let a = self.pop().unwrap();
 
@@ -150,6 +985,7 @@ impl VM {
             (OpCode::Subtract, Value::Float(a), Value::Float(b)) => self.push(Value::Float(a - b)),
             (OpCode::Multiply, Value::Float(a), Value::Float(b)) => self.push(Value::Float(a * b)),
             (OpCode::Divide, Value::Float(a), Value::Float(b)) => self.push(Value::Float(a / b)),
+            (OpCode::Modulo, Value::Float(a), Value::Float(b)) => self.push(Value::Float(a % b)),
             (OpCode::Greater, Value::Float(a), Value::Float(b)) => self.push(Value::Bool(a > b)),
             (OpCode::Less, Value::Float(a), Value::Float(b)) => self.push(Value::Bool(a < b)),
             (OpCode::Add, Value::Int(a), Value::Float(b)) => self.push(Value::Float(a as f64 + b)),
@@ -162,6 +998,9 @@ impl VM {
             (OpCode::Divide, Value::Int(a), Value::Float(b)) => {
                 self.push(Value::Float(a as f64 / b))
             }
+            (OpCode::Modulo, Value::Int(a), Value::Float(b)) => {
+                self.push(Value::Float(a as f64 % b))
+            }
             (OpCode::Greater, Value::Int(a), Value::Float(b)) => {
                 self.push(Value::Bool(a as f64 > b))
             }
@@ -178,82 +1017,283 @@ impl VM {
             (OpCode::Divide, Value::Float(a), Value::Int(b)) => {
                 self.push(Value::Float(a / b as f64))
             }
+            (OpCode::Modulo, Value::Float(a), Value::Int(b)) => {
+                self.push(Value::Float(a % b as f64))
+            }
             (OpCode::Greater, Value::Float(a), Value::Int(b)) => {
                 self.push(Value::Bool(a > b as f64))
             }
             (OpCode::Less, Value::Float(a), Value::Int(b)) => self.push(Value::Bool(a < b as f64)),
-            (OpCode::Add, Value::Int(a), Value::Int(b)) => self.push(Value::Int(a + b)),
-            (OpCode::Subtract, Value::Int(a), Value::Int(b)) => self.push(Value::Int(a - b)),
-            (OpCode::Multiply, Value::Int(a), Value::Int(b)) => self.push(Value::Int(a * b)),
-            (OpCode::Divide, Value::Int(a), Value::Int(b)) => self.push(Value::Int(a / b)),
+            (OpCode::Add, Value::Int(a), Value::Int(b)) => self.push_int(a.checked_add(b)),
+            (OpCode::Subtract, Value::Int(a), Value::Int(b)) => self.push_int(a.checked_sub(b)),
+            (OpCode::Multiply, Value::Int(a), Value::Int(b)) => self.push_int(a.checked_mul(b)),
+            // Integer division and modulo by zero are errors; floats follow
+            // IEEE and produce inf or NaN instead.
+            (OpCode::Divide, Value::Int(_), Value::Int(0)) => {
+                Err(self.runtime_error(ErrorKind::DivByZero, "Division by zero."))
+            }
+            (OpCode::Divide, Value::Int(a), Value::Int(b)) => self.push_int(a.checked_div(b)),
+            (OpCode::Modulo, Value::Int(_), Value::Int(0)) => {
+                Err(self.runtime_error(ErrorKind::DivByZero, "Modulo by zero."))
+            }
+            // wrapping_rem only differs for i64::MIN % -1, where the remainder is 0.
+            (OpCode::Modulo, Value::Int(a), Value::Int(b)) => {
+                self.push(Value::Int(a.wrapping_rem(b)))
+            }
             (OpCode::Greater, Value::Int(a), Value::Int(b)) => self.push(Value::Bool(a > b)),
             (OpCode::Less, Value::Int(a), Value::Int(b)) => self.push(Value::Bool(a < b)),
 
+            // Exponents are only exact for non-negative integer powers; the
+            // rest go through f64.
+            (OpCode::Power, Value::Int(a), Value::Int(b)) if b >= 0 => self.push_int(int_pow(a, b)),
+            (OpCode::Power, Value::Int(a), Value::Int(b)) => {
+                self.push(Value::Float((a as f64).powf(b as f64)))
+            }
+            (OpCode::Power, Value::Int(a), Value::Float(b)) => {
+                self.push(Value::Float((a as f64).powf(b)))
+            }
+            (OpCode::Power, Value::Float(a), Value::Int(b)) => {
+                self.push(Value::Float(a.powf(b as f64)))
+            }
+            (OpCode::Power, Value::Float(a), Value::Float(b)) => self.push(Value::Float(a.powf(b))),
+
             (OpCode::Equal, a, b) => self.push(Value::Bool(a == b)),
             (OpCode::Add, Value::String(a), Value::String(b)) => {
                 let s = a + &b;
-                self.push(Value::String(s));
+                self.push(Value::String(s))
             }
+            // Lexicographic by byte, so a prefix sorts first.
+            (OpCode::Greater, Value::String(a), Value::String(b)) => self.push(Value::Bool(a > b)),
+            (OpCode::Less, Value::String(a), Value::String(b)) => self.push(Value::Bool(a < b)),
 
             (o, a, b) => {
-                self.runtime_error(
+                let expected = match o {
+                    OpCode::Add | OpCode::Greater | OpCode::Less => "two numbers or two strings",
+                    _ => "two numbers",
+                };
+                Err(self.runtime_error(
+                    ErrorKind::Type,
+                    format!(
+                        "Operands of '{}' must be {}, got {} and {}",
+                        operator_symbol(o),
+                        expected,
+                        a.type_name(),
+                        b.type_name()
+                    )
+                    .as_str(),
+                ))
+            }
+        }
+    }
+
+    // The method overriding `op` when the left operand is an instance whose
+    // class defines `__lt__`, `__gt__` or `__eq__`.
+    fn operator_method(&self, op: OpCode) -> Option<Rc<RwLock<Closure>>> {
+        let name = match op {
+            OpCode::Less => "__lt__",
+            OpCode::Greater => "__gt__",
+            OpCode::Equal => "__eq__",
+            _ => return None,
+        };
+
+        match self.peek(1) {
+            Some(Value::Instance(instance)) => instance
+                .read()
+                .class
+                .read()
+                .methods
+                .read()
+                .get(&Symbol::intern(name))
+                .cloned(),
+            _ => None,
+        }
+    }
+
+    // Resolves `index` to a position in `0..len`. Negative indices count
+    // back from the end, so -1 is the last element.
+    fn check_index(&mut self, index: &Value, len: usize) -> Result<usize, ErrorKind> {
+        match index {
+            Value::Int(i) if (-(len as i64)..len as i64).contains(i) => {
+                Ok(i.rem_euclid(len as i64) as usize)
+            }
+            Value::Int(i) => Err(self.runtime_error(
+                ErrorKind::Index,
+                format!("Index {} out of bounds for length {}", i, len).as_str(),
+            )),
+            _ => Err(self.runtime_error(
+                ErrorKind::Type,
+                format!("Index must be an integer, got {}", index.type_name()).as_str(),
+            )),
+        }
+    }
+
+    fn get_index(&mut self) -> Result<(), ErrorKind> {
+        let index = self.pop().unwrap();
+        let target = self.pop().unwrap();
+
+        let value = match &target {
+            Value::Array(array) => {
+                let len = array.read().len();
+                let i = self.check_index(&index, len)?;
+                array.read()[i].clone()
+            }
+            Value::String(s) => {
+                let len = s.chars().count();
+                let i = self.check_index(&index, len)?;
+                Value::String(s.chars().nth(i).unwrap().to_string())
+            }
+            Value::Bytes(bytes) => {
+                let len = bytes.read().len();
+                let i = self.check_index(&index, len)?;
+                Value::Int(bytes.read()[i] as i64)
+            }
+            _ => {
+                return Err(self.runtime_error(
+                    ErrorKind::Type,
                     format!(
-                        "Operands must be two numbers or two strings. Got {:?} {:?} {:?}",
-                        o, a, b
+                        "Can only index arrays, strings and bytes, got {}",
+                        target.type_name()
                     )
                     .as_str(),
+                ));
+            }
+        };
+
+        self.push(value)?;
+        Ok(())
+    }
+
+    // The element of the sequence in local `slot` at the cursor in the local
+    // after it, advancing the cursor past it. Strings are walked by byte
+    // offset rather than character index, so a whole loop is linear.
+    fn next_element(&mut self, slot: usize) -> Result<Option<Value>, ErrorKind> {
+        let slots = &mut self.frames.last_mut().unwrap().slots;
+        // Compiled loops always hold a valid cursor; a corrupt bytecode file
+        // may not.
+        let cursor = match slots[slot + 1] {
+            Value::Int(cursor) if cursor >= 0 => cursor as usize,
+            _ => return Err(self.runtime_error(ErrorKind::Internal, "Invalid loop cursor")),
+        };
+
+        let next = match &slots[slot] {
+            Value::String(s) => match s.get(cursor..) {
+                Some(rest) => rest
+                    .chars()
+                    .next()
+                    .map(|c| (Value::String(c.to_string()), cursor + c.len_utf8())),
+                None => return Err(self.runtime_error(ErrorKind::Internal, "Invalid loop cursor")),
+            },
+            Value::Array(array) => array.read().get(cursor).map(|v| (v.clone(), cursor + 1)),
+            Value::Bytes(bytes) => bytes
+                .read()
+                .get(cursor)
+                .map(|&byte| (Value::Int(byte as i64), cursor + 1)),
+            sequence => {
+                let message = format!(
+                    "Can only loop over arrays, strings and bytes, got {}",
+                    sequence.type_name()
                 );
+                return Err(self.runtime_error(ErrorKind::Type, &message));
+            }
+        };
+
+        Ok(next.map(|(element, cursor)| {
+            slots[slot + 1] = Value::Int(cursor as i64);
+            element
+        }))
+    }
+
+    fn set_index(&mut self) -> Result<(), ErrorKind> {
+        let value = self.pop().unwrap();
+        let index = self.pop().unwrap();
+        let target = self.pop().unwrap();
+
+        match &target {
+            Value::Array(array) => {
+                let len = array.read().len();
+                let i = self.check_index(&index, len)?;
+                array.write()[i] = value.clone();
+            }
+            _ => {
+                return Err(self.runtime_error(
+                    ErrorKind::Type,
+                    format!(
+                        "Can only assign to array elements, got {}",
+                        target.type_name()
+                    )
+                    .as_str(),
+                ));
             }
         }
+
+        self.push(value)?;
+        Ok(())
+    }
+
+    // Pushes the result of a checked integer operation, raising an error
+    // instead of wrapping when it overflowed.
+    fn push_int(&mut self, result: Option<i64>) -> Result<(), ErrorKind> {
+        match result {
+            Some(i) => self.push(Value::Int(i)),
+            None => Err(self.runtime_error(ErrorKind::Overflow, "Integer overflow.")),
+        }
     }
 
-    fn run(&mut self) -> InterpretResult {
+    fn run(&mut self) -> Result<(), ErrorKind> {
         loop {
-            let instruction: OpCode = OpCode::from(self.read_byte());
+            let instruction = match OpCode::try_from(self.read_byte()?) {
+                Ok(instruction) => instruction,
+                Err(byte) => {
+                    return Err(self.runtime_error(
+                        ErrorKind::Internal,
+                        format!("Unknown opcode {}", byte).as_str(),
+                    ));
+                }
+            };
 
             if DEBUG_TRACE_EXECUTION {
                 let frame = self.frames.last().unwrap();
-                print!("          ");
-                for slot in &frame.slots {
-                    print!("[ {} ]", slot);
-                }
-                println!();
+                let slots = frame
+                    .slots
+                    .iter()
+                    .map(|slot| format!("[ {} ]", slot))
+                    .collect::<String>();
 
-                frame.closure.function.read().chunk.read().disassemble(
-                    frame.closure.function.clone().read().name.as_str(),
-                    Some(frame.ip - 1),
-                );
+                let function = frame.closure.read().function.clone();
+                let function = function.read();
+                let listing =
+                    debug::disassemble(&function.chunk(), function.name(), Some(frame.ip - 1));
+                write!(self.out, "          {}\n{}", slots, listing)
+                    .expect("Failed to write trace");
             }
 
             match instruction {
-                OpCode::SuperInvoke => {
-                    let method = self.read_constant();
-                    let arg_count = self.read_byte();
+                OpCode::SuperInvoke | OpCode::SuperInvokeLong => {
+                    let method = self.read_symbol(instruction)?;
+                    let arg_count = self.read_byte()?;
                     let superclass = self.pop().unwrap();
                     match superclass {
                         Value::Class(superclass) => {
-                            if !self.invoke_from_class(superclass, method, arg_count) {
-                                return InterpretResult::RuntimeError;
-                            }
+                            self.invoke_from_class(superclass, method, arg_count)?;
                         }
                         _ => {
-                            self.runtime_error("Superclass must be a class");
-                            return InterpretResult::RuntimeError;
+                            return Err(
+                                self.runtime_error(ErrorKind::Type, "Superclass must be a class")
+                            );
                         }
                     }
                 }
-                OpCode::GetSuper => {
-                    let name = self.read_constant();
+                OpCode::GetSuper | OpCode::GetSuperLong => {
+                    let name = self.read_symbol(instruction)?;
                     let superclass = self.pop().unwrap();
                     match superclass {
                         Value::Class(_) => {
-                            if !self.bind_method(Rc::new(RwLock::new(superclass)), name) {
-                                return InterpretResult::RuntimeError;
-                            }
+                            self.bind_method(Rc::new(RwLock::new(superclass)), name)?;
                         }
                         _ => {
-                            self.runtime_error("Superclass must be a class");
-                            return InterpretResult::RuntimeError;
+                            return Err(
+                                self.runtime_error(ErrorKind::Type, "Superclass must be a class")
+                            );
                         }
                     }
                 }
@@ -272,268 +1312,477 @@ impl VM {
                                     self.pop();
                                 }
                                 _ => {
-                                    self.runtime_error("Superclass must be a class");
-                                    return InterpretResult::RuntimeError;
+                                    return Err(self.runtime_error(
+                                        ErrorKind::Type,
+                                        "Superclass must be a class",
+                                    ));
                                 }
                             }
                         }
                         _ => {
-                            self.runtime_error("Superclass must be a class");
-                            return InterpretResult::RuntimeError;
+                            return Err(
+                                self.runtime_error(ErrorKind::Type, "Superclass must be a class")
+                            );
                         }
                     }
                 }
-                OpCode::Invoke => {
-                    let method = self.read_constant();
-                    let arg_count = self.read_byte();
-                    if !self.invoke(method, arg_count) {
-                        return InterpretResult::RuntimeError;
-                    }
+                OpCode::Invoke | OpCode::InvokeLong => {
+                    let method = self.read_symbol(instruction)?;
+                    let arg_count = self.read_byte()?;
+                    self.invoke(method, arg_count)?;
                 }
-                OpCode::Closure => {
-                    let constant = self.read_constant();
+                OpCode::Closure | OpCode::ClosureLong => {
+                    let constant = self.read_constant(instruction)?;
                     let function = match constant {
                         Value::Function(function) => function,
                         _ => panic!("Expected function"),
                     };
                     let closure = Closure::new(function.clone());
 
-                    for _ in 0..function.read().up_value_count {
-                        let is_local = self.read_byte() == 1;
-                        let index = self.read_byte();
+                    for _ in 0..function.read().up_value_count() {
+                        let is_local = self.read_byte()? == 1;
+                        let index = self.read_byte()?;
                         if is_local {
-                            closure.up_values.write().push(self.capture_up_value(
-                                self.frames.last().unwrap().slots[index as usize].clone(),
-                            ));
+                            closure
+                                .up_values
+                                .write()
+                                .push(self.capture_up_value(index as usize));
                         } else {
                             closure.up_values.write().push(
-                                self.frames.last().unwrap().closure.up_values.read()
+                                self.frames.last().unwrap().closure.read().up_values.read()
                                     [index as usize]
                                     .clone(),
                             );
                         }
                     }
 
-                    self.push(Value::Closure(Box::new(closure)));
+                    let closure = Value::Closure(Rc::new(RwLock::new(closure)));
+                    self.track(&closure);
+                    self.push(closure)?;
                 }
                 OpCode::Return => {
                     let result = self.pop();
 
                     match result {
                         Some(result) => {
+                            self.close_up_values(0);
                             let frame = self.frames.pop().unwrap();
-                            if self.frames.len() == 0 {
-                                self.stack.pop();
-                                return InterpretResult::Ok;
+                            self.stack_depth -= frame.slots.len();
+                            if self.frames.is_empty() {
+                                self.result = result;
+                                return Ok(());
                             }
 
-                            let parent_frame = self.frames.last_mut().unwrap();
-                            parent_frame
-                                .slots
-                                .truncate(parent_frame.slots.len() - frame.slots.len());
-
-                            self.push(result);
+                            self.push(result)?;
                         }
                         None => {
-                            self.runtime_error("Stack underflow");
-                            return InterpretResult::RuntimeError;
+                            return Err(self.runtime_error(ErrorKind::Internal, "Stack underflow"));
                         }
                     }
                 }
-                OpCode::Constant => {
-                    let constant = self.read_constant();
-                    self.push(constant);
+                OpCode::Constant | OpCode::ConstantLong => {
+                    let constant = self.read_constant(instruction)?;
+                    self.push(constant)?;
                 }
                 OpCode::Negate => {
                     let value = self.pop().unwrap();
                     match value {
-                        Value::Int(value) => self.push(Value::Int(-value)),
-                        Value::Float(value) => self.push(Value::Float(-value)),
+                        Value::Int(value) => self.push_int(value.checked_neg())?,
+                        Value::Float(value) => self.push(Value::Float(-value))?,
                         _ => {
-                            self.runtime_error("Operand must be a number");
-                            return InterpretResult::RuntimeError;
+                            return Err(
+                                self.runtime_error(ErrorKind::Type, "Operand must be a number")
+                            );
                         }
                     }
                 }
-                OpCode::Equal => self.binary_op(OpCode::Equal),
-                OpCode::Greater => self.binary_op(OpCode::Greater),
-                OpCode::Less => self.binary_op(OpCode::Less),
-                OpCode::Add => self.binary_op(OpCode::Add),
-                OpCode::Subtract => self.binary_op(OpCode::Subtract),
-                OpCode::Multiply => self.binary_op(OpCode::Multiply),
-                OpCode::Divide => self.binary_op(OpCode::Divide),
-                OpCode::Nil => self.push(Value::Nil),
-                OpCode::True => self.push(Value::Bool(true)),
-                OpCode::False => self.push(Value::Bool(false)),
+                OpCode::Equal
+                | OpCode::Greater
+                | OpCode::Less
+                | OpCode::Add
+                | OpCode::Subtract
+                | OpCode::Multiply
+                | OpCode::Divide
+                | OpCode::Modulo
+                | OpCode::Power => self.binary_op(instruction)?,
+                OpCode::Nil => self.push(Value::Nil)?,
+                OpCode::True => self.push(Value::Bool(true))?,
+                OpCode::False => self.push(Value::Bool(false))?,
                 OpCode::Not => {
                     let value = self.pop().unwrap();
-                    self.push(Value::Bool(value.is_falsely()));
+                    self.push(Value::Bool(value.is_falsely()))?;
                 }
                 OpCode::Print => {
-                    println!("{}", self.pop().unwrap());
+                    let count = self.read_byte()? as usize;
+                    let slots = &mut self.frames.last_mut().unwrap().slots;
+                    let values = slots.split_off(slots.len() - count);
+                    self.stack_depth -= count;
+
+                    let line = values
+                        .iter()
+                        .map(|value| self.format_value(value))
+                        .collect::<Vec<_>>();
+                    let mut written = writeln!(self.out, "{}", line.join(" "));
+                    if self.interactive {
+                        written = written.and_then(|_| self.out.flush());
+                    }
+                    if written.is_err() {
+                        return Err(self.runtime_error(ErrorKind::Io, "Failed to write output"));
+                    }
                 }
                 OpCode::Pop => {
                     self.pop();
                 }
-                OpCode::DefineGlobal => {
-                    let constant = self.read_constant();
-                    let name = constant.to_string();
+                OpCode::PopN => {
+                    let count = self.read_byte()?;
+                    for _ in 0..count {
+                        self.pop();
+                    }
+                }
+                OpCode::DefineGlobal | OpCode::DefineGlobalLong => {
+                    let name = self.read_symbol(instruction)?;
                     let value = self.pop().unwrap();
                     self.globals.insert(name, value);
                 }
-                OpCode::GetGlobal => {
-                    let constant = self.read_constant();
-                    let name = constant.to_string();
+                OpCode::GetGlobal | OpCode::GetGlobalLong => {
+                    let name = self.read_symbol(instruction)?;
                     let value = self.globals.get(&name);
 
                     match value {
-                        Some(value) => self.push(value.clone()),
+                        Some(value) => self.push(value.clone())?,
                         None => {
-                            self.runtime_error(format!("Undefined variable '{}'", name).as_str());
-                            return InterpretResult::RuntimeError;
+                            return Err(self.runtime_error(
+                                ErrorKind::Name,
+                                format!("Undefined variable '{}'", name).as_str(),
+                            ));
                         }
                     }
                 }
-                OpCode::SetGlobal => {
-                    let constant = self.read_constant();
-                    let name = constant.to_string();
+                OpCode::SetGlobal | OpCode::SetGlobalLong => {
+                    let name = self.read_symbol(instruction)?;
                     if self.globals.contains_key(&name) {
-                        let value = self.pop().unwrap();
+                        let value = self.peek(0).unwrap().clone();
                         self.globals.insert(name, value);
                     } else {
-                        self.runtime_error(format!("Undefined variable '{}'", name).as_str());
-                        return InterpretResult::RuntimeError;
+                        return Err(self.runtime_error(
+                            ErrorKind::Name,
+                            format!("Undefined variable '{}'", name).as_str(),
+                        ));
                     }
                 }
                 OpCode::GetLocal => {
-                    let slot = self.read_byte();
+                    let slot = self.read_byte()?;
                     let value = self.frames.last().unwrap().slots[slot as usize].clone();
-                    self.push(value);
+                    self.push(value)?;
                 }
                 OpCode::SetLocal => {
-                    let slot = self.read_byte();
+                    let slot = self.read_byte()?;
                     let value = self.peek(0).unwrap().clone();
                     self.frames.last_mut().unwrap().slots[slot as usize] = value;
                 }
                 OpCode::GetUpvalue => {
-                    let slot = self.read_byte();
-                    let value = self.frames.last().unwrap().closure.up_values.read()[slot as usize]
-                        .read()
-                        .location
+                    let slot = self.read_byte()?;
+                    let up_value = self.frames.last().unwrap().closure.read().up_values.read()
+                        [slot as usize]
                         .clone();
-                    self.push(value);
+                    let value = match &*up_value.read() {
+                        value::UpValueObject::Open { frame, slot } => {
+                            self.frames[*frame].slots[*slot].clone()
+                        }
+                        value::UpValueObject::Closed(value) => value.clone(),
+                    };
+                    self.push(value)?;
                 }
                 OpCode::SetUpvalue => {
-                    let slot = self.read_byte();
+                    let slot = self.read_byte()?;
                     let value = self.peek(0).unwrap().clone();
-                    self.frames.last_mut().unwrap().closure.up_values.read()[slot as usize]
-                        .write()
-                        .location = value;
+                    let up_value = self.frames.last().unwrap().closure.read().up_values.read()
+                        [slot as usize]
+                        .clone();
+                    let mut up_value = up_value.write();
+                    match &mut *up_value {
+                        value::UpValueObject::Open { frame, slot } => {
+                            self.frames[*frame].slots[*slot] = value
+                        }
+                        value::UpValueObject::Closed(closed) => *closed = value,
+                    }
                 }
                 OpCode::CloseUpvalue => {
-                    self.close_up_values();
+                    let top = self.frames.last().unwrap().slots.len() - 1;
+                    self.close_up_values(top);
                     self.pop();
                 }
                 OpCode::JumpIfFalse => {
-                    let offset = self.read_short();
+                    let offset = self.read_short()?;
                     if self.peek(0).unwrap().is_falsely() {
                         self.frames.last_mut().unwrap().ip += offset as usize;
                     }
                 }
                 OpCode::JumpIfTrue => {
-                    let offset = self.read_short();
+                    let offset = self.read_short()?;
                     if !self.peek(0).unwrap().is_falsely() {
                         self.frames.last_mut().unwrap().ip += offset as usize;
                     }
                 }
                 OpCode::Jump => {
-                    let offset = self.read_short();
+                    let offset = self.read_short()?;
                     self.frames.last_mut().unwrap().ip += offset as usize;
                 }
+                OpCode::Switch | OpCode::SwitchLong => {
+                    let first = match self.read_constant(instruction)? {
+                        Value::Int(first) => first,
+                        _ => {
+                            return Err(self.runtime_error(
+                                ErrorKind::Internal,
+                                "Switch table must start at an int",
+                            ));
+                        }
+                    };
+                    let count = self.read_byte()? as usize;
+
+                    // Anything not equal to an int label in range takes the
+                    // last, default, entry.
+                    let slot = self
+                        .peek(0)
+                        .and_then(|subject| subject.as_exact_int())
+                        .and_then(|value| value.checked_sub(first))
+                        .filter(|slot| (0..count as i64).contains(slot))
+                        .map_or(count, |slot| slot as usize);
+
+                    let table = self.frames.last().unwrap().ip;
+                    self.frames.last_mut().unwrap().ip = table + 2 * slot;
+                    let offset = self.read_short()?;
+                    self.frames.last_mut().unwrap().ip = table + 2 * (count + 1) + offset as usize;
+                }
                 OpCode::Loop => {
-                    let offset = self.read_short();
+                    let offset = self.read_short()?;
                     self.frames.last_mut().unwrap().ip -= offset as usize;
                 }
                 OpCode::Duplicate => {
                     if let Some(value) = self.peek(0) {
-                        self.push(value.clone());
+                        self.push(value.clone())?;
                     } else {
-                        self.runtime_error("Stack underflow");
-                        return InterpretResult::RuntimeError;
+                        return Err(self.runtime_error(ErrorKind::Internal, "Stack underflow"));
+                    }
+                }
+                // [a b] -> [b a b]: keeps the right operand of a chained
+                // comparison for the next link.
+                OpCode::Tuck => {
+                    let b = self.pop().unwrap();
+                    let a = self.pop().unwrap();
+                    self.push(b.clone())?;
+                    self.push(a)?;
+                    self.push(b)?;
+                }
+                OpCode::BuildArray => {
+                    let count = self.read_byte()? as usize;
+                    let slots = &mut self.frames.last_mut().unwrap().slots;
+                    let elements = slots.split_off(slots.len() - count);
+                    self.stack_depth -= count;
+                    let array = Value::Array(Rc::new(RwLock::new(elements)));
+                    self.track(&array);
+                    self.push(array)?;
+                }
+                OpCode::ToString => {
+                    let value = self.pop().unwrap();
+                    match value {
+                        Value::String(_) => self.push(value)?,
+                        value => self.push(Value::String(value.to_string()))?,
+                    }
+                }
+                OpCode::Next => {
+                    let slot = self.read_byte()? as usize;
+                    match self.next_element(slot)? {
+                        Some(element) => {
+                            self.push(element)?;
+                            self.push(Value::Bool(true))?;
+                        }
+                        None => {
+                            self.push(Value::Nil)?;
+                            self.push(Value::Bool(false))?;
+                        }
                     }
                 }
+                OpCode::CheckCount => match self.peek(0).unwrap() {
+                    Value::Int(count) if *count >= 0 => {}
+                    count => {
+                        let message =
+                            format!("Count must be a non-negative integer, got {}", count);
+                        return Err(self.runtime_error(ErrorKind::Type, &message));
+                    }
+                },
+                OpCode::Resource => match self.peek(0).unwrap() {
+                    Value::File(file) => {
+                        let file = file.clone();
+                        let frame = self.frames.last_mut().unwrap();
+                        frame.resources.retain(|file| !file.read().is_closed());
+                        frame.resources.push(file);
+                    }
+                    value => {
+                        let message = format!("Resource must be a file, got {}", value.type_name());
+                        return Err(self.runtime_error(ErrorKind::Type, &message));
+                    }
+                },
                 OpCode::Call => {
-                    let arg_count = self.read_byte();
-                    if !self.call_value(self.peek(arg_count as usize).unwrap().clone(), arg_count) {
-                        return InterpretResult::RuntimeError;
+                    let arg_count = self.read_byte()?;
+                    let callee = self.peek(arg_count as usize).unwrap().clone();
+                    self.call_value(callee, arg_count)?;
+                }
+                OpCode::TailCall => {
+                    let arg_count = self.read_byte()?;
+                    let callee = self.peek(arg_count as usize).unwrap().clone();
+
+                    // The caller's locals die with it, so close over them now,
+                    // while the frame still exists.
+                    self.close_up_values(0);
+                    let frames = self.frames.len();
+                    self.call_value(callee, arg_count)?;
+
+                    // The callee's frame takes the caller's place. Otherwise
+                    // the result is already on the stack for the Return that
+                    // follows. Frames never pile up, so unbounded tail
+                    // recursion such as `fun f() { return f(); }` loops
+                    // forever rather than overflowing the stack.
+                    if self.frames.len() > frames {
+                        let caller = self.frames.remove(frames - 1);
+                        self.stack_depth -= caller.slots.len();
                     }
                 }
-                OpCode::Class => {
-                    let name = self.read_constant();
-                    self.push(Value::Class(Rc::new(RwLock::new(value::Class::new(
-                        name.to_string(),
-                    )))));
+                OpCode::Class | OpCode::ClassLong => {
+                    let name = self.read_constant(instruction)?;
+                    let class =
+                        Value::Class(Rc::new(RwLock::new(value::Class::new(name.to_string()))));
+                    self.track(&class);
+                    self.push(class)?;
                 }
-                OpCode::GetProperty => {
-                    let name = self.read_constant();
-                    let value = self.pop().unwrap();
+                OpCode::GetProperty | OpCode::GetPropertyLong => {
+                    let name = self.read_symbol(instruction)?;
+                    let value = self.peek(0).unwrap().clone();
                     match value {
                         Value::Instance(ref instance) => {
-                            if let Some(value) =
-                                instance.read().fields.read().get(&name.to_string())
-                            {
-                                self.push(value.clone());
-                            } else if !self.bind_method(Rc::new(RwLock::new(value.clone())), name) {
-                                return InterpretResult::RuntimeError;
+                            let field = instance.read().fields.read().get(&name).cloned();
+                            if let Some(field) = field {
+                                self.pop();
+                                self.push(field)?;
+                            } else {
+                                self.bind_method(Rc::new(RwLock::new(value.clone())), name)?;
                             }
                         }
                         _ => {
-                            self.runtime_error("Only instances have properties");
-                            return InterpretResult::RuntimeError;
+                            return Err(self
+                                .runtime_error(ErrorKind::Type, "Only instances have properties"));
                         }
                     }
                 }
-                OpCode::SetProperty => {
-                    let name = self.read_constant();
+                OpCode::GetIndex => self.get_index()?,
+                OpCode::SetIndex => self.set_index()?,
+                OpCode::SetProperty | OpCode::SetPropertyLong => {
+                    let name = self.read_symbol(instruction)?;
                     let instance = self.peek(1).unwrap().clone();
                     match instance {
                         Value::Instance(instance) => {
-                            let value = self.peek(0).unwrap().clone();
-                            instance
-                                .write()
-                                .fields
-                                .write()
-                                .insert(name.to_string(), value);
+                            let value = self.pop().unwrap();
+                            instance.write().fields.write().insert(name, value.clone());
+                            self.pop();
+                            self.push(value)?;
                         }
                         _ => {
-                            self.runtime_error("Only instances have fields");
-                            return InterpretResult::RuntimeError;
+                            return Err(
+                                self.runtime_error(ErrorKind::Type, "Only instances have fields")
+                            );
                         }
                     }
                 }
-                OpCode::Method => {
-                    let name = self.read_constant();
-                    self.define_method(name);
+                OpCode::Method | OpCode::MethodLong => {
+                    let name = self.read_symbol(instruction)?;
+                    self.define_method(name)?;
                 }
             }
         }
     }
 
-    fn invoke(&mut self, name: Value, arg_count: u8) -> bool {
+    fn invoke(&mut self, name: Symbol, arg_count: u8) -> Result<(), ErrorKind> {
         let receiver = self.peek(arg_count as usize).unwrap().clone();
 
         match receiver {
             Value::Instance(instance) => {
-                if let Some(value) = instance.read().fields.read().get(&name.to_string()) {
-                    self.stack.pop();
-                    return self.call_value(value.clone(), arg_count);
+                let field = instance.read().fields.read().get(&name).cloned();
+                if let Some(value) = field {
+                    self.set_callee_slot(value.clone(), arg_count);
+                    return self.call_value(value, arg_count);
                 }
 
-                self.invoke_from_class(instance.read().clone().class, name, arg_count)
+                let class = instance.read().class.clone();
+                self.invoke_from_class(class, name, arg_count)
             }
-            _ => {
-                self.runtime_error("Only instances have methods");
-                false
+            Value::File(file) => self.invoke_file(file, name, arg_count),
+            _ => Err(self.runtime_error(ErrorKind::Type, "Only instances have methods")),
+        }
+    }
+
+    fn invoke_file(
+        &mut self,
+        file: Rc<RwLock<value::File>>,
+        name: Symbol,
+        arg_count: u8,
+    ) -> Result<(), ErrorKind> {
+        let method = name.to_string();
+        let arity = if method == "write" { 1 } else { 0 };
+        if !matches!(method.as_str(), "read" | "readline" | "write" | "close") {
+            return Err(self.runtime_error(
+                ErrorKind::Name,
+                format!("Undefined property '{}'", name).as_str(),
+            ));
+        }
+        if arg_count as usize != arity {
+            return Err(self.runtime_error(
+                ErrorKind::Arity,
+                format!("Expected {} arguments but got {}", arity, arg_count).as_str(),
+            ));
+        }
+
+        let mut file = file.write();
+        if method == "close" {
+            file.close();
+            self.pop();
+            self.push(Value::Nil)?;
+            return Ok(());
+        }
+        // Errors unwinding the frame close its resources, so the file must
+        // be unlocked before reporting one.
+        if file.is_closed() {
+            let message = format!("File '{}' is closed", file.path);
+            drop(file);
+            return Err(self.runtime_error(ErrorKind::Io, &message));
+        }
+
+        let result = match method.as_str() {
+            "read" => file.read().map(Value::String),
+            "readline" => file
+                .read_line()
+                .map(|line| line.map_or(Value::Nil, Value::String)),
+            _ => match self.peek(0).unwrap() {
+                Value::String(s) => file.write(s).map(|_| Value::Nil),
+                value => {
+                    let message = format!("Expected string, got {}", value.type_name());
+                    drop(file);
+                    return Err(self.runtime_error(ErrorKind::Type, &message));
+                }
+            },
+        };
+
+        match result {
+            Ok(value) => {
+                for _ in 0..=arg_count {
+                    self.pop();
+                }
+                self.push(value)?;
+                Ok(())
+            }
+            Err(_) => {
+                let message = format!("Failed to {} file '{}'", method, file.path);
+                drop(file);
+                Err(self.runtime_error(ErrorKind::Io, &message))
             }
         }
     }
@@ -541,263 +1790,305 @@ impl VM {
     fn invoke_from_class(
         &mut self,
         class: Rc<RwLock<value::Class>>,
-        name: Value,
+        name: Symbol,
         arg_count: u8,
-    ) -> bool {
-        if let Some(method) = class.read().methods.read().get(&name.to_string()) {
-            self.call(method.clone(), arg_count, true);
-            true
-        } else {
-            self.runtime_error(format!("Undefined property '{}'", name).as_str());
-            false
+    ) -> Result<(), ErrorKind> {
+        let method = class.read().methods.read().get(&name).cloned();
+        match method {
+            Some(method) => self.call(method, arg_count),
+            None => Err(self.runtime_error(
+                ErrorKind::Name,
+                format!("Undefined property '{}'", name).as_str(),
+            )),
         }
     }
 
-    fn bind_method(&mut self, value: Rc<RwLock<value::Value>>, name: Value) -> bool {
+    fn bind_method(
+        &mut self,
+        value: Rc<RwLock<value::Value>>,
+        name: Symbol,
+    ) -> Result<(), ErrorKind> {
         match &*value.read() {
             Value::Class(class) => {
-                if let Some(method) = class.read().methods.read().get(&name.to_string()) {
+                if let Some(method) = class.read().methods.read().get(&name) {
                     let bound_method = Value::BoundMethod(Rc::new(RwLock::new(
                         value::BoundMethod::new(value.clone(), method.clone()),
                     )));
                     self.pop();
-                    self.push(bound_method);
-                    true
+                    self.push(bound_method)?;
+                    Ok(())
                 } else {
-                    self.runtime_error(format!("Undefined property '{}'", name).as_str());
-                    false
+                    Err(self.runtime_error(
+                        ErrorKind::Name,
+                        format!("Undefined property '{}'", name).as_str(),
+                    ))
                 }
             }
             Value::Instance(instance) => {
-                if let Some(method) = instance
-                    .read()
-                    .class
-                    .read()
-                    .methods
-                    .read()
-                    .get(&name.to_string())
-                {
+                if let Some(method) = instance.read().class.read().methods.read().get(&name) {
                     let bound_method = Value::BoundMethod(Rc::new(RwLock::new(
                         value::BoundMethod::new(value.clone(), method.clone()),
                     )));
                     self.pop();
-                    self.push(bound_method);
-                    true
+                    self.push(bound_method)?;
+                    Ok(())
                 } else {
-                    self.runtime_error(format!("Undefined property '{}'", name).as_str());
-                    false
+                    Err(self.runtime_error(
+                        ErrorKind::Name,
+                        format!("Undefined property '{}'", name).as_str(),
+                    ))
                 }
             }
-            _ => {
-                self.runtime_error("Only instances have methods");
-                false
-            }
+            _ => Err(self.runtime_error(ErrorKind::Type, "Only instances have methods")),
         }
     }
 
-    fn define_method(&mut self, name: Value) {
+    fn define_method(&mut self, name: Symbol) -> Result<(), ErrorKind> {
         let method = self.peek(0).unwrap().clone();
         let class = self.peek(1).unwrap().clone();
         match (class, method) {
             (Value::Class(class), Value::Closure(method)) => {
-                class
-                    .write()
-                    .methods
-                    .write()
-                    .insert(name.to_string(), method);
+                class.write().methods.write().insert(name, method);
             }
             _ => {
-                self.runtime_error("Only classes have methods");
-                return;
+                return Err(self.runtime_error(ErrorKind::Type, "Only classes have methods"));
             }
         }
         self.pop();
+        Ok(())
     }
 
-    fn close_up_values(&mut self) {
-        let frame = self.frames.last().unwrap();
-        let mut i = 0;
-        for up_value in frame.closure.up_values.read().iter() {
+    // Moves the values of the current frame's captured slots from `first` up
+    // into their upvalues, which stop tracking the slots.
+    fn close_up_values(&mut self, first: usize) {
+        let current = self.frames.len() - 1;
+        let frames = &self.frames;
+        self.open_up_values.retain(|up_value| {
             let mut up_value = up_value.write();
-            if up_value.location == Value::Nil {
-                up_value.location = frame.slots[i].clone();
-                up_value.closed = true;
+            match *up_value {
+                value::UpValueObject::Open { frame, slot } if frame == current && slot >= first => {
+                    *up_value = value::UpValueObject::Closed(frames[frame].slots[slot].clone());
+                    false
+                }
+                _ => true,
             }
-            i += 1;
-        }
+        });
     }
 
-    fn capture_up_value(&mut self, local: Value) -> Rc<RwLock<value::UpValueObject>> {
-        let last_frame = self.frames.last_mut().unwrap();
-        for up_value in last_frame.closure.up_values.read().iter() {
-            if up_value.read().location == local {
-                return up_value.clone();
-            }
+    // The upvalue for `slot` in the current frame, shared with any closure
+    // that already captured it.
+    fn capture_up_value(&mut self, slot: usize) -> Rc<RwLock<value::UpValueObject>> {
+        let location = value::UpValueObject::Open {
+            frame: self.frames.len() - 1,
+            slot,
+        };
+        if let Some(up_value) = self
+            .open_up_values
+            .iter()
+            .find(|up_value| *up_value.read() == location)
+        {
+            return up_value.clone();
         }
 
-        let up_value = Rc::new(RwLock::new(value::UpValueObject::new(Value::Nil)));
-        last_frame.closure.up_values.write().push(up_value.clone());
-        up_value.write().location = local;
-        up_value.write().closed = false;
+        let up_value = Rc::new(RwLock::new(location));
+        self.open_up_values.push(up_value.clone());
         up_value
     }
 
-    fn call_value(&mut self, callee: Value, arg_count: u8) -> bool {
+    fn call_value(&mut self, callee: Value, arg_count: u8) -> Result<(), ErrorKind> {
         match callee {
             Value::BoundMethod(bound_method) => {
-                let bound_method = bound_method.write();
-                let method = bound_method.method.clone();
-                let receiver = bound_method.receiver.clone();
-
-                let frame = self.frames.last_mut().unwrap();
-
-                frame.slots.insert(
-                    frame.slots.len() - arg_count as usize,
-                    receiver.read().clone(),
-                );
-
-                self.call(method, arg_count, true)
-            }
-            Value::Closure(closure) => {
-                let frame = self.frames.last_mut().unwrap();
-
-                frame
-                    .slots
-                    .insert(frame.slots.len() - arg_count as usize, Value::Nil);
-
-                self.call(closure, arg_count, false)
+                let bound_method = bound_method.read().clone();
+                self.set_callee_slot(bound_method.receiver.read().clone(), arg_count);
+                self.call(bound_method.method, arg_count)
             }
+            Value::Closure(closure) => self.call(closure, arg_count),
             Value::Class(class) => {
-                self.stack.pop();
-                let instance = Rc::new(RwLock::new(value::Instance::new(class.clone())));
-
-                let class = class.read();
-                let methods = class.methods.read();
-                let initializer = methods.get("init");
+                let instance =
+                    Value::Instance(Rc::new(RwLock::new(value::Instance::new(class.clone()))));
+                self.track(&instance);
+                self.set_callee_slot(instance, arg_count);
 
+                let initializer = class.read().methods.read().get(&Symbol::INIT).cloned();
                 match initializer {
-                    Some(initializer) => {
-                        let frame = self.frames.last_mut().unwrap();
-
-                        frame.slots.insert(
-                            frame.slots.len() - arg_count as usize,
-                            Value::Instance(instance.clone()),
-                        );
-
-                        if !self.call(initializer.clone(), arg_count, true) {
-                            return false;
-                        }
-                    }
-                    None => {
-                        if arg_count != 0 {
-                            self.runtime_error("Expected 0 arguments but got 1");
-                            return false;
-                        }
-                    }
+                    Some(initializer) => self.call(initializer, arg_count),
+                    None if arg_count != 0 => Err(self.runtime_error(
+                        ErrorKind::Arity,
+                        format!("Expected 0 arguments but got {}", arg_count).as_str(),
+                    )),
+                    None => Ok(()),
                 }
-
-                self.pop();
-                self.push(Value::Instance(instance.clone()));
-
-                true
             }
             Value::NativeFunction(function) => {
-                let result = self.native_call(function, arg_count);
+                let arity = function.read().arity;
+                if arg_count as usize != arity {
+                    return Err(self.runtime_error(
+                        ErrorKind::Arity,
+                        format!("Expected {} arguments but got {}", arity, arg_count).as_str(),
+                    ));
+                }
 
-                let frame = self.frames.last_mut().unwrap();
-                frame.slots.truncate(frame.slots.len() - arg_count as usize);
+                let result = self.native_call(function, arg_count);
+                if let Value::RunTimeError(kind, message) = result {
+                    return Err(self.runtime_error(kind, &message));
+                }
 
                 self.pop();
-                self.push(result);
-                true
-            }
-            _ => {
-                self.runtime_error("Can only call functions and classes");
-                false
+                self.push(result)?;
+                Ok(())
             }
+            _ => Err(self.runtime_error(ErrorKind::Type, "Can only call functions and classes")),
         }
     }
 
+    // Replaces the callee below the arguments, which becomes slot 0 of the
+    // frame `call` creates.
+    fn set_callee_slot(&mut self, value: Value, arg_count: u8) {
+        let slots = &mut self.frames.last_mut().unwrap().slots;
+        let index = slots.len() - 1 - arg_count as usize;
+        slots[index] = value;
+    }
+
     fn native_call(&mut self, function: Rc<RwLock<value::NativeFunction>>, arg_count: u8) -> Value {
         let mut args = Vec::new();
         for _ in 0..arg_count {
             args.push(self.pop().unwrap());
         }
         args.reverse();
-        (function.read().function)(args)
+        let native = function.read().function;
+        let result = match native {
+            value::NativeFn::Plain(native) => native(args),
+            value::NativeFn::Output(native) => native(args, &mut self.out),
+        };
+        self.track(&result);
+        result
     }
 
-    fn call(&mut self, closure: Box<Closure>, arg_count: u8, is_method: bool) -> bool {
-        if arg_count != closure.function.read().arity as u8 {
-            self.runtime_error(
-                format!(
-                    "Expected {} arguments but got {}",
-                    closure.function.read().arity,
-                    arg_count
-                )
-                .as_str(),
-            );
-            return false;
+    // Hands a new object to the cycle collector, collecting first if enough
+    // have piled up since the last time.
+    fn track(&mut self, value: &Value) {
+        if self.heap.should_collect() {
+            self.heap.collect();
         }
+        self.heap.track(value);
+    }
 
-        let frame = self.frames.last_mut().unwrap();
+    fn call(&mut self, closure: Rc<RwLock<Closure>>, arg_count: u8) -> Result<(), ErrorKind> {
+        let function = closure.read().function.clone();
+        let (arity, variadic) = (function.read().arity(), function.read().variadic());
+
+        let mut arg_count = arg_count as usize;
+        if variadic {
+            if arg_count < arity - 1 {
+                return Err(self.runtime_error(
+                    ErrorKind::Arity,
+                    format!(
+                        "Expected at least {} arguments but got {}",
+                        arity - 1,
+                        arg_count
+                    )
+                    .as_str(),
+                ));
+            }
+
+            // Surplus arguments become the array bound to the rest parameter.
+            let surplus = arg_count - (arity - 1);
+            let slots = &mut self.frames.last_mut().unwrap().slots;
+            let rest = slots.split_off(slots.len() - surplus);
+            self.stack_depth -= surplus;
+            let rest = Value::Array(Rc::new(RwLock::new(rest)));
+            self.track(&rest);
+            self.push(rest)?;
+            arg_count = arity;
+        }
+
+        if arg_count != arity {
+            return Err(self.runtime_error(
+                ErrorKind::Arity,
+                format!("Expected {} arguments but got {}", arity, arg_count).as_str(),
+            ));
+        }
 
-        if !is_method {
-            frame
-                .slots
-                .insert(frame.slots.len() - arg_count as usize, Value::Nil);
+        if self.frames.len() == FRAMES_MAX {
+            return Err(self.runtime_error(ErrorKind::Internal, "Stack overflow"));
         }
 
-        let slots = frame
-            .slots
-            .split_off(frame.slots.len() - arg_count as usize - 1);
+        // The callee (or the receiver, for methods) and its arguments move
+        // into the new frame, becoming slot 0 and the parameters.
+        let frame = self.frames.last_mut().unwrap();
+        let slots = frame.slots.split_off(frame.slots.len() - arg_count - 1);
 
         self.frames.push(CallFrame {
             closure,
             ip: 0,
             slots,
+            resources: Vec::new(),
         });
 
-        true
+        Ok(())
     }
 
-    fn runtime_error(&mut self, message: &str) {
-        eprintln!("{}", message);
+    fn runtime_error(&mut self, kind: ErrorKind, message: &str) -> ErrorKind {
+        let mut report = format!("{}\n", message);
 
         for frame in self.frames.iter().rev() {
-            let function = frame.closure.function.clone();
+            let function = frame.closure.read().function.clone();
             let function = function.read();
-            let chunk = function.chunk.read();
-            let instruction = chunk.code[frame.ip - 1];
-            let line = chunk.lines[frame.ip - 1];
-            eprintln!("[line {}] in {}", line, function.name);
-
-            match OpCode::from(instruction) {
-                OpCode::Call => eprintln!("    called here"),
-                OpCode::Closure => eprintln!("    defined here"),
+            let chunk = function.chunk();
+            // The instruction that was running: the last byte read. A frame
+            // that failed before reading anything points at its first byte,
+            // which an empty chunk doesn't have.
+            let offset = frame.ip.saturating_sub(1);
+            let line = chunk.lines.get(offset).copied().unwrap_or(0);
+            let column = chunk.columns.get(offset).copied().unwrap_or(0);
+            report.push_str(&format!(
+                "[line {}:col {}] in {}\n",
+                line,
+                column,
+                function.display_name()
+            ));
+
+            match chunk.code.get(offset).map(|&byte| OpCode::try_from(byte)) {
+                Some(Ok(OpCode::Call | OpCode::TailCall)) => report.push_str("    called here\n"),
+                Some(Ok(OpCode::Closure)) => report.push_str("    defined here\n"),
                 _ => (),
             }
         }
 
-        let mut frame = self.frames.last_mut().unwrap();
-
-        self.stack.truncate(frame.slots.len());
+        if self.report_errors {
+            eprint!("{}", report);
+        }
+        self.error_report = report;
 
-        if self.frames.len() == 1 {
-            self.stack.pop();
-        } else {
-            self.frames.pop();
-            frame = self.frames.last_mut().unwrap();
-            frame.ip += 1;
+        // Errors are never caught, so unwind everything: the next script
+        // starts from a clean VM and values held by dead frames are dropped.
+        // Files from `with` are closed even if they escaped the frame.
+        for frame in &self.frames {
+            for file in &frame.resources {
+                file.write().close();
+            }
         }
+        self.reset_stack();
+
+        kind
     }
 
-    fn define_native(
+    fn define_native(&mut self, name: String, function: fn(Vec<Value>) -> Value, arity: usize) {
+        self.define_native_fn(name, value::NativeFn::Plain(function), arity);
+    }
+
+    // Natives that print go through here, so their output lands wherever
+    // `print`'s does.
+    fn define_output_native(
         &mut self,
         name: String,
-        function: Box<fn(Vec<Value>) -> Value>,
+        function: fn(Vec<Value>, &mut dyn Write) -> Value,
         arity: usize,
     ) {
+        self.define_native_fn(name, value::NativeFn::Output(function), arity);
+    }
+
+    fn define_native_fn(&mut self, name: String, function: value::NativeFn, arity: usize) {
         self.stack.push(Value::String(name.clone()));
         let native_function = Rc::new(RwLock::new(value::NativeFunction::new(
             name.clone(),
@@ -806,64 +2097,120 @@ impl VM {
         )));
         self.stack
             .push(Value::NativeFunction(native_function.clone()));
-        self.globals
-            .insert(name.clone(), Value::NativeFunction(native_function));
+        self.globals.insert(
+            Symbol::intern(&name),
+            Value::NativeFunction(native_function),
+        );
         self.stack.pop();
         self.stack.pop();
     }
 
     #[inline(always)]
-    fn read_byte(&mut self) -> u8 {
-        let frame = self.frames.last_mut();
-        match frame {
+    fn read_byte(&mut self) -> Result<u8, ErrorKind> {
+        let byte = match self.frames.last_mut() {
             Some(frame) => {
-                let function = frame.closure.function.clone();
+                let function = frame.closure.read().function.clone();
                 let function = function.read();
-                let byte = function.chunk.read().code[frame.ip];
-                frame.ip += 1;
+                let byte = function.chunk().code.get(frame.ip).copied();
+                if byte.is_some() {
+                    frame.ip += 1;
+                }
                 byte
             }
             None => panic!("Expected frame"),
+        };
+
+        byte.ok_or_else(|| self.end_of_bytecode())
+    }
+
+    // Reads the constant index operand of `instruction`: a byte, or three
+    // for the long forms. Returns the constant it refers to.
+    #[inline(always)]
+    fn read_constant(&mut self, instruction: OpCode) -> Result<Value, ErrorKind> {
+        let mut index = self.read_byte()? as usize;
+        if instruction.is_long() {
+            index = index << 16 | self.read_short()? as usize;
         }
+        self.constant(index)
+    }
+
+    // The current function's constant at `index`. An index past the end of
+    // the table, from a compiler bug or a corrupt file, ends the run.
+    fn constant(&mut self, index: usize) -> Result<Value, ErrorKind> {
+        let constant = {
+            let function = self.frames.last().unwrap().closure.read().function.clone();
+            let function = function.read();
+            let constant = function.chunk().constants.get(index).cloned();
+            constant
+        };
+
+        constant.ok_or_else(|| {
+            self.runtime_error(
+                ErrorKind::Internal,
+                format!("Constant {} out of range", index).as_str(),
+            )
+        })
     }
 
     #[inline(always)]
-    fn read_constant(&mut self) -> Value {
-        let frame = self.frames.last_mut();
-        match frame {
-            Some(frame) => {
-                let constant = frame.closure.function.read().chunk.read().code[frame.ip];
-                frame.ip += 1;
-                frame.closure.function.read().chunk.read().constants[constant as usize].clone()
-            }
-            None => panic!("Expected frame"),
+    fn read_symbol(&mut self, instruction: OpCode) -> Result<Symbol, ErrorKind> {
+        match self.read_constant(instruction)? {
+            Value::Symbol(symbol) => Ok(symbol),
+            _ => panic!("Expected symbol"),
         }
     }
 
     #[inline(always)]
-    fn read_short(&mut self) -> u16 {
-        let frame = self.frames.last_mut();
-        match frame {
+    fn read_short(&mut self) -> Result<u16, ErrorKind> {
+        let short = match self.frames.last_mut() {
             Some(frame) => {
-                let function = frame.closure.function.clone();
+                let function = frame.closure.read().function.clone();
                 let function = function.read();
-                let byte1 = function.chunk.read().code[frame.ip];
-                let byte2 = function.chunk.read().code[frame.ip + 1];
-                frame.ip += 2;
-                (byte1 as u16) << 8 | byte2 as u16
+                let chunk = function.chunk();
+                match chunk.code.get(frame.ip..frame.ip + 2) {
+                    Some(&[byte1, byte2]) => {
+                        frame.ip += 2;
+                        Some((byte1 as u16) << 8 | byte2 as u16)
+                    }
+                    _ => None,
+                }
             }
             None => panic!("Expected frame"),
-        }
+        };
+
+        short.ok_or_else(|| self.end_of_bytecode())
+    }
+
+    // A chunk that stops partway through an instruction, from a compiler bug
+    // or a truncated file, ends the run instead of indexing past the end.
+    #[cold]
+    fn end_of_bytecode(&mut self) -> ErrorKind {
+        self.runtime_error(ErrorKind::Internal, "Unexpected end of bytecode")
     }
 
     #[inline(always)]
-    fn push(&mut self, value: Value) {
+    fn push(&mut self, value: Value) -> Result<(), ErrorKind> {
+        if self.stack_depth == STACK_MAX {
+            return Err(self.stack_overflow());
+        }
         self.frames.last_mut().unwrap().slots.push(value);
+        self.stack_depth += 1;
+        self.max_stack_depth = self.max_stack_depth.max(self.stack_depth);
+        Ok(())
+    }
+
+    #[cold]
+    fn stack_overflow(&mut self) -> ErrorKind {
+        self.runtime_error(ErrorKind::Internal, "Stack overflow")
     }
 
     #[inline(always)]
     fn pop(&mut self) -> Option<Value> {
-        self.frames.last_mut().unwrap().slots.pop()
+        let value = self.frames.last_mut().unwrap().slots.pop();
+        if value.is_some() {
+            self.stack_depth -= 1;
+        }
+        value
     }
 
     #[inline(always)]
@@ -872,3 +2219,2214 @@ impl VM {
         self.frames.last().unwrap().slots.get(len - 1 - distance)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Runs `source`, which must succeed, leaving its globals to inspect.
+    fn run(source: &str) -> VM {
+        let mut vm = VM::new();
+        assert!(vm.interpret(source.to_string()) == InterpretResult::Ok);
+        vm
+    }
+
+    // A VM printing into a buffer the test can read back.
+    fn captured_vm() -> (VM, OutputBuffer) {
+        let out = OutputBuffer::new();
+        (VM::with_writer(Box::new(out.clone())), out)
+    }
+
+    // A VM printing into a buffer, with error reports kept off stderr.
+    fn quiet_vm() -> (VM, OutputBuffer) {
+        let out = OutputBuffer::new();
+        let mut vm = VM::with_writer(Box::new(out.clone()));
+        vm.set_report_errors(false);
+        (vm, out)
+    }
+
+    // A writer that counts the flushes it gets.
+    struct Flushes(Rc<RwLock<usize>>);
+
+    impl Write for Flushes {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            *self.0.write() += 1;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn bytes_round_trip_through_a_file() {
+        let dir = std::env::temp_dir();
+        let input = dir.join(format!("ci-bytes-in-{}", std::process::id()));
+        let output = dir.join(format!("ci-bytes-out-{}", std::process::id()));
+        let path = |path: &std::path::Path| Value::String(path.display().to_string());
+        std::fs::write(&input, [0u8, 7, 255, 10]).unwrap();
+
+        let bytes = read_bytes_native(vec![path(&input)]);
+        assert_eq!(bytes.to_string(), "<bytes 4>");
+        assert_eq!(len_native(vec![bytes.clone()]), Value::Int(4));
+        assert_eq!(
+            write_bytes_native(vec![path(&output), bytes.clone()]),
+            Value::Bool(true)
+        );
+        assert_eq!(std::fs::read(&output).unwrap(), [0u8, 7, 255, 10]);
+
+        let vm = run(&format!(
+            "var b = read_bytes(\"{}\"); var n = len(b); var ok = write_bytes(\"{}\", b);",
+            output.display(),
+            input.display()
+        ));
+        assert_eq!(vm.get_global("n"), Some(Value::Int(4)));
+        assert_eq!(vm.get_global("ok"), Some(Value::Bool(true)));
+
+        std::fs::remove_file(&input).unwrap();
+        std::fs::remove_file(&output).unwrap();
+        assert!(matches!(
+            read_bytes_native(vec![path(&input)]),
+            Value::RunTimeError(..)
+        ));
+        assert!(matches!(
+            write_bytes_native(vec![path(&output), Value::Int(1)]),
+            Value::RunTimeError(..)
+        ));
+        assert!(matches!(
+            len_native(vec![Value::Nil]),
+            Value::RunTimeError(..)
+        ));
+    }
+
+    #[test]
+    fn parse_int_takes_a_radix() {
+        let parse = |s: &str, radix: i64| {
+            parse_int_native(vec![Value::String(s.to_string()), Value::Int(radix)])
+        };
+        assert_eq!(parse("ff", 16), Value::Int(255));
+        assert_eq!(parse("-101", 2), Value::Int(-5));
+        assert_eq!(parse("z", 36), Value::Int(35));
+        for (digits, radix) in [("z", 10), ("1", 1), ("1", 37)] {
+            assert!(matches!(parse(digits, radix), Value::RunTimeError(..)));
+        }
+
+        let vm = run("var n = parse_int(\"ff\", 16);");
+        assert_eq!(vm.get_global("n"), Some(Value::Int(255)));
+    }
+
+    #[test]
+    fn break_leaves_the_stack_balanced() {
+        let vm = run("var a; var b;
+             for (var run = 0; run < 2; run = run + 1) {
+                 var before = 10;
+                 var i = 0;
+                 while (true) {
+                     var inside = i * 2;
+                     if (i == 3) { var deeper = inside; break; }
+                     i = i + 1;
+                 }
+                 var after = 5;
+                 while (i < 100) { i = i + 1; if (i > 6) break; }
+                 b = a;
+                 a = before * 10000 + i * 100 + after;
+             }");
+        assert_eq!(vm.get_global("a"), Some(Value::Int(100705)));
+        assert_eq!(vm.get_global("b"), Some(Value::Int(100705)));
+    }
+
+    #[test]
+    fn properties_and_methods_resolve_by_interned_name() {
+        let mut vm = run("class P { get() { return 1; } }
+             var p = P();
+             p.x = 1; p.y = p.x + 1;
+             var m = p.get;
+             var a = p.y; var b = p.get(); var c = m();");
+        assert_eq!(vm.get_global("a"), Some(Value::Int(2)));
+        assert_eq!(vm.get_global("b"), Some(Value::Int(1)));
+        assert_eq!(vm.get_global("c"), Some(Value::Int(1)));
+
+        assert!(matches!(
+            vm.interpret("p.missing();".to_string()),
+            InterpretResult::RuntimeError(ErrorKind::Name, _)
+        ));
+    }
+
+    #[test]
+    fn math_natives_keep_the_numeric_kind_where_exact() {
+        assert_eq!(abs_native(vec![Value::Int(-3)]), Value::Int(3));
+        assert_eq!(abs_native(vec![Value::Float(-2.5)]), Value::Float(2.5));
+        assert!(matches!(
+            abs_native(vec![Value::Int(i64::MIN)]),
+            Value::RunTimeError(..)
+        ));
+        assert_eq!(sqrt_native(vec![Value::Int(4)]), Value::Float(2.0));
+
+        let vm = run("var a = abs(-3); var b = sqrt(4);");
+        assert_eq!(vm.get_global("a"), Some(Value::Int(3)));
+        assert_eq!(vm.get_global("b"), Some(Value::Float(2.0)));
+    }
+
+    #[test]
+    fn runtime_errors_carry_their_kind() {
+        let cases = [
+            ("1 + nil;", ErrorKind::Type),
+            ("-\"a\";", ErrorKind::Type),
+            ("nil();", ErrorKind::Type),
+            ("undefined_name;", ErrorKind::Name),
+            ("fun f(a) {} f();", ErrorKind::Arity),
+        ];
+        for (source, expected) in cases {
+            let mut vm = VM::new();
+            assert!(
+                matches!(vm.interpret(source.to_string()), InterpretResult::RuntimeError(actual, _) if actual == expected),
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn globals_lists_nil_globals_but_not_undefined_ones() {
+        let vm = run("var x; var a = 1;");
+        assert!(
+            vm.globals()
+                == vec![
+                    ("a".to_string(), Value::Int(1)),
+                    ("x".to_string(), Value::Nil)
+                ]
+        );
+    }
+
+    #[test]
+    fn operand_errors_name_the_operator_and_types() {
+        assert_eq!(operator_symbol(OpCode::Less), "<");
+        assert_eq!(operator_symbol(OpCode::Multiply), "*");
+        assert_eq!(Value::Int(1).type_name(), "int");
+        assert_eq!(Value::Bool(true).type_name(), "bool");
+        assert_eq!(Value::String("a".to_string()).type_name(), "string");
+
+        for source in ["true * 2;", "nil < 1;", "\"a\" + 1;"] {
+            let mut vm = VM::new();
+            assert!(
+                matches!(
+                    vm.interpret(source.to_string()),
+                    InterpretResult::RuntimeError(ErrorKind::Type, _)
+                ),
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn continue_in_a_for_loop_runs_the_increment() {
+        let vm = run(
+            "var sum = 0;
+             var last;
+             for (var i = 0; i < 10; i = i + 1) {
+                 last = i;
+                 if (i != 3 and i != 6) continue;
+                 sum = sum + i;
+             }
+             var j = 0;
+             var odd = 0;
+             while (j < 6) { j = j + 1; if (j == 2 or j == 4 or j == 6) continue; odd = odd + 1; }",
+        );
+        assert_eq!(vm.get_global("sum"), Some(Value::Int(9)));
+        assert_eq!(vm.get_global("last"), Some(Value::Int(9)));
+        assert_eq!(vm.get_global("odd"), Some(Value::Int(3)));
+    }
+
+    #[test]
+    fn a_closure_shared_by_a_variable_and_a_field_shares_upvalues() {
+        let vm = run("fun counter() {
+                 var n = 0;
+                 fun bump() { n = n + 1; return n; }
+                 return bump;
+             }
+             class Holder {
+                 init(f) { this.f = f; }
+                 call() { return this.f(); }
+             }
+             var bump = counter();
+             var holder = Holder(bump);
+             bump();
+             holder.call();
+             holder.f();
+             var a = bump();
+             var other = counter();
+             var b = other();
+             var peeker;
+             fun make() {
+                 var n = 0;
+                 class C { inc() { n = n + 1; return n; } }
+                 fun peek() { return n; }
+                 peeker = peek;
+                 return C();
+             }
+             var c = make();
+             var inc = c.inc;
+             inc(); c.inc();
+             var d = peeker();");
+        assert_eq!(vm.get_global("a"), Some(Value::Int(4)));
+        assert_eq!(vm.get_global("b"), Some(Value::Int(1)));
+        assert_eq!(vm.get_global("d"), Some(Value::Int(2)));
+    }
+
+    #[test]
+    fn functions_are_equal_only_to_themselves() {
+        let vm = run("fun f() {} fun g() {}
+             var a = f == g; var b = f == f;
+             var h = f; var c = h == f;
+             fun make() { fun inner() {} return inner; }
+             var d = make() == make();");
+        assert_eq!(vm.get_global("a"), Some(Value::Bool(false)));
+        assert_eq!(vm.get_global("b"), Some(Value::Bool(true)));
+        assert_eq!(vm.get_global("c"), Some(Value::Bool(true)));
+        assert_eq!(vm.get_global("d"), Some(Value::Bool(false)));
+    }
+
+    #[test]
+    fn recursion_runs_up_to_the_frame_limit() {
+        let depth = "fun depth(n) { if (n == 0) return 0; return 1 + depth(n - 1); }";
+        let deepest = FRAMES_MAX - 2;
+        let vm = run(&format!("{} var d = depth({});", depth, deepest));
+        assert_eq!(vm.get_global("d"), Some(Value::Int(deepest as i64)));
+        assert_eq!(vm.frames.capacity(), FRAMES_MAX);
+
+        let mut vm = VM::new();
+        assert!(matches!(
+            vm.interpret(format!("{} depth({});", depth, FRAMES_MAX)),
+            InterpretResult::RuntimeError(ErrorKind::Internal, _)
+        ));
+    }
+
+    #[test]
+    fn modulo_keeps_the_sign_of_the_dividend() {
+        let vm = run("var a = 7 % 3; var b = (-7) % 3; var c = 7 % -3;
+             var d = 7.5 % 2; var e = 7 % 2.5;
+             var f = (-9223372036854775807 - 1) % -1;
+             var g = 7.0 % 0;");
+        let global = |name: &str| vm.get_global(name);
+        assert_eq!(global("a"), Some(Value::Int(1)));
+        assert_eq!(global("b"), Some(Value::Int(-1)));
+        assert_eq!(global("c"), Some(Value::Int(1)));
+        assert_eq!(global("d"), Some(Value::Float(1.5)));
+        assert_eq!(global("e"), Some(Value::Float(2.0)));
+        assert_eq!(global("f"), Some(Value::Int(0)));
+        assert!(matches!(global("g"), Some(Value::Float(f)) if f.is_nan()));
+
+        let mut vm = VM::new();
+        assert!(matches!(
+            vm.interpret("7 % 0;".to_string()),
+            InterpretResult::RuntimeError(ErrorKind::DivByZero, _)
+        ));
+    }
+
+    #[test]
+    fn integer_division_by_zero_is_a_runtime_error() {
+        for source in [
+            "var a = 1/0;",
+            "var a = 1 % 0;",
+            "var z = 0; var a = 10 / z;",
+        ] {
+            let mut vm = VM::new();
+            assert!(
+                matches!(
+                    vm.interpret(source.to_string()),
+                    InterpretResult::RuntimeError(ErrorKind::DivByZero, _)
+                ),
+                "{}",
+                source
+            );
+            assert_eq!(vm.get_global("a"), None);
+        }
+
+        let vm = run("var a = 1.0 / 0; var b = -1 / 0.0; var c = 0.0 / 0;");
+        assert_eq!(vm.get_global("a"), Some(Value::Float(f64::INFINITY)));
+        assert_eq!(vm.get_global("b"), Some(Value::Float(f64::NEG_INFINITY)));
+        assert!(matches!(vm.get_global("c"), Some(Value::Float(f)) if f.is_nan()));
+    }
+
+    #[test]
+    fn integer_overflow_is_a_runtime_error() {
+        let limits = "var max = 9223372036854775807; var min = -max - 1;";
+        for source in [
+            "max + 1;",
+            "min - 1;",
+            "max * 2;",
+            "min * -1;",
+            "-min;",
+            "min / -1;",
+        ] {
+            let mut vm = VM::new();
+            assert!(
+                matches!(
+                    vm.interpret(format!("{} {}", limits, source)),
+                    InterpretResult::RuntimeError(ErrorKind::Overflow, _)
+                ),
+                "{}",
+                source
+            );
+        }
+
+        let vm = run(&format!(
+            "{} var a = (max - 1) + 1 == max; var b = -max;",
+            limits
+        ));
+        assert_eq!(vm.get_global("a"), Some(Value::Bool(true)));
+        assert_eq!(vm.get_global("b"), Some(Value::Int(-i64::MAX)));
+        assert_eq!(vm.get_global("min"), Some(Value::Int(i64::MIN)));
+    }
+
+    #[test]
+    fn native_errors_are_raised_with_their_kind() {
+        let cases = [
+            ("assert(false);", ErrorKind::Assertion),
+            ("throw(\"up\");", ErrorKind::Thrown),
+            ("abs(nil);", ErrorKind::Type),
+        ];
+        for (source, expected) in cases {
+            let mut vm = VM::new();
+            assert!(
+                matches!(vm.interpret(source.to_string()), InterpretResult::RuntimeError(actual, _) if actual == expected),
+                "{}",
+                source
+            );
+        }
+        let vm = run("assert(true); var a = 1;");
+        assert_eq!(vm.get_global("a"), Some(Value::Int(1)));
+    }
+
+    #[test]
+    fn continue_discards_the_body_locals() {
+        let vm = run("var sum = 0;
+             var count = 0;
+             var keep;
+             for (var i = 0; i < 10; i = i + 1) {
+                 var a = i;
+                 var b = a * 2;
+                 fun get() { return b; }
+                 if (i == 5) { keep = get; continue; }
+                 count = count + 1;
+                 sum = sum + b;
+             }
+             var kept = keep();");
+        assert_eq!(vm.get_global("count"), Some(Value::Int(9)));
+        assert_eq!(vm.get_global("sum"), Some(Value::Int(80)));
+        assert_eq!(vm.get_global("kept"), Some(Value::Int(10)));
+    }
+
+    #[test]
+    fn functions_print_with_their_name() {
+        let vm = run("fun named() {} class A { m() {} } var m = A().m;");
+        let shown = |name: &str| vm.get_global(name).unwrap().to_string();
+        assert_eq!(shown("named"), "<fn named>");
+        assert_eq!(shown("m"), "<bound method m>");
+    }
+
+    #[test]
+    fn records_the_stack_high_water_mark() {
+        let mut vm = VM::new();
+        assert_eq!(vm.max_stack_used(), 0);
+        vm.interpret("var a = 1 + 2;".to_string());
+        // The script itself, then both operands.
+        assert_eq!(vm.max_stack_used(), 3);
+
+        vm.interpret("var b = 1;".to_string());
+        assert_eq!(vm.max_stack_used(), 3);
+
+        vm.interpret("fun down(n) { if (n > 0) down(n - 1); } down(100);".to_string());
+        // Each call holds at least the callee and its argument.
+        let used = vm.max_stack_used();
+        assert!((200..400).contains(&used), "{}", used);
+    }
+
+    #[test]
+    fn compound_assignment_updates_every_kind_of_variable() {
+        let vm = run("var g = 10;
+             g += 5; g -= 3; g *= 4; g /= 6;
+             fun f() {
+                 var l = 1;
+                 l += 2;
+                 l -= 5;
+                 return l;
+             }
+             var r = f();
+             fun counter() {
+                 var n = 0;
+                 fun inc() { n += 2; return n; }
+                 return inc;
+             }
+             var c = counter();
+             c();
+             var k = c();
+             class P { init() { this.x = 1; } }
+             var p = P();
+             p.x += 41;
+             var x = p.x;
+             var s = \"a\";
+             s += \"b\";
+             var h = g += 1;");
+        assert_eq!(vm.get_global("r"), Some(Value::Int(-2)));
+        assert_eq!(vm.get_global("k"), Some(Value::Int(4)));
+        assert_eq!(vm.get_global("x"), Some(Value::Int(42)));
+        assert_eq!(vm.get_global("s"), Some(Value::String("ab".to_string())));
+        assert_eq!(vm.get_global("g"), Some(Value::Int(9)));
+        assert_eq!(vm.get_global("h"), Some(Value::Int(9)));
+    }
+
+    #[test]
+    fn repeat_runs_its_body_count_times() {
+        let vm = run("var xs = 0;
+             repeat (3) { xs = xs + 1; }
+             var never = 0;
+             repeat (0) { never = 1; }
+             var calls = 0;
+             fun count() { calls = calls + 1; return 2; }
+             var runs = 0;
+             repeat (count()) { runs = runs + 1; }
+             var n = 0;
+             repeat (10) { n = n + 1; if (n == 4) break; }");
+        for (name, expected) in [("xs", 3), ("never", 0), ("calls", 1), ("runs", 2), ("n", 4)] {
+            assert_eq!(vm.get_global(name), Some(Value::Int(expected)), "{}", name);
+        }
+
+        for source in ["repeat (-1) {}", "repeat (1.5) {}", "repeat (\"3\") {}"] {
+            let mut vm = VM::new();
+            assert!(
+                matches!(
+                    vm.interpret(source.to_string()),
+                    InterpretResult::RuntimeError(ErrorKind::Type, _)
+                ),
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn integer_power() {
+        let huge = "4294967296";
+        let vm = run(&format!(
+            "var a = 2 ** 10; var b = 2 ** -1; var c = 2.0 ** 0.5;
+             var d = 0 ** {0}; var e = 1 ** {0}; var f = (-1) ** {0}; var g = (-1) ** ({0} + 1);
+             var h = 2 ** 3 ** 2; var i = 10 - 1 + 1; var j = 2 * 3 % 4;",
+            huge
+        ));
+        let global = |name: &str| vm.get_global(name);
+        assert_eq!(global("a"), Some(Value::Int(1024)));
+        assert_eq!(global("b"), Some(Value::Float(0.5)));
+        assert_eq!(global("c"), Some(Value::Float(2f64.sqrt())));
+        assert_eq!(global("d"), Some(Value::Int(0)));
+        assert_eq!(global("e"), Some(Value::Int(1)));
+        assert_eq!(global("f"), Some(Value::Int(1)));
+        assert_eq!(global("g"), Some(Value::Int(-1)));
+        // `**` is right-associative.
+        assert_eq!(global("h"), Some(Value::Int(512)));
+        // The other binary operators associate to the left.
+        assert_eq!(global("i"), Some(Value::Int(10)));
+        assert_eq!(global("j"), Some(Value::Int(2)));
+
+        for source in [format!("2 ** {};", huge), "2 ** 63;".to_string()] {
+            let mut vm = VM::new();
+            assert!(
+                matches!(
+                    vm.interpret(source.clone()),
+                    InterpretResult::RuntimeError(ErrorKind::Overflow, _)
+                ),
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn with_closes_files_however_its_scope_ends() {
+        let path = std::env::temp_dir().join(format!("ci-with-{}", std::process::id()));
+        std::fs::write(&path, "one\ntwo\nthree\n").unwrap();
+        let open = format!("var path = \"{}\"; var handle;", path.display());
+        let closed = |vm: &VM| matches!(vm.get_global("handle"), Some(Value::File(file)) if file.read().is_closed());
+
+        let vm = run(&format!(
+            "{} var lines = \"\";
+             with (f = open(path)) {{
+                 var line = f.readline();
+                 while (line != nil) {{ lines = lines + line; line = f.readline(); }}
+                 handle = f;
+             }}",
+            open
+        ));
+        assert_eq!(
+            vm.get_global("lines"),
+            Some(Value::String("onetwothree".to_string()))
+        );
+        assert!(closed(&vm));
+
+        let vm = run(&format!(
+            "{} fun first() {{ with (f = open(path)) {{ handle = f; return f.readline(); }} }}
+             var line = first();",
+            open
+        ));
+        assert_eq!(
+            vm.get_global("line"),
+            Some(Value::String("one".to_string()))
+        );
+        assert!(closed(&vm));
+
+        let vm = run(&format!(
+            "{} while (true) {{ with (f = open(path)) {{ handle = f; break; }} }}",
+            open
+        ));
+        assert!(closed(&vm));
+
+        for body in ["nil + 1;", "f.write(\"read only\");", "f.write(1);"] {
+            let mut vm = VM::new();
+            let source = format!("{} with (f = open(path)) {{ handle = f; {} }}", open, body);
+            assert!(vm.interpret(source) != InterpretResult::Ok, "{}", body);
+            assert!(closed(&vm), "{}", body);
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn with_rejects_values_that_are_not_files() {
+        for resource in ["5", "nil", "\"path\""] {
+            let mut vm = VM::new();
+            let source = format!("var ran = false; with (f = {}) {{ ran = true; }}", resource);
+            assert!(
+                matches!(
+                    vm.interpret(source),
+                    InterpretResult::RuntimeError(ErrorKind::Type, _)
+                ),
+                "{}",
+                resource
+            );
+            assert_eq!(vm.get_global("ran"), Some(Value::Bool(false)));
+        }
+    }
+
+    #[test]
+    fn flush_is_callable_from_scripts() {
+        assert_eq!(flush_native(vec![], &mut Vec::new()), Value::Nil);
+
+        let mut vm = run("flush(); var a = flush();");
+        assert_eq!(vm.get_global("a"), Some(Value::Nil));
+
+        assert!(!vm.interactive);
+        vm.set_interactive(true);
+        assert!(vm.interpret("print 1; flush();".to_string()) == InterpretResult::Ok);
+    }
+
+    #[test]
+    fn chained_comparisons_evaluate_each_operand_once() {
+        let vm = run("var calls = 0;
+             var seen = 0;
+             fun mid(x) { calls = calls + 1; seen = seen * 10 + x; return x; }
+             var a = 1 < mid(2) < 3;
+             var b = 1 < mid(0) < 3;
+             var c = 3 > mid(2) >= 2 == true;
+             var d = calls;
+             var e = 5 < mid(1) < mid(9);");
+        let global = |name: &str| vm.get_global(name);
+        assert_eq!(global("a"), Some(Value::Bool(true)));
+        assert_eq!(global("b"), Some(Value::Bool(false)));
+        assert_eq!(global("c"), Some(Value::Bool(true)));
+        assert_eq!(global("d"), Some(Value::Int(3)));
+        assert_eq!(global("e"), Some(Value::Bool(false)));
+        // A failed link skips the rest of the chain, so mid(9) never ran.
+        assert_eq!(global("seen"), Some(Value::Int(2021)));
+    }
+
+    #[test]
+    fn radix_prefixed_literals_are_plain_ints() {
+        let vm = run("var a = 0xFF; var b = 0b1010 + 0o10; var c = -0x10;");
+        assert_eq!(vm.get_global("a"), Some(Value::Int(255)));
+        assert_eq!(vm.get_global("b"), Some(Value::Int(18)));
+        assert_eq!(vm.get_global("c"), Some(Value::Int(-16)));
+    }
+
+    #[test]
+    fn sets_deduplicate_their_elements() {
+        let vm = run("var s = set();
+             var a = add(s, 1); var b = add(s, 1); var c = add(s, \"a\"); var d = add(s, nil);
+             var full = s;
+             var e = has(s, 1); var f = has(s, \"b\");
+             var g = remove(s, 1); var h = remove(s, 1);");
+        let global = |name: &str| vm.get_global(name);
+        for (name, expected) in [
+            ("a", true),
+            ("b", false),
+            ("c", true),
+            ("d", true),
+            ("e", true),
+            ("f", false),
+            ("g", true),
+            ("h", false),
+        ] {
+            assert_eq!(global(name), Some(Value::Bool(expected)), "{}", name);
+        }
+        assert_eq!(global("s").unwrap().to_string(), "<set 2>");
+
+        for source in [
+            "fun f() {} add(set(), f);",
+            "class C {} has(set(), C());",
+            "add(1, 2);",
+        ] {
+            let mut vm = VM::new();
+            assert!(
+                matches!(
+                    vm.interpret(source.to_string()),
+                    InterpretResult::RuntimeError(ErrorKind::Type, _)
+                ),
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn runs_independent_scripts_in_one_vm() {
+        let mut vm = VM::new();
+        assert!(vm.run_script("var greeting = \"hi\";".to_string()) == InterpretResult::Ok);
+        assert!(matches!(
+            vm.run_script(
+                "var loud = greeting + \"!\"; fun f() { return nil + 1; } f();".to_string()
+            ),
+            InterpretResult::RuntimeError(ErrorKind::Type, _)
+        ));
+        assert!(vm.frames.is_empty());
+
+        // The failed script's frames are gone: the next one starts at the top.
+        assert!(
+            vm.run_script("var copy = greeting; var two = 1 + 1;".to_string())
+                == InterpretResult::Ok
+        );
+        assert_eq!(
+            vm.get_global("loud"),
+            Some(Value::String("hi!".to_string()))
+        );
+        assert_eq!(vm.get_global("copy"), Some(Value::String("hi".to_string())));
+        assert_eq!(vm.get_global("two"), Some(Value::Int(2)));
+        assert!(vm.run_script("print ;".to_string()) == InterpretResult::CompileError);
+    }
+
+    #[test]
+    fn array_literals_build_arrays() {
+        let vm = run("var x = 2;
+             var a = [1, x, x * 2];
+             var b = [];
+             var c = [[1], [2, 3]] == [[1], [2, 3]];
+             var d = [1, 2] == [1, 2, 3];
+             var e = a; var f = a == e;");
+        let shown = |name: &str| vm.get_global(name).unwrap().to_string();
+        assert_eq!(shown("a"), "[1, 2, 4]");
+        assert_eq!(shown("b"), "[]");
+        assert_eq!(shown("c"), "true");
+        assert_eq!(shown("d"), "false");
+        assert_eq!(shown("f"), "true");
+
+        let elements = |count: usize| vec!["0"; count].join(", ");
+        let mut vm = VM::new();
+        assert!(vm.interpret(format!("var a = [{}];", elements(255))) == InterpretResult::Ok);
+        assert!(
+            vm.interpret(format!("var a = [{}];", elements(256))) == InterpretResult::CompileError
+        );
+    }
+
+    #[test]
+    fn runtime_errors_unwind_every_frame() {
+        let mut vm = VM::new();
+        let failed = vm.interpret(
+            "fun c(x) { var local = [x]; return local + 1; }
+             fun b(x) { var y = x * 2; return c(y) + y; }
+             fun a() { return 1 + b(3); }
+             a();"
+                .to_string(),
+        );
+        assert!(matches!(
+            failed,
+            InterpretResult::RuntimeError(ErrorKind::Type, _)
+        ));
+        assert!(vm.frames.is_empty());
+        assert!(vm.stack.is_empty());
+        assert_eq!(vm.stack_depth, 0);
+
+        assert!(
+            vm.interpret("var z = 4; var zz = z * z; var f = a;".to_string())
+                == InterpretResult::Ok
+        );
+        assert_eq!(vm.get_global("zz"), Some(Value::Int(16)));
+        assert_eq!(vm.get_global("f").unwrap().to_string(), "<fn a>");
+        assert!(vm.frames.is_empty());
+    }
+
+    #[test]
+    fn abort_ends_the_run_with_its_own_kind() {
+        let mut vm = VM::new();
+        let result = vm.run_script(
+            "var last = 0;
+             fun check(n) { if (n > 2) abort(\"too big\"); last = n; }
+             for (var i = 1; i < 5; i = i + 1) check(i);"
+                .to_string(),
+        );
+        assert!(matches!(
+            result,
+            InterpretResult::RuntimeError(ErrorKind::Abort, _)
+        ));
+        assert_eq!(vm.get_global("last"), Some(Value::Int(2)));
+
+        // `throw` raises the catchable kind instead.
+        assert!(matches!(
+            vm.run_script("throw(\"oops\");".to_string()),
+            InterpretResult::RuntimeError(ErrorKind::Thrown, _)
+        ));
+    }
+
+    #[test]
+    fn index_reads_and_writes_arrays_and_strings() {
+        let mut vm = run("var xs = [10, 20, 30];
+             var sum = xs[0] + xs[2];
+             var set = xs[1] = 25;
+             var copy = xs[0] = xs[1] = 7;
+             var ch = \"hey\"[1];
+             var grid = [[1, 2], [3, 4]];
+             grid[1][0] = grid[1][0] + 10;");
+        let shown = |vm: &VM, name: &str| vm.get_global(name).unwrap().to_string();
+        assert_eq!(shown(&vm, "sum"), "40");
+        assert_eq!(shown(&vm, "set"), "25");
+        assert_eq!(shown(&vm, "xs"), "[7, 7, 30]");
+        assert_eq!(shown(&vm, "copy"), "7");
+        assert_eq!(shown(&vm, "ch"), "e");
+        assert_eq!(shown(&vm, "grid"), "[[1, 2], [13, 4]]");
+
+        for (source, kind) in [
+            ("xs[3];", ErrorKind::Index),
+            ("xs[3] = 1;", ErrorKind::Index),
+            ("\"hey\"[3];", ErrorKind::Index),
+            ("xs[1.0];", ErrorKind::Type),
+            ("xs[\"0\"] = 1;", ErrorKind::Type),
+            ("5[0];", ErrorKind::Type),
+        ] {
+            assert!(
+                matches!(vm.run_script(source.to_string()), InterpretResult::RuntimeError(actual, _) if actual == kind),
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn arrays_can_contain_themselves() {
+        let vm = run("var a = [1]; a[0] = a;
+             var b = [1]; b[0] = b;
+             var same = a == b;
+             var c = [2, 3]; c[1] = c;
+             var different = a == c;");
+        assert_eq!(vm.get_global("a").unwrap().to_string(), "[[...]]");
+        assert_eq!(vm.get_global("c").unwrap().to_string(), "[2, [...]]");
+        assert_eq!(vm.get_global("same"), Some(Value::Bool(true)));
+        assert_eq!(vm.get_global("different"), Some(Value::Bool(false)));
+    }
+
+    #[test]
+    fn line_intrinsic_gives_its_line() {
+        let vm = run(&format!(
+            "{}var a = __line__;\nfun f() {{\n  return __line__;\n}}\nvar b = f();",
+            "\n".repeat(9)
+        ));
+        assert_eq!(vm.get_global("a"), Some(Value::Int(10)));
+        assert_eq!(vm.get_global("b"), Some(Value::Int(12)));
+    }
+
+    #[test]
+    fn subclasses_inherit_and_call_super() {
+        let mut vm = run("class A {
+                 init(name) { this.name = name; }
+                 greet() { return \"A:\" + this.name; }
+                 shout() { return this.greet() + \"!\"; }
+             }
+             class B < A {
+                 init(name) { super.init(name + \"b\"); }
+                 greet() { return \"B>\" + super.greet(); }
+             }
+             class C < B {}
+             var c = C(\"x\");
+             var shouted = c.shout();
+             var method = B(\"y\").greet;
+             var greeted = method();");
+        assert_eq!(
+            vm.get_global("shouted"),
+            Some(Value::String("B>A:xb!".to_string()))
+        );
+        assert_eq!(
+            vm.get_global("greeted"),
+            Some(Value::String("B>A:yb".to_string()))
+        );
+
+        assert!(matches!(
+            vm.run_script("var NotAClass = 1; class D < NotAClass {}".to_string()),
+            InterpretResult::RuntimeError(ErrorKind::Type, _)
+        ));
+    }
+
+    #[test]
+    fn type_names_the_class_of_instances() {
+        let vm = run("class Animal {} class Dog < Animal {}
+             var d = Dog();
+             var names = [type(d), type(Dog), type(Animal()), type(1), type(nil)];
+             var is_dog = type(d) == \"Dog\";");
+        assert_eq!(
+            vm.get_global("names").unwrap().to_string(),
+            "[Dog, class, Animal, int, nil]"
+        );
+        assert_eq!(vm.get_global("is_dog"), Some(Value::Bool(true)));
+    }
+
+    #[test]
+    fn lambdas_can_be_passed_to_functions() {
+        let mut vm = run("fun map(xs, f) {
+                 for (var i = 0; i < len(xs); i = i + 1) xs[i] = f(xs[i]);
+                 return xs;
+             }
+             var factor = 3;
+             var mapped = map([1, 2, 3], fun(x) { return x * factor; });
+             var called = fun(a, b) { return a - b; }(10, 4);
+             var f = fun(x) { return x; };");
+        let shown = |vm: &VM, name: &str| vm.get_global(name).unwrap().to_string();
+        assert_eq!(shown(&vm, "mapped"), "[3, 6, 9]");
+        assert_eq!(shown(&vm, "called"), "6");
+        assert_eq!(shown(&vm, "f"), "<fn anonymous>");
+
+        assert!(matches!(
+            vm.run_script("map([1], fun(x) { return x + nil; });".to_string()),
+            InterpretResult::RuntimeError(ErrorKind::Type, _)
+        ));
+    }
+
+    #[test]
+    fn negative_indices_count_from_the_end() {
+        let mut vm = run("var a = \"abc\"[-1] == \"c\" and [1, 2, 3][-2] == 2;
+             var b = \"abc\"[-3];
+             var xs = [1, 2, 3]; xs[-1] = 9; var c = xs[2];");
+        assert_eq!(vm.get_global("a"), Some(Value::Bool(true)));
+        assert_eq!(vm.get_global("b"), Some(Value::String("a".to_string())));
+        assert_eq!(vm.get_global("c"), Some(Value::Int(9)));
+
+        for source in ["\"abc\"[-4];", "xs[-4];", "xs[-4] = 0;", "[][-1];"] {
+            assert!(
+                matches!(
+                    vm.run_script(source.to_string()),
+                    InterpretResult::RuntimeError(ErrorKind::Index, _)
+                ),
+                "{}",
+                source
+            );
+        }
+    }
+
+    // Runs a script function whose chunk holds exactly `code`.
+    fn run_code(vm: &mut VM, code: &[u8]) -> Result<(), ErrorKind> {
+        let function = value::Function::new_script();
+        for &byte in code {
+            function.chunk.write().write(byte, 1, 1);
+        }
+        let closure = Rc::new(RwLock::new(Closure::new(Rc::new(RwLock::new(function)))));
+        vm.frames.push(CallFrame {
+            closure: closure.clone(),
+            ip: 0,
+            slots: vec![Value::Closure(closure)],
+            resources: Vec::new(),
+        });
+        vm.run()
+    }
+
+    #[test]
+    fn empty_chunk_is_a_runtime_error() {
+        let mut vm = VM::new();
+        assert!(matches!(run_code(&mut vm, &[]), Err(ErrorKind::Internal)));
+        assert!(vm.frames.is_empty());
+    }
+
+    #[test]
+    fn constant_out_of_range_is_a_runtime_error() {
+        let mut vm = VM::new();
+        assert!(matches!(
+            run_code(&mut vm, &[OpCode::Constant as u8, 5]),
+            Err(ErrorKind::Internal)
+        ));
+    }
+
+    #[test]
+    fn truncated_operand_is_a_runtime_error() {
+        let mut vm = VM::new();
+        assert!(matches!(
+            run_code(&mut vm, &[OpCode::Jump as u8, 0]),
+            Err(ErrorKind::Internal)
+        ));
+    }
+
+    #[test]
+    fn rest_parameters_collect_surplus_arguments() {
+        let vm = run("fun sum(first, ...rest) {
+                 var total = first;
+                 for (var i = 0; i < len(rest); i = i + 1) total = total + rest[i];
+                 return total;
+             }
+             fun rest_of(first, ...rest) { return rest; }
+             var all = sum(1, 2, 3, 4);
+             var one = sum(5);
+             var none = rest_of(1);
+             var some = rest_of(1, [2], nil);");
+        assert_eq!(vm.get_global("all"), Some(Value::Int(10)));
+        assert_eq!(vm.get_global("one"), Some(Value::Int(5)));
+        assert_eq!(vm.get_global("none").unwrap().to_string(), "[]");
+        assert_eq!(vm.get_global("some").unwrap().to_string(), "[[2], nil]");
+
+        let mut vm = vm;
+        assert!(matches!(
+            vm.run_script("sum();".to_string()),
+            InterpretResult::RuntimeError(ErrorKind::Arity, _)
+        ));
+    }
+
+    #[test]
+    fn indexed_for_walks_a_sequence() {
+        let vm = run("var pairs = [nil, nil, nil];
+             for (var i, ch in \"abc\") pairs[i] = [i, ch];
+             var empty = true;
+             for (var i, ch in \"\") empty = false;
+             var calls = 0;
+             fun word() { calls = calls + 1; return \"xyz\"; }
+             var seen = \"\";
+             for (var i, ch in word()) {
+                 if (i == 1) continue;
+                 seen = seen + ch;
+             }
+             var before = nil;
+             for (var i, x in [5, 6, 7]) { if (x == 6) break; before = i; }");
+        assert_eq!(
+            vm.get_global("pairs").unwrap().to_string(),
+            "[[0, a], [1, b], [2, c]]"
+        );
+        assert_eq!(vm.get_global("empty"), Some(Value::Bool(true)));
+        assert_eq!(vm.get_global("seen"), Some(Value::String("xz".to_string())));
+        assert_eq!(vm.get_global("calls"), Some(Value::Int(1)));
+        assert_eq!(vm.get_global("before"), Some(Value::Int(0)));
+    }
+
+    #[test]
+    fn indexed_for_rejects_values_that_are_not_sequences() {
+        for source in ["for (var i, x in 1) {}", "for (var i, x in set()) {}"] {
+            let mut vm = VM::new();
+            assert!(
+                matches!(
+                    vm.run_script(source.to_string()),
+                    InterpretResult::RuntimeError(ErrorKind::Type, _)
+                ),
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn methods_returning_this_chain() {
+        let vm = run("class Builder {
+                 init() { this.parts = \"\"; }
+                 add(part) { this.parts = this.parts + part; return this; }
+             }
+             class Loud < Builder {
+                 shout() { return super.add(\"!\"); }
+             }
+             var b = Builder().add(\"a\").add(\"b\");
+             var chained = b.add(\"c\").parts;
+             var loud = Loud().add(\"x\").shout().add(\"y\").shout().parts;
+             var add = b.add;
+             var same = add(\"d\").add(\"e\") == b;
+             var parts = b.parts;");
+        let string = |s: &str| Some(Value::String(s.to_string()));
+        assert_eq!(vm.get_global("chained"), string("abc"));
+        assert_eq!(vm.get_global("loud"), string("x!y!"));
+        assert_eq!(vm.get_global("same"), Some(Value::Bool(true)));
+        assert_eq!(vm.get_global("parts"), string("abcde"));
+    }
+
+    #[test]
+    fn const_globals_stay_const_across_scripts() {
+        let mut vm = run("const x = 1;");
+        assert!(vm.interpret("x = 2;".to_string()) == InterpretResult::CompileError);
+        assert_eq!(vm.get_global("x"), Some(Value::Int(1)));
+    }
+
+    #[test]
+    fn redeclaration_drops_const() {
+        let mut vm = run("const x = 1; const c = 1;");
+        assert!(vm.interpret("var x = 2; x = 3;".to_string()) == InterpretResult::Ok);
+        assert_eq!(vm.get_global("x"), Some(Value::Int(3)));
+        assert!(vm.interpret("class c {} c = 4;".to_string()) == InterpretResult::Ok);
+        assert_eq!(vm.get_global("c"), Some(Value::Int(4)));
+    }
+
+    #[test]
+    fn failed_compile_declares_no_consts() {
+        let mut vm = VM::new();
+        assert!(vm.interpret("const z = 1; print ;".to_string()) == InterpretResult::CompileError);
+        // Not rejected as const; z was never defined at all.
+        assert!(matches!(
+            vm.interpret("z = 2;".to_string()),
+            InterpretResult::RuntimeError(ErrorKind::Name, _)
+        ));
+    }
+
+    #[test]
+    fn eval_returns_the_last_expression_statement() {
+        let mut vm = VM::new();
+        let mut eval = |source: &str| match vm.eval(source.to_string()) {
+            Ok(value) => value,
+            Err(_) => panic!("{} failed", source),
+        };
+        assert_eq!(eval("1 + 2;"), Value::Int(3));
+        assert_eq!(eval("var x = 5;"), Value::Nil);
+        assert_eq!(eval("x * 2; var y = 1;"), Value::Nil);
+        assert_eq!(eval("x = 7;"), Value::Int(7));
+        assert_eq!(eval("{ x; }"), Value::Nil);
+        assert_eq!(eval("if (true) x;"), Value::Int(7));
+        assert_eq!(eval("var i = 0; while (i < 3) i = i + 1;"), Value::Nil);
+        assert_eq!(eval("i;"), Value::Int(3));
+
+        // Outside eval, expression statements are discarded.
+        assert!(vm.run_script("1 + 2;".to_string()) == InterpretResult::Ok);
+        assert!(vm.eval("print ;".to_string()) == Err(InterpretResult::CompileError));
+    }
+
+    #[test]
+    fn interpolation_embeds_expressions() {
+        let mut vm = VM::new();
+        let mut eval = |source: &str| match vm.eval(source.to_string()) {
+            Ok(value) => value.to_string(),
+            Err(_) => panic!("{} failed", source),
+        };
+        eval(r#"var name = "Ada"; var age = 36;"#);
+        assert_eq!(
+            eval(r#""Hello ${name}, you are ${age} years old";"#),
+            "Hello Ada, you are 36 years old"
+        );
+        assert_eq!(
+            eval(r#""next year: ${age + 1}, list: ${[1, 2]}, nil: ${nil}";"#),
+            "next year: 37, list: [1, 2], nil: nil"
+        );
+        eval(r#"fun pick(m) { if (m) { return "yes"; } return "no"; }"#);
+        assert_eq!(
+            eval(r#""nested: ${pick(true) + "/${pick(false)}"}!";"#),
+            "nested: yes/no!"
+        );
+        assert_eq!(eval(r#""${1.5}${true}";"#), "1.5true");
+    }
+
+    #[test]
+    fn backslash_dollar_is_a_literal_dollar() {
+        let mut vm = VM::new();
+        let mut eval = |source: &str| match vm.eval(source.to_string()) {
+            Ok(value) => value.to_string(),
+            Err(_) => panic!("{} failed", source),
+        };
+        eval("var n = 5;");
+        assert_eq!(eval(r#""\${n}";"#), "${n}");
+        assert_eq!(eval(r#""costs \$5";"#), "costs $5");
+        assert_eq!(eval(r#""\${n} is ${n}, \$${n}";"#), "${n} is 5, $5");
+    }
+
+    #[test]
+    fn number_formats_group_and_mark_decimals() {
+        let us = NumberFormat {
+            thousands: Some(','),
+            decimal: '.',
+        };
+        let european = NumberFormat {
+            thousands: Some('.'),
+            decimal: ',',
+        };
+        let cases = [
+            ("1234567.5", "1,234,567.5", "1.234.567,5"),
+            ("-1234", "-1,234", "-1.234"),
+            ("999", "999", "999"),
+            ("100000", "100,000", "100.000"),
+            ("0.25", "0.25", "0,25"),
+            ("inf", "inf", "inf"),
+            ("NaN", "NaN", "NaN"),
+        ];
+        for (plain, grouped, swapped) in cases {
+            assert_eq!(NumberFormat::default().format(plain), plain);
+            assert_eq!(us.format(plain), grouped);
+            assert_eq!(european.format(plain), swapped);
+        }
+
+        // Only numbers printed on their own are reformatted.
+        let mut vm = VM::new();
+        vm.set_number_format(european);
+        assert_eq!(vm.format_value(&Value::Float(1234567.5)), "1.234.567,5");
+        assert_eq!(vm.format_value(&Value::Int(-1234)), "-1.234");
+        assert_eq!(
+            vm.format_value(&Value::String("1234567".to_string())),
+            "1234567"
+        );
+        let array = Value::Array(Rc::new(RwLock::new(vec![Value::Int(1234)])));
+        assert_eq!(vm.format_value(&array), "[1234]");
+    }
+
+    #[test]
+    fn math_natives_compute_and_reject_non_numbers() {
+        type Native = fn(Vec<Value>) -> Value;
+        let one = |f: Native, x: Value| f(vec![x]);
+        let two = |f: Native, a: Value, b: Value| f(vec![a, b]);
+
+        assert_eq!(one(floor_native, Value::Float(2.7)), Value::Float(2.0));
+        assert_eq!(one(floor_native, Value::Float(-2.5)), Value::Float(-3.0));
+        assert_eq!(one(ceil_native, Value::Float(2.1)), Value::Float(3.0));
+        assert_eq!(one(round_native, Value::Float(2.5)), Value::Float(3.0));
+        assert_eq!(one(round_native, Value::Int(7)), Value::Int(7));
+        assert_eq!(
+            two(pow_native, Value::Int(3), Value::Int(4)),
+            Value::Int(81)
+        );
+        assert_eq!(
+            two(pow_native, Value::Int(-1), Value::Int(1 << 40)),
+            Value::Int(1)
+        );
+        assert!(matches!(
+            two(pow_native, Value::Int(2), Value::Int(64)),
+            Value::RunTimeError(ErrorKind::Overflow, _)
+        ));
+        assert_eq!(
+            two(pow_native, Value::Int(4), Value::Float(0.5)),
+            Value::Float(2.0)
+        );
+        assert_eq!(
+            two(pow_native, Value::Int(2), Value::Int(-1)),
+            Value::Float(0.5)
+        );
+        assert_eq!(
+            two(min_native, Value::Float(1.5), Value::Int(2)),
+            Value::Float(1.5)
+        );
+        assert_eq!(
+            two(max_native, Value::Float(1.5), Value::Int(2)),
+            Value::Int(2)
+        );
+        assert_eq!(one(cos_native, Value::Int(0)), Value::Float(1.0));
+        assert_eq!(one(tan_native, Value::Float(0.0)), Value::Float(0.0));
+        assert_eq!(one(log_native, Value::Int(1)), Value::Float(0.0));
+
+        let unary: [(Native, &str); 8] = [
+            (floor_native, "Floor"),
+            (ceil_native, "Ceil"),
+            (round_native, "Round"),
+            (abs_native, "Abs"),
+            (sin_native, "Sin"),
+            (cos_native, "Cos"),
+            (tan_native, "Tan"),
+            (log_native, "Log"),
+        ];
+        for (f, name) in unary {
+            match one(f, Value::String("1".to_string())) {
+                Value::RunTimeError(ErrorKind::Type, message) => {
+                    assert_eq!(message, format!("{} argument must be a number", name))
+                }
+                value => panic!("{}: expected a type error, got {}", name, value),
+            }
+        }
+        for f in [pow_native, min_native, max_native] {
+            assert!(matches!(
+                two(f, Value::Int(1), Value::Nil),
+                Value::RunTimeError(ErrorKind::Type, _)
+            ));
+        }
+
+        let vm = run("var m = max(floor(2.5), min(3, 7));");
+        assert_eq!(vm.get_global("m"), Some(Value::Int(3)));
+    }
+
+    #[test]
+    fn conversion_natives() {
+        let mut vm = VM::new();
+        let mut eval = |source: &str| match vm.eval(source.to_string()) {
+            Ok(value) => value,
+            Err(_) => panic!("{} failed", source),
+        };
+        assert_eq!(eval("int(\"42\");"), Value::Int(42));
+        assert_eq!(eval("int(\" -7 \");"), Value::Int(-7));
+        assert_eq!(eval("int(3.9);"), Value::Int(3));
+        assert_eq!(eval("int(-3.9);"), Value::Int(-3));
+        assert_eq!(eval("float(\"2.5\");"), Value::Float(2.5));
+        assert_eq!(eval("float(2);"), Value::Float(2.0));
+        assert_eq!(eval("str(123);"), Value::String("123".to_string()));
+        assert_eq!(
+            eval("str([1, nil]);"),
+            Value::String("[1, nil]".to_string())
+        );
+        assert_eq!(
+            eval("var a = [1]; a[0] = a; str(a);"),
+            Value::String("[[...]]".to_string())
+        );
+        assert_eq!(eval("int(str(5)) + 1;"), Value::Int(6));
+
+        let string = |s: &str| Value::String(s.to_string());
+        for (result, kind, message) in [
+            (
+                int_native(vec![string("abc")]),
+                ErrorKind::Argument,
+                "Cannot convert 'abc' to int",
+            ),
+            (
+                int_native(vec![string("1.5")]),
+                ErrorKind::Argument,
+                "Cannot convert '1.5' to int",
+            ),
+            (
+                int_native(vec![Value::Float(1e300)]),
+                ErrorKind::Argument,
+                "Cannot convert 1e300 to int",
+            ),
+            (
+                int_native(vec![Value::Nil]),
+                ErrorKind::Type,
+                "Cannot convert nil to int",
+            ),
+            (
+                float_native(vec![string("x")]),
+                ErrorKind::Argument,
+                "Cannot convert 'x' to float",
+            ),
+            (
+                float_native(vec![Value::Bool(true)]),
+                ErrorKind::Type,
+                "Cannot convert bool to float",
+            ),
+        ] {
+            match result {
+                Value::RunTimeError(actual, report) => {
+                    assert!(actual == kind, "{}", message);
+                    assert_eq!(report, message);
+                }
+                value => panic!("{}: expected a runtime error, got {}", message, value),
+            }
+        }
+    }
+
+    #[test]
+    fn subjectless_switch_picks_the_first_true_guard() {
+        let vm = run("fun grade(n) {
+                 var result;
+                 switch {
+                     case n >= 90: { result = \"A\"; }
+                     case n >= 80: { result = \"B\"; }
+                     case n >= 80: { result = \"never\"; }
+                     default: { result = \"F\"; }
+                 }
+                 return result;
+             }
+             var grades = [grade(95), grade(85), grade(10)];
+             var calls = 0;
+             fun check(x) { calls = calls + 1; return x; }
+             var picked = 0;
+             switch {
+                 case check(false): { picked = 1; }
+                 case check(true): { picked = 2; }
+                 case check(true): { picked = 3; }
+             }
+             var unmatched = true;
+             switch { case false: { unmatched = false; } }");
+        assert_eq!(vm.get_global("grades").unwrap().to_string(), "[A, B, F]");
+        assert_eq!(vm.get_global("picked"), Some(Value::Int(2)));
+        assert_eq!(vm.get_global("calls"), Some(Value::Int(2)));
+        assert_eq!(vm.get_global("unmatched"), Some(Value::Bool(true)));
+    }
+
+    #[test]
+    fn write_and_append_files() {
+        let dir = std::env::temp_dir().join(format!("ci-files-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("out.txt");
+        let file = Value::String(path.display().to_string());
+        let text = |s: &str| Value::String(s.to_string());
+
+        assert_eq!(
+            append_file_native(vec![file.clone(), text("new ")]),
+            Value::Bool(true)
+        );
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "new ");
+        assert_eq!(
+            write_file_native(vec![file.clone(), text("one")]),
+            Value::Bool(true)
+        );
+        assert_eq!(
+            append_file_native(vec![file.clone(), text(" two")]),
+            Value::Bool(true)
+        );
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "one two");
+        assert_eq!(
+            write_file_native(vec![file.clone(), text("three")]),
+            Value::Bool(true)
+        );
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "three");
+
+        let missing = Value::String(dir.join("no/such/dir").display().to_string());
+        for native in [write_file_native, append_file_native] {
+            assert!(matches!(
+                native(vec![missing.clone(), text("x")]),
+                Value::RunTimeError(ErrorKind::Io, _)
+            ));
+            assert!(matches!(
+                native(vec![file.clone(), Value::Int(1)]),
+                Value::RunTimeError(ErrorKind::Type, _)
+            ));
+            assert!(matches!(
+                native(vec![Value::Nil, text("x")]),
+                Value::RunTimeError(ErrorKind::Type, _)
+            ));
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn assertions_pass_silently_and_fail_with_the_values() {
+        let mut vm = VM::new();
+        assert!(
+            vm.run_script(
+                "assert(1 < 2); assert(\"\"); assert_eq(1 + 1, 2); assert_eq([1], [1]);"
+                    .to_string()
+            ) == InterpretResult::Ok
+        );
+        for source in ["assert(nil);", "assert(false);", "assert_eq(1, 2);"] {
+            assert!(
+                matches!(
+                    vm.run_script(source.to_string()),
+                    InterpretResult::RuntimeError(ErrorKind::Assertion, _)
+                ),
+                "{}",
+                source
+            );
+        }
+
+        match assert_eq_native(vec![Value::Int(3), Value::String("three".to_string())]) {
+            Value::RunTimeError(ErrorKind::Assertion, message) => {
+                assert_eq!(message, "Assertion failed: 3 != three")
+            }
+            value => panic!("expected an assertion error, got {}", value),
+        }
+    }
+
+    #[test]
+    fn popping_scopes_leaves_outer_locals_intact() {
+        let vm = run("fun f() {
+                 var x = \"outer\";
+                 var sums = [];
+                 { var a = 1; var b = 2; var c = 3; sums = [a + b + c]; }
+                 { var d = 4; var e = fun() { return d; }; var g = 6; sums = [sums[0], e() + g]; }
+                 for (var i = 0; i < 1; i = i + 1) { var p = 1; var q = 2; break; }
+                 return [x, sums];
+             }
+             var result = f();");
+        assert_eq!(
+            vm.get_global("result").unwrap().to_string(),
+            "[outer, [6, 10]]"
+        );
+        assert_eq!(vm.stack_depth, 0);
+    }
+
+    #[test]
+    fn sorts_instances_that_define_lt() {
+        let vm = run("class Money {
+                 init(cents) { this.cents = cents; }
+                 __lt__(other) { return this.cents < other.cents; }
+                 __gt__(other) { return this.cents > other.cents; }
+                 __eq__(other) { return this.cents == other.cents; }
+             }
+             fun sort(items) {
+                 for (var i = 1; i < len(items); i = i + 1) {
+                     var j = i;
+                     while (j > 0 and items[j] < items[j - 1]) {
+                         var held = items[j];
+                         items[j] = items[j - 1];
+                         items[j - 1] = held;
+                         j = j - 1;
+                     }
+                 }
+             }
+             var wallet = [Money(500), Money(25), Money(1200), Money(25), Money(90)];
+             sort(wallet);
+             var cents = \"\";
+             for (var i, money in wallet) cents = cents + str(money.cents) + \" \";
+             var greater = Money(3) > Money(2);
+             var equal = Money(7) == Money(7);
+             var unequal = Money(7) == Money(8);");
+        assert_eq!(
+            vm.get_global("cents").unwrap().to_string(),
+            "25 25 90 500 1200 "
+        );
+        assert_eq!(vm.get_global("greater"), Some(Value::Bool(true)));
+        assert_eq!(vm.get_global("equal"), Some(Value::Bool(true)));
+        assert_eq!(vm.get_global("unequal"), Some(Value::Bool(false)));
+
+        // Without the methods, comparison keeps its usual behavior.
+        let mut vm = run("class P {} var p = P(); var same = p == p; var other = p == P();");
+        assert_eq!(vm.get_global("same"), Some(Value::Bool(true)));
+        assert_eq!(vm.get_global("other"), Some(Value::Bool(false)));
+        assert!(matches!(
+            vm.run_script("p < P();".to_string()),
+            InterpretResult::RuntimeError(ErrorKind::Type, _)
+        ));
+    }
+
+    #[test]
+    fn dis_returns_a_functions_disassembly() {
+        let mut vm = VM::new();
+        let listing = match vm.eval("fun add(a, b) { return a + b; } dis(add);".to_string()) {
+            Ok(Value::String(listing)) => listing,
+            Ok(value) => panic!("expected a string, got {}", value),
+            Err(_) => panic!("dis(add) failed"),
+        };
+        assert!(listing.starts_with("== add =="), "{}", listing);
+        for op in ["OP_GET_LOCAL", "OP_ADD", "OP_RETURN"] {
+            assert!(listing.contains(op), "{}", listing);
+        }
+        assert!(!listing.contains("OP_DEFINE_GLOBAL"), "{}", listing);
+
+        assert!(matches!(
+            vm.run_script("dis(1);".to_string()),
+            InterpretResult::RuntimeError(ErrorKind::Type, _)
+        ));
+    }
+
+    #[test]
+    fn runs_cleanly_after_a_runtime_error_and_a_compile_error() {
+        let mut vm = VM::new();
+        let failed = vm.run_script(
+            "fun inner(x) { return x + nil; }
+             fun outer() { var local = 1; return inner(local); }
+             outer();"
+                .to_string(),
+        );
+        assert!(matches!(
+            failed,
+            InterpretResult::RuntimeError(ErrorKind::Type, _)
+        ));
+        assert!(vm.frames.is_empty());
+        assert_eq!(vm.stack_depth, 0);
+
+        assert!(vm.interpret("var = ;".to_string()) == InterpretResult::CompileError);
+        assert!(vm.frames.is_empty());
+
+        assert!(
+            vm.interpret("fun twice(x) { return x * 2; } var answer = twice(21);".to_string())
+                == InterpretResult::Ok
+        );
+        assert_eq!(vm.get_global("answer"), Some(Value::Int(42)));
+        assert!(vm.frames.is_empty());
+        assert_eq!(vm.stack_depth, 0);
+    }
+
+    #[test]
+    fn print_and_write_go_to_the_vm_output() {
+        let (mut vm, out) = captured_vm();
+        vm.run_script("print 1, \"a\"; write(\"b\"); write(2); print [nil];".to_string());
+        assert_eq!(out.contents(), "1 a\nb2[nil]\n");
+    }
+
+    #[test]
+    fn failed_writes_are_io_errors() {
+        struct Broken;
+        impl Write for Broken {
+            fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+                Err(std::io::Error::other("broken"))
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        for source in ["print 1;", "write(1);"] {
+            let mut vm = VM::with_writer(Box::new(Broken));
+            assert!(
+                matches!(
+                    vm.run_script(source.to_string()),
+                    InterpretResult::RuntimeError(ErrorKind::Io, _)
+                ),
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn flush_flushes_the_vm_output() {
+        let flushes = Rc::new(RwLock::new(0));
+        let mut vm = VM::with_writer(Box::new(Flushes(flushes.clone())));
+        vm.run_script("flush(); print 1; flush();".to_string());
+        assert_eq!(*flushes.read(), 2);
+    }
+
+    #[test]
+    fn interactive_prints_flush_the_output() {
+        let flushes = Rc::new(RwLock::new(0));
+        let mut vm = VM::with_writer(Box::new(Flushes(flushes.clone())));
+        vm.set_interactive(true);
+        vm.run_script("print 1; print 2;".to_string());
+        assert_eq!(*flushes.read(), 2);
+    }
+
+    #[test]
+    fn destructures_fields_into_variables() {
+        let (mut vm, out) = captured_vm();
+        let result = vm.run_script(
+            "class Person { init(name, age) { this.name = name; this.age = age; } }
+             var { name, age } = Person(\"Ada\", 36);
+             print name; print age;
+             fun greet(person) {
+                 var { age, name } = person;
+                 return name + \" is \" + str(age);
+             }
+             print greet(Person(\"Bob\", 41));
+             print name;"
+                .to_string(),
+        );
+        assert!(result == InterpretResult::Ok);
+        assert_eq!(out.contents(), "Ada\n36\nBob is 41\nAda\n");
+        assert_eq!(vm.stack_depth, 0);
+
+        assert!(matches!(
+            vm.run_script("{ var { name, height } = Person(\"Cy\", 1); }".to_string()),
+            InterpretResult::RuntimeError(ErrorKind::Name, _)
+        ));
+    }
+
+    #[test]
+    fn destructuring_replaces_a_const_global() {
+        let mut vm = run("const a = 1;");
+        assert!(
+            vm.run_script("class O { init() { this.a = 5; } } var { a } = O(); a = 2;".to_string())
+                == InterpretResult::Ok
+        );
+        assert_eq!(vm.get_global("a"), Some(Value::Int(2)));
+    }
+
+    #[test]
+    fn classifies_nan_and_infinite_floats() {
+        let (mut vm, out) = captured_vm();
+        let result = vm.run_script(
+            "var inf = 10.0 ** 400;
+             var values = [0.0 / 0.0, inf - inf, inf, -inf, 1.5, 7];
+             for (var i, v in values) print(is_nan(v), is_infinite(v), is_finite(v));"
+                .to_string(),
+        );
+        assert!(result == InterpretResult::Ok);
+        assert_eq!(
+            out.contents(),
+            "true false false\ntrue false false\nfalse true false\n\
+             false true false\nfalse false true\nfalse false true\n"
+        );
+
+        for predicate in ["is_nan", "is_infinite", "is_finite"] {
+            assert!(matches!(
+                vm.run_script(format!("{}(\"1.0\");", predicate)),
+                InterpretResult::RuntimeError(ErrorKind::Type, _)
+            ));
+        }
+    }
+
+    #[test]
+    fn eval_reports_failures_without_a_stale_value() {
+        let mut vm = VM::new();
+        assert!(vm.eval("\"a\" + \"b\";".to_string()) == Ok(Value::String("ab".to_string())));
+        assert!(vm.eval("var = 1;".to_string()) == Err(InterpretResult::CompileError));
+        assert!(matches!(
+            vm.eval("41; 1 / 0;".to_string()),
+            Err(InterpretResult::RuntimeError(ErrorKind::DivByZero, _))
+        ));
+        // A value computed before a failure is not returned by the next run.
+        assert!(vm.eval("42; var y = 6;".to_string()) == Ok(Value::Nil));
+        assert!(vm.eval("1; 2; 3;".to_string()) == Ok(Value::Int(3)));
+
+        // interpret keeps returning only the outcome.
+        assert!(vm.interpret("1 + 2;".to_string()) == InterpretResult::Ok);
+    }
+
+    // Labels 0 to 19 are dense enough for a jump table.
+    fn dense_switch(subject: &str) -> String {
+        let cases: String = (0..20)
+            .map(|i| format!("case {}: {{ r = \"c{}\"; }}\n", i, i))
+            .collect();
+        format!(
+            "var r; switch ({}) {{ {} default: {{ r = \"default\"; }} }} r;",
+            subject, cases
+        )
+    }
+
+    #[test]
+    fn dense_switch_picks_every_case_and_the_default() {
+        let (mut vm, _) = captured_vm();
+        for i in 0..20 {
+            assert!(vm.eval(dense_switch(&i.to_string())) == Ok(Value::String(format!("c{}", i))));
+        }
+        for subject in ["-1", "20", "3.5", "\"3\"", "nil"] {
+            assert!(
+                vm.eval(dense_switch(subject)) == Ok(Value::String("default".to_string())),
+                "subject {}",
+                subject
+            );
+        }
+        assert!(vm.eval(dense_switch("3.0")) == Ok(Value::String("c3".to_string())));
+    }
+
+    #[test]
+    fn corrupt_bytecode_files_never_panic() {
+        let source = "class A { m(x) { return x * 2; } }
+        fun f(a, ...rest) { var t = a; for (var i, r in rest) t = t + r; return t; }
+        fun outer() { var n = 1; fun inner() { n = n + 1; return n; } return inner; }
+        print A().m(f(1, 2, 3)); print outer()(); switch (2) { case 1: print 1; case 2: print 2; }";
+        let bytes = chunk::serialize(&crate::compiler::compile(source.to_string()).function.read())
+            .unwrap();
+
+        for position in 5..bytes.len() {
+            for replacement in [0, 1, 0x7f, 0xff] {
+                let mut corrupt = bytes.clone();
+                corrupt[position] = replacement;
+                let (mut vm, _) = captured_vm();
+                vm.set_report_errors(false);
+                let _ = vm.run_bytecode(&corrupt);
+            }
+        }
+    }
+
+    #[test]
+    fn invalid_bytecode_file_is_a_compile_error() {
+        let (mut vm, out) = captured_vm();
+        assert!(vm.run_bytecode(b"LBC\0") == InterpretResult::CompileError);
+
+        let source = "var a = 2; print a * 21;";
+        let bytes = chunk::serialize(&crate::compiler::compile(source.to_string()).function.read())
+            .unwrap();
+        assert!(vm.run_bytecode(&bytes) == InterpretResult::Ok);
+        assert_eq!(out.contents(), "42\n");
+    }
+
+    #[test]
+    fn runtime_errors_report_the_column() {
+        let (mut vm, _) = quiet_vm();
+        match vm.eval("var a = 1; var b = a / 0; var c = 2;".to_string()) {
+            Err(InterpretResult::RuntimeError(ErrorKind::DivByZero, report)) => {
+                assert!(report.contains("[line 1:col 22] in script"), "{}", report)
+            }
+            _ => panic!("expected a division by zero"),
+        }
+    }
+
+    #[test]
+    fn strings_compare_lexicographically() {
+        let (mut vm, out) = quiet_vm();
+        let result = vm.run_script(
+            r#"print("ab" < "abc", "abc" > "ab", "abc" < "abd", "b" > "abc");
+               print("" < "a", "a" <= "a", "a" >= "b", "Z" < "a");"#
+                .to_string(),
+        );
+        assert!(result == InterpretResult::Ok);
+        assert_eq!(
+            out.contents(),
+            "true true true true\ntrue true false true\n"
+        );
+
+        for source in ["\"1\" < 2;", "2 > \"1\";", "\"a\" <= nil;"] {
+            assert!(
+                matches!(
+                    vm.eval(source.to_string()),
+                    Err(InterpretResult::RuntimeError(ErrorKind::Type, _))
+                ),
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn switch_cases_match_ints_and_equal_floats() {
+        let (mut vm, out) = quiet_vm();
+        let result = vm.run_script(
+            "fun name(n) {
+                 switch (n) {
+                     case 1: { return \"one\"; }
+                     case 2.0: { return \"two\"; }
+                     case 3: { return \"three\"; }
+                     default: { return \"other\"; }
+                 }
+             }
+             print(name(1.0), name(2), name(3.0), name(2.5), name(\"1\"));
+             print(1 == 1.0, 2.0 == 2, 1 != 1.0, 1 == 1.5);
+             // Dense int cases dispatch through a jump table.
+             fun letter(n) {
+                 switch (n) {
+                     case 1: { return \"a\"; } case 2: { return \"b\"; }
+                     case 3: { return \"c\"; } case 4: { return \"d\"; }
+                     default: { return \"z\"; }
+                 }
+             }
+             print(letter(2.0), letter(4.0), letter(2.5));"
+                .to_string(),
+        );
+        assert!(result == InterpretResult::Ok);
+        assert_eq!(
+            out.contents(),
+            "one two three other other\ntrue true false false\nb d z\n"
+        );
+    }
+
+    #[test]
+    fn sets_treat_ints_and_equal_floats_as_one_key() {
+        let (mut vm, out) = quiet_vm();
+        let result = vm.run_script(
+            "var s = set(); add(s, 1);
+         print(add(s, 1.0), has(s, 1.0), add(s, 1.5), len(s));
+         print(remove(s, 1.0), has(s, 1));"
+                .to_string(),
+        );
+        assert!(result == InterpretResult::Ok);
+        assert_eq!(out.contents(), "false true true 2\ntrue false\n");
+    }
+
+    #[test]
+    fn closures_share_captured_variables() {
+        let (mut vm, out) = quiet_vm();
+        let result = vm.run_script(
+            "fun counter() {
+             var n = 0;
+             fun inc() { n = n + 1; }
+             fun get() { return n; }
+             return [inc, get];
+         }
+         var a = counter(); var b = counter();
+         a[0](); a[0](); b[0]();
+         print(a[1](), b[1]());
+
+         fun later() {
+             var x = 1;
+             fun show() { return x; }
+             x = 2;
+             return show;
+         }
+         print later()();
+
+         fun outer() {
+             var y = \"before\";
+             fun middle() { fun inner() { y = \"after\"; } inner(); }
+             middle();
+             return y;
+         }
+         print outer();"
+                .to_string(),
+        );
+        assert!(result == InterpretResult::Ok);
+        assert_eq!(out.contents(), "2 1\n2\nafter\n");
+    }
+
+    #[test]
+    fn closure_escaping_a_failed_call_keeps_its_upvalue() {
+        let (mut vm, _) = quiet_vm();
+        let failed = vm.eval(
+            "var g; fun outer() { var x = 1; fun inner() { return x; } g = inner; return nil + 1; }
+         outer();"
+                .to_string(),
+        );
+        assert!(matches!(
+            failed,
+            Err(InterpretResult::RuntimeError(ErrorKind::Type, _))
+        ));
+
+        assert_eq!(vm.eval("g();".to_string()), Ok(Value::Int(1)));
+    }
+
+    #[test]
+    fn scripts_reclaim_cycles_as_they_run() {
+        let (mut vm, out) = quiet_vm();
+        let result = vm.run_script(
+            "class Node { init() { this.me = fun() { return this; }; } }
+             fun churn(n) {
+                 var last;
+                 for (var i = 0; i < n; i = i + 1) { last = Node(); }
+                 return last;
+             }
+             var kept = churn(20000);
+             print kept.me() == kept;"
+                .to_string(),
+        );
+        assert_eq!(result, InterpretResult::Ok);
+        assert_eq!(out.contents(), "true\n");
+        // Collections ran while the loop did, so little is left to free.
+        assert!(vm.collect_garbage() < 2048);
+    }
+
+    #[test]
+    fn tail_calls_reuse_the_frame() {
+        let (mut vm, out) = quiet_vm();
+        let depth = 10 * FRAMES_MAX;
+        let result = vm.run_script(format!(
+            "fun sum(n, acc) {{ if (n == 0) return acc; return sum(n - 1, acc + n); }}
+             fun even(n) {{ if (n == 0) return true; return odd(n - 1); }}
+             fun odd(n) {{ if (n == 0) return false; return even(n - 1); }}
+             fun capture(n, f) {{ if (n == 0) return f(); return capture(n - 1, fun() {{ return n; }}); }}
+             print sum({depth}, 0);
+             print(even({depth}), odd({depth}));
+             print capture({depth}, nil);
+             fun native_tail(x) {{ return str(x); }}
+             print native_tail(5);"
+        ));
+        assert_eq!(result, InterpretResult::Ok);
+        assert_eq!(
+            out.contents(),
+            format!("{}\ntrue false\n1\n5\n", depth * (depth + 1) / 2)
+        );
+
+        // Only calls whose result is returned as is are tail calls.
+        match vm.eval(format!(
+            "fun count(n) {{ if (n == 0) return 0; return 1 + count(n - 1); }} count({});",
+            depth
+        )) {
+            Err(InterpretResult::RuntimeError(ErrorKind::Internal, report)) => {
+                assert!(report.starts_with("Stack overflow"))
+            }
+            result => panic!("expected a stack overflow, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn globals_resolve_by_interned_name() {
+        let (mut vm, out) = quiet_vm();
+        let result = vm.run_script(
+            "var total = 0; var count = 0;
+             class Box { init() { this.total = 100; } count() { return total; } }
+             fun add(n) { total = total + n; count = count + 1; }
+             for (var i = 0; i < 20000; i = i + 1) add(i);
+             var box = Box();
+             print(total, count, box.total, box.count());"
+                .to_string(),
+        );
+        assert_eq!(result, InterpretResult::Ok);
+        assert_eq!(out.contents(), "199990000 20000 100 199990000\n");
+        assert!(vm.globals.contains_key(&Symbol::intern("total")));
+        assert_eq!(vm.get_global("count"), Some(Value::Int(20000)));
+
+        match vm.eval("totl = 1;".to_string()) {
+            Err(InterpretResult::RuntimeError(ErrorKind::Name, report)) => {
+                assert!(report.contains("'totl'"), "{}", report)
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    // Declares `count` globals, g0 = 0 up to g{count - 1}, filling the
+    // script's constant table past what a byte can index.
+    fn many_globals(count: usize) -> String {
+        (0..count)
+            .map(|i| format!("var g{} = {};\n", i, i))
+            .collect()
+    }
+
+    #[test]
+    fn more_than_256_globals() {
+        let (mut vm, _) = quiet_vm();
+        let source = many_globals(300) + "g299 = g299 + g1; g299;";
+        assert_eq!(vm.eval(source), Ok(Value::Int(300)));
+    }
+
+    #[test]
+    fn names_past_256_constants() {
+        let (mut vm, out) = quiet_vm();
+        let source = many_globals(300)
+            + "class Base { greet(name) { return \"hi \" + name; } }
+               class Point < Base {
+                 init(x) { this.x = x; }
+                 greet(name) { return super.greet(name) + \"!\"; }
+                 shout() { var greet = super.greet; return greet(\"you\"); }
+               }
+               var p = Point(g299);
+               p.x = p.x + 1;
+               fun adder(n) { return fun(m) { return n + m; }; }
+               switch (p.x) { case 299: { print \"low\"; } case 300: { print adder(p.x)(1); } }
+               print p.greet(\"me\");
+               print p.shout();";
+        assert_eq!(vm.run_script(source), InterpretResult::Ok);
+        assert_eq!(out.contents(), "301\nhi me!\nhi you\n");
+    }
+
+    #[test]
+    fn deep_temporaries_trip_the_stack_limit() {
+        let (mut vm, _) = quiet_vm();
+        // Each frame holds about 300 temporaries, so the stack fills up well
+        // before the frame limit is reached.
+        let nested = format!("{}f(n - 1){}", "0 + (".repeat(300), ")".repeat(300));
+        let source = format!(
+            "fun f(n) {{ if (n == 0) return 0; return {}; }} f({});",
+            nested, FRAMES_MAX
+        );
+        match vm.eval(source) {
+            Err(InterpretResult::RuntimeError(ErrorKind::Internal, report)) => {
+                assert!(report.starts_with("Stack overflow"), "{}", report)
+            }
+            result => panic!("expected a stack overflow, got {:?}", result),
+        }
+        assert_eq!(vm.max_stack_used(), STACK_MAX);
+        assert_eq!(vm.eval("f(3);".to_string()), Ok(Value::Int(0)));
+    }
+
+    #[test]
+    fn do_while_runs_its_body_before_the_condition() {
+        let (mut vm, out) = quiet_vm();
+        let result = vm.run_script(
+            "do { print \"once\"; } while (false);
+             var i = 0;
+             do i = i + 1; while (i < 3);
+             print i;
+             var seen = \"\";
+             var n = 0;
+             do {
+                 n = n + 1;
+                 if (n == 2) continue;
+                 if (n == 5) break;
+                 var label = str(n);
+                 seen = seen + label;
+             } while (n < 10);
+             print(seen, n);"
+                .to_string(),
+        );
+        assert_eq!(result, InterpretResult::Ok);
+        // `continue` goes on to the condition; `break` leaves the loop.
+        assert_eq!(out.contents(), "once\n3\n134 5\n");
+        assert_eq!(vm.stack_depth, 0);
+    }
+
+    #[test]
+    fn labeled_break_and_continue_target_outer_loops() {
+        let (mut vm, out) = quiet_vm();
+        let result = vm.run_script(
+            "var found = nil;
+             outer: for (var i = 0; i < 5; i = i + 1) {
+                 for (var j = 0; j < 5; j = j + 1) {
+                     var product = i * j;
+                     if (product == 6) { found = [i, j]; break outer; }
+                 }
+             }
+             print found;
+             var pairs = \"\";
+             rows: for (var i = 0; i < 3; i = i + 1) {
+                 var j = 0;
+                 while (true) {
+                     j = j + 1;
+                     if (j > i) continue rows;
+                     pairs = pairs + str(i) + str(j) + \" \";
+                 }
+             }
+             print pairs;
+             var n = 0;
+             counting: do { repeat (3) { n = n + 1; if (n == 5) break counting; } } while (true);
+             print n;"
+                .to_string(),
+        );
+        assert_eq!(result, InterpretResult::Ok);
+        assert_eq!(out.contents(), "[2, 3]\n11 21 22 \n5\n");
+        assert_eq!(vm.stack_depth, 0);
+    }
+
+    #[test]
+    fn zero_and_empty_values_are_truthy() {
+        let (mut vm, out) = quiet_vm();
+        let result = vm.run_script(
+            "if (0) print \"yes\"; else print \"no\";
+             if (0.0) print \"float\";
+             if (\"\") print \"string\";
+             print(!0, !nil, !false, 0 and 1, nil or 0, false or nil);
+             var n = 0;
+             while (n) { n = nil; print \"looped\"; }"
+                .to_string(),
+        );
+        assert_eq!(result, InterpretResult::Ok);
+        assert_eq!(
+            out.contents(),
+            "yes\nfloat\nstring\nfalse true true 1 0 nil\nlooped\n"
+        );
+    }
+
+    #[test]
+    fn typeof_names_every_kind_of_value() {
+        let (mut vm, _) = quiet_vm();
+        vm.run_script(
+            "class Point { norm() {} }
+             fun named() {}
+             var p = Point();"
+                .to_string(),
+        );
+        let cases = [
+            ("1", "int"),
+            ("1.5", "float"),
+            ("true", "bool"),
+            ("nil", "nil"),
+            ("\"s\"", "string"),
+            ("named", "function"),
+            ("fun() {}", "function"),
+            ("p.norm", "function"),
+            ("clock", "native function"),
+            ("Point", "class"),
+            ("p", "instance"),
+            ("[1, 2]", "array"),
+            ("set()", "set"),
+            ("typeof(1)", "string"),
+        ];
+        for (source, name) in cases {
+            assert_eq!(
+                vm.eval(format!("typeof({});", source)),
+                Ok(Value::String(name.to_string())),
+                "{}",
+                source
+            );
+        }
+        assert_eq!(
+            vm.eval(
+                "var kind; if (typeof(p) == \"instance\") kind = \"object\"; kind;".to_string()
+            ),
+            Ok(Value::String("object".to_string()))
+        );
+    }
+
+    #[test]
+    fn string_natives_transform_text() {
+        let (mut vm, out) = quiet_vm();
+        // Non-ASCII text comes in through globals rather than literals.
+        vm.set_global("word", Value::String("h\u{e9}llo".to_string()));
+        vm.set_global("street", Value::String("Stra\u{df}e".to_string()));
+        vm.set_global("accented", Value::String("\u{c0}BC".to_string()));
+        let result = vm.run_script(
+            "var parts = split(\"a,b,c\", \",\");
+             print(parts, len(parts), parts[2]);
+             print(split(\"a,,b,\", \",\"), len(split(word, \"\")), split(\"abc\", \";\"));
+             print(upper(street), len(lower(accented)));
+             print(\"[\" + trim(\"  x y \t\") + \"]\");
+             print(replace(\"a-b-c\", \"-\", \"+\"), replace(\"aaa\", \"aa\", \"b\"), replace(\"abc\", \"z\", \"y\"));"
+                .to_string(),
+        );
+        assert_eq!(result, InterpretResult::Ok);
+        assert_eq!(
+            out.contents(),
+            "[a, b, c] 3 c\n[a, , b, ] 5 [abc]\nSTRASSE 3\n[x y]\na+b+c ba abc\n"
+        );
+        assert_eq!(
+            vm.eval("lower(accented);".to_string()),
+            Ok(Value::String("\u{e0}bc".to_string()))
+        );
+
+        for source in [
+            "upper(1);",
+            "lower(nil);",
+            "trim([]);",
+            "split(\"a\", 1);",
+            "split(1, \",\");",
+            "replace(\"a\", \"a\", 1);",
+        ] {
+            assert!(
+                matches!(
+                    vm.eval(source.to_string()),
+                    Err(InterpretResult::RuntimeError(ErrorKind::Type, _))
+                ),
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn substring_counts_characters_and_clamps() {
+        let (mut vm, _) = quiet_vm();
+        vm.set_global(
+            "accented",
+            Value::String("h\u{e9}llo w\u{f6}rld".to_string()),
+        );
+        vm.set_global(
+            "japanese",
+            Value::String("\u{65e5}\u{672c}\u{8a9e}\u{30c6}\u{30ad}\u{30b9}\u{30c8}".to_string()),
+        );
+        vm.set_global("check", Value::String("a\u{2713}b".to_string()));
+        let cases = [
+            ("\"hello\", 1, 4", "ell"),
+            ("\"hello\", 0, 5", "hello"),
+            ("accented, 1, 5", "\u{e9}llo"),
+            ("japanese, 3, 7", "\u{30c6}\u{30ad}\u{30b9}\u{30c8}"),
+            ("check, 1, 2", "\u{2713}"),
+            // Out-of-range indices clamp to the string.
+            ("\"hello\", -3, 2", "he"),
+            ("\"hello\", 3, 99", "lo"),
+            ("\"hello\", 4, 2", ""),
+            ("\"\", 0, 1", ""),
+        ];
+        for (args, expected) in cases {
+            assert_eq!(
+                vm.eval(format!("substring({});", args)),
+                Ok(Value::String(expected.to_string())),
+                "{}",
+                args
+            );
+        }
+        assert!(matches!(
+            vm.eval("substring(\"abc\", 0.5, 2);".to_string()),
+            Err(InterpretResult::RuntimeError(ErrorKind::Type, _))
+        ));
+    }
+
+    #[test]
+    fn scripts_can_hold_non_ascii_text() {
+        let (mut vm, out) = quiet_vm();
+        let result = vm.run_script(
+            "var caf\u{e9} = \"h\u{e9}llo \u{2713}\";
+             var chars = \"\";
+             for (var i, ch in \"h\u{e9}llo\") chars = chars + str(i) + ch;
+             print(chars, len(split(caf\u{e9}, \"\")), substring(caf\u{e9}, 1, 2));
+             print upper(caf\u{e9});"
+                .to_string(),
+        );
+        assert_eq!(result, InterpretResult::Ok);
+        assert_eq!(
+            out.contents(),
+            "0h1\u{e9}2l3l4o 7 \u{e9}\nH\u{c9}LLO \u{2713}\n"
+        );
+    }
+
+    #[test]
+    fn ord_and_chr_convert_code_points() {
+        let (mut vm, out) = quiet_vm();
+        let result = vm.run_script(
+            "print(ord(\"A\"), ord(\"abc\"), ord(\"é\"), ord(\"✓\"));
+             print(chr(ord(\"A\")), chr(97), chr(233), chr(10003));
+             var shifted = \"\";
+             for (var i, ch in \"HAL\") shifted = shifted + chr(ord(ch) + 1);
+             print shifted;"
+                .to_string(),
+        );
+        assert_eq!(result, InterpretResult::Ok);
+        assert_eq!(out.contents(), "65 97 233 10003\nA a é ✓\nIBM\n");
+
+        let cases = [
+            ("ord(\"\");", ErrorKind::Argument),
+            ("ord(65);", ErrorKind::Type),
+            ("chr(-1);", ErrorKind::Argument),
+            ("chr(55296);", ErrorKind::Argument),
+            ("chr(1114112);", ErrorKind::Argument),
+            ("chr(\"A\");", ErrorKind::Type),
+        ];
+        for (source, kind) in cases {
+            match vm.eval(source.to_string()) {
+                Err(InterpretResult::RuntimeError(actual, _)) => {
+                    assert_eq!(actual, kind, "{}", source)
+                }
+                other => panic!("unexpected result {:?} for {}", other, source),
+            }
+        }
+    }
+}
@@ -0,0 +1,103 @@
+// Uses the library only through its public API, the way an embedding crate
+// would.
+use ci_bytecode_vm::{ErrorKind, InterpretResult, NumberFormat, OutputBuffer, Value, VM};
+
+#[test]
+fn runs_scripts_into_a_writer() {
+    let out = OutputBuffer::new();
+    let mut vm = VM::with_writer(Box::new(out.clone()));
+    assert!(vm.run_script("print 1 + 2; write(4);".to_string()) == InterpretResult::Ok);
+    assert_eq!(out.contents(), "3\n4");
+}
+
+#[test]
+fn runs_scripts_that_share_globals() {
+    let mut vm = VM::default();
+    assert!(vm.run_script("var base = 40;".to_string()) == InterpretResult::Ok);
+    assert!(vm.eval("var answer = base + 2; answer;".to_string()) == Ok(Value::Int(42)));
+    assert_eq!(
+        vm.globals(),
+        vec![
+            ("answer".to_string(), Value::Int(42)),
+            ("base".to_string(), Value::Int(40)),
+        ]
+    );
+}
+
+#[test]
+fn reports_errors_with_their_kind() {
+    let mut vm = VM::new();
+    assert!(vm.run_script("print ;".to_string()) == InterpretResult::CompileError);
+    assert!(matches!(
+        vm.run_script("print 1 / 0;".to_string()),
+        InterpretResult::RuntimeError(ErrorKind::DivByZero, _)
+    ));
+    assert!(matches!(
+        vm.run_script("assert(false);".to_string()),
+        InterpretResult::RuntimeError(ErrorKind::Assertion, _)
+    ));
+}
+
+#[test]
+fn formats_numbers() {
+    let mut vm = VM::new();
+    vm.set_number_format(NumberFormat {
+        thousands: Some(','),
+        decimal: '.',
+    });
+    assert_eq!(vm.format_value(&Value::Int(1234567)), "1,234,567");
+    assert_eq!(vm.format_value(&Value::Nil), "nil");
+}
+
+#[test]
+fn round_trips_globals_through_a_script() {
+    let out = OutputBuffer::new();
+    let mut vm = VM::with_writer(Box::new(out.clone()));
+    assert_eq!(vm.get_global("config"), None);
+    vm.set_global("config", Value::String("debug".to_string()));
+    vm.set_global("limit", Value::Int(3));
+    vm.set_global("limit", Value::Int(5));
+    assert!(
+        vm.run_script(
+            "print config; var result = config + \"!\"; var doubled = limit * 2;".to_string()
+        ) == InterpretResult::Ok
+    );
+    assert_eq!(out.contents(), "debug\n");
+    assert_eq!(
+        vm.get_global("result"),
+        Some(Value::String("debug!".to_string()))
+    );
+    assert_eq!(vm.get_global("doubled"), Some(Value::Int(10)));
+    assert_eq!(vm.get_global("missing"), None);
+}
+
+// Reruns this test binary on just this test, uncaptured, so the child's real
+// stderr can be checked for anything the VM printed.
+#[test]
+fn silenced_runtime_errors_print_nothing() {
+    if std::env::var_os("CI_VM_SILENCED_CHILD").is_some() {
+        let mut vm = VM::new();
+        vm.set_report_errors(false);
+        match vm.run_script("fun f() { return nil + 1; }\nf();".to_string()) {
+            InterpretResult::RuntimeError(ErrorKind::Type, report) => print!("{}", report),
+            _ => panic!("expected a type error"),
+        }
+        return;
+    }
+
+    let output = std::process::Command::new(std::env::current_exe().unwrap())
+        .args([
+            "--exact",
+            "silenced_runtime_errors_print_nothing",
+            "--nocapture",
+        ])
+        .env("CI_VM_SILENCED_CHILD", "1")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert_eq!(String::from_utf8_lossy(&output.stderr), "");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("Operands of '+'"), "{}", stdout);
+    assert!(stdout.contains("[line 1:col 22] in f\n"), "{}", stdout);
+    assert!(stdout.contains("[line 2:col 3] in script\n"), "{}", stdout);
+}
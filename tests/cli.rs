@@ -0,0 +1,267 @@
+// Runs the interpreter binary the way a user would.
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+
+fn run_with_stdin(args: &[&str], stdin: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_ci-bytecode-vm"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("failed to start the interpreter");
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(stdin.as_bytes())
+        .unwrap();
+    child.wait_with_output().unwrap()
+}
+
+// Writes `source` to a script file unique to this test run.
+fn script(name: &str, source: &str) -> std::path::PathBuf {
+    let path = std::env::temp_dir().join(format!("ci-cli-{}-{}.lox", std::process::id(), name));
+    std::fs::write(&path, source).unwrap();
+    path
+}
+
+#[test]
+fn failed_assertions_exit_with_71() {
+    let path = script("assert", "print 1;\nassert(false);\nprint 2;\n");
+    let output = run_with_stdin(&[path.to_str().unwrap()], "");
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(output.status.code(), Some(71));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "1\n");
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Assertion failed"));
+
+    let path = script("runtime", "print nil + 1;\n");
+    let output = run_with_stdin(&[path.to_str().unwrap()], "");
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(output.status.code(), Some(70));
+
+    let path = script("compile", "print ;\n");
+    let output = run_with_stdin(&[path.to_str().unwrap()], "");
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(output.status.code(), Some(65));
+}
+
+#[test]
+fn runs_scripts_starting_with_a_shebang() {
+    let path = script("shebang", "#!/usr/bin/env rlox\nprint 1 + 2;\n");
+    let output = run_with_stdin(&[path.to_str().unwrap()], "");
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "3\n");
+}
+
+#[test]
+fn keywords_used_as_names_are_named() {
+    let cases = [
+        ("var class = 1;", "class", "a variable"),
+        ("const while = 1;", "while", "a constant"),
+        ("fun return() {}", "return", "a function"),
+        ("class if {}", "if", "a class"),
+        ("fun f(for) {}", "for", "a parameter"),
+        ("class A { var() {} }", "var", "a method"),
+        ("var a; a.this;", "this", "a property"),
+    ];
+    for (i, (source, keyword, kind)) in cases.into_iter().enumerate() {
+        let path = script(&format!("keyword-{}", i), source);
+        let output = run_with_stdin(&[path.to_str().unwrap()], "");
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(output.status.code(), Some(65), "{}", source);
+        // One error: parsing carries on after the keyword.
+        assert_eq!(
+            String::from_utf8_lossy(&output.stderr),
+            format!(
+                "[line 1:col {2}] Error at '{0}': '{0}' is a reserved keyword and cannot be used as {1} name.\n",
+                keyword,
+                kind,
+                source.find(keyword).unwrap() + 1
+            ),
+            "{}",
+            source
+        );
+    }
+
+    let path = script("keyword-prefix", "var classy = 1; var for_each = classy;");
+    let output = run_with_stdin(&[path.to_str().unwrap()], "");
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(output.status.code(), Some(0));
+}
+
+#[test]
+fn print_takes_one_or_more_values() {
+    let source = "print(1);
+         print(1, \"a\", nil);
+         print 1, 2;
+         print (1), 2;
+         print (1 + 2) * 3;
+         print();";
+    let path = script("print", source);
+    let output = run_with_stdin(&[path.to_str().unwrap()], "");
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "1\n1 a nil\n1 2\n1 2\n9\n\n"
+    );
+
+    let path = script("print-nothing", "print;");
+    let output = run_with_stdin(&[path.to_str().unwrap()], "");
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(output.status.code(), Some(65));
+}
+
+#[test]
+fn write_prints_without_a_newline() {
+    let source = "write(\"a\"); write(\"b\");
+         write(1.5); write(nil); write([1, \"x\"]); print \"\";";
+    let path = script("write", source);
+    let output = run_with_stdin(&[path.to_str().unwrap()], "");
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "ab1.5nil[1, x]\n");
+}
+
+#[test]
+fn local_arity_mismatches_are_compile_errors() {
+    let path = script("arity", "print 1;\n{ fun f(a) {} f(1, 2); }\n");
+    let output = run_with_stdin(&[path.to_str().unwrap()], "");
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(output.status.code(), Some(65));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "");
+    assert!(String::from_utf8_lossy(&output.stderr)
+        .contains("[line 2:col 21] Error at ')': Expected 1 arguments but got 2."));
+}
+
+#[test]
+fn unbounded_recursion_is_a_runtime_error() {
+    // `f();` is not in tail position. `return f();` would be a tail call,
+    // which reuses its frame and so loops forever, like `while (true) {}`.
+    let path = script(
+        "recursion",
+        "fun f() { f(); }\nprint \"before\";\nf();\nprint \"after\";\n",
+    );
+    let output = run_with_stdin(&[path.to_str().unwrap()], "");
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(output.status.code(), Some(70));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "before\n");
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Stack overflow"));
+
+    let path = script(
+        "mutual",
+        "class A { init() { B(); } }\nclass B { init() { A(); } }\nA();\n",
+    );
+    let output = run_with_stdin(&[path.to_str().unwrap()], "");
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(output.status.code(), Some(70));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Stack overflow"));
+}
+
+#[test]
+fn operand_errors_point_at_the_operator() {
+    let cases = [
+        (
+            "plus",
+            "var a = 1;\nvar b =\n  a\n  +\n  \"x\";\n",
+            "[line 4:col 3] in script",
+        ),
+        (
+            "times",
+            "fun f(a) {\n  return a\n    * nil;\n}\nf(2);\n",
+            "[line 3:col 5] in f",
+        ),
+        (
+            "less",
+            "print 1 <\n\n  \"two\";\n",
+            "[line 1:col 9] in script",
+        ),
+    ];
+    for (name, source, location) in cases {
+        let path = script(name, source);
+        let output = run_with_stdin(&[path.to_str().unwrap()], "");
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(output.status.code(), Some(70), "{}", name);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.contains(location), "{}", stderr);
+    }
+}
+
+#[test]
+fn compiles_scripts_to_bytecode_files_that_run() {
+    let path = script(
+        "compile-src",
+        "var s = \"str\"; var i = 42; var f = 2.5; var t = true; var n = nil;\n\
+         fun outer(x) { fun inner() { return x * 2; } return inner; }\n\
+         class Box { get() { return \"boxed\"; } }\n\
+         print(s, i, f, t, n, outer(21)(), Box().get());\n",
+    );
+    let bytecode = path.with_extension("lbc");
+    let output = run_with_stdin(
+        &[
+            "--compile",
+            bytecode.to_str().unwrap(),
+            path.to_str().unwrap(),
+        ],
+        "",
+    );
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(output.status.code(), Some(0));
+    assert!(output.stdout.is_empty());
+
+    let bytes = std::fs::read(&bytecode).unwrap();
+    assert_eq!(&bytes[..5], b"LBC\0\x02");
+
+    let output = run_with_stdin(&[bytecode.to_str().unwrap()], "");
+    std::fs::remove_file(&bytecode).unwrap();
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "str 42 2.5 true nil 42 boxed\n"
+    );
+
+    let path = script("compile-bad", "print ;\n");
+    let bytecode = path.with_extension("lbc");
+    let output = run_with_stdin(
+        &[
+            "--compile",
+            bytecode.to_str().unwrap(),
+            path.to_str().unwrap(),
+        ],
+        "",
+    );
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(output.status.code(), Some(65));
+    assert!(!bytecode.exists());
+}
+
+#[test]
+fn repl_exits_cleanly_on_empty_stdin() {
+    let output = run_with_stdin(&[], "");
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "> \n");
+}
+
+#[test]
+fn repl_exits_after_the_last_line() {
+    let output = run_with_stdin(&[], "print 1;\n");
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(String::from_utf8_lossy(&output.stdout), "> 1\n> \n");
+}
+
+#[test]
+fn repl_continues_incomplete_statements() {
+    let output = run_with_stdin(
+        &[],
+        "fun f() {\n  return \"a\nb\";\n}\nprint f();\nprint (1 +\n\nprint 2;\n",
+    );
+    assert_eq!(output.status.code(), Some(0));
+    // A blank line runs the incomplete statement, error and all.
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        "> ... ... ... > a\nb\n> ... > 2\n> \n"
+    );
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Expect expression."));
+}